@@ -0,0 +1,61 @@
+//! Compares naive and flash attention at a 512-token context on the CPU. 
+//! Peak memory is the process's resident set high-water mark, which is reset between runs 
+//! through `/proc/self/clear_refs`, so this bench only reports memory on Linux.
+
+use std::fs;
+use std::time::Instant;
+
+use burn::tensor::{Tensor, Distribution};
+use burn_tch::TchBackend;
+
+use stablediffusion::model::attention::{qkv_attention, flash_attention};
+
+type Backend = TchBackend<f32>;
+
+fn reset_peak_memory() {
+    let _ = fs::write("/proc/self/clear_refs", "5");
+}
+
+fn peak_memory_kib() -> Option<u64> {
+    fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find(|line| line.starts_with("VmHWM:"))?
+        .split_whitespace()
+        .nth(1)?
+        .parse()
+        .ok()
+}
+
+fn report(name: &str, f: impl FnOnce() -> Tensor<Backend, 3>) {
+    reset_peak_memory();
+    let baseline = peak_memory_kib();
+
+    let start = Instant::now();
+    let output = f();
+    let _ = output.into_data();
+    let elapsed = start.elapsed();
+
+    match (baseline, peak_memory_kib()) {
+        (Some(baseline), Some(peak)) => println!("{:>8}: {:>8.1?}, peak memory +{} MiB", name, elapsed, peak.saturating_sub(baseline) / 1024),
+        _ => println!("{:>8}: {:>8.1?}", name, elapsed),
+    }
+}
+
+fn main() {
+    let n_batch = 2;
+    let n_ctx = 512;
+    let n_state = 640;
+    let n_head = 10;
+
+    let q: Tensor<Backend, 3> = Tensor::random([n_batch, n_ctx, n_state], Distribution::Normal(0.0, 1.0));
+    let k: Tensor<Backend, 3> = Tensor::random([n_batch, n_ctx, n_state], Distribution::Normal(0.0, 1.0));
+    let v: Tensor<Backend, 3> = Tensor::random([n_batch, n_ctx, n_state], Distribution::Normal(0.0, 1.0));
+
+    println!("Self-attention over {} tokens, {} heads, batch of {}", n_ctx, n_head, n_batch);
+
+    report("naive", || qkv_attention(q.clone(), k.clone(), v.clone(), None, n_head));
+    for block_size in [64, 128, 256] {
+        report(&format!("flash {}", block_size), || flash_attention(q.clone(), k.clone(), v.clone(), n_head, block_size));
+    }
+}
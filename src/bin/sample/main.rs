@@ -6,7 +6,7 @@ use stablediffusion::model::unet::{UNet, UNetConfig, load::load_unet};
 use stablediffusion::model::autoencoder::{Decoder, DecoderConfig, load::load_decoder};
 use stablediffusion::model::autoencoder::{Encoder, EncoderConfig, load::load_encoder};
 use stablediffusion::model::clip::{CLIP, CLIPConfig, load::load_clip_text_transformer};
-use stablediffusion::model::stablediffusion::{RESOLUTIONS, offset_cosine_schedule_cumprod, Embedder, EmbedderConfig, Diffuser, DiffuserConfig, LatentDecoder, LatentDecoderConfig, load::*};
+use stablediffusion::model::stablediffusion::{SdxlResolution, offset_cosine_schedule_cumprod, Embedder, EmbedderConfig, Diffuser, DiffuserConfig, Refiner, RefinerConfig, LatentDecoder, LatentDecoderConfig, load::*};
 
 use burn::{
     config::Config, 
@@ -19,35 +19,53 @@ use burn::{
     },
 };
 
-use burn_tch::{TchBackend, TchDevice};
+cfg_if::cfg_if! {
+    if #[cfg(feature = "wgpu")] {
+        use burn_wgpu::{WgpuBackend, WgpuDevice, AutoGraphicsApi};
 
-use burn::record::{self, Recorder, BinFileRecorder, HalfPrecisionSettings};
+        type DefaultBackend = WgpuBackend<AutoGraphicsApi, f32, i32>;
+        // burn-wgpu has no half precision support, so the diffuser runs in f32
+        type DefaultBackend_f16 = WgpuBackend<AutoGraphicsApi, f32, i32>;
+
+        fn default_device() -> WgpuDevice {
+            WgpuDevice::BestAvailable
+        }
+    } else {
+        use burn_tch::{TchBackend, TchDevice};
 
-fn load_embedder_model<B: Backend>(model_name: &str) -> Result<Embedder<B>, Box<dyn Error>> {
-    let config = EmbedderConfig::load(&format!("{}.cfg", model_name))?;
-    let record = BinFileRecorder::<HalfPrecisionSettings>::new()
-        .load(model_name.into())?;
+        type DefaultBackend = TchBackend<f32>;
+        type DefaultBackend_f16 = TchBackend<tensor::f16>;
+
+        fn default_device() -> TchDevice {
+            TchDevice::Cuda(0)
+        }
+    }
+}
+
+use burn::record::{self, Recorder, BinFileRecorder, HalfPrecisionSettings};
+use stablediffusion::model::load::{LoadError, load_config, load_record};
 
-    Ok( config.init().load_record(record) )
+fn load_embedder_model<B: Backend>(model_name: &str) -> Result<Embedder<B>, LoadError> {
+    let config: EmbedderConfig = load_config(&format!("{}.cfg", model_name))?;
+    load_record(config.init(), model_name)
 }
 
-fn load_diffuser_model<B: Backend>(model_name: &str) -> Result<Diffuser<B>, Box<dyn Error>> {
-    let config = DiffuserConfig::load(&format!("{}.cfg", model_name))?;
-    let record = BinFileRecorder::<HalfPrecisionSettings>::new()
-        .load(model_name.into())?;
-    
-    Ok( config.init().load_record(record) )
+fn load_diffuser_model<B: Backend>(model_name: &str) -> Result<Diffuser<B>, LoadError> {
+    let config: DiffuserConfig = load_config(&format!("{}.cfg", model_name))?;
+    load_record(config.init(), model_name)
 }
 
-fn load_latent_decoder_model<B: Backend>(model_name: &str) -> Result<LatentDecoder<B>, Box<dyn Error>> {
-    let config = LatentDecoderConfig::load(&format!("{}.cfg", model_name))?;
-    let record = BinFileRecorder::<HalfPrecisionSettings>::new()
-        .load(model_name.into())?;
+fn load_refiner_model<B: Backend>(model_name: &str) -> Result<Refiner<B>, LoadError> {
+    let config: RefinerConfig = load_config(&format!("{}.cfg", model_name))?;
+    load_record(config.init(), model_name)
+}
 
-    Ok( config.init().load_record(record) )
+fn load_latent_decoder_model<B: Backend>(model_name: &str) -> Result<LatentDecoder<B>, LoadError> {
+    let config: LatentDecoderConfig = load_config(&format!("{}.cfg", model_name))?;
+    load_record(config.init(), model_name)
 }
 
-use stablediffusion::helper::to_float;
+use stablediffusion::helper::{to_float, tensor_to_backend};
 
 fn arb_tensor<B: Backend, const D: usize>(dims: [usize; D]) -> Tensor<B, D> {
     let prod = dims.iter().cloned().product();
@@ -60,19 +78,11 @@ use num_traits::cast::ToPrimitive;
 use stablediffusion::model::stablediffusion::Conditioning;
 use burn::tensor::ElementConversion;
 
-fn switch_backend<B1: Backend, B2: Backend, const D: usize>(x: Tensor<B1, D>, device: &B2::Device) -> Tensor<B2, D> {
-    let data = x.into_data();
-
-    let data = tensor::Data::new(data.value.into_iter().map(|v| v.elem()).collect(), data.shape);
-
-    Tensor::from_data_device(data, device)
-}
-
 fn main() {
-    type Backend = TchBackend<f32>;
-    type Backend_f16 = TchBackend<tensor::f16>;
+    type Backend = DefaultBackend;
+    type Backend_f16 = DefaultBackend_f16;
 
-    let device = TchDevice::Cuda(0);
+    let device = default_device();
 
     let args: Vec<String> = std::env::args().collect();
     if args.len() != 6 {
@@ -94,41 +104,60 @@ fn main() {
 
     let conditioning = {
         println!("Loading embedder...");
-        let embedder: Embedder<Backend> = load_embedder_model(&format!("{}/embedder", model_name)).unwrap();
+        let embedder: Embedder<Backend> = load_embedder_model(&format!("{}/embedder", model_name)).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        });
         let embedder = embedder.to_device(&device);
 
-        let resolution = [1024, 1024]; //RESOLUTIONS[8];
+        let resolution = SdxlResolution::Square1024.dims();
 
         let size = Tensor::from_ints(resolution).to_device(&device).unsqueeze();
         let crop = Tensor::from_ints([0, 0]).to_device(&device).unsqueeze();
         let ar = Tensor::from_ints(resolution).to_device(&device).unsqueeze();
 
         println!("Running embedder...");
-        embedder.text_to_conditioning(prompt, size, crop, ar)
+        embedder.text_to_conditioning(prompt, size, crop, ar, 1).unwrap()
     };
 
-    let conditioning = Conditioning {
-        unconditional_context: switch_backend::<Backend, Backend_f16, 2>(conditioning.unconditional_context, &device), 
-        context: switch_backend::<Backend, Backend_f16, 3>(conditioning.context, &device), 
-        unconditional_channel_context: switch_backend::<Backend, Backend_f16, 1>(conditioning.unconditional_channel_context, &device), 
-        channel_context: switch_backend::<Backend, Backend_f16, 2>(conditioning.channel_context, &device), 
-        resolution: conditioning.resolution, 
-    };
+    let conditioning = conditioning.to_backend::<Backend_f16>(&device);
 
     let latent = {
         println!("Loading diffuser...");
-        let diffuser: Diffuser<Backend_f16> = load_diffuser_model(&format!("{}/diffuser", model_name)).unwrap();
+        let diffuser: Diffuser<Backend_f16> = load_diffuser_model(&format!("{}/diffuser", model_name)).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        });
         let diffuser = diffuser.to_device(&device);
 
-        println!("Running diffuser...");
-        diffuser.sample_latent(conditioning, unconditional_guidance_scale, n_steps)
+        // finish the last 20% of the steps with the refiner if one was converted
+        let refiner_name = format!("{}/refiner", model_name);
+        if std::path::Path::new(&format!("{}.cfg", refiner_name)).exists() {
+            println!("Loading refiner...");
+            let refiner: Refiner<Backend_f16> = load_refiner_model(&refiner_name).unwrap_or_else(|e| {
+                eprintln!("Error: {}", e);
+                process::exit(1);
+            });
+            let refiner = refiner.to_device(&device);
+
+            let refiner_steps = n_steps / 5;
+
+            println!("Running diffuser with refiner...");
+            diffuser.sample_latent_with_refiner(conditioning, &refiner, n_steps - refiner_steps, refiner_steps, unconditional_guidance_scale, 0.0)
+        } else {
+            println!("Running diffuser...");
+            diffuser.sample_latent(conditioning, unconditional_guidance_scale, 0.0, n_steps)
+        }
     };
 
-    let latent = switch_backend::<Backend_f16, Backend, 4>(latent, &device);
+    let latent = tensor_to_backend::<Backend_f16, Backend, 4>(latent, &device);
 
     let images = {
         println!("Loading latent decoder...");
-        let latent_decoder: LatentDecoder<Backend> = load_latent_decoder_model(&format!("{}/latent_decoder", model_name)).unwrap();
+        let latent_decoder: LatentDecoder<Backend> = load_latent_decoder_model(&format!("{}/latent_decoder", model_name)).unwrap_or_else(|e| {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        });
         let latent_decoder = latent_decoder.to_device(&device);
 
         println!("Running decoder...");
@@ -136,7 +165,7 @@ fn main() {
     };
 
     println!("Saving images...");
-    save_images(&images.buffer, output_image_name, images.width as u32, images.height as u32).unwrap();
+    save_images(&images, output_image_name).unwrap();
     println!("Done.");
 
     return;
@@ -144,11 +173,15 @@ fn main() {
 
 
 use image::{self, ImageResult, ColorType::Rgb8};
+use stablediffusion::model::stablediffusion::RawImages;
+
+/// Saves RGB images, or single-channel ones such as those of `LatentDecoder::latent_to_grayscale`.
+fn save_images(images: &RawImages, basepath: &str) -> Result<(), Box<dyn Error>> {
+    let color = images.color_type().ok_or_else(|| format!("Can't save images with {} channels.", images.channels))?;
 
-fn save_images(images: &Vec<Vec<u8>>, basepath: &str, width: u32, height: u32) -> ImageResult<()> {
-    for (index, img_data) in images.iter().enumerate() {
+    for (index, img_data) in images.buffer.iter().enumerate() {
         let path = format!("{}{}.png", basepath, index);
-        image::save_buffer(path, &img_data[..], width, height, Rgb8)?;
+        image::save_buffer(path, &img_data[..], images.width as u32, images.height as u32, color)?;
     }
 
     Ok(())
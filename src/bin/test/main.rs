@@ -22,32 +22,24 @@ use burn::{
 use burn_tch::{TchBackend, TchDevice};
 
 use burn::record::{self, Recorder, BinFileRecorder, HalfPrecisionSettings};
+use stablediffusion::model::load::{LoadError, load_config, load_record};
 
-fn load_embedder_model<B: Backend>(model_name: &str) -> Result<Embedder<B>, Box<dyn Error>> {
-    let config = EmbedderConfig::load(&format!("{}.cfg", model_name))?;
-    let record = BinFileRecorder::<HalfPrecisionSettings>::new()
-        .load(model_name.into())?;
-
-    Ok( config.init().load_record(record) )
+fn load_embedder_model<B: Backend>(model_name: &str) -> Result<Embedder<B>, LoadError> {
+    let config: EmbedderConfig = load_config(&format!("{}.cfg", model_name))?;
+    load_record(config.init(), model_name)
 }
 
-fn load_diffuser_model<B: Backend>(model_name: &str) -> Result<Diffuser<B>, Box<dyn Error>> {
-    let config = DiffuserConfig::load(&format!("{}.cfg", model_name))?;
-    let record = BinFileRecorder::<HalfPrecisionSettings>::new()
-        .load(model_name.into())?;
-    
-    Ok( config.init().load_record(record) )
+fn load_diffuser_model<B: Backend>(model_name: &str) -> Result<Diffuser<B>, LoadError> {
+    let config: DiffuserConfig = load_config(&format!("{}.cfg", model_name))?;
+    load_record(config.init(), model_name)
 }
 
-fn load_latent_decoder_model<B: Backend>(model_name: &str) -> Result<LatentDecoder<B>, Box<dyn Error>> {
-    let config = LatentDecoderConfig::load(&format!("{}.cfg", model_name))?;
-    let record = BinFileRecorder::<HalfPrecisionSettings>::new()
-        .load(model_name.into())?;
-
-    Ok( config.init().load_record(record) )
+fn load_latent_decoder_model<B: Backend>(model_name: &str) -> Result<LatentDecoder<B>, LoadError> {
+    let config: LatentDecoderConfig = load_config(&format!("{}.cfg", model_name))?;
+    load_record(config.init(), model_name)
 }
 
-use stablediffusion::helper::to_float;
+use stablediffusion::helper::{to_float, tensor_to_backend};
 
 fn arb_tensor<B: Backend, const D: usize>(dims: [usize; D]) -> Tensor<B, D> {
     let prod = dims.iter().cloned().product();
@@ -156,109 +148,211 @@ fn test_tiny_decoder<B: Backend>(device: &B::Device) {
 
 use num_traits::cast::ToPrimitive;
 use stablediffusion::model::stablediffusion::Conditioning;
+use stablediffusion::model::stablediffusion::latent_io::{save_latent, load_latent};
 use burn::tensor::ElementConversion;
 
-fn switch_backend<B1: Backend, B2: Backend, const D: usize>(x: Tensor<B1, D>, device: &B2::Device) -> Tensor<B2, D> {
-    let data = x.into_data();
-
-    let data = tensor::Data::new(data.value.into_iter().map(|v| v.elem()).collect(), data.shape);
-
-    Tensor::from_data_device(data, device)
+const USAGE: &str = "\
+Usage:
+    test [OPTIONS]
+    test convert <dump_path> [--model-dir <dir>]
+
+Options:
+    --prompt <text>       The prompt [default: \"A beautiful photo of a seaside bluff.\"]
+    --negative <text>     The negative prompt [default: \"\"]
+    --steps <n>           Number of diffusion steps [default: 30]
+    --guidance <scale>    Classifier-free guidance scale [default: 7.5]
+    --seed <n>            Sampling seed [default: random]
+    --width <px>          Image width, rounded to a multiple of 8 [default: 1024]
+    --height <px>         Image height, rounded to a multiple of 8 [default: 1024]
+    --out <path>          Base path of the saved images, numbered per batch entry [default: img]
+    --model-dir <dir>     Folder holding the converted embedder, diffuser and latent decoder [default: .]
+    --latent <path>       Decodes the latent saved at <path> if it exists, otherwise saves the sampled latent there
+    -h, --help            Prints this message";
+
+struct GenerateArgs {
+    prompt: String, 
+    negative: String, 
+    steps: usize, 
+    guidance: f64, 
+    seed: Option<u64>, 
+    width: usize, 
+    height: usize, 
+    out: String, 
+    model_dir: String, 
+    latent: Option<String>, 
 }
 
-fn main() {
-    //type Backend = NdArrayBackend<f32>;
-    //let device = NdArrayDevice::Cpu;
-
-    type Backend = TchBackend<f32>;
-    type Backend_f16 = TchBackend<tensor::f16>;
+enum Command {
+    Generate(GenerateArgs), 
+    Convert { dump_path: String, model_dir: String }, 
+    Help, 
+}
 
-    let cpu_device = TchDevice::Cpu;
-    let device = /*TchDevice::Cpu;*/ TchDevice::Cuda(0);
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut args = args.iter();
+
+    if args.as_slice().first().map(|arg| arg.as_str()) == Some("convert") {
+        args.next();
+
+        let mut dump_path = None;
+        let mut model_dir = ".".to_string();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--model-dir" => model_dir = flag_value(arg, args.next())?, 
+                "-h" | "--help" => return Ok(Command::Help), 
+                _ if arg.starts_with('-') => return Err( format!("Unknown option '{}' for convert.", arg) ), 
+                _ if dump_path.is_none() => dump_path = Some( arg.clone() ), 
+                _ => return Err( format!("Unexpected argument '{}'.", arg) ), 
+            }
+        }
+
+        let dump_path = dump_path.ok_or("convert needs the path of a weight dump folder.")?;
+        return Ok( Command::Convert { dump_path, model_dir } );
+    }
 
-    //test_clip::<Backend>(&device);
-    //test_tiny_open_clip::<Backend>(&device);
-    //test_open_clip::<Backend>(&device);
+    let mut generate = GenerateArgs {
+        prompt: "A beautiful photo of a seaside bluff.".into(), 
+        negative: String::new(), 
+        steps: 30, 
+        guidance: 7.5, 
+        seed: None, 
+        width: 1024, 
+        height: 1024, 
+        out: "img".into(), 
+        model_dir: ".".into(), 
+        latent: None, 
+    };
 
-    let text = "A beautiful photo of a seaside bluff.";
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--prompt" => generate.prompt = flag_value(arg, args.next())?, 
+            "--negative" => generate.negative = flag_value(arg, args.next())?, 
+            "--steps" => generate.steps = flag_value(arg, args.next())?, 
+            "--guidance" => generate.guidance = flag_value(arg, args.next())?, 
+            "--seed" => generate.seed = Some( flag_value(arg, args.next())? ), 
+            "--width" => generate.width = flag_value(arg, args.next())?, 
+            "--height" => generate.height = flag_value(arg, args.next())?, 
+            "--out" => generate.out = flag_value(arg, args.next())?, 
+            "--model-dir" => generate.model_dir = flag_value(arg, args.next())?, 
+            "--latent" => generate.latent = Some( flag_value(arg, args.next())? ), 
+            "-h" | "--help" => return Ok(Command::Help), 
+            _ => return Err( format!("Unknown argument '{}'.", arg) ), 
+        }
+    }
 
-    let conditioning = {
-        println!("Loading embedder...");
-        let embedder: Embedder<Backend> = load_embedder_model("embedder").unwrap();
-        let embedder = embedder.to_device(&device);
+    if generate.steps == 0 {
+        return Err( "--steps must be at least 1.".into() );
+    }
+    if generate.width < 8 || generate.height < 8 {
+        return Err( "--width and --height must be at least 8.".into() );
+    }
 
-        let resolution = RESOLUTIONS[8];
+    Ok( Command::Generate(generate) )
+}
 
-        let size = Tensor::from_ints(resolution).to_device(&device).unsqueeze();
-        let crop = Tensor::from_ints([0, 0]).to_device(&device).unsqueeze();
-        let ar = Tensor::from_ints(resolution).to_device(&device).unsqueeze();
+fn flag_value<T: std::str::FromStr>(flag: &str, value: Option<&String>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("{} needs a value.", flag))?;
+    value.parse().map_err(|_| format!("Invalid value '{}' for {}.", value, flag))
+}
 
-        println!("Running embedder...");
-        embedder.text_to_conditioning(text, size, crop, ar)
-    };
+fn main() {
+    type Backend = TchBackend<f32>;
+    type Backend_f16 = TchBackend<tensor::f16>;
 
-    let conditioning = Conditioning {
-        unconditional_context: switch_backend::<Backend, Backend_f16, 2>(conditioning.unconditional_context, &device), 
-        context: switch_backend::<Backend, Backend_f16, 3>(conditioning.context, &device), 
-        unconditional_channel_context: switch_backend::<Backend, Backend_f16, 1>(conditioning.unconditional_channel_context, &device), 
-        channel_context: switch_backend::<Backend, Backend_f16, 2>(conditioning.channel_context, &device), 
-        resolution: conditioning.resolution, 
+    let args: Vec<String> = env::args().skip(1).collect();
+    let command = parse_args(&args).unwrap_or_else(|e| {
+        eprintln!("Error: {}\n\n{}", e, USAGE);
+        process::exit(2);
+    });
+
+    let result = match command {
+        Command::Help => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        Command::Convert { dump_path, model_dir } => convert_dump::<Backend>(&dump_path, &model_dir, &TchDevice::Cpu), 
+        Command::Generate(args) => generate::<Backend, Backend_f16>(&args, &TchDevice::Cuda(0)), 
     };
 
-    let latent = {
-        println!("Loading diffuser...");
-        let diffuser: Diffuser<Backend_f16> = load_diffuser_model("diffuser").unwrap();
-        let diffuser = diffuser.to_device(&device);
-
-        let unconditional_guidance_scale = 7.5;
-        let n_steps = 30;
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
 
-        println!("Running diffuser...");
-        diffuser.sample_latent(conditioning, unconditional_guidance_scale, n_steps)
+fn generate<B: Backend, B16: Backend<Device = B::Device>>(args: &GenerateArgs, device: &B::Device) -> Result<(), Box<dyn Error>> {
+    let model_path = |name: &str| format!("{}/{}", args.model_dir, name);
+
+    // a saved latent lets the decoder be tested without rerunning diffusion
+    let saved_latent = args.latent.as_deref().filter(|path| std::path::Path::new(path).exists());
+    let latent = if let Some(path) = saved_latent {
+        println!("Loading saved latent...");
+        load_latent::<B>(path, device)?
+    } else {
+        let conditioning = {
+            println!("Loading embedder...");
+            let embedder: Embedder<B> = load_embedder_model(&model_path("embedder"))?;
+            let embedder = embedder.to_device(device);
+
+            let resolution = [args.height as i32, args.width as i32];
+
+            let size = Tensor::from_ints(resolution).to_device(device).unsqueeze();
+            let crop = Tensor::from_ints([0, 0]).to_device(device).unsqueeze();
+            let ar = Tensor::from_ints(resolution).to_device(device).unsqueeze();
+
+            println!("Running embedder...");
+            embedder.text_to_conditioning_with_negative(&args.prompt, &args.negative, size, crop, ar, 1)?
+        };
+
+        let conditioning = conditioning.to_backend::<B16>(device);
+
+        let latent = {
+            println!("Loading diffuser...");
+            let diffuser: Diffuser<B16> = load_diffuser_model(&model_path("diffuser"))?;
+            let diffuser = diffuser.to_device(device);
+            diffuser.check_conditioning(&conditioning)?;
+
+            println!("Running diffuser...");
+            match args.seed {
+                Some(seed) => diffuser.sample_latent_with_seed(conditioning, args.guidance, 0.0, args.steps, seed, 0.0), 
+                None => diffuser.sample_latent(conditioning, args.guidance, 0.0, args.steps), 
+            }
+        };
+
+        let latent = tensor_to_backend::<B16, B, 4>(latent, device);
+
+        if let Some(path) = args.latent.as_deref() {
+            save_latent(&latent, path)?;
+        }
+        latent
     };
 
-    let latent = switch_backend::<Backend_f16, Backend, 4>(latent, &device);
-
     let images = {
         println!("Loading latent decoder...");
-        let latent_decoder: LatentDecoder<Backend> = load_latent_decoder_model("latent_decoder").unwrap();
-        let latent_decoder = latent_decoder.to_device(&device);
+        let latent_decoder: LatentDecoder<B> = load_latent_decoder_model(&model_path("latent_decoder"))?;
+        let latent_decoder = latent_decoder.to_device(device);
 
         println!("Running decoder...");
         latent_decoder.latent_to_image(latent)
     };
 
     println!("Saving images...");
-    save_images(&images.buffer, "img", images.width as u32, images.height as u32).unwrap();
+    save_images(&images, &args.out)?;
     println!("Done.");
 
-    return;
-
-
-    /*let args: Vec<String> = env::args().collect();
-    if args.len() != 3 {
-        eprintln!("Usage: {} <dump_path> <model_name>", args[0]);
-        process::exit(1);
-    }
-
-    let dump_path = &args[1];
-    let model_name = &args[2];
-
-    if let Err(e) = convert_dump_to_model::<Backend>(dump_path, model_name, &device) {
-        eprintln!("Failed to convert dump to model: {:?}", e);
-        process::exit(1);
-    }
-
-    println!("Successfully converted {} to {}", dump_path, model_name);*/
+    Ok(())
 }
 
-
 use image::{self, ImageResult, ColorType::Rgb8};
+use stablediffusion::model::stablediffusion::RawImages;
+
+/// Saves RGB images, or single-channel ones such as those of `LatentDecoder::latent_to_grayscale`.
+fn save_images(images: &RawImages, basepath: &str) -> Result<(), Box<dyn Error>> {
+    let color = images.color_type().ok_or_else(|| format!("Can't save images with {} channels.", images.channels))?;
 
-fn save_images(images: &Vec<Vec<u8>>, basepath: &str, width: u32, height: u32) -> ImageResult<()> {
-    for (index, img_data) in images.iter().enumerate() {
+    for (index, img_data) in images.buffer.iter().enumerate() {
         let path = format!("{}{}.png", basepath, index);
-        image::save_buffer(path, &img_data[..], width, height, Rgb8)?;
+        image::save_buffer(path, &img_data[..], images.width as u32, images.height as u32, color)?;
     }
 
     Ok(())
@@ -1,4 +1,5 @@
 use burn::{
+    nn::conv::Conv2d, 
     tensor::{
         backend::Backend,
         activation::relu, 
@@ -10,6 +11,10 @@ use burn::{
         BasicOps, 
         Numeric, 
         Element, 
+        Distribution, 
+        Shape, 
+        Data, 
+        ElementConversion, 
     },
 };
 
@@ -84,4 +89,201 @@ pub fn reverse<B: Backend, const D: usize, K: TensorKind<B> + BasicOps<B> + Nume
 
 pub fn div_roundup(x: usize, y: usize) -> usize {
     (x + y - 1) / y
-}
\ No newline at end of file
+}
+
+pub fn tensor_to_vec<B: Backend, const D: usize>(x: Tensor<B, D>) -> Vec<f64> {
+    x.into_data()
+        .value
+        .into_iter()
+        .map(|v| v.to_f64().unwrap())
+        .collect()
+}
+
+/// Standard normal noise created on the backend's default device and then moved to `device`. 
+/// With the `wgpu` feature it is built from uniform noise with the Box-Muller transform, 
+/// since burn-wgpu does not implement the normal distribution.
+pub fn random_normal<B: Backend, const D: usize, S: Into<Shape<D>>>(shape: S, device: &B::Device) -> Tensor<B, D> {
+    let shape = shape.into();
+
+    let noise = if cfg!(feature = "wgpu") {
+        // 1 - u lies in (0, 1], keeping the logarithm finite
+        let u1: Tensor<B, D> = Tensor::random(shape.clone(), Distribution::Standard).mul_scalar(-1.0).add_scalar(1.0);
+        let u2: Tensor<B, D> = Tensor::random(shape, Distribution::Standard);
+
+        (u1.log() * -2.0).sqrt() * (u2 * (2.0 * std::f64::consts::PI)).cos()
+    } else {
+        Tensor::random(shape, Distribution::Normal(0.0, 1.0))
+    };
+
+    noise.to_device(device)
+}
+
+/// Moves a float tensor to another backend, e.g. between f32 and f16, converting every element.
+pub fn tensor_to_backend<B1: Backend, B2: Backend, const D: usize>(x: Tensor<B1, D>, device: &B2::Device) -> Tensor<B2, D> {
+    let data = x.into_data();
+    let data = Data::new(data.value.into_iter().map(|v| v.elem()).collect(), data.shape);
+
+    Tensor::from_data_device(data, device)
+}
+
+/// Resizes the last two dimensions of `x` to `height` x `width` with bilinear interpolation, 
+/// sampling at pixel centers like PyTorch's `interpolate(..., mode="bilinear", align_corners=False)`.
+pub fn upsample_bilinear<B: Backend>(x: Tensor<B, 4>, height: usize, width: usize) -> Tensor<B, 4> {
+    let [_, _, in_height, in_width] = x.dims();
+    let device = x.device();
+
+    let x = interpolate_dim(x, 2, in_height, height, &device);
+    interpolate_dim(x, 3, in_width, width, &device)
+}
+
+fn interpolate_dim<B: Backend>(x: Tensor<B, 4>, dim: usize, in_len: usize, out_len: usize, device: &B::Device) -> Tensor<B, 4> {
+    let mut low = Vec::with_capacity(out_len);
+    let mut high = Vec::with_capacity(out_len);
+    let mut frac = Vec::with_capacity(out_len);
+
+    let scale = in_len as f64 / out_len as f64;
+    for i in 0..out_len {
+        let src = ((i as f64 + 0.5) * scale - 0.5).max(0.0).min((in_len - 1) as f64);
+        let i0 = src.floor() as usize;
+
+        low.push(i0 as i32);
+        high.push((i0 + 1).min(in_len - 1) as i32);
+        frac.push(src - i0 as f64);
+    }
+
+    let mut weight_shape = [1; 4];
+    weight_shape[dim] = out_len;
+    let weight: Tensor<B, 4> = Tensor::from_data_device(
+        Data::new(frac.into_iter().map(|f| f.elem()).collect(), Shape::new(weight_shape)), 
+        device
+    );
+
+    let low = x.clone().select(dim, Tensor::from_ints(&low[..]).to_device(device));
+    let high = x.select(dim, Tensor::from_ints(&high[..]).to_device(device));
+
+    low.clone() + (high - low) * weight
+}
+
+/// Pads the last two dimensions of `x` by `pad` on every side with the rows and columns of the opposite edge, 
+/// as if the image wrapped around.
+pub fn pad_circular<B: Backend>(x: Tensor<B, 4>, pad: usize) -> Tensor<B, 4> {
+    let [n_batch, n_channel, height, width] = x.dims();
+    if pad == 0 {
+        return x;
+    }
+    assert!(pad <= height && pad <= width, "Can't pad a {}x{} image circularly by {}.", height, width, pad);
+
+    let x = Tensor::cat(vec![
+        x.clone().slice([0..n_batch, 0..n_channel, height - pad..height, 0..width]), 
+        x.clone(), 
+        x.slice([0..n_batch, 0..n_channel, 0..pad, 0..width]), 
+    ], 2);
+
+    let height = height + 2 * pad;
+    Tensor::cat(vec![
+        x.clone().slice([0..n_batch, 0..n_channel, 0..height, width - pad..width]), 
+        x.clone(), 
+        x.slice([0..n_batch, 0..n_channel, 0..height, 0..pad]), 
+    ], 3)
+}
+
+/// Runs `conv`, which zero pads by `(kernel_size - 1) / 2` like all the 3x3 convolutions of the models, as if 
+/// it padded circularly instead, so the output wraps around at the edges like the input. The input is padded 
+/// circularly by twice the convolution's own padding, which covers strides 1 and 2, and the output rows and 
+/// columns that saw the zero padding are cropped.
+pub fn conv2d_circular<B: Backend>(conv: &Conv2d<B>, x: Tensor<B, 4>) -> Tensor<B, 4> {
+    let [_, _, kernel_size, _] = conv.weight.val().dims();
+    let padding = (kernel_size - 1) / 2;
+    if padding == 0 {
+        return conv.forward(x);
+    }
+
+    let [_, _, height, width] = x.dims();
+    let pad = 2 * padding;
+
+    let x = conv.forward( pad_circular(x, pad) );
+    let [n_batch, n_channel, out_height, _] = x.dims();
+
+    let stride = ((height + 2 * pad) as f64 / out_height as f64).round() as usize;
+    let offset = pad / stride;
+
+    x.slice([
+        0..n_batch, 
+        0..n_channel, 
+        offset..offset + div_roundup(height, stride), 
+        offset..offset + div_roundup(width, stride), 
+    ])
+}
+
+/// Rotates `x` along `dim` (2 for rows or 3 for columns) so that the first `shift` entries move to the end.
+pub fn roll<B: Backend>(x: Tensor<B, 4>, dim: usize, shift: usize) -> Tensor<B, 4> {
+    let dims = x.dims();
+    let shift = shift % dims[dim];
+
+    let mut head = dims.map(|d| 0..d);
+    let mut tail = head.clone();
+    head[dim] = 0..shift;
+    tail[dim] = shift..dims[dim];
+
+    Tensor::cat(vec![x.clone().slice(tail), x.slice(head)], dim)
+}
+
+/// `conv.forward(x)`, with circular padding if `tileable`, see `conv2d_circular`.
+pub fn conv2d_forward<B: Backend>(conv: &Conv2d<B>, x: Tensor<B, 4>, tileable: bool) -> Tensor<B, 4> {
+    if tileable {
+        conv2d_circular(conv, x)
+    } else {
+        conv.forward(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::nn;
+    use burn_tch::TchBackend;
+
+    type TestBackend = TchBackend<f32>;
+
+    #[test]
+    fn test_upsample_bilinear() {
+        let x: Tensor<TestBackend, 4> = Tensor::from_floats([[[[0.0, 4.0], [8.0, 12.0]]]]);
+        let upsampled = upsample_bilinear(x, 4, 4);
+
+        // matches torch.nn.functional.interpolate(x, size=(4, 4), mode="bilinear", align_corners=False)
+        let expected = [
+            0.0, 1.0, 3.0, 4.0, 
+            2.0, 3.0, 5.0, 6.0, 
+            6.0, 7.0, 9.0, 10.0, 
+            8.0, 9.0, 11.0, 12.0, 
+        ];
+
+        assert_eq!(upsampled.dims(), [1, 1, 4, 4]);
+        for (value, expected) in tensor_to_vec(upsampled).into_iter().zip(expected) {
+            assert!((value - expected).abs() < 1e-5, "{} != {}", value, expected);
+        }
+    }
+
+    #[test]
+    fn test_conv2d_circular() {
+        let x: Tensor<TestBackend, 4> = Tensor::random([1, 2, 8, 6], Distribution::Normal(0.0, 1.0));
+        let padded = pad_circular(x.clone(), 1);
+        assert_eq!(padded.dims(), [1, 2, 10, 8]);
+        let wrapped = padded.clone().slice([0..1, 0..2, 0..1, 1..7]) - x.clone().slice([0..1, 0..2, 7..8, 0..6]);
+        assert!(tensor_max_element(wrapped.abs()) < 1e-6);
+
+        // shifting the input around the edges shifts the output the same way
+        for stride in [1, 2] {
+            let conv = nn::conv::Conv2dConfig::new([2, 3], [3, 3])
+                .with_stride([stride, stride])
+                .with_padding(nn::PaddingConfig2d::Explicit(1, 1))
+                .init::<TestBackend>();
+
+            let out = conv2d_circular(&conv, x.clone());
+            assert_eq!(out.dims(), conv.forward(x.clone()).dims());
+
+            let rolled = conv2d_circular(&conv, roll(x.clone(), 3, 2 * stride));
+            assert!(tensor_max_element((rolled - roll(out, 3, 2)).abs()) < 1e-5);
+        }
+    }
+}
@@ -8,7 +8,22 @@ use burn::{
 
 use std::f32::NEG_INFINITY;
 
+use crate::helper::tensor_max;
+
 pub fn qkv_attention<B: Backend>(q: Tensor<B, 3>, k: Tensor<B, 3>, v: Tensor<B, 3>, mask: Option<Tensor<B, 2>>, n_head: usize) -> Tensor<B, 3> {
+    let [n_batch, n_ctx, n_state] = v.dims();
+    let n_hstate = n_state / n_head;
+
+    let w = qkv_attention_weights(q, k, mask, n_head);
+    let v = v.reshape([n_batch, n_ctx, n_head, n_hstate]).swap_dims(1, 2);
+
+    let o = w.matmul(v).swap_dims(1, 2).flatten(2, 3);
+
+    return o;
+}
+
+/// The softmax attention weights of `qkv_attention`, shaped `[n_batch, n_head, n_qctx, n_ctx]`.
+pub fn qkv_attention_weights<B: Backend>(q: Tensor<B, 3>, k: Tensor<B, 3>, mask: Option<Tensor<B, 2>>, n_head: usize) -> Tensor<B, 4> {
     let [n_batch, n_qctx, n_state] = q.dims();
     let [_, n_ctx, _] = k.dims();
 
@@ -17,7 +32,6 @@ pub fn qkv_attention<B: Backend>(q: Tensor<B, 3>, k: Tensor<B, 3>, v: Tensor<B,
 
     let q = q.reshape([n_batch, n_qctx, n_head, n_hstate]).swap_dims(1, 2) * scale;
     let k = k.reshape([n_batch, n_ctx, n_head, n_hstate]).swap_dims(1, 2).transpose() * scale;
-    let v = v.reshape([n_batch, n_ctx, n_head, n_hstate]).swap_dims(1, 2);
 
     let qk = q.matmul(k);
 
@@ -29,10 +43,157 @@ pub fn qkv_attention<B: Backend>(q: Tensor<B, 3>, k: Tensor<B, 3>, v: Tensor<B,
     };
 
     // normalize value weightings
-    let w = softmax(qk, 3);
-    let o = w.matmul(v).swap_dims(1, 2).flatten(2, 3);
+    softmax(qk, 3)
+}
 
-    return o;
+/// Like `qkv_attention`, but keeps `q·kᵀ` from overflowing in f16. The scores and softmax are still computed in 
+/// the backend's float type, so the result matches `qkv_attention` up to that type's rounding, not an f32 softmax. 
+/// Large activations make `q·kᵀ` exceed the f16 range, and the resulting infinities turn the softmax 
+/// into NaNs. Here every query row and the keys of every head are divided by their largest magnitude before 
+/// the product, and the scores are only scaled back after subtracting their row maximum, so they can only 
+/// underflow towards -inf, where the softmax weight is 0 anyway. This costs two extra reductions over `q` and `k` 
+/// rather than an f32 copy of the scores. Processes the queries `slice_size` at a time if given, like `qkv_attention_sliced`.
+pub fn qkv_attention_upcast<B: Backend>(q: Tensor<B, 3>, k: Tensor<B, 3>, v: Tensor<B, 3>, mask: Option<Tensor<B, 2>>, n_head: usize, slice_size: Option<usize>) -> Tensor<B, 3> {
+    let [n_batch, n_qctx, n_state] = q.dims();
+    let [_, n_ctx, _] = k.dims();
+    let n_hstate = n_state / n_head;
+
+    let attend = |q: Tensor<B, 3>, mask: Option<Tensor<B, 2>>| {
+        let w = qkv_attention_weights_upcast(q, k.clone(), mask, n_head);
+        let v = v.clone().reshape([n_batch, n_ctx, n_head, n_hstate]).swap_dims(1, 2);
+
+        w.matmul(v).swap_dims(1, 2).flatten(2, 3)
+    };
+
+    let slice_size = slice_size.unwrap_or(n_qctx).max(1);
+    if slice_size >= n_qctx {
+        return attend(q, mask);
+    }
+
+    let slices = (0..n_qctx)
+        .step_by(slice_size)
+        .map(|start| {
+            let end = (start + slice_size).min(n_qctx);
+            let q_slice = q.clone().slice([0..n_batch, start..end, 0..n_state]);
+            let mask_slice = mask.clone().map(|mask| mask.slice([start..end, 0..n_ctx]));
+
+            attend(q_slice, mask_slice)
+        })
+        .collect();
+
+    Tensor::cat(slices, 1)
+}
+
+/// The softmax attention weights of `qkv_attention_upcast`, shaped `[n_batch, n_head, n_qctx, n_ctx]`.
+pub fn qkv_attention_weights_upcast<B: Backend>(q: Tensor<B, 3>, k: Tensor<B, 3>, mask: Option<Tensor<B, 2>>, n_head: usize) -> Tensor<B, 4> {
+    let [n_batch, n_qctx, n_state] = q.dims();
+    let [_, n_ctx, _] = k.dims();
+
+    let scale = (n_state as f64 / n_head as f64).powf(-0.25);
+    let n_hstate = n_state / n_head;
+
+    let q = q.reshape([n_batch, n_qctx, n_head, n_hstate]).swap_dims(1, 2);
+    let k = k.reshape([n_batch, n_ctx, n_head, n_hstate]).swap_dims(1, 2).transpose();
+
+    let q_scale = q.clone().abs().max_dim(3).add_scalar(1e-6);
+    let k_scale = k.clone().abs().max_dim(3).max_dim(2).add_scalar(1e-6);
+
+    // every score is now at most n_hstate in magnitude
+    let qk = (q / q_scale.clone()).matmul(k / k_scale.clone());
+
+    // the mask is 0 or -inf, so it can be applied before scaling back
+    let qk = if let Some(mask) = mask {
+        qk + mask.slice([0..n_qctx, 0..n_ctx]).unsqueeze::<4>()
+    } else {
+        qk
+    };
+
+    let qk = qk.clone() - qk.max_dim(3);
+    let qk = qk * q_scale.mul_scalar(scale) * k_scale.mul_scalar(scale);
+
+    softmax(qk, 3)
+}
+
+/// Same result as `qkv_attention`, but processes the queries `slice_size` at a time so that only 
+/// a `[n_batch, n_head, slice_size, n_ctx]` slice of the attention weights exists at any point.
+pub fn qkv_attention_sliced<B: Backend>(q: Tensor<B, 3>, k: Tensor<B, 3>, v: Tensor<B, 3>, mask: Option<Tensor<B, 2>>, n_head: usize, slice_size: usize) -> Tensor<B, 3> {
+    let [n_batch, n_qctx, n_state] = q.dims();
+    let [_, n_ctx, _] = k.dims();
+
+    let slice_size = slice_size.max(1);
+    if slice_size >= n_qctx {
+        return qkv_attention(q, k, v, mask, n_head);
+    }
+
+    let slices = (0..n_qctx)
+        .step_by(slice_size)
+        .map(|start| {
+            let end = (start + slice_size).min(n_qctx);
+            let q_slice = q.clone().slice([0..n_batch, start..end, 0..n_state]);
+            let mask_slice = mask.clone().map(|mask| mask.slice([start..end, 0..n_ctx]));
+
+            qkv_attention(q_slice, k.clone(), v.clone(), mask_slice, n_head)
+        })
+        .collect();
+
+    Tensor::cat(slices, 1)
+}
+
+/// Memory-efficient attention with the same result as `qkv_attention` (without a mask). Queries and keys are 
+/// processed in blocks of `block_size` and the softmax is computed online, keeping a running maximum and 
+/// normalizer per query, so only a `[n_batch, n_head, block_size, block_size]` block of scores exists at a time.
+pub fn flash_attention<B: Backend>(q: Tensor<B, 3>, k: Tensor<B, 3>, v: Tensor<B, 3>, n_head: usize, block_size: usize) -> Tensor<B, 3> {
+    let [n_batch, n_qctx, n_state] = q.dims();
+    let [_, n_ctx, _] = k.dims();
+
+    let block_size = block_size.max(1);
+    let scale = (n_state as f64 / n_head as f64).powf(-0.25);
+    let n_hstate = n_state / n_head;
+
+    let q = q.reshape([n_batch, n_qctx, n_head, n_hstate]).swap_dims(1, 2) * scale;
+    let k = k.reshape([n_batch, n_ctx, n_head, n_hstate]).swap_dims(1, 2).transpose() * scale;
+    let v = v.reshape([n_batch, n_ctx, n_head, n_hstate]).swap_dims(1, 2);
+
+    let blocks = (0..n_qctx)
+        .step_by(block_size)
+        .map(|q_start| {
+            let q_end = (q_start + block_size).min(n_qctx);
+            let q_block = q.clone().slice([0..n_batch, 0..n_head, q_start..q_end, 0..n_hstate]);
+
+            // running maximum, normalizer and unnormalized output of the online softmax
+            let mut state: Option<(Tensor<B, 4>, Tensor<B, 4>, Tensor<B, 4>)> = None;
+
+            for k_start in (0..n_ctx).step_by(block_size) {
+                let k_end = (k_start + block_size).min(n_ctx);
+                let k_block = k.clone().slice([0..n_batch, 0..n_head, 0..n_hstate, k_start..k_end]);
+                let v_block = v.clone().slice([0..n_batch, 0..n_head, k_start..k_end, 0..n_hstate]);
+
+                let qk = q_block.clone().matmul(k_block);
+                let block_max = qk.clone().max_dim(3);
+
+                state = Some( match state {
+                    None => {
+                        let w = (qk - block_max.clone()).exp();
+                        (block_max, w.clone().sum_dim(3), w.matmul(v_block))
+                    }
+                    Some((max, sum, out)) => {
+                        let new_max = tensor_max(block_max, max.clone());
+                        let correction = (max - new_max.clone()).exp();
+                        let w = (qk - new_max.clone()).exp();
+
+                        let sum = sum * correction.clone() + w.clone().sum_dim(3);
+                        let out = out * correction + w.matmul(v_block);
+                        (new_max, sum, out)
+                    }
+                });
+            }
+
+            let (_, sum, out) = state.expect("The key sequence must not be empty.");
+            out / sum
+        })
+        .collect();
+
+    Tensor::cat(blocks, 2).swap_dims(1, 2).flatten(2, 3)
 }
 
 pub fn attn_decoder_mask<B: Backend>(seq_length: usize, device: &B::Device) -> Tensor<B, 2> {
@@ -44,4 +205,85 @@ pub fn attn_decoder_mask<B: Backend>(seq_length: usize, device: &B::Device) -> T
     }
 
     return mask.to_device(device);
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::tensor_max_element;
+    use burn::tensor::{Distribution, f16};
+    use burn_tch::TchBackend;
+
+    type TestBackend = TchBackend<f32>;
+
+    #[test]
+    fn test_sliced_attention_matches_unsliced() {
+        let q: Tensor<TestBackend, 3> = Tensor::random([2, 37, 32], Distribution::Normal(0.0, 1.0));
+        let k: Tensor<TestBackend, 3> = Tensor::random([2, 11, 32], Distribution::Normal(0.0, 1.0));
+        let v: Tensor<TestBackend, 3> = Tensor::random([2, 11, 32], Distribution::Normal(0.0, 1.0));
+
+        let full = qkv_attention(q.clone(), k.clone(), v.clone(), None, 4);
+        for slice_size in [1, 8, 36, 64] {
+            let sliced = qkv_attention_sliced(q.clone(), k.clone(), v.clone(), None, 4, slice_size);
+            assert_eq!(sliced.dims(), full.dims());
+            assert!(tensor_max_element((sliced - full.clone()).abs()) < 1e-5);
+        }
+
+        // the causal mask rows must follow the query slices
+        let q = q.slice([0..2, 0..11, 0..32]);
+        let mask = attn_decoder_mask(11, &q.device());
+        let full = qkv_attention(q.clone(), k.clone(), v.clone(), Some(mask.clone()), 4);
+        let sliced = qkv_attention_sliced(q, k, v, Some(mask), 4, 3);
+        assert!(tensor_max_element((sliced - full).abs()) < 1e-5);
+    }
+
+    #[test]
+    fn test_upcast_attention() {
+        let q: Tensor<TestBackend, 3> = Tensor::random([2, 37, 32], Distribution::Normal(0.0, 1.0));
+        let k: Tensor<TestBackend, 3> = Tensor::random([2, 11, 32], Distribution::Normal(0.0, 1.0));
+        let v: Tensor<TestBackend, 3> = Tensor::random([2, 11, 32], Distribution::Normal(0.0, 1.0));
+
+        let full = qkv_attention(q.clone(), k.clone(), v.clone(), None, 4);
+        for slice_size in [None, Some(8)] {
+            let upcast = qkv_attention_upcast(q.clone(), k.clone(), v.clone(), None, 4, slice_size);
+            assert_eq!(upcast.dims(), full.dims());
+            assert!(tensor_max_element((upcast - full.clone()).abs()) < 1e-4);
+        }
+
+        let q = q.slice([0..2, 0..11, 0..32]);
+        let mask = attn_decoder_mask(11, &q.device());
+        let full = qkv_attention(q.clone(), k.clone(), v.clone(), Some(mask.clone()), 4);
+        let upcast = qkv_attention_upcast(q, k, v, Some(mask), 4, Some(3));
+        assert!(tensor_max_element((upcast - full).abs()) < 1e-4);
+    }
+
+    #[test]
+    fn test_upcast_attention_f16_large_activations() {
+        type HalfBackend = TchBackend<f16>;
+
+        // the scores of these overflow f16, like the attention activations of prompts that NaN at high guidance
+        let q: Tensor<HalfBackend, 3> = Tensor::random([1, 16, 32], Distribution::Normal(0.0, 1.0)).mul_scalar(300.0);
+        let k: Tensor<HalfBackend, 3> = Tensor::random([1, 7, 32], Distribution::Normal(0.0, 1.0)).mul_scalar(300.0);
+        let v: Tensor<HalfBackend, 3> = Tensor::random([1, 7, 32], Distribution::Normal(0.0, 1.0));
+
+        let plain = qkv_attention(q.clone(), k.clone(), v.clone(), None, 4).into_data().convert::<f32>().value;
+        assert!(plain.iter().any(|v| !v.is_finite()));
+
+        let upcast = qkv_attention_upcast(q, k, v, None, 4, None).into_data().convert::<f32>().value;
+        assert!(upcast.iter().all(|v| v.is_finite() && v.abs() < 10.0));
+    }
+
+    #[test]
+    fn test_flash_attention_matches_naive() {
+        let q: Tensor<TestBackend, 3> = Tensor::random([2, 37, 32], Distribution::Normal(0.0, 1.0));
+        let k: Tensor<TestBackend, 3> = Tensor::random([2, 53, 32], Distribution::Normal(0.0, 1.0));
+        let v: Tensor<TestBackend, 3> = Tensor::random([2, 53, 32], Distribution::Normal(0.0, 1.0));
+
+        let naive = qkv_attention(q.clone(), k.clone(), v.clone(), None, 4);
+        for block_size in [1, 16, 53, 128] {
+            let flash = flash_attention(q.clone(), k.clone(), v.clone(), 4, block_size);
+            assert_eq!(flash.dims(), naive.dims());
+            // well within f16 precision
+            assert!(tensor_max_element((flash - naive.clone()).abs()) < 1e-3);
+        }
+    }
+}
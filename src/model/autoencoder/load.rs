@@ -1,7 +1,6 @@
 use super::GroupNorm;
 use crate::model::load::*;
 
-use std::error::Error;
 
 use burn::{
     config::Config, 
@@ -16,7 +15,7 @@ use burn::{
 use super::*;
 use crate::model::groupnorm::load::load_group_norm;
 
-fn load_conv_self_attention_block<B: Backend>(path: &str, device: &B::Device) -> Result<ConvSelfAttentionBlock<B>, Box<dyn Error>> {
+fn load_conv_self_attention_block<B: Backend>(path: &str, device: &B::Device) -> Result<ConvSelfAttentionBlock<B>, LoadError> {
     let norm = load_group_norm(&format!("{}/{}", path, "norm"), device)?;
     let q = load_conv2d(&format!("{}/{}", path, "q"), device)?;
     let k = load_conv2d(&format!("{}/{}", path, "k"), device)?;
@@ -26,7 +25,7 @@ fn load_conv_self_attention_block<B: Backend>(path: &str, device: &B::Device) ->
     Ok(ConvSelfAttentionBlock { norm, q, k, v, proj_out })
 }
 
-fn load_resnet_block<B: Backend>(path: &str, device: &B::Device) -> Result<ResnetBlock<B>, Box<dyn Error>> {
+fn load_resnet_block<B: Backend>(path: &str, device: &B::Device) -> Result<ResnetBlock<B>, LoadError> {
     let norm1 = load_group_norm(&format!("{}/{}", path, "norm1"), device)?;
     let silu1 = SILU {};
     let conv1 = load_conv2d(&format!("{}/{}", path, "conv1"), device)?;
@@ -38,7 +37,7 @@ fn load_resnet_block<B: Backend>(path: &str, device: &B::Device) -> Result<Resne
     Ok(ResnetBlock { norm1, silu1, conv1, norm2, silu2, conv2, nin_shortcut })
 }
 
-fn load_mid<B: Backend>(path: &str, device: &B::Device) -> Result<Mid<B>, Box<dyn Error>> {
+fn load_mid<B: Backend>(path: &str, device: &B::Device) -> Result<Mid<B>, LoadError> {
     let block_1 = load_resnet_block(&format!("{}/{}", path, "block_1"), device)?;
     let attn = load_conv_self_attention_block(&format!("{}/{}", path, "attn"), device)?;
     let block_2 = load_resnet_block(&format!("{}/{}", path, "block_2"), device)?;
@@ -46,7 +45,7 @@ fn load_mid<B: Backend>(path: &str, device: &B::Device) -> Result<Mid<B>, Box<dy
     Ok(Mid { block_1, attn, block_2 })
 }
 
-fn load_padded_conv2d<B: Backend>(path: &str, device: &B::Device) -> Result<PaddedConv2d<B>, Box<dyn Error>> {
+fn load_padded_conv2d<B: Backend>(path: &str, device: &B::Device) -> Result<PaddedConv2d<B>, LoadError> {
     let conv = load_conv2d(&format!("{}/{}", path, "conv"), device)?;
 
     let channels = load_tensor::<B, 1>("channels", path, device)?;
@@ -71,7 +70,7 @@ fn load_padded_conv2d<B: Backend>(path: &str, device: &B::Device) -> Result<Padd
     Ok(padded_conv)
 }
 
-fn load_decoder_block<B: Backend>(path: &str, device: &B::Device) -> Result<DecoderBlock<B>, Box<dyn Error>> {
+fn load_decoder_block<B: Backend>(path: &str, device: &B::Device) -> Result<DecoderBlock<B>, LoadError> {
     let res1 = load_resnet_block(&format!("{}/{}", path, "res1"), device)?;
     let res2 = load_resnet_block(&format!("{}/{}", path, "res2"), device)?;
     let res3 = load_resnet_block(&format!("{}/{}", path, "res3"), device)?;
@@ -80,7 +79,7 @@ fn load_decoder_block<B: Backend>(path: &str, device: &B::Device) -> Result<Deco
     Ok(DecoderBlock { res1, res2, res3, upsampler })
 }
 
-fn load_encoder_block<B: Backend>(path: &str, device: &B::Device) -> Result<EncoderBlock<B>, Box<dyn Error>> {
+fn load_encoder_block<B: Backend>(path: &str, device: &B::Device) -> Result<EncoderBlock<B>, LoadError> {
     let res1 = load_resnet_block(&format!("{}/{}", path, "res1"), device)?;
     let res2 = load_resnet_block(&format!("{}/{}", path, "res2"), device)?;
     let downsampler = load_padded_conv2d(&format!("{}/{}", path, "downsampler"), device).ok();
@@ -88,7 +87,7 @@ fn load_encoder_block<B: Backend>(path: &str, device: &B::Device) -> Result<Enco
     Ok(EncoderBlock { res1, res2, downsampler })
 }
 
-pub fn load_decoder<B: Backend>(path: &str, device: &B::Device) -> Result<Decoder<B>, Box<dyn Error>> {
+pub fn load_decoder<B: Backend>(path: &str, device: &B::Device) -> Result<Decoder<B>, LoadError> {
     let conv_in = load_conv2d(&format!("{}/{}", path, "conv_in"), device)?;
     let mid = load_mid(&format!("{}/{}", path, "mid"), device)?;
 
@@ -103,10 +102,10 @@ pub fn load_decoder<B: Backend>(path: &str, device: &B::Device) -> Result<Decode
     let silu = SILU {};
     let conv_out = load_conv2d(&format!("{}/{}", path, "conv_out"), device)?;
 
-    Ok(Decoder { conv_in, mid, blocks, norm_out, silu, conv_out })
+    Ok(Decoder { conv_in, mid, blocks, norm_out, silu, conv_out, tileable: false })
 }
 
-pub fn load_encoder<B: Backend>(path: &str, device: &B::Device) -> Result<Encoder<B>, Box<dyn Error>> {
+pub fn load_encoder<B: Backend>(path: &str, device: &B::Device) -> Result<Encoder<B>, LoadError> {
     let conv_in = load_conv2d(&format!("{}/{}", path, "conv_in"), device)?;
     let mid = load_mid(&format!("{}/{}", path, "mid"), device)?;
 
@@ -124,11 +123,183 @@ pub fn load_encoder<B: Backend>(path: &str, device: &B::Device) -> Result<Encode
     Ok(Encoder { conv_in, mid, blocks, norm_out, silu, conv_out })
 }
 
-pub fn load_autoencoder<B: Backend>(path: &str, device: &B::Device) -> Result<Autoencoder<B>, Box<dyn Error>> {
+pub fn load_autoencoder<B: Backend>(path: &str, device: &B::Device) -> Result<Autoencoder<B>, LoadError> {
     let encoder = load_encoder(&format!("{}/{}", path, "encoder"), device)?;
     let decoder = load_decoder(&format!("{}/{}", path, "decoder"), device)?;
     let quant_conv = load_conv2d(&format!("{}/{}", path, "quant_conv"), device)?;
     let post_quant_conv = load_conv2d(&format!("{}/{}", path, "post_quant_conv"), device)?;
 
     Ok(Autoencoder { encoder, decoder, quant_conv, post_quant_conv })
-}
\ No newline at end of file
+}
+
+
+use crate::model::safetensors::{SafeTensorsFile, join, names::{self, lookup}};
+use crate::model::groupnorm::load::load_group_norm_safetensors;
+
+const SAFETENSORS_GROUP_NORM_EPS: f64 = 1e-6;
+
+fn load_conv_self_attention_block_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, device: &B::Device) -> Result<ConvSelfAttentionBlock<B>, LoadError> {
+    let table = if st.contains(&join(prefix, "to_q.weight")) {
+        names::VAE_ATTENTION
+    } else {
+        names::VAE_ATTENTION_LEGACY
+    };
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(table, n)?) ) };
+
+    let norm = load_group_norm_safetensors(st, &name("norm")?, 32, SAFETENSORS_GROUP_NORM_EPS, device)?;
+    let q = load_conv2d_safetensors(st, &name("q")?, 1, 0, device)?;
+    let k = load_conv2d_safetensors(st, &name("k")?, 1, 0, device)?;
+    let v = load_conv2d_safetensors(st, &name("v")?, 1, 0, device)?;
+    let proj_out = load_conv2d_safetensors(st, &name("proj_out")?, 1, 0, device)?;
+
+    Ok(ConvSelfAttentionBlock { norm, q, k, v, proj_out })
+}
+
+fn load_resnet_block_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, device: &B::Device) -> Result<ResnetBlock<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::VAE_RESNET_BLOCK, n)?) ) };
+
+    let norm1 = load_group_norm_safetensors(st, &name("norm1")?, 32, SAFETENSORS_GROUP_NORM_EPS, device)?;
+    let silu1 = SILU {};
+    let conv1 = load_conv2d_safetensors(st, &name("conv1")?, 1, 1, device)?;
+    let norm2 = load_group_norm_safetensors(st, &name("norm2")?, 32, SAFETENSORS_GROUP_NORM_EPS, device)?;
+    let silu2 = SILU {};
+    let conv2 = load_conv2d_safetensors(st, &name("conv2")?, 1, 1, device)?;
+    let nin_shortcut = if st.contains(&join(&name("nin_shortcut")?, "weight")) {
+        Some( load_conv2d_safetensors(st, &name("nin_shortcut")?, 1, 0, device)? )
+    } else {
+        None
+    };
+
+    Ok(ResnetBlock { norm1, silu1, conv1, norm2, silu2, conv2, nin_shortcut })
+}
+
+fn load_mid_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, device: &B::Device) -> Result<Mid<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::VAE_CODER, n)?) ) };
+
+    let block_1 = load_resnet_block_safetensors(st, &name("mid/block_1")?, device)?;
+    let attn = load_conv_self_attention_block_safetensors(st, &name("mid/attn")?, device)?;
+    let block_2 = load_resnet_block_safetensors(st, &name("mid/block_2")?, device)?;
+
+    Ok(Mid { block_1, attn, block_2 })
+}
+
+fn load_downsampler_safetensors<B: Backend>(st: &SafeTensorsFile, name: &str, device: &B::Device) -> Result<PaddedConv2d<B>, LoadError> {
+    // the asymmetric padding isn't stored in the checkpoint
+    let conv = load_conv2d_safetensors(st, name, 2, 0, device)?;
+    let [n_channels_out, n_channels_in, kernel_size, _] = conv.weight.val().dims();
+
+    let mut record = conv.into_record();
+
+    let mut padded_conv: PaddedConv2d<B> = PaddedConv2dConfig::new([n_channels_in, n_channels_out], kernel_size, Padding::new(0, 1, 0, 1))
+        .with_stride(2)
+        .init();
+    let padding_actual = PaddingConfig2d::Explicit(padded_conv.padding_actual[0], padded_conv.padding_actual[1]);
+
+    record.padding = <PaddingConfig2d as Module<B>>::into_record(padding_actual);
+    padded_conv.conv = padded_conv.conv.load_record(record);
+
+    Ok(padded_conv)
+}
+
+fn load_decoder_block_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, device: &B::Device) -> Result<DecoderBlock<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::VAE_DECODER_BLOCK, n)?) ) };
+
+    let res1 = load_resnet_block_safetensors(st, &name("res1")?, device)?;
+    let res2 = load_resnet_block_safetensors(st, &name("res2")?, device)?;
+    let res3 = load_resnet_block_safetensors(st, &name("res3")?, device)?;
+    let upsampler = if st.contains(&join(&name("upsampler")?, "weight")) {
+        Some( load_conv2d_safetensors(st, &name("upsampler")?, 1, 1, device)? )
+    } else {
+        None
+    };
+
+    Ok(DecoderBlock { res1, res2, res3, upsampler })
+}
+
+fn load_encoder_block_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, device: &B::Device) -> Result<EncoderBlock<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::VAE_ENCODER_BLOCK, n)?) ) };
+
+    let res1 = load_resnet_block_safetensors(st, &name("res1")?, device)?;
+    let res2 = load_resnet_block_safetensors(st, &name("res2")?, device)?;
+    let downsampler = if st.contains(&join(&name("downsampler/conv")?, "weight")) {
+        Some( load_downsampler_safetensors(st, &name("downsampler/conv")?, device)? )
+    } else {
+        None
+    };
+
+    Ok(EncoderBlock { res1, res2, downsampler })
+}
+
+fn count_blocks(st: &SafeTensorsFile, blocks: &str) -> usize {
+    (0..)
+        .take_while(|i| st.contains(&format!("{}.{}.resnets.0.conv1.weight", blocks, i)))
+        .count()
+}
+
+fn decoder_from_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, device: &B::Device) -> Result<Decoder<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::VAE_CODER, n)?) ) };
+
+    let conv_in = load_conv2d_safetensors(st, &name("conv_in")?, 1, 1, device)?;
+    let mid = load_mid_safetensors(st, prefix, device)?;
+
+    let blocks_name = name("decoder/blocks")?;
+    let n_block = count_blocks(st, &blocks_name);
+    let blocks = (0..n_block)
+        .into_iter()
+        .map(|i| {
+            load_decoder_block_safetensors::<B>(st, &join(&blocks_name, &i.to_string()), device)
+        }).collect::<Result<Vec<_>, _>>()?;
+
+    let norm_out = load_group_norm_safetensors(st, &name("norm_out")?, 32, SAFETENSORS_GROUP_NORM_EPS, device)?;
+    let silu = SILU {};
+    let conv_out = load_conv2d_safetensors(st, &name("conv_out")?, 1, 1, device)?;
+
+    Ok(Decoder { conv_in, mid, blocks, norm_out, silu, conv_out, tileable: false })
+}
+
+fn encoder_from_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, device: &B::Device) -> Result<Encoder<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::VAE_CODER, n)?) ) };
+
+    let conv_in = load_conv2d_safetensors(st, &name("conv_in")?, 1, 1, device)?;
+    let mid = load_mid_safetensors(st, prefix, device)?;
+
+    let blocks_name = name("encoder/blocks")?;
+    let n_block = count_blocks(st, &blocks_name);
+    let blocks = (0..n_block)
+        .into_iter()
+        .map(|i| {
+            load_encoder_block_safetensors::<B>(st, &join(&blocks_name, &i.to_string()), device)
+        }).collect::<Result<Vec<_>, _>>()?;
+
+    let norm_out = load_group_norm_safetensors(st, &name("norm_out")?, 32, SAFETENSORS_GROUP_NORM_EPS, device)?;
+    let silu = SILU {};
+    let conv_out = load_conv2d_safetensors(st, &name("conv_out")?, 1, 1, device)?;
+
+    Ok(Encoder { conv_in, mid, blocks, norm_out, silu, conv_out })
+}
+
+/// Loads the decoder half of a diffusers-format VAE `.safetensors` file.
+pub fn load_decoder_safetensors<B: Backend>(path: &str, device: &B::Device) -> Result<Decoder<B>, LoadError> {
+    let st = SafeTensorsFile::open(path)?;
+    decoder_from_safetensors(&st, lookup(names::AUTOENCODER, "decoder")?, device)
+}
+
+/// Loads the encoder half of a diffusers-format VAE `.safetensors` file.
+pub fn load_encoder_safetensors<B: Backend>(path: &str, device: &B::Device) -> Result<Encoder<B>, LoadError> {
+    let st = SafeTensorsFile::open(path)?;
+    encoder_from_safetensors(&st, lookup(names::AUTOENCODER, "encoder")?, device)
+}
+
+/// Loads a diffusers-format VAE `.safetensors` file, e.g. `vae/diffusion_pytorch_model.safetensors`.
+pub fn load_autoencoder_safetensors<B: Backend>(path: &str, device: &B::Device) -> Result<Autoencoder<B>, LoadError> {
+    let st = SafeTensorsFile::open(path)?;
+    let st = &st;
+    let name = |n: &str| lookup(names::AUTOENCODER, n);
+
+    let encoder = encoder_from_safetensors(st, name("encoder")?, device)?;
+    let decoder = decoder_from_safetensors(st, name("decoder")?, device)?;
+    let quant_conv = load_conv2d_safetensors(st, name("quant_conv")?, 1, 0, device)?;
+    let post_quant_conv = load_conv2d_safetensors(st, name("post_quant_conv")?, 1, 0, device)?;
+
+    Ok(Autoencoder { encoder, decoder, quant_conv, post_quant_conv })
+}
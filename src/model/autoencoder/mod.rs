@@ -14,7 +14,7 @@ use burn::{
     },
 };
 
-use crate::helper::div_roundup;
+use crate::helper::{div_roundup, random_normal, tensor_max_scalar, tensor_min_scalar, conv2d_forward};
 
 use super::silu::*;
 use super::groupnorm::*;
@@ -24,12 +24,16 @@ use std::iter;
 
 
 #[derive(Config)]
-pub struct AutoencoderConfig {}
+pub struct AutoencoderConfig {
+    /// See `DecoderConfig::upcast_group_norm`.
+    #[config(default = false)]
+    upcast_group_norm: bool, 
+}
 
 impl AutoencoderConfig {
     pub fn init<B: Backend>(&self) -> Autoencoder<B> {
         let encoder = EncoderConfig::new(vec![(128, 128), (128, 256), (256, 512), (512, 512)], 32, 8).init();
-        let decoder = DecoderConfig::new(vec![(512, 512), (512, 512), (512, 256), (256, 128)], 32).init();
+        let decoder = DecoderConfig::new(vec![(512, 512), (512, 512), (512, 256), (256, 128)], 32).with_upcast_group_norm(self.upcast_group_norm).init();
         let quant_conv = Conv2dConfig::new([8, 8], [1, 1]).init();
         let post_quant_conv = Conv2dConfig::new([4, 4], [1, 1]).init();
 
@@ -52,16 +56,36 @@ pub struct Autoencoder<B: Backend> {
 }
 
 impl<B: Backend> Autoencoder<B> {
+    /// See `Decoder::with_tileable`.
+    pub fn with_tileable(self, tileable: bool) -> Self {
+        Self {
+            decoder: self.decoder.with_tileable(tileable), 
+            ..self
+        }
+    }
+
     pub fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
         self.decode_latent( self.encode_image(x) )
     }
 
+    /// Encodes to the mean of the latent posterior, unscaled.
     pub fn encode_image(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
-        let [n_batch, _, _, _] = x.dims();
+        let (mean, _) = self.encode_distribution(x);
+        mean
+    }
+
+    /// The diagonal Gaussian latent posterior of `x` as `(mean, logvar)`, both `[n_batch, 4, height / 8, width / 8]` 
+    /// and without the latent scale factor applied. Draw from it with `sample`.
+    pub fn encode_distribution(&self, x: Tensor<B, 4>) -> (Tensor<B, 4>, Tensor<B, 4>) {
         let latent = self.encoder.forward(x);
-        let latent = self.quant_conv.forward(latent);
-        let latent = latent.slice([0..n_batch, 0..4]);
-        latent
+        let moments = self.quant_conv.forward(latent);
+
+        let [n_batch, n_moments, height, width] = moments.dims();
+        let n_channel = n_moments / 2;
+        let mean = moments.clone().slice([0..n_batch, 0..n_channel, 0..height, 0..width]);
+        let logvar = moments.slice([0..n_batch, n_channel..n_moments, 0..height, 0..width]);
+
+        (mean, logvar)
     }
 
     pub fn decode_latent(&self, latent: Tensor<B, 4>) -> Tensor<B, 4> {
@@ -70,6 +94,19 @@ impl<B: Backend> Autoencoder<B> {
     }
 }
 
+/// Draws a latent from the posterior returned by `Autoencoder::encode_distribution` with the reparameterization 
+/// `mean + exp(logvar / 2) * noise`, where the noise is the first draw after seeding the backend RNG with `seed`. 
+/// `logvar` is clamped to [-30, 20] as in the reference implementation.
+pub fn sample<B: Backend>(mean: Tensor<B, 4>, logvar: Tensor<B, 4>, seed: u64) -> Tensor<B, 4> {
+    let logvar = tensor_min_scalar(tensor_max_scalar(logvar, 20.0), -30.0);
+    let std = (logvar * 0.5).exp();
+
+    B::seed(seed);
+    let noise = random_normal(mean.shape(), &mean.device());
+
+    mean + std * noise
+}
+
 #[derive(Config)]
 pub struct EncoderConfig {
     channels: Vec<(usize, usize)>,  
@@ -125,7 +162,7 @@ impl<B: Backend> Encoder<B> {
             x = block.forward(x);
         }
 
-        let x = self.mid.forward(x);
+        let x = self.mid.forward(x, false);
         self.conv_out.forward( self.silu.forward( self.norm_out.forward(x) ) )
     }
 }
@@ -136,6 +173,14 @@ impl<B: Backend> Encoder<B> {
 pub struct DecoderConfig {
     channels: Vec<(usize, usize)>,  
     n_group: usize, 
+    /// Computes every group norm with overflow safe statistics, see `GroupNormConfig::upcast`. 
+    /// The decoder runs in f16 without it unless the activations grow large, which some VAEs' 
+    /// (including the original SDXL VAE's) do in the last blocks, turning the image black or NaN.
+    #[config(default = false)]
+    upcast_group_norm: bool, 
+    /// See `Decoder::with_tileable`.
+    #[config(default = false)]
+    tileable: bool, 
 }
 
 impl DecoderConfig {
@@ -144,14 +189,14 @@ impl DecoderConfig {
         let n_condensed_channels = self.channels.last().unwrap().1;
 
         let conv_in = Conv2dConfig::new([4, n_expanded_channels], [3, 3]).with_padding(PaddingConfig2d::Explicit(1, 1)).init();
-        let mid = MidConfig::new(n_expanded_channels).init();
+        let mid = MidConfig::new(n_expanded_channels).with_upcast_group_norm(self.upcast_group_norm).init();
 
         let blocks = self.channels.iter().enumerate().map(|(i, &(n_channel_in, n_channel_out))| {
             let upsample = i != self.channels.len() - 1;
-            DecoderBlockConfig::new(n_channel_in, n_channel_out, upsample).init()
+            DecoderBlockConfig::new(n_channel_in, n_channel_out, upsample).with_upcast_group_norm(self.upcast_group_norm).init()
         }).collect();
 
-        let norm_out = GroupNormConfig::new(self.n_group, n_condensed_channels).init();
+        let norm_out = GroupNormConfig::new(self.n_group, n_condensed_channels).with_upcast(self.upcast_group_norm).init();
         let silu = SILU::new();
         let conv_out = Conv2dConfig::new([n_condensed_channels, 3], [3, 3]).with_padding(PaddingConfig2d::Explicit(1, 1)).init();
 
@@ -162,6 +207,7 @@ impl DecoderConfig {
             norm_out, 
             silu, 
             conv_out, 
+            tileable: self.tileable, 
         }
     }
 }
@@ -175,19 +221,31 @@ pub struct Decoder<B: Backend> {
     norm_out: GroupNorm<B>, 
     silu: SILU, 
     conv_out: Conv2d<B>, 
+    tileable: bool, 
 }
 
 impl<B: Backend> Decoder<B> {
+    /// Pads every convolution circularly instead of with zeros, so that a latent sampled with a tileable 
+    /// `UNet` decodes to an image that tiles seamlessly.
+    pub fn with_tileable(self, tileable: bool) -> Self {
+        Self {
+            tileable, 
+            ..self
+        }
+    }
+
     pub fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
-        let x = self.conv_in.forward(x);
-        let x = self.mid.forward(x);
+        let tileable = self.tileable;
+
+        let x = conv2d_forward(&self.conv_in, x, tileable);
+        let x = self.mid.forward(x, tileable);
 
         let mut x = x;
         for block in &self.blocks {
-            x = block.forward(x);
+            x = block.forward(x, tileable);
         }
 
-        self.conv_out.forward( self.silu.forward( self.norm_out.forward(x) ) )
+        conv2d_forward(&self.conv_out, self.silu.forward( self.norm_out.forward(x) ), tileable)
     }
 }
 
@@ -226,8 +284,8 @@ pub struct EncoderBlock<B: Backend> {
 
 impl<B: Backend> EncoderBlock<B> {
     fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
-        let x = self.res1.forward(x);
-        let x = self.res2.forward(x);
+        let x = self.res1.forward(x, false);
+        let x = self.res2.forward(x, false);
         if let Some(d) = self.downsampler.as_ref() {
             d.forward(x)
         } else {
@@ -241,13 +299,15 @@ pub struct DecoderBlockConfig {
     n_channels_in: usize, 
     n_channels_out: usize, 
     upsample: bool, 
+    #[config(default = false)]
+    upcast_group_norm: bool, 
 }
 
 impl DecoderBlockConfig {
     fn init<B: Backend>(&self) -> DecoderBlock<B> {
-        let res1 = ResnetBlockConfig::new(self.n_channels_in, self.n_channels_out).init();
-        let res2 = ResnetBlockConfig::new(self.n_channels_out, self.n_channels_out).init();
-        let res3 = ResnetBlockConfig::new(self.n_channels_out, self.n_channels_out).init();
+        let res1 = ResnetBlockConfig::new(self.n_channels_in, self.n_channels_out).with_upcast_group_norm(self.upcast_group_norm).init();
+        let res2 = ResnetBlockConfig::new(self.n_channels_out, self.n_channels_out).with_upcast_group_norm(self.upcast_group_norm).init();
+        let res3 = ResnetBlockConfig::new(self.n_channels_out, self.n_channels_out).with_upcast_group_norm(self.upcast_group_norm).init();
         let upsampler = if self.upsample {
             Some( Conv2dConfig::new([self.n_channels_out, self.n_channels_out], [3, 3]).with_padding(PaddingConfig2d::Explicit(1, 1)).init() )
         } else {
@@ -272,10 +332,10 @@ pub struct DecoderBlock<B: Backend> {
 }
 
 impl<B: Backend> DecoderBlock<B> {
-    fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
-        let x = self.res1.forward(x);
-        let x = self.res2.forward(x);
-        let x = self.res3.forward(x);
+    fn forward(&self, x: Tensor<B, 4>, tileable: bool) -> Tensor<B, 4> {
+        let x = self.res1.forward(x, tileable);
+        let x = self.res2.forward(x, tileable);
+        let x = self.res3.forward(x, tileable);
 
         if let Some(d) = self.upsampler.as_ref() {
             let [n_batch, n_channel, height, width] = x.dims();
@@ -284,7 +344,7 @@ impl<B: Backend> DecoderBlock<B> {
                     .repeat(3, 2)
                     .repeat(5, 2)
                     .reshape([n_batch, n_channel, 2 * height, 2 * width]);
-            d.forward(x)
+            conv2d_forward(d, x, tileable)
         } else {
             x
         }
@@ -378,13 +438,15 @@ pub struct Padding {
 #[derive(Config)]
 pub struct MidConfig {
     n_channel: usize, 
+    #[config(default = false)]
+    upcast_group_norm: bool, 
 }
 
 impl MidConfig {
     fn init<B: Backend>(&self) -> Mid<B> {
-        let block_1 = ResnetBlockConfig::new(self.n_channel, self.n_channel).init();
-        let attn = ConvSelfAttentionBlockConfig::new(self.n_channel).init();
-        let block_2 = ResnetBlockConfig::new(self.n_channel, self.n_channel).init();
+        let block_1 = ResnetBlockConfig::new(self.n_channel, self.n_channel).with_upcast_group_norm(self.upcast_group_norm).init();
+        let attn = ConvSelfAttentionBlockConfig::new(self.n_channel).with_upcast_group_norm(self.upcast_group_norm).init();
+        let block_2 = ResnetBlockConfig::new(self.n_channel, self.n_channel).with_upcast_group_norm(self.upcast_group_norm).init();
 
         Mid {
             block_1, 
@@ -402,10 +464,10 @@ pub struct Mid<B: Backend> {
 }
 
 impl<B: Backend> Mid<B> {
-    fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
-        let x = self.block_1.forward(x);
+    fn forward(&self, x: Tensor<B, 4>, tileable: bool) -> Tensor<B, 4> {
+        let x = self.block_1.forward(x, tileable);
         let x = self.attn.forward(x);
-        let x = self.block_2.forward(x);
+        let x = self.block_2.forward(x, tileable);
         x
     }
 }
@@ -415,13 +477,15 @@ impl<B: Backend> Mid<B> {
 pub struct ResnetBlockConfig {
     in_channels: usize, 
     out_channels: usize, 
+    #[config(default = false)]
+    upcast_group_norm: bool, 
 }
 
 impl ResnetBlockConfig {
     fn init<B: Backend>(&self) -> ResnetBlock<B> {
-        let norm1 = GroupNormConfig::new(32, self.in_channels).init();
+        let norm1 = GroupNormConfig::new(32, self.in_channels).with_upcast(self.upcast_group_norm).init();
         let conv1 = Conv2dConfig::new([self.in_channels, self.out_channels], [3, 3]).with_padding(PaddingConfig2d::Explicit(1, 1)).init();
-        let norm2 = GroupNormConfig::new(32, self.out_channels).init();
+        let norm2 = GroupNormConfig::new(32, self.out_channels).with_upcast(self.upcast_group_norm).init();
         let conv2 = Conv2dConfig::new([self.out_channels, self.out_channels], [3, 3]).with_padding(PaddingConfig2d::Explicit(1, 1)).init();
         let nin_shortcut = if self.in_channels != self.out_channels {
             Some( Conv2dConfig::new([self.in_channels, self.out_channels], [1, 1]).init() )
@@ -456,9 +520,9 @@ pub struct ResnetBlock<B: Backend> {
 }
 
 impl<B: Backend> ResnetBlock<B> {
-    fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
-        let h = self.conv1.forward( self.silu1.forward(self.norm1.forward(x.clone())) );
-        let h = self.conv2.forward( self.silu2.forward(self.norm2.forward(h)) );
+    fn forward(&self, x: Tensor<B, 4>, tileable: bool) -> Tensor<B, 4> {
+        let h = conv2d_forward(&self.conv1, self.silu1.forward(self.norm1.forward(x.clone())), tileable);
+        let h = conv2d_forward(&self.conv2, self.silu2.forward(self.norm2.forward(h)), tileable);
 
         
         if let Some(ns) = self.nin_shortcut.as_ref() {
@@ -472,11 +536,13 @@ impl<B: Backend> ResnetBlock<B> {
 #[derive(Config)]
 pub struct ConvSelfAttentionBlockConfig {
     n_channel: usize,  
+    #[config(default = false)]
+    upcast_group_norm: bool, 
 }
 
 impl ConvSelfAttentionBlockConfig {
     fn init<B: Backend>(&self) -> ConvSelfAttentionBlock<B> {
-        let norm = GroupNormConfig::new(32, self.n_channel).init();
+        let norm = GroupNormConfig::new(32, self.n_channel).with_upcast(self.upcast_group_norm).init();
         let q = Conv2dConfig::new([self.n_channel, self.n_channel], [1, 1]).init();
         let k = Conv2dConfig::new([self.n_channel, self.n_channel], [1, 1]).init();
         let v = Conv2dConfig::new([self.n_channel, self.n_channel], [1, 1]).init();
@@ -520,3 +586,49 @@ impl<B: Backend> ConvSelfAttentionBlock<B> {
         x + projected
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::{tensor_max_element, tensor_to_vec, roll};
+    use burn_tch::TchBackend;
+
+    type TestBackend = TchBackend<f32>;
+
+    #[test]
+    fn test_sample() {
+        let mean: Tensor<TestBackend, 4> = Tensor::random([2, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+
+        // a vanishing variance leaves the mean
+        let logvar = Tensor::zeros([2, 4, 8, 8]).sub_scalar(1000.0);
+        let latent = sample(mean.clone(), logvar, 0);
+        assert!(tensor_max_element((latent - mean.clone()).abs()) < 1e-5);
+
+        // the same seed draws the same noise
+        let logvar = Tensor::zeros([2, 4, 8, 8]);
+        let first = sample(mean.clone(), logvar.clone(), 3);
+        let second = sample(mean.clone(), logvar, 3);
+        assert_eq!(tensor_to_vec(first.clone()), tensor_to_vec(second));
+        assert!(tensor_max_element((first - mean).abs()) > 0.1);
+    }
+
+    #[test]
+    fn test_tileable_decoder_edges() {
+        let decoder: Decoder<TestBackend> = DecoderConfig::new(vec![(32, 32), (32, 32)], 32).with_tileable(true).init();
+        let latent: Tensor<TestBackend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let image = decoder.forward(latent.clone());
+        assert_eq!(image.dims(), [1, 3, 16, 16]);
+
+        // decoding the latent shifted by half its size moves the left/right and top/bottom edges of the image 
+        // into its middle, so they continue into each other like any other neighbouring columns and rows
+        for dim in [2, 3] {
+            let shifted = decoder.forward(roll(latent.clone(), dim, 4));
+            assert!(tensor_max_element((shifted - roll(image.clone(), dim, 8)).abs()) < 1e-4);
+        }
+
+        // with zero padding they don't
+        let decoder = decoder.with_tileable(false);
+        let shifted = decoder.forward(roll(latent.clone(), 3, 4));
+        assert!(tensor_max_element((shifted - roll(decoder.forward(latent), 3, 8)).abs()) > 1e-3);
+    }
+}
@@ -1,4 +1,3 @@
-use std::error::Error;
 use burn::tensor::ElementConversion;
 
 use burn::{
@@ -15,7 +14,7 @@ use super::*;
 use crate::model::load::*;
 use crate::model::layernorm::load::load_layer_norm;
 
-pub fn load_mlp<B: Backend>(path: &str, device: &B::Device, is_open_clip: bool) -> Result<MLP<B>, Box<dyn Error>> {
+pub fn load_mlp<B: Backend>(path: &str, device: &B::Device, is_open_clip: bool) -> Result<MLP<B>, LoadError> {
     let fc1 = load_linear(&format!("{}/{}", path, "fc1"), device)?;
     let qgelu = QuickGELU::new();
     let gelu = nn::GELU::new();
@@ -34,7 +33,7 @@ pub fn load_mlp<B: Backend>(path: &str, device: &B::Device, is_open_clip: bool)
     Ok(mlp)
 }
 
-pub fn load_multi_head_self_attention<B: Backend>(path: &str, device: &B::Device) -> Result<MultiHeadSelfAttention<B>, Box<dyn Error>> {
+pub fn load_multi_head_self_attention<B: Backend>(path: &str, device: &B::Device) -> Result<MultiHeadSelfAttention<B>, LoadError> {
     let n_head = load_usize::<B>("n_head", path, device)?;
     let query = load_linear(&format!("{}/{}", path, "query"), device)?;
     let key = load_linear(&format!("{}/{}", path, "key"), device)?;
@@ -52,7 +51,7 @@ pub fn load_multi_head_self_attention<B: Backend>(path: &str, device: &B::Device
     Ok(mhsa)
 }
 
-pub fn load_residual_decoder_attention_block<B: Backend>(path: &str, device: &B::Device, is_open_clip: bool) -> Result<ResidualDecoderAttentionBlock<B>, Box<dyn Error>> {
+pub fn load_residual_decoder_attention_block<B: Backend>(path: &str, device: &B::Device, is_open_clip: bool) -> Result<ResidualDecoderAttentionBlock<B>, LoadError> {
     let mlp = load_mlp(&format!("{}/{}", path, "mlp"), device, is_open_clip)?;
     let attn = load_multi_head_self_attention(&format!("{}/{}", path, "attn"), device)?;
     let attn_ln = load_layer_norm(&format!("{}/{}", path, "attn_ln"), device)?;
@@ -68,7 +67,7 @@ pub fn load_residual_decoder_attention_block<B: Backend>(path: &str, device: &B:
     Ok(rdab)
 }
 
-pub fn load_clip_text_transformer<B: Backend>(path: &str, device: &B::Device, is_open_clip: bool) -> Result<CLIP<B>, Box<dyn Error>> {
+pub fn load_clip_text_transformer<B: Backend>(path: &str, device: &B::Device, is_open_clip: bool) -> Result<CLIP<B>, LoadError> {
     let token_embedding = load_embedding(&format!("{}/{}", path, "token_embedding"), device)?;
     let position_embedding = load_tensor("weight", &format!("{}/position_embedding", path), device)?.into();
 
@@ -89,7 +88,85 @@ pub fn load_clip_text_transformer<B: Backend>(path: &str, device: &B::Device, is
         blocks: blocks,
         layer_norm: layer_norm,
         text_projection, 
+        pooled_layer_norm: true, 
     };
     
     Ok(clip)
 }
+
+
+
+use crate::model::safetensors::{SafeTensorsFile, join, names::{self, lookup}};
+use crate::model::layernorm::load::load_layer_norm_safetensors;
+
+const SAFETENSORS_HEAD_DIM: usize = 64;
+
+fn load_residual_decoder_attention_block_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, device: &B::Device, is_open_clip: bool) -> Result<ResidualDecoderAttentionBlock<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::CLIP_BLOCK, n)?) ) };
+    let attn_name = |n: &str| -> Result<String, LoadError> { Ok( join(&name("attn")?, lookup(names::CLIP_ATTENTION, n)?) ) };
+    let mlp_name = |n: &str| -> Result<String, LoadError> { Ok( join(&name("mlp")?, lookup(names::CLIP_MLP, n)?) ) };
+
+    let query = load_linear_safetensors(st, &attn_name("query")?, device)?;
+    let [_, n_state] = query.weight.val().dims();
+
+    let attn = MultiHeadSelfAttention {
+        n_head: n_state / SAFETENSORS_HEAD_DIM, 
+        query, 
+        key: load_linear_safetensors(st, &attn_name("key")?, device)?, 
+        value: load_linear_safetensors(st, &attn_name("value")?, device)?, 
+        out: load_linear_safetensors(st, &attn_name("out")?, device)?, 
+    };
+
+    let mlp = MLP {
+        fc1: load_linear_safetensors(st, &mlp_name("fc1")?, device)?, 
+        qgelu: QuickGELU::new(), 
+        gelu: nn::GELU::new(), 
+        fc2: load_linear_safetensors(st, &mlp_name("fc2")?, device)?, 
+        quick_gelu: !is_open_clip, 
+    };
+
+    Ok(ResidualDecoderAttentionBlock {
+        attn, 
+        attn_ln: load_layer_norm_safetensors(st, &name("attn_ln")?, 1e-5, device)?, 
+        mlp, 
+        mlp_ln: load_layer_norm_safetensors(st, &name("mlp_ln")?, 1e-5, device)?, 
+    })
+}
+
+/// Loads a text encoder from a transformers-format `.safetensors` file, e.g. `text_encoder/model.safetensors`.
+pub fn load_clip_text_transformer_safetensors<B: Backend>(path: &str, device: &B::Device, is_open_clip: bool) -> Result<CLIP<B>, LoadError> {
+    let st = SafeTensorsFile::open(path)?;
+    let st = &st;
+    let name = |n: &str| lookup(names::CLIP, n);
+
+    let token_embedding = load_embedding_safetensors(st, name("token_embedding")?, device)?;
+    let position_embedding = st.tensor::<B, 2>(&join(name("position_embedding")?, "weight"), device)?.into();
+
+    let blocks_name = name("blocks")?;
+    let block_name = |i: usize| join(blocks_name, &i.to_string());
+    let n_layer = (0..)
+        .take_while(|&i| st.contains(&join(&block_name(i), "layer_norm1.weight")))
+        .count();
+
+    let blocks = (0..n_layer)
+        .into_iter()
+        .map(|i| {
+            load_residual_decoder_attention_block_safetensors::<B>(st, &block_name(i), device, is_open_clip)
+        }).collect::<Result<Vec<_>, _>>()?;
+
+    let layer_norm = load_layer_norm_safetensors(st, name("layer_norm")?, 1e-5, device)?;
+
+    // stored as a linear layer's [n_out, n_in] weight
+    let text_projection = st.tensor::<B, 2>(&join(name("text_projection")?, "weight"), device)
+        .ok()
+        .map(|w| w.transpose().into());
+
+    Ok(CLIP {
+        token_embedding, 
+        position_embedding, 
+        blocks, 
+        layer_norm, 
+        text_projection, 
+        pooled_layer_norm: true, 
+    })
+}
@@ -13,6 +13,7 @@ use burn::{
         Tensor,
         Distribution, 
         Int, 
+        Bool, 
     },
 };
 
@@ -28,6 +29,10 @@ pub struct CLIPConfig {
     n_ctx: usize, 
     n_layer: usize, 
     quick_gelu: bool, 
+    /// Whether the final layer norm is applied to the end of text features before pooling. 
+    /// Some SDXL variants expect the pooled projection of the un-normed features.
+    #[config(default = true)]
+    pooled_layer_norm: bool, 
 }
 
 impl CLIPConfig {
@@ -47,6 +52,7 @@ impl CLIPConfig {
             blocks, 
             layer_norm, 
             text_projection, 
+            pooled_layer_norm: self.pooled_layer_norm, 
         }
     }
 }
@@ -60,6 +66,7 @@ pub struct CLIP<B: Backend> {
     blocks: Vec<ResidualDecoderAttentionBlock<B>>, 
     layer_norm: LayerNorm<B>, 
     text_projection: Option<Param<Tensor<B, 2>>>, 
+    pooled_layer_norm: bool, 
 }
 
 impl<B: Backend> CLIP<B> {
@@ -85,15 +92,21 @@ impl<B: Backend> CLIP<B> {
         }
     }*/
 
+    /// The input of the first transformer block: the token embeddings of `tokens` plus the positional embeddings, 
+    /// shaped `[n_batch, seq_len, n_state]`, i.e. `[n_batch, 77, 768]` for CLIP and `[n_batch, 77, 1280]` for OpenCLIP.
+    pub fn token_embeddings(&self, tokens: Tensor<B, 2, Int>) -> Tensor<B, 3> {
+        let [_, seq_len] = tokens.dims();
+
+        self.token_embedding.forward(tokens) 
+            + self.position_embedding.val().slice([0..seq_len]).unsqueeze()
+    }
+
     pub fn forward_hidden(&self, x: Tensor<B, 2, Int>, hidden_idx: usize) -> Tensor<B, 3> {
         let [n_batch, seq_len] = x.dims();
         
         let mask = attn_decoder_mask(seq_len, &x.device());
 
-        let embedded = self.token_embedding.forward(x) 
-            + self.position_embedding.val().slice([0..seq_len]).unsqueeze();
-        
-        let mut x = embedded;
+        let mut x = self.token_embeddings(x);
         for block in &self.blocks[0..hidden_idx] {
             x = block.forward(x, mask.clone());
         }
@@ -101,15 +114,22 @@ impl<B: Backend> CLIP<B> {
         x
     }
 
+    /// Pools at `end_of_text_indices(text)`, the end of text token for both CLIP's padding with end of text 
+    /// tokens and OpenCLIP's padding with 0. Features never attend to later positions, so the padding after 
+    /// the end of text token doesn't change the pooled output.
     pub fn forward_hidden_pooled(&self, text: Tensor<B, 2, Int>, hidden_idx: usize) -> (Tensor<B, 3>, Tensor<B, 2>) {
+        let eot_indices = end_of_text_indices(text.clone());
+        self.forward_hidden_pooled_at(text, hidden_idx, eot_indices)
+    }
+
+    /// Like `forward_hidden_pooled`, but pools the features at the given end of text positions. 
+    /// Needed when the text holds ids above the end of text token, such as textual inversion tokens.
+    pub fn forward_hidden_pooled_at(&self, text: Tensor<B, 2, Int>, hidden_idx: usize, eot_indices: Tensor<B, 1, Int>) -> (Tensor<B, 3>, Tensor<B, 2>) {
         let [n_batch, seq_len] = text.dims();
         
         let mask = attn_decoder_mask(seq_len, &text.device());
 
-        let embedded = self.token_embedding.forward(text.clone()) 
-            + self.position_embedding.val().slice([0..seq_len]).unsqueeze();
-        
-        let mut x = embedded;
+        let mut x = self.token_embeddings(text);
         let mut h_out = Tensor::empty(x.shape());
         for (i, block) in self.blocks.iter().enumerate() {
             if i == hidden_idx {
@@ -119,10 +139,18 @@ impl<B: Backend> CLIP<B> {
             x = block.forward(x, mask.clone());
         }
 
-        // get features from the eot embedding (eot_token is the highest number in each sequence)
-        let eot_indices = text.argmax(1).squeeze(1);
-        let normed = self.layer_norm.forward(x);
-        let o = normed.select(1, eot_indices).squeeze(1);
+        // get features from the eot embedding
+        let normed = if self.pooled_layer_norm {
+            self.layer_norm.forward(x)
+        } else {
+            x
+        };
+        let o = Tensor::cat(
+            (0..n_batch).map(|b| {
+                normed.clone().slice([b..b + 1]).select(1, eot_indices.clone().slice([b..b + 1]))
+            }).collect(), 
+            0
+        ).squeeze(1);
         let pooled = if let Some(t_proj) = self.text_projection.as_ref() {
             o.matmul(t_proj.val())
         } else {
@@ -132,6 +160,28 @@ impl<B: Backend> CLIP<B> {
         (h_out, pooled)
     }
 
+    /// Returns a copy with `vectors` appended to the token embedding table, so that 
+    /// token id `n_vocab + i` embeds to row `i` of `vectors`.
+    pub fn with_added_token_embeddings(&self, vectors: Tensor<B, 2>) -> CLIP<B> {
+        let weight = self.token_embedding.clone().into_record().weight.val();
+        let [n_vocab, n_state] = weight.dims();
+        let [n_added, _] = vectors.dims();
+
+        let record = nn::EmbeddingRecord {
+            weight: Tensor::cat(vec![weight, vectors], 0).into(), 
+        };
+
+        CLIP {
+            token_embedding: nn::EmbeddingConfig::new(n_vocab + n_added, n_state).init_with(record), 
+            ..self.clone()
+        }
+    }
+
+    /// The `[n_vocab, n_state]` size of the token embedding table.
+    pub fn token_embedding_dims(&self) -> [usize; 2] {
+        self.token_embedding.clone().into_record().weight.val().dims()
+    }
+
     pub fn max_sequence_length(&self) -> usize {
         self.position_embedding.dims()[0]
     }
@@ -184,6 +234,40 @@ impl<B: Backend> ResidualDecoderAttentionBlock<B> {
 }
 
 
+/// Position of the end of text token in each sequence, taken as the first occurrence of the sequence's highest id, 
+/// which is where `CLIP::forward_hidden_pooled` pools. This is the end of text token whether the tokenizer pads 
+/// with it (CLIP) or with 0 (OpenCLIP), as long as the sequence holds no ids above it such as textual inversion 
+/// tokens; use `first_token_indices` with the tokenizer's end of text token then.
+pub fn end_of_text_indices<B: Backend>(text: Tensor<B, 2, Int>) -> Tensor<B, 1, Int> {
+    let [_, seq_len] = text.dims();
+
+    let max = text.clone().max_dim(1).repeat(1, seq_len);
+    first_unmasked_indices(text.dims(), [text.clone().lower(max)], &text.device())
+}
+
+/// Position of the first occurrence of `token` in each sequence.
+pub fn first_token_indices<B: Backend>(text: Tensor<B, 2, Int>, token: u32) -> Tensor<B, 1, Int> {
+    let token = token as i32;
+    let masks = [text.clone().lower_elem(token), text.clone().greater_elem(token)];
+
+    first_unmasked_indices(text.dims(), masks, &text.device())
+}
+
+/// Index of the first position in each row not covered by any of `masks`. Argmax breaks ties differently 
+/// across backends, so later positions get lower ranks to make the first one the unique maximum.
+fn first_unmasked_indices<B: Backend, const N: usize>([n_batch, seq_len]: [usize; 2], masks: [Tensor<B, 2, Bool>; N], device: &B::Device) -> Tensor<B, 1, Int> {
+    let ranks = (-Tensor::arange_device(0..seq_len, device) + seq_len as i64)
+        .unsqueeze()
+        .repeat(0, n_batch);
+
+    masks
+        .into_iter()
+        .fold(ranks, |ranks, mask| ranks.mask_fill(mask, 0))
+        .argmax(1)
+        .squeeze(1)
+}
+
+
 #[derive(Config)]
 pub struct MultiHeadSelfAttentionConfig {
     n_state: usize,
@@ -300,3 +384,67 @@ impl QuickGELU {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::tensor_max_element;
+    use burn_tch::TchBackend;
+
+    type TestBackend = TchBackend<f32>;
+
+    #[test]
+    fn test_pooled_layer_norm() {
+        let clip: CLIP<TestBackend> = CLIPConfig::new(16, 16, 8, 2, 6, 2, false).init();
+        assert!(clip.pooled_layer_norm);
+
+        let unnormed = CLIP {
+            pooled_layer_norm: false, 
+            ..clip.clone()
+        };
+
+        let text = Tensor::from_ints([[1, 4, 7, 15, 0, 0]]);
+        let (hidden, pooled) = clip.forward_hidden_pooled(text.clone(), 1);
+        let (unnormed_hidden, unnormed_pooled) = unnormed.forward_hidden_pooled(text, 1);
+
+        assert_eq!(pooled.dims(), [1, 8]);
+        assert_eq!(unnormed_pooled.dims(), [1, 8]);
+        assert_eq!(hidden.clone().into_data(), unnormed_hidden.into_data());
+
+        assert!(tensor_max_element((pooled - unnormed_pooled).abs()) > 1e-4);
+    }
+
+    #[test]
+    fn test_pooled_ignores_padding() {
+        let clip: CLIP<TestBackend> = CLIPConfig::new(16, 16, 8, 2, 6, 2, false).init();
+
+        // OpenCLIP pads with 0, CLIP with the end of text token
+        let open_clip_text = Tensor::from_ints([[1, 4, 15, 0, 0, 0]]);
+        let clip_text = Tensor::from_ints([[1, 4, 15, 15, 15, 15]]);
+        assert_eq!(end_of_text_indices(open_clip_text.clone()).into_data().value, vec![2]);
+        assert_eq!(end_of_text_indices(clip_text.clone()).into_data().value, vec![2]);
+
+        let (_, pooled) = clip.forward_hidden_pooled(open_clip_text, 1);
+        let (_, clip_pooled) = clip.forward_hidden_pooled(clip_text, 1);
+        let (_, short_pooled) = clip.forward_hidden_pooled(Tensor::from_ints([[1, 4, 15]]), 1);
+        assert!(tensor_max_element((pooled.clone() - clip_pooled).abs()) < 1e-5);
+        assert!(tensor_max_element((pooled - short_pooled).abs()) < 1e-5);
+
+        // ids above the end of text token, e.g. textual inversions, need the token itself
+        let text = Tensor::from_ints([[1, 20, 15, 0], [1, 4, 7, 15]]);
+        assert_eq!(end_of_text_indices(text.clone()).into_data().value, vec![1, 3]);
+        assert_eq!(first_token_indices(text, 15).into_data().value, vec![2, 3]);
+    }
+
+    #[test]
+    fn test_token_embeddings() {
+        let clip: CLIP<TestBackend> = CLIPConfig::new(16, 16, 8, 2, 6, 2, false).init();
+
+        let text = Tensor::from_ints([[1, 4, 7, 15, 0, 0]]);
+        let embedded = clip.token_embeddings(text.clone());
+        assert_eq!(embedded.dims(), [1, 6, 16]);
+
+        // no transformer block has run yet
+        assert_eq!(embedded.into_data(), clip.forward_hidden(text, 0).into_data());
+    }
+}
@@ -1,7 +1,7 @@
 use super::GroupNorm;
 use crate::model::load::*;
+use crate::model::safetensors::{SafeTensorsFile, join};
 
-use std::error::Error;
 
 use burn::{
     config::Config, 
@@ -13,7 +13,7 @@ use burn::{
     },
 };
 
-pub fn load_group_norm<B: Backend>(path: &str, device: &B::Device) -> Result<GroupNorm<B>, Box<dyn Error>> {
+pub fn load_group_norm<B: Backend>(path: &str, device: &B::Device) -> Result<GroupNorm<B>, LoadError> {
     let n_group = load_usize::<B>("n_group", path, device)?.into();
     let n_channel = load_usize::<B>("n_channel", path, device)?.into();
     let eps = load_f32::<B>("eps", path, device)?.into();
@@ -28,6 +28,25 @@ pub fn load_group_norm<B: Backend>(path: &str, device: &B::Device) -> Result<Gro
             gamma, 
             beta, 
             eps, 
+            upcast: false, 
+        } 
+    )
+}
+
+pub fn load_group_norm_safetensors<B: Backend>(st: &SafeTensorsFile, name: &str, n_group: usize, eps: f64, device: &B::Device) -> Result<GroupNorm<B>, LoadError> {
+    let gamma = st.tensor::<B, 1>(&join(name, "weight"), device)?;
+    let beta = st.tensor::<B, 1>(&join(name, "bias"), device)?;
+
+    let [n_channel] = gamma.dims();
+
+    Ok( 
+        GroupNorm { 
+            n_group, 
+            n_channel, 
+            gamma: gamma.into(), 
+            beta: beta.into(), 
+            eps, 
+            upcast: false, 
         } 
     )
 }
\ No newline at end of file
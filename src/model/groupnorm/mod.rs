@@ -15,6 +15,11 @@ pub struct GroupNormConfig {
     n_channel: usize, 
     #[config(default = 1e-5)]
     eps: f64, 
+    /// Computes the statistics of every group scaled down by its largest magnitude, which gives the same 
+    /// result but keeps the squared values in range. In f16, activations above 256 otherwise overflow to 
+    /// infinity when squared. Backends only have one float type, so this stands in for an f32 upcast.
+    #[config(default = false)]
+    upcast: bool, 
 }
 
 impl GroupNormConfig {
@@ -34,6 +39,7 @@ impl GroupNormConfig {
             gamma, 
             beta, 
             eps, 
+            upcast: self.upcast, 
         }
     }
 }
@@ -45,9 +51,19 @@ pub struct GroupNorm<B: Backend> {
     gamma: Param<Tensor<B, 1>>, 
     beta: Param<Tensor<B, 1>>, 
     eps: f64, 
+    upcast: bool, 
 }
 
 impl<B: Backend> GroupNorm<B> {
+    /// The scale and shift, named `weight` and `bias` as in the weight files.
+    pub fn params(&self) -> [(&'static str, &Param<Tensor<B, 1>>); 2] {
+        [("weight", &self.gamma), ("bias", &self.beta)]
+    }
+
+    pub fn params_mut(&mut self) -> [(&'static str, &mut Param<Tensor<B, 1>>); 2] {
+        [("weight", &mut self.gamma), ("bias", &mut self.beta)]
+    }
+
     pub fn forward<const D: usize>(&self, x: Tensor<B, D>) -> Tensor<B, D> {
         let shape = x.shape();
         let n_batch = shape.dims[0];
@@ -56,7 +72,14 @@ impl<B: Backend> GroupNorm<B> {
         let mut affine_shape = [1; D];
         affine_shape[1] = self.n_channel;
 
-        layernorm( x.reshape([n_batch, self.n_group, num_elements / (n_batch * self.n_group) ]), self.eps )
+        let x = x.reshape([n_batch, self.n_group, num_elements / (n_batch * self.n_group) ]);
+        let normalized = if self.upcast {
+            layernorm_scaled(x, self.eps)
+        } else {
+            layernorm(x, self.eps)
+        };
+
+        normalized
             .reshape(shape)
             .mul(self.gamma.val().reshape(affine_shape))
             .add(self.beta.val().reshape(affine_shape))
@@ -69,4 +92,48 @@ pub fn layernorm<B: Backend, const D: usize>(x: Tensor<B, D>, eps: f64) -> Tenso
 
     let u = x.clone() - x.mean_dim(D - 1);
     u.clone().div( (u.clone() * u).mean_dim(D - 1).add_scalar(eps).sqrt() )
-}
\ No newline at end of file
+}
+
+/// `layernorm` computed on the centered values divided by their largest magnitude. Normalization is 
+/// scale invariant, so only `eps` needs rescaling, and no intermediate exceeds the input's magnitude.
+pub fn layernorm_scaled<B: Backend, const D: usize>(x: Tensor<B, D>, eps: f64) -> Tensor<B, D> {
+    let u = x.clone() - x.mean_dim(D - 1);
+    let scale = u.clone().abs().max_dim(D - 1).add_scalar(1e-6);
+
+    // eps / scale^2 without squaring the scale
+    let eps_scaled = scale.clone().powf(-1.0).mul_scalar(eps.sqrt()).powf(2.0);
+
+    let u = u.div(scale);
+    u.clone().div( (u.clone() * u).mean_dim(D - 1).add(eps_scaled).sqrt() )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::tensor::{Distribution, f16};
+
+    type TestBackend = burn_tch::TchBackend<f32>;
+
+    #[test]
+    fn test_upcast_matches_plain() {
+        let x: Tensor<TestBackend, 4> = Tensor::random([2, 8, 4, 4], Distribution::Normal(0.0, 3.0));
+
+        let plain: GroupNorm<TestBackend> = GroupNormConfig::new(4, 8).init();
+        let upcast: GroupNorm<TestBackend> = GroupNormConfig::new(4, 8).with_upcast(true).init();
+
+        let diff = (plain.forward(x.clone()) - upcast.forward(x)).abs().max().into_scalar();
+        assert!(diff < 1e-4);
+    }
+
+    #[test]
+    fn test_upcast_f16_large_activations() {
+        type HalfBackend = burn_tch::TchBackend<f16>;
+
+        // squares of these overflow f16
+        let x: Tensor<HalfBackend, 4> = Tensor::random([1, 8, 4, 4], Distribution::Normal(0.0, 1.0)).mul_scalar(1000.0);
+        let norm: GroupNorm<HalfBackend> = GroupNormConfig::new(4, 8).with_upcast(true).init();
+
+        let values = norm.forward(x).into_data().convert::<f32>().value;
+        assert!(values.iter().all(|v| v.is_finite() && v.abs() < 10.0));
+    }
+}
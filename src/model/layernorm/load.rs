@@ -1,7 +1,7 @@
 use super::LayerNorm;
 use crate::model::load::*;
+use crate::model::safetensors::{SafeTensorsFile, join};
 
-use std::error::Error;
 
 use burn::{
     config::Config, 
@@ -13,12 +13,25 @@ use burn::{
     },
 };
 
-pub fn load_layer_norm<B: Backend>(path: &str, device: &B::Device) -> Result<LayerNorm<B>, Box<dyn Error>> {
+pub fn load_layer_norm<B: Backend>(path: &str, device: &B::Device) -> Result<LayerNorm<B>, LoadError> {
     let eps = load_f32::<B>("eps", path, device)?.into();
 
     let gamma = load_tensor::<B, 1>("weight", path, device)?.into();
     let beta = load_tensor::<B, 1>("bias", path, device)?.into();
 
+    Ok( 
+        LayerNorm { 
+            gamma, 
+            beta, 
+            eps, 
+        } 
+    )
+}
+
+pub fn load_layer_norm_safetensors<B: Backend>(st: &SafeTensorsFile, name: &str, eps: f64, device: &B::Device) -> Result<LayerNorm<B>, LoadError> {
+    let gamma = st.tensor::<B, 1>(&join(name, "weight"), device)?.into();
+    let beta = st.tensor::<B, 1>(&join(name, "bias"), device)?.into();
+
     Ok( 
         LayerNorm { 
             gamma, 
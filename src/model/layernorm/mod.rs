@@ -39,6 +39,15 @@ pub struct LayerNorm<B: Backend> {
 }
 
 impl<B: Backend> LayerNorm<B> {
+    /// The scale and shift, named `weight` and `bias` as in the weight files.
+    pub fn params(&self) -> [(&'static str, &Param<Tensor<B, 1>>); 2] {
+        [("weight", &self.gamma), ("bias", &self.beta)]
+    }
+
+    pub fn params_mut(&mut self) -> [(&'static str, &mut Param<Tensor<B, 1>>); 2] {
+        [("weight", &mut self.gamma), ("bias", &mut self.beta)]
+    }
+
     pub fn forward<const D: usize>(&self, x: Tensor<B, D>) -> Tensor<B, D> {
         layernorm(x, self.eps)
             .mul(self.gamma.val().unsqueeze())
@@ -1,6 +1,8 @@
 use std::error::Error;
+use std::fmt;
 use std::io::Read;
 use npy::{self, NpyData};
+use sha2::{Sha256, Digest};
 use num_traits::cast::ToPrimitive;
 
 use burn::{
@@ -15,6 +17,127 @@ use burn::{
 };
 
 use burn::tensor::ElementConversion;
+use burn::record::{Recorder, BinFileRecorder, HalfPrecisionSettings};
+
+use super::safetensors::{SafeTensorsFile, join};
+
+/// Why a model or one of its parts failed to load, with the file or tensor it failed on.
+#[derive(Debug)]
+pub enum LoadError {
+    /// A model's `.cfg` file is missing or can't be parsed.
+    ConfigNotFound { path: String, message: String }, 
+    /// A saved record can't be read or doesn't match the module it is loaded into.
+    RecordMismatch { path: String, message: String }, 
+    /// A tensor doesn't have the shape the module expects.
+    ShapeMismatch { name: String, message: String }, 
+    /// A parameter file or checkpoint is missing, unreadable, or doesn't contain the tensor.
+    ReadFailed { path: String, message: String }, 
+    /// A config parses but describes a model the crate can't represent, e.g. the refiner UNet in a diffusers directory.
+    UnsupportedConfig { path: String, message: String }, 
+    /// A weight file's SHA-256 digest differs from the expected one, e.g. after a truncated download.
+    ChecksumMismatch { path: String, expected: String, actual: String }, 
+}
+
+impl LoadError {
+    pub fn read_failed(path: &str, error: impl fmt::Display) -> Self {
+        LoadError::ReadFailed {
+            path: path.to_string(), 
+            message: error.to_string(), 
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::ConfigNotFound { path, message } => write!(f, "Failed to load config {}: {}", path, message), 
+            LoadError::RecordMismatch { path, message } => write!(f, "Failed to load record {}: {}", path, message), 
+            LoadError::ShapeMismatch { name, message } => write!(f, "Shape mismatch in {}: {}", name, message), 
+            LoadError::ReadFailed { path, message } => write!(f, "Failed to read {}: {}", path, message), 
+            LoadError::UnsupportedConfig { path, message } => write!(f, "Unsupported config {}: {}", path, message), 
+            LoadError::ChecksumMismatch { path, expected, actual } => write!(f, "Checksum mismatch in {}: expected SHA-256 {} but got {}, the file may be corrupt or incomplete", path, expected, actual), 
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+/// Loads a model config saved with `Config::save`, e.g. `embedder.cfg`.
+pub fn load_config<C: Config>(path: &str) -> Result<C, LoadError> {
+    C::load(path).map_err(|e| LoadError::ConfigNotFound {
+        path: path.to_string(), 
+        message: e.to_string(), 
+    })
+}
+
+/// Loads the half precision record saved at `path` (without the `.bin` extension) into `module`.
+pub fn load_record<B: Backend, M: Module<B>>(module: M, path: &str) -> Result<M, LoadError> {
+    let record = BinFileRecorder::<HalfPrecisionSettings>::new()
+        .load(path.into())
+        .map_err(|e| LoadError::RecordMismatch {
+            path: path.to_string(), 
+            message: e.to_string(), 
+        })?;
+
+    Ok( module.load_record(record) )
+}
+
+/// Saves the record of `module` at `path` (without the `.bin` extension) in the half precision format `load_record` reads.
+pub fn save_record<B: Backend, M: Module<B>>(module: M, path: &str) -> Result<(), LoadError> {
+    BinFileRecorder::<HalfPrecisionSettings>::new()
+        .record(module.into_record(), path.into())
+        .map_err(|e| LoadError::RecordMismatch {
+            path: path.to_string(), 
+            message: e.to_string(), 
+        })
+}
+
+/// Like `load_record`, but first checks the SHA-256 digest of the record file `{path}.bin` against 
+/// `expected_sha256` (hex, case insensitive). `None` skips the check, which reads the whole file once more.
+pub fn load_record_verified<B: Backend, M: Module<B>>(module: M, path: &str, expected_sha256: Option<&str>) -> Result<M, LoadError> {
+    if let Some(expected) = expected_sha256 {
+        verify_sha256(&format!("{}.bin", path), expected)?;
+    }
+
+    load_record(module, path)
+}
+
+/// Runs a loader on the weight file at `path` after checking its SHA-256 digest against `expected_sha256`, 
+/// e.g. `load_verified(path, Some(digest), |path| load_unet_safetensors(path, &device))`. `None` skips the check.
+pub fn load_verified<T>(path: &str, expected_sha256: Option<&str>, load: impl FnOnce(&str) -> Result<T, LoadError>) -> Result<T, LoadError> {
+    if let Some(expected) = expected_sha256 {
+        verify_sha256(path, expected)?;
+    }
+
+    load(path)
+}
+
+/// Fails with `LoadError::ChecksumMismatch` unless the file at `path` has the SHA-256 digest `expected` 
+/// (hex, case insensitive). The file is streamed, so this works for multi-gigabyte checkpoints.
+pub fn verify_sha256(path: &str, expected: &str) -> Result<(), LoadError> {
+    let mut file = std::fs::File::open(path).map_err(|e| LoadError::read_failed(path, e))?;
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buf).map_err(|e| LoadError::read_failed(path, e))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    let actual: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+    if !actual.eq_ignore_ascii_case(expected.trim()) {
+        return Err(LoadError::ChecksumMismatch {
+            path: path.to_string(), 
+            expected: expected.to_string(), 
+            actual, 
+        });
+    }
+
+    Ok(())
+}
 
 pub fn numpy_to_tensor<B: Backend, const D: usize>(numpy_data: NpyData<f32>, device: &B::Device) -> Tensor<B, D> {
     let mut v = numpy_data.to_vec();
@@ -25,31 +148,44 @@ pub fn numpy_to_tensor<B: Backend, const D: usize>(numpy_data: NpyData<f32>, dev
     Tensor::from_data_device(Data::new(data, shape.into()), device)
 }
 
-pub fn load_tensor<B: Backend, const D: usize>(name: &str, path: &str, device: &B::Device) -> Result<Tensor<B, D>, Box<dyn Error>> {
+pub fn load_tensor<B: Backend, const D: usize>(name: &str, path: &str, device: &B::Device) -> Result<Tensor<B, D>, LoadError> {
     let tensor_path = format!("{}/{}.npy", path, name);
 
     let mut buf = vec![];
-    std::fs::File::open(&tensor_path)?
-        .read_to_end(&mut buf)?;
-
-    let tensor_numpy: NpyData<f32> = NpyData::from_bytes(&buf)?;
+    std::fs::File::open(&tensor_path)
+        .and_then(|mut file| file.read_to_end(&mut buf))
+        .map_err(|e| LoadError::read_failed(&tensor_path, e))?;
+
+    let tensor_numpy: NpyData<f32> = NpyData::from_bytes(&buf)
+        .map_err(|e| LoadError::read_failed(&tensor_path, e))?;
+
+    // the dimensions are stored in front of the values
+    let v = tensor_numpy.to_vec();
+    let shape: Vec<usize> = v.iter().take(D).map(|&v| v as usize).collect();
+    if v.len() < D || v.len() - D != shape.iter().product::<usize>() {
+        return Err(LoadError::ShapeMismatch {
+            name: tensor_path, 
+            message: format!("expected a {}D tensor but the file holds {} values for dimensions {:?}", D, v.len(), shape), 
+        });
+    }
 
-    let tensor = numpy_to_tensor(tensor_numpy, device);
+    let data: Vec<B::FloatElem> = v[D..].into_iter().map(|e| e.elem()).collect();
+    let tensor = Tensor::from_data_device(Data::new(data, shape.into()), device);
 
     println!("{}", tensor_path);
 
     Ok(tensor)
 }
 
-pub fn load_f32<B: Backend>(name: &str, path: &str, device: &B::Device) -> Result<f32, Box<dyn Error>> {
+pub fn load_f32<B: Backend>(name: &str, path: &str, device: &B::Device) -> Result<f32, LoadError> {
     load_tensor::<B, 1>(name, path, device).map(|t| t.into_scalar().to_f32().unwrap())
 }
 
-pub fn load_usize<B: Backend>(name: &str, path: &str, device: &B::Device) -> Result<usize, Box<dyn Error>> {
+pub fn load_usize<B: Backend>(name: &str, path: &str, device: &B::Device) -> Result<usize, LoadError> {
     load_tensor::<B, 1>(name, path, device).map(|t| t.into_scalar().to_usize().unwrap())
 }
 
-pub fn load_linear<B: Backend>(path: &str, device: &B::Device) -> Result<nn::Linear<B>, Box<dyn Error>> {
+pub fn load_linear<B: Backend>(path: &str, device: &B::Device) -> Result<nn::Linear<B>, LoadError> {
     let weight = load_tensor::<B, 2>("weight", path, device)?;
     let bias = load_tensor::<B, 1>("bias", path, device).ok();
 
@@ -62,7 +198,7 @@ pub fn load_linear<B: Backend>(path: &str, device: &B::Device) -> Result<nn::Lin
     Ok(linear)
 }
 
-pub fn load_embedding<B: Backend>(path: &str, device: &B::Device) -> Result<nn::Embedding<B>, Box<dyn Error>> {
+pub fn load_embedding<B: Backend>(path: &str, device: &B::Device) -> Result<nn::Embedding<B>, LoadError> {
     let weight = load_tensor::<B, 2>("weight", path, device)?;
     let [n_vocab, n_state] = weight.dims();
 
@@ -74,7 +210,7 @@ pub fn load_embedding<B: Backend>(path: &str, device: &B::Device) -> Result<nn::
     Ok(embedding)
 }
 
-/*pub fn load_layer_norm<B: Backend>(path: &str, device: &B::Device) -> Result<nn::LayerNorm<B>, Box<dyn Error>> {
+/*pub fn load_layer_norm<B: Backend>(path: &str, device: &B::Device) -> Result<nn::LayerNorm<B>, LoadError> {
     let weight = load_tensor::<B, 1>("weight", path, device)?;
     let bias = load_tensor::<B, 1>("bias", path, device)?;
     let eps = load_f32::<B>("eps", path, device)? as f64;
@@ -93,7 +229,7 @@ pub fn load_embedding<B: Backend>(path: &str, device: &B::Device) -> Result<nn::
 }*/
 
 
-/*pub fn load_rmsnorm<B: Backend>(path: &str, device: &B::Device) -> Result<RMSNorm<B>, Box<dyn Error>> {
+/*pub fn load_rmsnorm<B: Backend>(path: &str, device: &B::Device) -> Result<RMSNorm<B>, LoadError> {
     let weight = load_tensor::<B, 1>("weight", path, device)?;
     let eps = load_f32::<B>("eps", path, device)?.into();
 
@@ -105,7 +241,7 @@ pub fn load_embedding<B: Backend>(path: &str, device: &B::Device) -> Result<nn::
     Ok(rmsnorm)
 }*/
 
-pub fn load_conv2d<B: Backend>(path: &str, device: &B::Device) -> Result<conv::Conv2d<B>, Box<dyn Error>> {
+pub fn load_conv2d<B: Backend>(path: &str, device: &B::Device) -> Result<conv::Conv2d<B>, LoadError> {
     let weight = load_tensor::<B, 4>("weight", path, device)?;
     let bias = load_tensor::<B, 1>("bias", path, device).ok();
     let has_bias = bias.is_some();
@@ -148,6 +284,80 @@ pub fn load_conv2d<B: Backend>(path: &str, device: &B::Device) -> Result<conv::C
     Ok(conv2d)
 }
 
+pub fn load_linear_safetensors<B: Backend>(st: &SafeTensorsFile, name: &str, device: &B::Device) -> Result<nn::Linear<B>, LoadError> {
+    // torch stores linear weights as [n_out, n_in]
+    let weight = st.tensor::<B, 2>(&join(name, "weight"), device)?.transpose();
+    let bias = st.tensor::<B, 1>(&join(name, "bias"), device).ok();
+    let has_bias = bias.is_some();
+
+    let [n_in, n_out] = weight.dims();
+
+    let record = nn::LinearRecord {
+        weight: weight.into(),
+        bias: bias.map(|t| t.into()),
+    };
+
+    let linear: nn::Linear<B> = nn::LinearConfig::new(n_in, n_out)
+        .with_bias(has_bias)
+        .init_with(record);
+    Ok(linear)
+}
+
+pub fn load_embedding_safetensors<B: Backend>(st: &SafeTensorsFile, name: &str, device: &B::Device) -> Result<nn::Embedding<B>, LoadError> {
+    let weight = st.tensor::<B, 2>(&join(name, "weight"), device)?;
+    let [n_vocab, n_state] = weight.dims();
+
+    let record = nn::EmbeddingRecord {
+        weight: weight.into(),
+    };
+
+    let embedding = nn::EmbeddingConfig::new(n_vocab, n_state).init_with(record);
+    Ok(embedding)
+}
+
+/// Safetensors checkpoints don't store conv hyperparameters so stride and padding must be supplied.
+/// Linear weights of shape [n_out, n_in] are accepted as 1x1 convolutions.
+pub fn load_conv2d_safetensors<B: Backend>(st: &SafeTensorsFile, name: &str, stride: usize, padding: usize, device: &B::Device) -> Result<conv::Conv2d<B>, LoadError> {
+    let weight_name = join(name, "weight");
+    let weight = match st.shape(&weight_name).map(|shape| shape.len()) {
+        Some(2) => {
+            let weight = st.tensor::<B, 2>(&weight_name, device)?;
+            let [n_out, n_in] = weight.dims();
+            weight.reshape([n_out, n_in, 1, 1])
+        }, 
+        _ => st.tensor::<B, 4>(&weight_name, device)?, 
+    };
+    let bias = st.tensor::<B, 1>(&join(name, "bias"), device).ok();
+    let has_bias = bias.is_some();
+
+    let [n_channels_out, n_channels_in, kernel_h, kernel_w] = weight.dims();
+
+    let stride = [stride, stride];
+    let kernel_size = [kernel_h, kernel_w];
+    let dilation = [1, 1];
+    let n_group = 1;
+    let padding = nn::PaddingConfig2d::Explicit(padding, padding);
+
+    let record = conv::Conv2dRecord {
+        weight: weight.into(),
+        bias: bias.map(|t| t.into()),
+        stride: <[usize; 2] as Module<B>>::into_record(stride), 
+        kernel_size: <[usize; 2] as Module<B>>::into_record(kernel_size), 
+        dilation: <[usize; 2] as Module<B>>::into_record(dilation), 
+        groups: <usize as Module<B>>::into_record(n_group),
+        padding: <nn::PaddingConfig2d as Module<B>>::into_record(padding.clone()), 
+    };
+
+    let conv2d: conv::Conv2d<B> = conv::Conv2dConfig::new([n_channels_in, n_channels_out], kernel_size)
+        .with_stride(stride)
+        .with_dilation(dilation)
+        .with_groups(n_group)
+        .with_padding(padding)
+        .with_bias(has_bias)
+        .init_with(record);
+    Ok(conv2d)
+}
+
 pub fn tensor_to_array_2<B: Backend>(x: Tensor<B, 1>) -> [usize; 2] {
     let vec = x.into_data().value;
     assert!(vec.len() == 2, "Tensor length must be 2.");
@@ -164,4 +374,46 @@ pub fn tensor_to_array<const N: usize, B: Backend>(x: Tensor<B, 1>) -> [usize; N
     }
 
     arr
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type TestBackend = burn_tch::TchBackend<f32>;
+
+    #[test]
+    fn test_missing_files_report_path() {
+        let device = Default::default();
+
+        match load_tensor::<TestBackend, 2>("weight", "does/not/exist", &device) {
+            Err(LoadError::ReadFailed { path, .. }) => assert_eq!(path, "does/not/exist/weight.npy"),
+            other => panic!("expected ReadFailed, got {:?}", other.map(|t| t.dims())),
+        }
+
+        match load_config::<nn::LinearConfig>("does/not/exist.cfg") {
+            Err(LoadError::ConfigNotFound { path, .. }) => assert_eq!(path, "does/not/exist.cfg"),
+            other => panic!("expected ConfigNotFound, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_verify_sha256() {
+        let path = std::env::temp_dir().join(format!("sdxl_checksum_{}.bin", std::process::id()));
+        std::fs::write(&path, b"abc").unwrap();
+        let path = path.to_str().unwrap();
+
+        // SHA-256 of "abc"
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert!(verify_sha256(path, digest).is_ok());
+        assert!(verify_sha256(path, &digest.to_uppercase()).is_ok());
+        assert_eq!(load_verified(path, None, |_| Ok(1)).unwrap(), 1);
+
+        std::fs::write(path, b"ab").unwrap();
+        match load_verified(path, Some(digest), |_| Ok(1)) {
+            Err(LoadError::ChecksumMismatch { actual, .. }) => assert_ne!(actual, digest),
+            other => panic!("expected ChecksumMismatch, got {:?}", other),
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -8,5 +8,7 @@ pub mod silu;
 pub mod groupnorm;
 pub mod layernorm;
 pub mod attention;
+pub mod params;
 
-pub mod load;
\ No newline at end of file
+pub mod load;
+pub mod safetensors;
\ No newline at end of file
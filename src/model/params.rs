@@ -0,0 +1,57 @@
+use burn::{
+    module::Module,
+    tensor::backend::Backend,
+};
+
+/// Floating point precision the weights of a model are stored in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Precision {
+    F16,
+    F32,
+}
+
+impl Precision {
+    pub fn bytes_per_parameter(&self) -> usize {
+        match self {
+            Precision::F16 => 2,
+            Precision::F32 => 4,
+        }
+    }
+}
+
+/// Parameter counting for any module, e.g. `UNet`, `Decoder`, `CLIP` or a whole `Diffuser`.
+pub trait ParameterCount<B: Backend>: Module<B> {
+    /// Total number of scalar parameters in the module and all its submodules.
+    fn num_parameters(&self) -> usize {
+        self.num_params()
+    }
+
+    /// Memory taken by the weights alone when stored in `precision`, excluding activations.
+    fn estimate_memory_bytes(&self, precision: Precision) -> usize {
+        estimate_memory_bytes(self.num_parameters(), precision)
+    }
+}
+
+impl<B: Backend, M: Module<B>> ParameterCount<B> for M {}
+
+/// Memory taken by `n_parameters` weights stored in `precision`.
+pub fn estimate_memory_bytes(n_parameters: usize, precision: Precision) -> usize {
+    n_parameters * precision.bytes_per_parameter()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::nn::LinearConfig;
+    use burn_tch::TchBackend;
+
+    type TestBackend = TchBackend<f32>;
+
+    #[test]
+    fn test_num_parameters() {
+        let linear = LinearConfig::new(3, 4).init::<TestBackend>();
+        assert_eq!(linear.num_parameters(), 3 * 4 + 4);
+        assert_eq!(linear.estimate_memory_bytes(Precision::F16), 32);
+        assert_eq!(linear.estimate_memory_bytes(Precision::F32), 64);
+    }
+}
@@ -0,0 +1,148 @@
+pub mod names;
+
+use crate::model::load::LoadError;
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Range;
+
+use safetensors::{SafeTensors, Dtype};
+
+use burn::tensor::{
+    backend::Backend,
+    Tensor,
+    Data, 
+    Shape, 
+    ElementConversion, 
+    f16, 
+};
+
+/// A `.safetensors` checkpoint held in memory.
+pub struct SafeTensorsFile {
+    buffer: Vec<u8>, 
+    /// Every tensor in the header, which is only parsed once in `open`.
+    tensors: HashMap<String, TensorEntry>, 
+}
+
+struct TensorEntry {
+    dtype: Dtype, 
+    shape: Vec<usize>, 
+    /// Where the tensor's bytes are in the file buffer.
+    bytes: Range<usize>, 
+}
+
+impl SafeTensorsFile {
+    pub fn open(path: &str) -> Result<Self, LoadError> {
+        let mut buffer = vec![];
+        std::fs::File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut buffer))
+            .map_err(|e| LoadError::read_failed(path, e))?;
+
+        // the data follows the 8 byte header length and the header itself
+        let (header_len, metadata) = SafeTensors::read_metadata(&buffer)
+            .map_err(|e| LoadError::read_failed(path, format!("{:?}", e)))?;
+        let data_start = 8 + header_len;
+
+        let mut tensors = HashMap::new();
+        for (name, info) in metadata.tensors() {
+            let (start, end) = info.data_offsets;
+            let bytes = data_start + start..data_start + end;
+            if start > end || bytes.end > buffer.len() {
+                return Err( LoadError::read_failed(path, format!("the data of {} is out of bounds", name)) );
+            }
+
+            tensors.insert(name, TensorEntry {
+                dtype: info.dtype, 
+                shape: info.shape.clone(), 
+                bytes, 
+            });
+        }
+
+        Ok(Self {
+            buffer, 
+            tensors, 
+        })
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.tensors.contains_key(name)
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.tensors.keys().cloned().collect()
+    }
+
+    pub fn shape(&self, name: &str) -> Option<Vec<usize>> {
+        self.tensors.get(name).map(|entry| entry.shape.clone())
+    }
+
+    pub fn tensor<B: Backend, const D: usize>(&self, name: &str, device: &B::Device) -> Result<Tensor<B, D>, LoadError> {
+        let (shape, values) = self.values(name)?;
+        if shape.len() != D {
+            return Err( shape_mismatch(name, format!("{:?} has {} dimensions but {} were expected", shape, shape.len(), D)) );
+        }
+
+        let mut dims = [0; D];
+        dims.copy_from_slice(&shape);
+
+        let data: Vec<B::FloatElem> = values.into_iter().map(|v| v.elem()).collect();
+        Ok( Tensor::from_data_device(Data::new(data, Shape::new(dims)), device) )
+    }
+
+    /// Reads a tensor holding a single value, such as a LoRA alpha.
+    pub fn scalar(&self, name: &str) -> Result<f32, LoadError> {
+        let (_, values) = self.values(name)?;
+        match values[..] {
+            [value] => Ok(value), 
+            _ => Err( shape_mismatch(name, format!("{} elements where a scalar was expected", values.len())) ), 
+        }
+    }
+
+    /// Reads a 1D or 2D tensor as a list of rows, such as the vectors of a textual inversion embedding.
+    pub fn rows(&self, name: &str) -> Result<Vec<Vec<f32>>, LoadError> {
+        let (shape, values) = self.values(name)?;
+        match shape[..] {
+            [_] => Ok( vec![values] ), 
+            [_, n_col] => Ok( values.chunks(n_col).map(|row| row.to_vec()).collect() ), 
+            _ => Err( shape_mismatch(name, format!("{:?} has {} dimensions but 1 or 2 were expected", shape, shape.len())) ), 
+        }
+    }
+
+    fn values(&self, name: &str) -> Result<(Vec<usize>, Vec<f32>), LoadError> {
+        let entry = self.tensors.get(name)
+            .ok_or_else(|| LoadError::read_failed(name, "no such tensor in the checkpoint"))?;
+
+        let bytes = &self.buffer[entry.bytes.clone()];
+        let values: Vec<f32> = match entry.dtype {
+            Dtype::F32 => bytes
+                .chunks_exact(4)
+                .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(), 
+            Dtype::F16 => bytes
+                .chunks_exact(2)
+                .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+                .collect(), 
+            Dtype::BF16 => bytes
+                .chunks_exact(2)
+                .map(|b| f32::from_bits( (u16::from_le_bytes([b[0], b[1]]) as u32) << 16 ))
+                .collect(), 
+            dtype => return Err( LoadError::read_failed(name, format!("unsupported dtype {:?}", dtype)) ), 
+        };
+
+        Ok( (entry.shape.clone(), values) )
+    }
+}
+
+fn shape_mismatch(name: &str, message: String) -> LoadError {
+    LoadError::ShapeMismatch {
+        name: name.to_string(), 
+        message, 
+    }
+}
+
+pub fn join(prefix: &str, name: &str) -> String {
+    if prefix.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", prefix, name)
+    }
+}
@@ -0,0 +1,179 @@
+// Maps the crate's module names (the same paths used by the weight dumps) onto the tensor names 
+// used by HuggingFace diffusers/transformers checkpoints. Each table is relative to its parent module.
+
+use crate::model::load::LoadError;
+
+pub type NameTable = &'static [(&'static str, &'static str)];
+
+pub fn lookup(table: NameTable, name: &str) -> Result<&'static str, LoadError> {
+    table
+        .iter()
+        .find(|(crate_name, _)| *crate_name == name)
+        .map(|(_, hf_name)| *hf_name)
+        .ok_or_else(|| LoadError::read_failed(name, "no safetensors name mapping"))
+}
+
+
+pub const UNET: NameTable = &[
+    ("lin1_time_embed", "time_embedding.linear_1"), 
+    ("lin2_time_embed", "time_embedding.linear_2"), 
+    ("lin1_label_embed", "add_embedding.linear_1"), 
+    ("lin2_label_embed", "add_embedding.linear_2"), 
+
+    ("input_blocks/conv", "conv_in"), 
+    ("input_blocks/r1", "down_blocks.0.resnets.0"), 
+    ("input_blocks/r2", "down_blocks.0.resnets.1"), 
+    ("input_blocks/d1", "down_blocks.0.downsamplers.0.conv"), 
+    ("input_blocks/rt1/res", "down_blocks.1.resnets.0"), 
+    ("input_blocks/rt1/transformer", "down_blocks.1.attentions.0"), 
+    ("input_blocks/rt2/res", "down_blocks.1.resnets.1"), 
+    ("input_blocks/rt2/transformer", "down_blocks.1.attentions.1"), 
+    ("input_blocks/d2", "down_blocks.1.downsamplers.0.conv"), 
+    ("input_blocks/rt3/res", "down_blocks.2.resnets.0"), 
+    ("input_blocks/rt3/transformer", "down_blocks.2.attentions.0"), 
+    ("input_blocks/rt4/res", "down_blocks.2.resnets.1"), 
+    ("input_blocks/rt4/transformer", "down_blocks.2.attentions.1"), 
+
+    ("middle_block/res1", "mid_block.resnets.0"), 
+    ("middle_block/transformer", "mid_block.attentions.0"), 
+    ("middle_block/res2", "mid_block.resnets.1"), 
+
+    ("output_blocks/rt1/res", "up_blocks.0.resnets.0"), 
+    ("output_blocks/rt1/transformer", "up_blocks.0.attentions.0"), 
+    ("output_blocks/rt2/res", "up_blocks.0.resnets.1"), 
+    ("output_blocks/rt2/transformer", "up_blocks.0.attentions.1"), 
+    ("output_blocks/rtu1/res", "up_blocks.0.resnets.2"), 
+    ("output_blocks/rtu1/transformer", "up_blocks.0.attentions.2"), 
+    ("output_blocks/rtu1/upsample/conv", "up_blocks.0.upsamplers.0.conv"), 
+    ("output_blocks/rt3/res", "up_blocks.1.resnets.0"), 
+    ("output_blocks/rt3/transformer", "up_blocks.1.attentions.0"), 
+    ("output_blocks/rt4/res", "up_blocks.1.resnets.1"), 
+    ("output_blocks/rt4/transformer", "up_blocks.1.attentions.1"), 
+    ("output_blocks/rtu2/res", "up_blocks.1.resnets.2"), 
+    ("output_blocks/rtu2/transformer", "up_blocks.1.attentions.2"), 
+    ("output_blocks/rtu2/upsample/conv", "up_blocks.1.upsamplers.0.conv"), 
+    ("output_blocks/r1", "up_blocks.2.resnets.0"), 
+    ("output_blocks/r2", "up_blocks.2.resnets.1"), 
+    ("output_blocks/r3", "up_blocks.2.resnets.2"), 
+
+    ("norm_out", "conv_norm_out"), 
+    ("conv_out", "conv_out"), 
+];
+
+pub const UNET_RES_BLOCK: NameTable = &[
+    ("norm_in", "norm1"), 
+    ("conv_in", "conv1"), 
+    ("lin_embed", "time_emb_proj"), 
+    ("norm_out", "norm2"), 
+    ("conv_out", "conv2"), 
+    ("skip_connection", "conv_shortcut"), 
+];
+
+pub const UNET_SPATIAL_TRANSFORMER: NameTable = &[
+    ("norm", "norm"), 
+    ("proj_in", "proj_in"), 
+    ("blocks", "transformer_blocks"), 
+    ("proj_out", "proj_out"), 
+];
+
+pub const UNET_TRANSFORMER_BLOCK: NameTable = &[
+    ("norm1", "norm1"), 
+    ("attn1", "attn1"), 
+    ("norm2", "norm2"), 
+    ("attn2", "attn2"), 
+    ("norm3", "norm3"), 
+    ("mlp/geglu/proj", "ff.net.0.proj"), 
+    ("mlp/lin", "ff.net.2"), 
+];
+
+pub const UNET_ATTENTION: NameTable = &[
+    ("query", "to_q"), 
+    ("key", "to_k"), 
+    ("value", "to_v"), 
+    ("out", "to_out.0"), 
+];
+
+
+pub const AUTOENCODER: NameTable = &[
+    ("encoder", "encoder"), 
+    ("decoder", "decoder"), 
+    ("quant_conv", "quant_conv"), 
+    ("post_quant_conv", "post_quant_conv"), 
+];
+
+pub const VAE_CODER: NameTable = &[
+    ("conv_in", "conv_in"), 
+    ("mid/block_1", "mid_block.resnets.0"), 
+    ("mid/attn", "mid_block.attentions.0"), 
+    ("mid/block_2", "mid_block.resnets.1"), 
+    ("encoder/blocks", "down_blocks"), 
+    ("decoder/blocks", "up_blocks"), 
+    ("norm_out", "conv_norm_out"), 
+    ("conv_out", "conv_out"), 
+];
+
+pub const VAE_ENCODER_BLOCK: NameTable = &[
+    ("res1", "resnets.0"), 
+    ("res2", "resnets.1"), 
+    ("downsampler/conv", "downsamplers.0.conv"), 
+];
+
+pub const VAE_DECODER_BLOCK: NameTable = &[
+    ("res1", "resnets.0"), 
+    ("res2", "resnets.1"), 
+    ("res3", "resnets.2"), 
+    ("upsampler", "upsamplers.0.conv"), 
+];
+
+pub const VAE_RESNET_BLOCK: NameTable = &[
+    ("norm1", "norm1"), 
+    ("conv1", "conv1"), 
+    ("norm2", "norm2"), 
+    ("conv2", "conv2"), 
+    ("nin_shortcut", "conv_shortcut"), 
+];
+
+pub const VAE_ATTENTION: NameTable = &[
+    ("norm", "group_norm"), 
+    ("q", "to_q"), 
+    ("k", "to_k"), 
+    ("v", "to_v"), 
+    ("proj_out", "to_out.0"), 
+];
+
+// older diffusers checkpoints
+pub const VAE_ATTENTION_LEGACY: NameTable = &[
+    ("norm", "group_norm"), 
+    ("q", "query"), 
+    ("k", "key"), 
+    ("v", "value"), 
+    ("proj_out", "proj_attn"), 
+];
+
+
+pub const CLIP: NameTable = &[
+    ("token_embedding", "text_model.embeddings.token_embedding"), 
+    ("position_embedding", "text_model.embeddings.position_embedding"), 
+    ("blocks", "text_model.encoder.layers"), 
+    ("layer_norm", "text_model.final_layer_norm"), 
+    ("text_projection", "text_projection"), 
+];
+
+pub const CLIP_BLOCK: NameTable = &[
+    ("attn", "self_attn"), 
+    ("attn_ln", "layer_norm1"), 
+    ("mlp", "mlp"), 
+    ("mlp_ln", "layer_norm2"), 
+];
+
+pub const CLIP_ATTENTION: NameTable = &[
+    ("query", "q_proj"), 
+    ("key", "k_proj"), 
+    ("value", "v_proj"), 
+    ("out", "out_proj"), 
+];
+
+pub const CLIP_MLP: NameTable = &[
+    ("fc1", "fc1"), 
+    ("fc2", "fc2"), 
+];
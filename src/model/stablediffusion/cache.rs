@@ -0,0 +1,146 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use burn::{
+    module::Module, 
+    tensor::{
+        backend::Backend, 
+        Data, 
+        Tensor, 
+    }, 
+};
+
+/// An LRU cache of text encoder outputs, see `Embedder::with_cache`. Entries are keyed by the prompts, 
+/// the `clip_skip` and whether the prompts were chunked, so changing any of them misses the cache.
+/// The embeddings are kept in host memory as f32, which makes the cache independent of the backend and
+/// device, e.g. when the embedder is moved between devices by `offload::StagedPipeline`.
+/// Clones share their entries.
+#[derive(Module, Clone, Debug)]
+pub struct EmbeddingCache {
+    capacity: usize, 
+    entries: Arc<Mutex<VecDeque<(EmbeddingKey, CachedEmbedding)>>>, 
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct EmbeddingKey {
+    texts: Vec<String>, 
+    clip_skip: usize, 
+    chunked: bool, 
+}
+
+#[derive(Clone, Debug)]
+struct CachedEmbedding {
+    context: Data<f32, 3>, 
+    pooled: Data<f32, 2>, 
+}
+
+impl EmbeddingCache {
+    /// A cache holding the embeddings of up to `capacity` prompt batches.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity, 
+            entries: Arc::new( Mutex::new( VecDeque::with_capacity(capacity) ) ), 
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn clear(&self) {
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// The cached `(context, pooled)` text embeddings of `texts`, marking them as most recently used.
+    pub fn get<B: Backend>(&self, texts: &[&str], clip_skip: usize, chunked: bool, device: &B::Device) -> Option<(Tensor<B, 3>, Tensor<B, 2>)> {
+        let key = EmbeddingKey::new(texts, clip_skip, chunked);
+
+        let mut entries = self.entries.lock().unwrap();
+        let index = entries.iter().position(|(k, _)| *k == key)?;
+        let entry = entries.remove(index).unwrap();
+        entries.push_back(entry.clone());
+
+        let (_, embedding) = entry;
+        Some((
+            Tensor::from_data_device(embedding.context.convert(), device), 
+            Tensor::from_data_device(embedding.pooled.convert(), device), 
+        ))
+    }
+
+    /// Stores the embeddings of `texts`, evicting the least recently used entry when full.
+    pub fn insert<B: Backend>(&self, texts: &[&str], clip_skip: usize, chunked: bool, context: &Tensor<B, 3>, pooled: &Tensor<B, 2>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        let key = EmbeddingKey::new(texts, clip_skip, chunked);
+        let embedding = CachedEmbedding {
+            context: context.clone().into_data().convert(), 
+            pooled: pooled.clone().into_data().convert(), 
+        };
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|(k, _)| *k != key);
+        while entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back( (key, embedding) );
+    }
+}
+
+impl EmbeddingKey {
+    fn new(texts: &[&str], clip_skip: usize, chunked: bool) -> Self {
+        Self {
+            texts: texts.iter().map(|text| text.to_string()).collect(), 
+            clip_skip, 
+            chunked, 
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::tensor::Distribution;
+    use burn_tch::TchBackend;
+
+    type TestBackend = TchBackend<f32>;
+
+    #[test]
+    fn test_embedding_cache() {
+        let device = Default::default();
+        let cache = EmbeddingCache::new(2);
+
+        let context: Tensor<TestBackend, 3> = Tensor::random([1, 77, 16], Distribution::Normal(0.0, 1.0));
+        let pooled: Tensor<TestBackend, 2> = Tensor::random([1, 8], Distribution::Normal(0.0, 1.0));
+        cache.insert(&["blurry"], 1, false, &context, &pooled);
+
+        let (cached_context, cached_pooled) = cache.get::<TestBackend>(&["blurry"], 1, false, &device).unwrap();
+        assert_eq!(cached_context.into_data(), context.clone().into_data());
+        assert_eq!(cached_pooled.into_data(), pooled.clone().into_data());
+
+        // the clip skip and chunking are part of the key
+        assert!(cache.get::<TestBackend>(&["blurry"], 2, false, &device).is_none());
+        assert!(cache.get::<TestBackend>(&["blurry"], 1, true, &device).is_none());
+
+        // "blurry" was used last, so "a" is evicted
+        cache.insert(&["a"], 1, false, &context, &pooled);
+        cache.get::<TestBackend>(&["blurry"], 1, false, &device).unwrap();
+        cache.insert(&["b"], 1, false, &context, &pooled);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get::<TestBackend>(&["a"], 1, false, &device).is_none());
+        assert!(cache.get::<TestBackend>(&["blurry"], 1, false, &device).is_some());
+
+        let shared = cache.clone();
+        shared.clear();
+        assert!(cache.is_empty());
+    }
+}
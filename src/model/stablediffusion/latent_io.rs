@@ -0,0 +1,115 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Write, BufReader, BufWriter};
+
+use burn::tensor::{
+    backend::Backend,
+    Tensor,
+    Data, 
+    Shape, 
+    ElementConversion, 
+    f16, 
+};
+
+use num_traits::ToPrimitive;
+
+// File layout: the magic bytes, one dtype byte, the four dimensions as little endian u32s, 
+// then the values in row-major order as little endian f32 or f16.
+const MAGIC: &[u8; 4] = b"SDXL";
+
+const DTYPE_F32: u8 = 0;
+const DTYPE_F16: u8 = 1;
+
+/// Saves a latent as f32, which round-trips exactly.
+pub fn save_latent<B: Backend>(latent: &Tensor<B, 4>, path: &str) -> Result<(), Box<dyn Error>> {
+    write_latent(latent, path, DTYPE_F32)
+}
+
+/// Saves a latent as f16, halving the file size at the cost of precision.
+pub fn save_latent_f16<B: Backend>(latent: &Tensor<B, 4>, path: &str) -> Result<(), Box<dyn Error>> {
+    write_latent(latent, path, DTYPE_F16)
+}
+
+fn write_latent<B: Backend>(latent: &Tensor<B, 4>, path: &str, dtype: u8) -> Result<(), Box<dyn Error>> {
+    let mut file = BufWriter::new( File::create(path)? );
+
+    file.write_all(MAGIC)?;
+    file.write_all(&[dtype])?;
+    for dim in latent.dims() {
+        file.write_all( &(dim as u32).to_le_bytes() )?;
+    }
+
+    for v in latent.clone().into_data().value {
+        let v = v.to_f32().unwrap();
+        match dtype {
+            DTYPE_F16 => file.write_all( &f16::from_f32(v).to_le_bytes() )?, 
+            _ => file.write_all( &v.to_le_bytes() )?, 
+        }
+    }
+
+    file.flush()?;
+    Ok(())
+}
+
+/// Loads a latent written by `save_latent` or `save_latent_f16`.
+pub fn load_latent<B: Backend>(path: &str, device: &B::Device) -> Result<Tensor<B, 4>, Box<dyn Error>> {
+    let mut buf = vec![];
+    BufReader::new( File::open(path)? ).read_to_end(&mut buf)?;
+
+    let header_len = MAGIC.len() + 1 + 4 * 4;
+    if buf.len() < header_len || &buf[0..MAGIC.len()] != MAGIC {
+        return Err( format!("{} is not a latent file.", path).into() );
+    }
+
+    let dtype = buf[MAGIC.len()];
+    let mut dims = [0; 4];
+    for (i, dim) in dims.iter_mut().enumerate() {
+        let start = MAGIC.len() + 1 + 4 * i;
+        *dim = u32::from_le_bytes([buf[start], buf[start + 1], buf[start + 2], buf[start + 3]]) as usize;
+    }
+
+    let bytes = &buf[header_len..];
+    let values: Vec<f32> = match dtype {
+        DTYPE_F32 => bytes
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect(), 
+        DTYPE_F16 => bytes
+            .chunks_exact(2)
+            .map(|b| f16::from_le_bytes([b[0], b[1]]).to_f32())
+            .collect(), 
+        _ => return Err( format!("{} has unknown dtype {}.", path, dtype).into() ), 
+    };
+
+    let n_elements: usize = dims.iter().product();
+    if values.len() != n_elements {
+        return Err( format!("{} holds {} values but its shape {:?} needs {}.", path, values.len(), dims, n_elements).into() );
+    }
+
+    let data: Vec<B::FloatElem> = values.into_iter().map(|v| v.elem()).collect();
+    Ok( Tensor::from_data_device(Data::new(data, Shape::new(dims)), device) )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::tensor::Distribution;
+    use burn_tch::TchBackend;
+
+    type Backend = TchBackend<f32>;
+
+    #[test]
+    fn test_latent_round_trip() {
+        let path = std::env::temp_dir().join(format!("sdxl_latent_round_trip_{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        let latent: Tensor<Backend, 4> = Tensor::random([1, 4, 8, 6], Distribution::Normal(0.0, 1.0));
+        save_latent(&latent, path).unwrap();
+        let loaded: Tensor<Backend, 4> = load_latent(path, &Default::default()).unwrap();
+
+        assert_eq!(loaded.dims(), latent.dims());
+        assert_eq!(loaded.into_data().value, latent.into_data().value);
+
+        std::fs::remove_file(path).ok();
+    }
+}
@@ -1,4 +1,3 @@
-use std::error::Error;
 use burn::tensor::ElementConversion;
 
 use burn::{
@@ -12,9 +11,9 @@ use burn::{
 };
 
 use super::*;
-use crate::model::{load::*, autoencoder::load::load_autoencoder, unet::load::load_unet, clip::load::load_clip_text_transformer};
+use crate::model::{load::*, autoencoder::load::load_autoencoder, unet::load::{load_unet, load_refiner_unet}, clip::load::load_clip_text_transformer};
 
-/*pub fn load_stable_diffusion<B: Backend>(path: &str, device: &B::Device) -> Result<StableDiffusion<B>, Box<dyn Error>> {
+/*pub fn load_stable_diffusion<B: Backend>(path: &str, device: &B::Device) -> Result<StableDiffusion<B>, LoadError> {
     let n_steps = load_usize::<B>("n_steps", path, device)?;
     let alpha_cumulative_products = load_tensor::<B, 1>("alphas_cumprod", path, device)?.into();
     let autoencoder = load_autoencoder(&format!("{}/{}", path, "autoencoder"), device)?;
@@ -31,22 +30,25 @@ use crate::model::{load::*, autoencoder::load::load_autoencoder, unet::load::loa
 }*/
 
 
-pub fn load_embedder<B: Backend>(path: &str, device: &B::Device) -> Result<Embedder<B>, Box<dyn Error>> {
+pub fn load_embedder<B: Backend>(path: &str, device: &B::Device) -> Result<Embedder<B>, LoadError> {
     let clip = load_clip_text_transformer(&format!("{}/{}", path, "clip"), device, false)?;
     let open_clip = load_clip_text_transformer(&format!("{}/{}", path, "open_clip"), device, true)?;
 
-    let clip_tokenizer = SimpleTokenizer::new()?;
-    let open_clip_tokenizer = OpenClipTokenizer::new()?;
+    let clip_tokenizer = SimpleTokenizer::new()
+        .map_err(|e| LoadError::read_failed("the CLIP tokenizer vocabulary", e))?;
+    let open_clip_tokenizer = OpenClipTokenizer::new()
+        .map_err(|e| LoadError::read_failed("the OpenCLIP tokenizer vocabulary", e))?;
 
     Ok(Embedder {
         clip, 
         open_clip, 
         clip_tokenizer, 
         open_clip_tokenizer, 
+        cache: None, 
     })
 }
 
-pub fn load_diffuser<B: Backend>(path: &str, device: &B::Device) -> Result<Diffuser<B>, Box<dyn Error>> {
+pub fn load_diffuser<B: Backend>(path: &str, device: &B::Device) -> Result<Diffuser<B>, LoadError> {
     let n_steps = load_usize::<B>("n_steps", path, device)?;
     let alpha_cumulative_products = load_tensor::<B, 1>("alphas_cumprod", path, device)?.into();
     let diffusion = load_unet(&format!("{}/{}", path, "unet"), device)?;
@@ -58,14 +60,358 @@ pub fn load_diffuser<B: Backend>(path: &str, device: &B::Device) -> Result<Diffu
     })
 }
 
-pub fn load_latent_decoder<B: Backend>(path: &str, device: &B::Device) -> Result<LatentDecoder<B>, Box<dyn Error>> {
+pub fn load_refiner<B: Backend>(path: &str, device: &B::Device) -> Result<Refiner<B>, LoadError> {
+    let n_steps = load_usize::<B>("n_steps", path, device)?;
+    let alpha_cumulative_products = load_tensor::<B, 1>("alphas_cumprod", path, device)?.into();
+    let diffusion = load_refiner_unet(&format!("{}/{}", path, "refiner_unet"), device)?;
+
+    Ok(Refiner {
+        n_steps, 
+        alpha_cumulative_products, 
+        diffusion, 
+    })
+}
+
+pub fn load_latent_decoder<B: Backend>(path: &str, device: &B::Device) -> Result<LatentDecoder<B>, LoadError> {
     let autoencoder = load_autoencoder(&format!("{}/{}", path, "autoencoder"), device)?;
     let scale_factor = load_f32::<B>("scale_factor", path, device)?.into();
 
     Ok(LatentDecoder {
         autoencoder, 
         scale_factor, 
+        output_gamma: 1.0, 
+        output_clamp: true, 
     })
 }
 
+/// Converts the weight dump at `dump_path` into burn records in `model_dir`: `embedder`, `diffuser`, 
+/// `latent_decoder`, and `refiner` with its `.cfg` if the dump has a `refiner_unet`.
+pub fn convert_dump<B: Backend>(dump_path: &str, model_dir: &str, device: &B::Device) -> Result<(), Box<dyn std::error::Error>> {
+    let model_path = |name: &str| format!("{}/{}", model_dir, name);
+
+    save_record(load_embedder::<B>(dump_path, device)?, &model_path("embedder"))?;
+    save_record(load_diffuser::<B>(dump_path, device)?, &model_path("diffuser"))?;
+
+    // the refiner is optional
+    if std::path::Path::new(&format!("{}/refiner_unet", dump_path)).exists() {
+        save_record(load_refiner::<B>(dump_path, device)?, &model_path("refiner"))?;
+
+        // there is no published config for the refiner
+        RefinerConfig::new(2560, 384, 64, 1280).save(&format!("{}.cfg", model_path("refiner")))?;
+    }
+
+    save_record(load_latent_decoder::<B>(dump_path, device)?, &model_path("latent_decoder"))?;
+
+    Ok(())
+}
+
+
+
+use crate::model::safetensors::SafeTensorsFile;
+
+/// Reads an SDXL textual inversion embedding from a `.safetensors` file, returning the `clip_l` vectors 
+/// for the CLIP text encoder and the `clip_g` vectors for the OpenCLIP one. 
+/// Pass them to `Embedder::add_textual_inversion`.
+pub fn load_textual_inversion(path: &str) -> Result<(Vec<Vec<f32>>, Vec<Vec<f32>>), LoadError> {
+    let st = SafeTensorsFile::open(path)?;
+
+    Ok( (st.rows("clip_l")?, st.rows("clip_g")?) )
+}
+
 
+use std::path::Path;
+use serde::{Deserialize, de::DeserializeOwned};
+use crate::model::{
+    unet::load::load_unet_safetensors, 
+    autoencoder::load::load_autoencoder_safetensors, 
+    clip::load::load_clip_text_transformer_safetensors, 
+};
+use super::pipeline::Pipeline;
+
+// the UNet and CLIP safetensors loaders derive the number of heads from this head width
+const DIFFUSERS_HEAD_DIM: usize = 64;
+
+#[derive(Deserialize)]
+struct DiffusersClipConfig {
+    vocab_size: usize, 
+    hidden_size: usize, 
+    projection_dim: Option<usize>, 
+    num_attention_heads: usize, 
+    max_position_embeddings: usize, 
+    num_hidden_layers: usize, 
+    hidden_act: String, 
+}
+
+/// Fields diffusers stores either once for all blocks or per block.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PerBlock {
+    Shared(usize), 
+    Blocks(Vec<usize>), 
+}
+
+#[derive(Deserialize)]
+struct DiffusersUNetConfig {
+    in_channels: usize, 
+    out_channels: usize, 
+    block_out_channels: Vec<usize>, 
+    attention_head_dim: PerBlock, 
+    cross_attention_dim: usize, 
+    addition_embed_type: Option<String>, 
+    projection_class_embeddings_input_dim: Option<usize>, 
+}
+
+#[derive(Deserialize)]
+struct DiffusersVaeConfig {
+    latent_channels: usize, 
+    block_out_channels: Vec<usize>, 
+    scaling_factor: Option<f64>, 
+}
+
+#[derive(Deserialize)]
+struct DiffusersSchedulerConfig {
+    num_train_timesteps: usize, 
+    beta_start: f64, 
+    beta_end: f64, 
+    beta_schedule: String, 
+    #[serde(default)]
+    rescale_betas_zero_snr: bool, 
+}
+
+/// Maps the `config.json` files of a HuggingFace diffusers SDXL directory (`text_encoder/`, `text_encoder_2/`, 
+/// `unet/` and `vae/`) onto the crate's configs. Save them with `Config::save` next to records converted from the 
+/// same directory to load them with `Pipeline::load` later. Fails on models the crate can't represent, 
+/// such as the refiner UNet or a VAE with a different block layout.
+pub fn load_diffusers_configs(dir: &str) -> Result<(EmbedderConfig, DiffuserConfig, LatentDecoderConfig), LoadError> {
+    let clip = clip_config(&read_json(&format!("{}/text_encoder/config.json", dir))?);
+    let open_clip = clip_config(&read_json(&format!("{}/text_encoder_2/config.json", dir))?);
+
+    let unet_path = format!("{}/unet/config.json", dir);
+    let unet: DiffusersUNetConfig = read_json(&unet_path)?;
+
+    let unsupported = |message: String| LoadError::UnsupportedConfig { path: unet_path.clone(), message };
+    if unet.addition_embed_type.as_deref() != Some("text_time") || unet.block_out_channels.len() != 3 {
+        return Err( unsupported("not an SDXL base UNet, which has three blocks and text_time embeddings".into()) );
+    }
+    if unet.in_channels != 4 || unet.out_channels != 4 {
+        return Err( unsupported(format!("{} input and {} output channels, only 4 are supported", unet.in_channels, unet.out_channels)) );
+    }
+
+    let adm_in_channels = unet.projection_class_embeddings_input_dim
+        .ok_or_else(|| unsupported("projection_class_embeddings_input_dim is missing".into()))?;
+
+    // diffusers' attention_head_dim is really the number of heads per block
+    let n_head = match &unet.attention_head_dim {
+        PerBlock::Shared(n_head) => *n_head, 
+        PerBlock::Blocks(n_heads) => *n_heads.get(1).ok_or_else(|| unsupported("attention_head_dim has fewer than three blocks".into()))?, 
+    };
+    let num_head_channels = unet.block_out_channels[1] / n_head.max(1);
+    if num_head_channels != DIFFUSERS_HEAD_DIM {
+        return Err( unsupported(format!("heads {} wide, only {} is supported", num_head_channels, DIFFUSERS_HEAD_DIM)) );
+    }
+
+    let diffuser = DiffuserConfig::new(adm_in_channels, unet.block_out_channels[0], num_head_channels, unet.cross_attention_dim);
+
+    let vae_path = format!("{}/vae/config.json", dir);
+    let vae: DiffusersVaeConfig = read_json(&vae_path)?;
+    if vae.latent_channels != 4 || vae.block_out_channels != [128, 256, 512, 512] {
+        return Err(LoadError::UnsupportedConfig {
+            path: vae_path, 
+            message: format!("a VAE with {} latent channels and blocks {:?} isn't supported", vae.latent_channels, vae.block_out_channels), 
+        });
+    }
+
+    let latent_decoder = LatentDecoderConfig::new()
+        .with_scale_factor(vae.scaling_factor.unwrap_or(SDXL_VAE_SCALE_FACTOR));
+
+    Ok( (EmbedderConfig::new(clip, open_clip), diffuser, latent_decoder) )
+}
+
+/// Loads a pipeline straight from a HuggingFace diffusers SDXL directory, without the weight dump conversion. 
+/// The weights are read from the `.safetensors` files in `text_encoder/`, `text_encoder_2/`, `unet/` and `vae/` 
+/// (preferring full precision over `.fp16` variants), the configs are mapped as in `load_diffusers_configs`, 
+/// and the noise schedule comes from `scheduler/scheduler_config.json`. A tensor that is missing from a checkpoint 
+/// fails with a `ReadFailed` error naming the checkpoint and the diffusers name that couldn't be mapped.
+pub fn load_pipeline_from_diffusers<B: Backend>(dir: &str, device: &B::Device) -> Result<Pipeline<B>, LoadError> {
+    let (_, diffuser_config, latent_decoder_config) = load_diffusers_configs(dir)?;
+
+    let clip = load_diffusers_text_encoder(dir, "text_encoder", device)?;
+    let open_clip = load_diffusers_text_encoder(dir, "text_encoder_2", device)?;
+
+    let clip_tokenizer = SimpleTokenizer::new()
+        .map_err(|e| LoadError::read_failed("the CLIP tokenizer vocabulary", e))?;
+    let open_clip_tokenizer = OpenClipTokenizer::new()
+        .map_err(|e| LoadError::read_failed("the OpenCLIP tokenizer vocabulary", e))?;
+
+    let embedder = Embedder {
+        clip, 
+        open_clip, 
+        clip_tokenizer, 
+        open_clip_tokenizer, 
+        cache: None, 
+    };
+
+    let unet_path = weights_path(dir, "unet", "diffusion_pytorch_model")?;
+    let diffusion = load_unet_safetensors(&unet_path, device).map_err(unmapped_tensor(&unet_path))?;
+    if diffusion.adm_in_channels() != diffuser_config.adm_in_channels || diffusion.context_dim() != diffuser_config.context_dim {
+        return Err(LoadError::ShapeMismatch {
+            name: unet_path, 
+            message: format!(
+                "the weights take {} label and {} context channels but the config says {} and {}", 
+                diffusion.adm_in_channels(), diffusion.context_dim(), diffuser_config.adm_in_channels, diffuser_config.context_dim, 
+            ), 
+        });
+    }
+
+    let (n_steps, alpha_cumulative_products) = diffusers_schedule(dir, device)?;
+    let diffuser = Diffuser {
+        n_steps, 
+        alpha_cumulative_products: alpha_cumulative_products.into(), 
+        diffusion, 
+    };
+
+    let vae_path = weights_path(dir, "vae", "diffusion_pytorch_model")?;
+    let latent_decoder = LatentDecoder {
+        autoencoder: load_autoencoder_safetensors(&vae_path, device).map_err(unmapped_tensor(&vae_path))?, 
+        scale_factor: latent_decoder_config.scale_factor, 
+        output_gamma: 1.0, 
+        output_clamp: true, 
+    };
+
+    Ok( Pipeline::new(embedder, diffuser, latent_decoder) )
+}
+
+fn clip_config(config: &DiffusersClipConfig) -> CLIPConfig {
+    CLIPConfig::new(
+        config.vocab_size, 
+        config.hidden_size, 
+        config.projection_dim.unwrap_or(config.hidden_size), 
+        config.num_attention_heads, 
+        config.max_position_embeddings, 
+        config.num_hidden_layers, 
+        config.hidden_act == "quick_gelu", 
+    )
+}
+
+fn load_diffusers_text_encoder<B: Backend>(dir: &str, component: &str, device: &B::Device) -> Result<CLIP<B>, LoadError> {
+    let config: DiffusersClipConfig = read_json(&format!("{}/{}/config.json", dir, component))?;
+    let path = weights_path(dir, component, "model")?;
+
+    // OpenCLIP uses exact GELU where CLIP uses the quick approximation
+    let is_open_clip = config.hidden_act != "quick_gelu";
+    let clip = load_clip_text_transformer_safetensors(&path, device, is_open_clip).map_err(unmapped_tensor(&path))?;
+
+    let expected_dims = [config.vocab_size, config.hidden_size];
+    let mismatch = if clip.token_embedding_dims() != expected_dims {
+        Some( format!("token embeddings of shape {:?} but the config says {:?}", clip.token_embedding_dims(), expected_dims) )
+    } else if clip.num_layers() != config.num_hidden_layers {
+        Some( format!("{} layers but the config says {}", clip.num_layers(), config.num_hidden_layers) )
+    } else if config.num_attention_heads * DIFFUSERS_HEAD_DIM != config.hidden_size {
+        Some( format!("{} heads in {} channels, only heads {} wide are supported", config.num_attention_heads, config.hidden_size, DIFFUSERS_HEAD_DIM) )
+    } else {
+        None
+    };
+
+    match mismatch {
+        Some(message) => Err( LoadError::ShapeMismatch { name: path, message } ), 
+        None => Ok(clip), 
+    }
+}
+
+/// The number of training timesteps and the cumulative alpha products of the diffusers scheduler config.
+fn diffusers_schedule<B: Backend>(dir: &str, device: &B::Device) -> Result<(usize, Tensor<B, 1>), LoadError> {
+    let path = format!("{}/scheduler/scheduler_config.json", dir);
+    let config: DiffusersSchedulerConfig = read_json(&path)?;
+
+    let n_steps = config.num_train_timesteps;
+    let alphas_cumprod = match config.beta_schedule.as_str() {
+        "scaled_linear" => scaled_linear_schedule_cumprod::<B>(n_steps, config.beta_start, config.beta_end, device), 
+        schedule => return Err(LoadError::UnsupportedConfig {
+            path, 
+            message: format!("the beta_schedule {} isn't supported, only scaled_linear", schedule), 
+        }), 
+    };
+
+    let alphas_cumprod = if config.rescale_betas_zero_snr {
+        rescale_zero_terminal_snr(alphas_cumprod)
+    } else {
+        alphas_cumprod
+    };
+
+    Ok( (n_steps, alphas_cumprod) )
+}
+
+/// The `.safetensors` weights of a diffusers component, e.g. `unet/diffusion_pytorch_model.safetensors`, 
+/// falling back to the `.fp16.safetensors` variant.
+fn weights_path(dir: &str, component: &str, stem: &str) -> Result<String, LoadError> {
+    let candidates = [
+        format!("{}/{}/{}.safetensors", dir, component, stem), 
+        format!("{}/{}/{}.fp16.safetensors", dir, component, stem), 
+    ];
+
+    candidates
+        .iter()
+        .find(|path| Path::new(path).exists())
+        .cloned()
+        .ok_or_else(|| LoadError::read_failed(&candidates[0], format!("no {} weights, also tried {}", component, candidates[1])))
+}
+
+fn read_json<T: DeserializeOwned>(path: &str) -> Result<T, LoadError> {
+    let config_error = |message: String| LoadError::ConfigNotFound { path: path.to_string(), message };
+
+    let json = std::fs::read_to_string(path).map_err(|e| config_error(e.to_string()))?;
+    serde_json::from_str(&json).map_err(|e| config_error(e.to_string()))
+}
+
+/// Reports tensors missing from the checkpoint at `path` under the checkpoint, naming the tensor.
+fn unmapped_tensor(path: &str) -> impl Fn(LoadError) -> LoadError + '_ {
+    move |e| match e {
+        LoadError::ReadFailed { path: name, message } if name != path => LoadError::ReadFailed {
+            path: path.to_string(), 
+            message: format!("no tensor {} to load into the model ({})", name, message), 
+        }, 
+        e => e, 
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_config(dir: &Path, component: &str, file: &str, json: &str) {
+        std::fs::create_dir_all(dir.join(component)).unwrap();
+        std::fs::write(dir.join(component).join(file), json).unwrap();
+    }
+
+    #[test]
+    fn test_load_diffusers_configs() {
+        let dir = std::env::temp_dir().join(format!("sdxl_diffusers_{}", std::process::id()));
+
+        // abridged from the stabilityai/stable-diffusion-xl-base-1.0 configs
+        write_config(&dir, "text_encoder", "config.json", r#"{"vocab_size": 49408, "hidden_size": 768, "projection_dim": 768, 
+            "num_attention_heads": 12, "max_position_embeddings": 77, "num_hidden_layers": 12, "hidden_act": "quick_gelu"}"#);
+        write_config(&dir, "text_encoder_2", "config.json", r#"{"vocab_size": 49408, "hidden_size": 1280, "projection_dim": 1280, 
+            "num_attention_heads": 20, "max_position_embeddings": 77, "num_hidden_layers": 32, "hidden_act": "gelu"}"#);
+        write_config(&dir, "unet", "config.json", r#"{"in_channels": 4, "out_channels": 4, "block_out_channels": [320, 640, 1280], 
+            "attention_head_dim": [5, 10, 20], "cross_attention_dim": 2048, "addition_embed_type": "text_time", 
+            "projection_class_embeddings_input_dim": 2816, "sample_size": 128}"#);
+        write_config(&dir, "vae", "config.json", r#"{"latent_channels": 4, "block_out_channels": [128, 256, 512, 512], "scaling_factor": 0.13025}"#);
+
+        let (embedder, diffuser, latent_decoder) = load_diffusers_configs(dir.to_str().unwrap()).unwrap();
+        assert_eq!(embedder.clip_config.to_string(), CLIPConfig::new(49408, 768, 768, 12, 77, 12, true).to_string());
+        assert_eq!(embedder.open_clip_config.to_string(), CLIPConfig::new(49408, 1280, 1280, 20, 77, 32, false).to_string());
+        assert_eq!(diffuser.to_string(), DiffuserConfig::new(2816, 320, 64, 2048).to_string());
+        assert_eq!(latent_decoder.scale_factor, SDXL_VAE_SCALE_FACTOR);
+
+        // the refiner's UNet has four blocks
+        write_config(&dir, "unet", "config.json", r#"{"in_channels": 4, "out_channels": 4, "block_out_channels": [384, 768, 1536, 1536], 
+            "attention_head_dim": [6, 12, 24, 24], "cross_attention_dim": 1280, "addition_embed_type": "text_time", 
+            "projection_class_embeddings_input_dim": 2560}"#);
+        match load_diffusers_configs(dir.to_str().unwrap()) {
+            Err(LoadError::UnsupportedConfig { path, .. }) => assert!(path.ends_with("unet/config.json")), 
+            other => panic!("expected UnsupportedConfig, got {:?}", other.map(|_| ())), 
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::BufWriter;
+
+/// PNG text chunk keyword AUTOMATIC1111's web UI reads generation parameters from.
+pub const PARAMETERS_KEY: &str = "parameters";
+
+/// The settings an image was generated with, written into saved PNGs by `save_images_with_metadata`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationParams {
+    pub prompt: String, 
+    pub negative_prompt: String, 
+    pub seed: u64, 
+    pub n_steps: usize, 
+    pub guidance_scale: f64, 
+    pub sampler: String, 
+    pub width: u32, 
+    pub height: u32, 
+}
+
+/// Formats the parameters the way AUTOMATIC1111 does, so the web UI and other tools
+/// that understand its "parameters" text can read them back.
+impl fmt::Display for GenerationParams {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.prompt)?;
+        if !self.negative_prompt.is_empty() {
+            writeln!(f, "Negative prompt: {}", self.negative_prompt)?;
+        }
+
+        write!(
+            f, 
+            "Steps: {}, Sampler: {}, CFG scale: {}, Seed: {}, Size: {}x{}", 
+            self.n_steps, self.sampler, self.guidance_scale, self.seed, self.width, self.height
+        )
+    }
+}
+
+/// Like `save_images` in the binaries, writing `{basepath}{index}.png` for each interleaved RGB buffer, 
+/// but also stores `params` in a "parameters" text chunk. tEXt chunks only hold Latin-1, so prompts
+/// with other characters are stored in an iTXt chunk under the same key instead.
+pub fn save_images_with_metadata(images: &Vec<Vec<u8>>, basepath: &str, width: u32, height: u32, params: &GenerationParams) -> Result<(), Box<dyn Error>> {
+    let text = params.to_string();
+
+    for (index, img_data) in images.iter().enumerate() {
+        let path = format!("{}{}.png", basepath, index);
+        let file = BufWriter::new( File::create(path)? );
+
+        let mut encoder = png::Encoder::new(file, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+
+        if text.chars().all(|c| (c as u32) < 256) {
+            encoder.add_text_chunk(PARAMETERS_KEY.to_string(), text.clone())?;
+        } else {
+            encoder.add_itxt_chunk(PARAMETERS_KEY.to_string(), text.clone())?;
+        }
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&img_data[..])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parameters_text() {
+        let mut params = GenerationParams {
+            prompt: "a photo of a cat".to_string(), 
+            negative_prompt: "blurry".to_string(), 
+            seed: 42, 
+            n_steps: 30, 
+            guidance_scale: 7.5, 
+            sampler: "DDIM".to_string(), 
+            width: 1024, 
+            height: 768, 
+        };
+
+        assert_eq!(
+            params.to_string(), 
+            "a photo of a cat\nNegative prompt: blurry\nSteps: 30, Sampler: DDIM, CFG scale: 7.5, Seed: 42, Size: 1024x768"
+        );
+
+        params.negative_prompt = String::new();
+        assert_eq!(
+            params.to_string(), 
+            "a photo of a cat\nSteps: 30, Sampler: DDIM, CFG scale: 7.5, Seed: 42, Size: 1024x768"
+        );
+    }
+}
@@ -1,4 +1,12 @@
 pub mod load;
+pub mod sampler;
+pub mod scheduler;
+pub mod latent_io;
+pub mod offload;
+pub mod metadata;
+pub mod output;
+pub mod pipeline;
+pub mod cache;
 
 use burn::{
     config::Config, 
@@ -10,16 +18,31 @@ use burn::{
         Float, 
         BasicOps, 
         Data, 
-        Distribution, 
+        Shape, 
+        ElementConversion, 
     },
 };
 
 use num_traits::ToPrimitive;
 
+use std::error::Error;
+
+use image::{ColorType, RgbImage, Rgb, imageops};
+use std::iter;
+use std::ops::Range;
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
 use super::autoencoder::{Autoencoder, AutoencoderConfig};
-use super::unet::{UNet, UNetConfig, conditioning_embedding};
-use super::clip::{CLIP, CLIPConfig};
-use crate::token::{Tokenizer, clip::SimpleTokenizer, open_clip::OpenClipTokenizer};
+use super::unet::{UNet, UNetConfig, AttentionOptions, RegionalContext, RefinerUNet, RefinerUNetConfig, conditioning_embedding, timestep_embedding, float_timestep_embedding};
+use super::clip::{CLIP, CLIPConfig, first_token_indices};
+use crate::token::{Tokenizer, TooManyTokens, clip::SimpleTokenizer, open_clip::OpenClipTokenizer, scheduling::parse_prompt_schedule};
+use crate::helper::{tensor_to_vec, div_roundup, random_normal, tensor_to_backend, upsample_bilinear, tensor_max_scalar, tensor_min_scalar};
+use cache::EmbeddingCache;
+use sampler::{Sampler, SamplerKind, DdimSampler, EulerAncestralSampler, DpmPlusPlus2mSampler, HeunSampler, LmsSampler, DynamicThresholdSampler};
+use scheduler::Scheduler;
 
 /*#[derive(Config)]
 pub struct StableDiffusionConfig {
@@ -99,7 +122,7 @@ impl<B: Backend> StableDiffusion<B> {
         let [n_batches, _, _] = context.dims();
 
         let gen_noise = || {
-            Tensor::random([n_batches, 4, 128, 128], Distribution::Normal(0.0, 1.0)).to_device(&device)
+            random_normal([n_batches, 4, 128, 128], &device)
         };
 
         let sigma = 0.0; // Use deterministic diffusion
@@ -173,26 +196,167 @@ impl<B: Backend> StableDiffusion<B> {
 }*/
 
 
+/// Decoded images, one buffer of `height * width * channels` interleaved bytes per batch entry.
 pub struct RawImages {
     pub buffer: Vec<Vec<u8>>, 
     pub width: usize, 
     pub height: usize, 
+    /// 3 for the RGB images of `LatentDecoder::latent_to_image`, 1 for those of `latent_to_grayscale`.
+    pub channels: usize, 
+}
+
+impl RawImages {
+    /// The color type to save the buffers with, `None` for channel counts other than 1 and 3.
+    pub fn color_type(&self) -> Option<ColorType> {
+        match self.channels {
+            1 => Some(ColorType::L8), 
+            3 => Some(ColorType::Rgb8), 
+            _ => None, 
+        }
+    }
+
+    /// Fails for images that aren't RGB, e.g. grayscale ones.
+    pub fn into_rgb_images(self) -> Result<Vec<RgbImage>, Box<dyn Error>> {
+        if self.channels != 3 {
+            return Err( format!("Expected RGB images but got {} channels.", self.channels).into() );
+        }
+
+        let width = self.width as u32;
+        let height = self.height as u32;
+
+        self.buffer
+            .into_iter()
+            .map(|buffer| RgbImage::from_raw(width, height, buffer).ok_or_else(|| "An image buffer has the wrong length.".into()))
+            .collect()
+    }
+}
+
+/// Lays a batch of images out in a grid with `cols` columns, e.g. to compare the results of a batched `sample_latent`. 
+/// Every cell is as large as the largest image and cells are separated from each other and from the border by 
+/// `padding` pixels of `bg`. When the images don't fill the last row, its trailing cells are left as background.
+pub fn make_grid(images: &[RgbImage], cols: usize, padding: u32, bg: [u8; 3]) -> RgbImage {
+    assert!(cols > 0, "The grid needs at least one column.");
+
+    let rows = div_roundup(images.len(), cols);
+    let cell_width = images.iter().map(|image| image.width()).max().unwrap_or(0);
+    let cell_height = images.iter().map(|image| image.height()).max().unwrap_or(0);
+
+    let grid_width = cols as u32 * (cell_width + padding) + padding;
+    let grid_height = rows as u32 * (cell_height + padding) + padding;
+    let mut grid = RgbImage::from_pixel(grid_width, grid_height, Rgb(bg));
+
+    for (i, image) in images.iter().enumerate() {
+        let x = padding + (i % cols) as u32 * (cell_width + padding);
+        let y = padding + (i / cols) as u32 * (cell_height + padding);
+        imageops::replace(&mut grid, image, x as i64, y as i64);
+    }
+
+    grid
+}
+
+
+/// The side `Pipeline::outpaint` extends an image towards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutpaintDirection {
+    Left, 
+    Right, 
+    Up, 
+    Down, 
+}
+
+/// Places `image` in a canvas extended by `pixels` towards `direction`, filling the new border by repeating the 
+/// image's edge so the VAE sees no hard seam. Returns the canvas and the `[top, left, bottom, right]` rectangle 
+/// (bottom and right exclusive) the original image occupies in it. Fails for an empty image, which has no edge to repeat.
+pub fn outpaint_canvas(image: &RgbImage, direction: OutpaintDirection, pixels: u32) -> Result<(RgbImage, [usize; 4]), Box<dyn Error>> {
+    let (width, height) = image.dimensions();
+    if width == 0 || height == 0 {
+        return Err( format!("Can't outpaint an empty {}x{} image.", width, height).into() );
+    }
+
+    let (canvas_width, canvas_height, left, top) = match direction {
+        OutpaintDirection::Left => (width + pixels, height, pixels, 0), 
+        OutpaintDirection::Right => (width + pixels, height, 0, 0), 
+        OutpaintDirection::Up => (width, height + pixels, 0, pixels), 
+        OutpaintDirection::Down => (width, height + pixels, 0, 0), 
+    };
+
+    let canvas = RgbImage::from_fn(canvas_width, canvas_height, |x, y| {
+        let x = x.saturating_sub(left).min(width - 1);
+        let y = y.saturating_sub(top).min(height - 1);
+        *image.get_pixel(x, y)
+    });
+
+    let known = [top as usize, left as usize, (top + height) as usize, (left + width) as usize];
+    Ok( (canvas, known) )
+}
+
+/// The `[1, 1, height / 8, width / 8]` inpainting mask of a `[height, width]` canvas that is 1 everywhere except 
+/// in the `known` `[top, left, bottom, right]` pixel rectangle, whose sides must be multiples of 8. 
+/// Fails for an unaligned or empty region, or one that doesn't fit in the canvas.
+pub fn outpaint_mask<B: Backend>(size: [usize; 2], known: [usize; 4], device: &B::Device) -> Result<Tensor<B, 4>, Box<dyn Error>> {
+    let [height, width] = size;
+    if known.iter().any(|side| side % LATENT_SCALE != 0) {
+        return Err( format!("The known region {:?} must be aligned to multiples of {}.", known, LATENT_SCALE).into() );
+    }
+
+    let [top, left, bottom, right] = known.map(|side| side / LATENT_SCALE);
+    let [height, width] = [height / LATENT_SCALE, width / LATENT_SCALE];
+    if top >= bottom || left >= right || bottom > height || right > width {
+        return Err( format!("The known region {:?} must be a non-empty rectangle inside the {}x{} canvas.", known, size[1], size[0]).into() );
+    }
+
+    Ok( Tensor::ones_device([1, 1, height, width], device)
+        .slice_assign([0..1, 0..1, top..bottom, left..right], Tensor::zeros_device([1, 1, bottom - top, right - left], device)) )
 }
 
 
+/// The VAE latent scaling factor of SDXL. SD 1.5 VAEs use 0.18215.
+pub const SDXL_VAE_SCALE_FACTOR: f64 = 0.13025;
+
+/// Linear approximation of the SDXL VAE decoder for previews: row `c` is the RGB contribution of latent channel `c`, 
+/// fitted on sampler latents (i.e. already multiplied by `SDXL_VAE_SCALE_FACTOR`) to decoded images in [-1, 1]. 
+/// These are the factors used by ComfyUI's latent previews.
+pub const SDXL_LATENT_RGB_FACTORS: [[f64; 3]; 4] = [
+    //   R        G        B
+    [ 0.3651,  0.4232,  0.4341], 
+    [-0.2533, -0.0042,  0.1068], 
+    [ 0.1076,  0.1111, -0.0362], 
+    [-0.3165, -0.2492, -0.2188], 
+];
+
+/// RGB offset added after `SDXL_LATENT_RGB_FACTORS`.
+pub const SDXL_LATENT_RGB_BIAS: [f64; 3] = [0.1084, -0.0175, -0.0011];
+
 #[derive(Config, Debug)]
 pub struct LatentDecoderConfig {
+    /// Latents are the VAE encoding multiplied by this factor. Defaults to `SDXL_VAE_SCALE_FACTOR`, 
+    /// set it to match the VAE when loading another one.
+    #[config(default = 0.13025)]
     scale_factor: f64, 
+    /// Decoded images are raised to `1 / output_gamma` before conversion to bytes. Defaults to 1.0, no correction.
+    #[config(default = 1.0)]
+    output_gamma: f64, 
+    /// Whether decoded images are clamped to the valid range before gamma correction. 
+    /// Bytes always saturate at 0 and 255 either way, but without clamping a gamma other than 1.0 turns 
+    /// negative values into NaN, which are written as 0.
+    #[config(default = true)]
+    output_clamp: bool, 
+    /// Computes the decoder's group norms with overflow safe statistics so it can run in f16, 
+    /// see `DecoderConfig::upcast_group_norm`.
+    #[config(default = false)]
+    upcast_group_norm: bool, 
 }
 
 impl LatentDecoderConfig {
     pub fn init<B: Backend>(&self) -> LatentDecoder<B> {
-        let autoencoder = AutoencoderConfig::new().init();
+        let autoencoder = AutoencoderConfig::new().with_upcast_group_norm(self.upcast_group_norm).init();
         let scale_factor = self.scale_factor;
 
         LatentDecoder {
             autoencoder, 
             scale_factor, 
+            output_gamma: self.output_gamma, 
+            output_clamp: self.output_clamp, 
         }
     }
 }
@@ -202,46 +366,209 @@ impl LatentDecoderConfig {
 pub struct LatentDecoder<B: Backend> {
     autoencoder: Autoencoder<B>, 
     scale_factor: f64, 
+    output_gamma: f64, 
+    output_clamp: bool, 
 }
 
 impl<B: Backend> LatentDecoder<B> {
+    /// Decodes with circular padding, see `Diffuser::with_tileable`.
+    pub fn with_tileable(self, tileable: bool) -> Self {
+        Self {
+            autoencoder: self.autoencoder.with_tileable(tileable), 
+            ..self
+        }
+    }
+
+    /// The factor applied to latents by both `encode_image` and `decode_latent`.
+    pub fn scale_factor(&self) -> f64 {
+        self.scale_factor
+    }
+
     pub fn latent_to_image(&self, latent: Tensor<B, 4>) -> RawImages {
-        let [n_batch, _, latent_height, latent_width] = latent.dims();
         let image = self.decode_latent(latent);
+        decoded_to_raw_images(image, self.output_gamma, self.output_clamp)
+    }
 
-        let n_channel = 3;
-        let height = latent_height * 8;
-        let width = latent_width * 8;
-        let num_elements_per_image = n_channel * height * width;
-
-        // correct size and scale and reorder to 
-        let image = (image + 1.0) / 2.0;
-        let image = image
-            .reshape([n_batch, n_channel, height, width])
-            .swap_dims(1, 2)
-            .swap_dims(2, 3)
-            .mul_scalar(255.0);
+    /// Like `latent_to_image`, but writes the interleaved RGB pixels into `out`, reusing its allocation, e.g. in a 
+    /// server loop. `out` is grown only if it is too small and holds exactly `n_batch * height * width * 3` bytes 
+    /// afterwards, the images one after the other in batch order.
+    pub fn latent_to_image_into(&self, latent: Tensor<B, 4>, out: &mut Vec<u8>) {
+        let image = self.decode_latent(latent);
+        decoded_to_pixels(image, self.output_gamma, self.output_clamp, out);
+    }
 
-        let flattened: Vec<_> = image.
-            into_data().
-            value;
+    /// Like `latent_to_image`, but decodes the batch `microbatch` latents at a time to bound the autoencoder's 
+    /// memory use. The buffers are in batch order and match decoding the whole batch at once.
+    pub fn latent_to_image_chunked(&self, latent: Tensor<B, 4>, microbatch: usize) -> RawImages {
+        assert!(microbatch > 0, "The microbatch size must be positive.");
 
-        let buffer = (0..n_batch).into_iter().map(|b| {
-            let start = b * num_elements_per_image;
-            let end = start + num_elements_per_image;
+        let [n_batch, n_channel, height, width] = latent.dims();
 
-            flattened[start..end].into_iter().map(|v| v.to_f64().unwrap().min(255.0).max(0.0).to_u8().unwrap()).collect()
-        }).collect();
+        let mut buffer = Vec::with_capacity(n_batch);
+        for start in (0..n_batch).step_by(microbatch) {
+            let end = (start + microbatch).min(n_batch);
+            let chunk = latent.clone().slice([start..end, 0..n_channel, 0..height, 0..width]);
+            buffer.extend(self.latent_to_image(chunk).buffer);
+        }
 
         RawImages {
-            buffer: buffer, 
-            width: width, 
-            height: height, 
+            buffer, 
+            width: width * LATENT_SCALE, 
+            height: height * LATENT_SCALE, 
+            channels: 3, 
+        }
+    }
+
+    pub fn latent_to_rgb_images(&self, latent: Tensor<B, 4>) -> Result<Vec<RgbImage>, Box<dyn Error>> {
+        self.latent_to_image(latent).into_rgb_images()
+    }
+
+    /// Like `latent_to_image`, but converts the decoded images to luminance (Rec. 709 weights), 
+    /// so every buffer holds one byte per pixel.
+    pub fn latent_to_grayscale(&self, latent: Tensor<B, 4>) -> RawImages {
+        let image = self.decode_latent(latent);
+        let [n_batch, _, height, width] = image.dims();
+
+        let luma = LUMA_WEIGHTS
+            .iter()
+            .enumerate()
+            .map(|(c, &weight)| image.clone().slice([0..n_batch, c..c + 1, 0..height, 0..width]) * weight)
+            .reduce(|a, b| a + b)
+            .unwrap();
+
+        decoded_to_raw_images(luma, self.output_gamma, self.output_clamp)
+    }
+
+    /// Like `latent_to_image`, but transfers the per-channel mean and standard deviation of the `reference` images 
+    /// (e.g. the img2img init image, in the format of `image_to_latent`) to the decoded images, see `match_color`. 
+    /// This stops the colors drifting toward gray or magenta when img2img is applied repeatedly.
+    pub fn latent_to_image_color_matched(&self, latent: Tensor<B, 4>, reference: &[Vec<u8>], width: usize, height: usize) -> RawImages {
+        let image = self.decode_latent(latent);
+        let reference = raw_images_to_tensor(reference, width, height, &image.device());
+
+        decoded_to_raw_images(match_color(image, reference), self.output_gamma, self.output_clamp)
+    }
+
+    /// Decodes a dummy latent for the (height, width) image `resolution`, see `Diffuser::warmup`.
+    pub fn warmup(&self, resolution: [usize; 2], device: &B::Device) {
+        let [height, width] = round_resolution(resolution);
+
+        let latent = Tensor::zeros_device([1, 4, height / LATENT_SCALE, width / LATENT_SCALE], device);
+        let _ = self.decode_latent(latent).into_data();
+    }
+
+    /// A cheap preview of the first latent in the batch at latent resolution (1/8 of the image size), made by 
+    /// projecting each latent pixel to RGB with `SDXL_LATENT_RGB_FACTORS` instead of running the decoder. 
+    /// Fast enough to call from the progress callback of `Diffuser::sample_latent_with_callback` every step.
+    pub fn quick_preview(&self, latent: Tensor<B, 4>) -> RgbImage {
+        let [_, n_channels, height, width] = latent.dims();
+        assert!(n_channels == SDXL_LATENT_RGB_FACTORS.len(), "Expected a latent with {} channels but got {}.", SDXL_LATENT_RGB_FACTORS.len(), n_channels);
+
+        let values = tensor_to_vec(latent.slice([0..1]));
+        let n_pixels = height * width;
+
+        let buffer = (0..n_pixels)
+            .flat_map(|i| {
+                let values = &values;
+                (0..3).map(move |rgb| {
+                    let v = (0..n_channels).fold(SDXL_LATENT_RGB_BIAS[rgb], |v, c| v + values[c * n_pixels + i] * SDXL_LATENT_RGB_FACTORS[c][rgb]);
+                    ((v + 1.0) * 127.5).round().clamp(0.0, 255.0) as u8
+                })
+            })
+            .collect();
+
+        RgbImage::from_raw(width as u32, height as u32, buffer).expect("Image buffer has the wrong length.")
+    }
+
+    /// Shows one channel of the first latent in the batch as a grayscale image for debugging, e.g. to see 
+    /// what the VAE latents encode. The channel is min-max normalized to 0-255 and upscaled by `LATENT_SCALE` 
+    /// with nearest neighbor filtering, so the image has the size the latent decodes to.
+    pub fn visualize_latent(&self, latent: Tensor<B, 4>, channel: usize) -> RgbImage {
+        let [_, n_channels, height, width] = latent.dims();
+        assert!(channel < n_channels, "Channel {} is out of range for a latent with {} channels.", channel, n_channels);
+
+        let values = tensor_to_vec(latent.slice([0..1, channel..channel + 1]));
+        let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        let range = (max - min).max(1e-8);
+
+        let buffer = values
+            .into_iter()
+            .flat_map(|v| {
+                let v = ((v - min) / range * 255.0).round() as u8;
+                [v, v, v]
+            })
+            .collect();
+
+        let image = RgbImage::from_raw(width as u32, height as u32, buffer).expect("Image buffer has the wrong length.");
+        imageops::resize(&image, (width * LATENT_SCALE) as u32, (height * LATENT_SCALE) as u32, imageops::FilterType::Nearest)
+    }
+
+    /// Decodes the latent in overlapping `tile_size` x `tile_size` tiles (in latent pixels) so that peak memory 
+    /// is bounded by the tile size rather than the image size. Overlapping regions are blended with a linear feather. 
+    /// A larger `overlap` hides seams better but decodes more pixels more than once; 
+    /// around a quarter of the tile size is usually seamless. 
+    /// If a single tile covers the whole latent the output is identical to `latent_to_image`.
+    pub fn latent_to_image_tiled(&self, latent: Tensor<B, 4>, tile_size: usize, overlap: usize) -> Result<RawImages, Box<dyn Error>> {
+        let image = self.decode_latent_tiled(latent, tile_size, overlap)?;
+        Ok( decoded_to_raw_images(image, self.output_gamma, self.output_clamp) )
+    }
+
+    /// See `latent_to_image_tiled`. Fails unless `overlap` is smaller than `tile_size`.
+    pub fn decode_latent_tiled(&self, latent: Tensor<B, 4>, tile_size: usize, overlap: usize) -> Result<Tensor<B, 4>, Box<dyn Error>> {
+        check_tiling(tile_size, overlap)?;
+
+        let [n_batch, n_channel_latent, latent_height, latent_width] = latent.dims();
+        if tile_size >= latent_height && tile_size >= latent_width {
+            return Ok( self.decode_latent(latent) );
         }
+
+        let device = latent.device();
+        Ok( blend_tiles([n_batch, 3], [latent_height, latent_width], tile_size, overlap, LATENT_SCALE, &device, |rows, cols| {
+            self.decode_latent( latent.clone().slice([0..n_batch, 0..n_channel_latent, rows, cols]) )
+        }) )
+    }
+
+    /// Encodes a batch of interleaved RGB images with pixel values in 0..=255 into scaled latents.
+    pub fn image_to_latent(&self, images: &[Vec<u8>], width: usize, height: usize) -> Tensor<B, 4> {
+        let device = &self.devices()[0];
+        self.encode_image( raw_images_to_tensor(images, width, height, device) )
     }
 
     pub fn encode_image(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
-        self.autoencoder.encode_image(x * self.scale_factor)
+        self.autoencoder.encode_image(x) * self.scale_factor
+    }
+
+    /// The raw VAE posterior `(mean, logvar)` of `x`, without the scale factor `encode_image` applies, 
+    /// see `Autoencoder::encode_distribution` and `autoencoder::sample`.
+    pub fn encode_distribution(&self, x: Tensor<B, 4>) -> (Tensor<B, 4>, Tensor<B, 4>) {
+        self.autoencoder.encode_distribution(x)
+    }
+
+    /// Encodes the image in overlapping `tile_size` x `tile_size` tiles (in image pixels), the counterpart of 
+    /// `decode_latent_tiled`. The image sides, `tile_size` and `overlap` must be multiples of 8. Every tile's 
+    /// convolutions see zero padding at its border, so the latents of overlapping tiles are blended with a linear 
+    /// feather to hide the seams. Encoding uses the latent mean, so the result is deterministic. 
+    /// If a single tile covers the whole image the output is identical to `encode_image`.
+    pub fn encode_image_tiled(&self, x: Tensor<B, 4>, tile_size: usize, overlap: usize) -> Result<Tensor<B, 4>, Box<dyn Error>> {
+        check_tiling(tile_size, overlap)?;
+
+        let [n_batch, n_channel, height, width] = x.dims();
+        if [tile_size, overlap, height, width].iter().any(|side| side % LATENT_SCALE != 0) {
+            return Err( format!("The image size {}x{}, tile size {} and overlap {} must be multiples of {}.", width, height, tile_size, overlap, LATENT_SCALE).into() );
+        }
+
+        if tile_size >= height && tile_size >= width {
+            return Ok( self.encode_image(x) );
+        }
+
+        let device = x.device();
+        let latent_size = [height / LATENT_SCALE, width / LATENT_SCALE];
+        Ok( blend_tiles([n_batch, 4], latent_size, tile_size / LATENT_SCALE, overlap / LATENT_SCALE, 1, &device, |rows, cols| {
+            let rows = rows.start * LATENT_SCALE..rows.end * LATENT_SCALE;
+            let cols = cols.start * LATENT_SCALE..cols.end * LATENT_SCALE;
+            self.encode_image( x.clone().slice([0..n_batch, 0..n_channel, rows, cols]) )
+        }) )
     }
 
     pub fn decode_latent(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
@@ -250,18 +577,44 @@ impl<B: Backend> LatentDecoder<B> {
 }
 
 
+/// The rarely needed knobs of `Diffuser::sample_latent_with_options`, e.g. 
+/// `SamplingOptions::new().with_microbatch(Some(2))`.
+#[derive(Config, Debug)]
+pub struct SamplingOptions {
+    /// Bounds how many batch entries go through the UNet at once, e.g. `Some(2)` samples a batch of 8 in four 
+    /// chunks of 2 to avoid running out of memory, see `Diffuser::sample_latent_microbatched`. 
+    /// Defaults to the whole batch at once.
+    pub microbatch: Option<usize>, 
+    /// The percentile, e.g. `Some(0.995)`, Imagen's dynamic thresholding clamps and rescales the x0 prediction to 
+    /// at every step, which reduces blown-out colors at high guidance scales, see `DynamicThresholdSampler`. 
+    /// Defaults to no thresholding.
+    pub dynamic_threshold: Option<f32>, 
+}
+
 #[derive(Config, Debug)]
 pub struct DiffuserConfig {
     adm_in_channels: usize, 
     model_channels: usize, 
     num_head_channels: usize, 
     context_dim: usize, 
+    /// See `UNetConfig`.
+    n_head: Option<usize>, 
+    /// See `AttentionOptions`.
+    #[config(default = "AttentionOptions::new()")]
+    attention: AttentionOptions, 
+    /// Use the noise schedule rescaled to zero terminal SNR, see `Diffuser::with_zero_terminal_snr`.
+    #[config(default = false)]
+    zero_terminal_snr: bool, 
 }
 
 impl DiffuserConfig {
     pub fn init<B: Backend>(&self) -> Diffuser<B> {
         let n_steps = 1000;
-        let alpha_cumulative_products = Tensor::zeros([1]).into(); //offset_cosine_schedule_cumprod::<B>(n_steps).into();
+        let alpha_cumulative_products = if self.zero_terminal_snr {
+            offset_cosine_schedule_cumprod_zero_snr::<B>(n_steps, &B::Device::default()).into()
+        } else {
+            offset_cosine_schedule_cumprod::<B>(n_steps, &B::Device::default()).into()
+        };
         //let diffusion = UNetConfig::new(2816, 4, 4, 320, 64, 2048).init();
         let diffusion = UNetConfig::new(
             self.adm_in_channels, 
@@ -270,7 +623,10 @@ impl DiffuserConfig {
             self.model_channels, 
             self.num_head_channels, 
             self.context_dim
-        ).init();
+        )
+        .with_n_head(self.n_head)
+        .with_attention(self.attention.clone())
+        .init();
 
         Diffuser {
             n_steps, 
@@ -288,236 +644,2001 @@ pub struct Diffuser<B: Backend> {
 }
 
 impl<B: Backend> Diffuser<B> {
-    pub fn sample_latent(&self, conditioning: Conditioning<B>, unconditional_guidance_scale: f64, n_steps: usize) -> Tensor<B, 4> {
-        let device = conditioning.context.device();
-
-        let step_size = self.n_steps / n_steps;
+    /// Runs one dummy UNet forward at the (height, width) image `resolution` so that kernel compilation and 
+    /// allocation happen before the first real generation. Call it once before a timed loop, since otherwise 
+    /// the first generation is much slower and skews benchmarks.
+    pub fn warmup(&self, resolution: [usize; 2], device: &B::Device) {
+        let [height, width] = round_resolution(resolution);
+
+        let latent = Tensor::zeros_device([1, 4, height / LATENT_SCALE, width / LATENT_SCALE], device);
+        let timestep = Tensor::from_ints([self.n_steps as i32 - 1]).to_device(device);
+        let context = Tensor::zeros_device([1, 77, self.diffusion.context_dim()], device);
+        let label = Tensor::zeros_device([1, self.diffusion.adm_in_channels()], device);
+
+        let _ = self.diffusion.forward(latent, timestep, context, label).into_data();
+    }
 
-        let [n_batches, _, _] = conditioning.context.dims();
-        let [height, width] = conditioning.resolution;
+    /// Rescales the noise schedule to zero terminal SNR, see `rescale_zero_terminal_snr`, e.g. for a diffuser 
+    /// whose schedule was loaded from a converted model. All samplers and timestep spacings built from the 
+    /// diffuser afterwards use the rescaled schedule. Only spacings that start at the final timestep then begin 
+    /// from pure noise, so avoid `TimestepSpacing::Leading`.
+    pub fn with_zero_terminal_snr(self) -> Self {
+        let alpha_cumulative_products = rescale_zero_terminal_snr(self.alpha_cumulative_products.val()).into();
 
-        let gen_noise = || {
-            Tensor::random([n_batches, 4, height / 8, width / 8], Distribution::Normal(0.0, 1.0)).to_device(&device)
-        };
+        Self {
+            alpha_cumulative_products, 
+            ..self
+        }
+    }
 
-        let sigma = 0.0; // Use deterministic diffusion
+    /// Samples seamless textures: the UNet pads its convolutions circularly, so the latent wraps around at its 
+    /// edges. Decode the result with `LatentDecoder::with_tileable` as well, or the VAE's zero padding shows 
+    /// at the seams again.
+    pub fn with_tileable(self, tileable: bool) -> Self {
+        Self {
+            diffusion: self.diffusion.with_tileable(tileable), 
+            ..self
+        }
+    }
 
-        let mut latent = gen_noise();
+    /// Checks that `conditioning` fits the UNet, so mismatched text encoders are reported as an error 
+    /// instead of a shape panic in the middle of sampling.
+    pub fn check_conditioning(&self, conditioning: &Conditioning<B>) -> Result<(), Box<dyn Error>> {
+        let context_dim = self.diffusion.context_dim();
+        let adm_in_channels = self.diffusion.adm_in_channels();
 
-        for t in (0..self.n_steps).rev().step_by(step_size) {
-            let current_alpha: f64 = self.alpha_cumulative_products.val().slice([t..t + 1]).into_scalar().to_f64().unwrap();
-            let prev_alpha: f64 = if t >= step_size {
-                let i = t - step_size;
-                self.alpha_cumulative_products.val().slice([i..i + 1]).into_scalar().to_f64().unwrap()
-            } else {
-                1.0
-            };
+        let [_, _, n_context_dim] = conditioning.context.dims();
+        let [_, n_unconditional_context_dim] = conditioning.unconditional_context.dims();
+        if n_context_dim != context_dim || n_unconditional_context_dim != context_dim {
+            return Err( format!("The UNet expects a context of width {} but the conditioning has width {}.", context_dim, n_context_dim).into() );
+        }
 
-            let sqrt_noise = (1.0 - current_alpha).sqrt();
+        let [_, n_channels] = conditioning.channel_context.dims();
+        let [n_unconditional_channels] = conditioning.unconditional_channel_context.dims();
+        if n_channels != adm_in_channels || n_unconditional_channels != adm_in_channels {
+            return Err( format!("The UNet expects a channel context of width {} but the conditioning has width {}.", adm_in_channels, n_channels).into() );
+        }
 
-            let timestep = Tensor::from_ints([t as i32]).to_device(&device);
-            let pred_noise = self.forward_diffuser(latent.clone(), timestep, conditioning.clone(), unconditional_guidance_scale);
-            let predx0 = (latent - pred_noise.clone() * sqrt_noise) / current_alpha.sqrt();
-            let dir_latent = pred_noise * (1.0 - prev_alpha - sigma * sigma).sqrt();
+        Ok(())
+    }
 
-            let prev_latent = predx0 * prev_alpha.sqrt() + dir_latent + gen_noise() * sigma;
-            latent = prev_latent;
-        }
+    pub fn sample_latent(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize) -> Tensor<B, 4> {
+        self.sample_latent_with_options(conditioning, guidance_scale, cfg_rescale, n_steps, &SamplingOptions::new())
+    }
 
-        latent
+    /// Like `sample_latent`, with the rarely needed knobs of `options`.
+    pub fn sample_latent_with_options(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, options: &SamplingOptions) -> Tensor<B, 4> {
+        let noise = self.seeded_noise(&conditioning, random_seed(), 0.0);
+        self.sample_latent_from_noise_with_options(conditioning, noise, guidance_scale, cfg_rescale, n_steps, options)
     }
 
-    fn forward_diffuser(&self, latent: Tensor<B, 4>, timestep: Tensor<B, 1, Int>, conditioning: Conditioning<B>, unconditional_guidance_scale: f64) -> Tensor<B, 4> {
-        let [n_batch, _, _, _] = latent.dims();
-        //let latent = latent.repeat(0, 2);
+    /// Like `sample_latent_with_seed`, but denoises the batch in chunks of at most `microbatch` entries, slicing the 
+    /// conditioning and the initial noise accordingly. Batch entries don't interact in the UNet, so the result matches 
+    /// the unchunked one up to floating point error while peak memory only scales with the chunk size.
+    pub fn sample_latent_microbatched(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, seed: u64, microbatch: usize) -> Tensor<B, 4> {
+        let noise = self.seeded_noise(&conditioning, seed, 0.0);
+        let options = SamplingOptions::new().with_microbatch(Some(microbatch));
 
-        let unconditional_latent = self.diffusion.forward(
-            latent.clone(), 
-            timestep.clone(), 
-            conditioning.unconditional_context.unsqueeze().repeat(0, n_batch), 
-            conditioning.unconditional_channel_context.unsqueeze().repeat(0, n_batch), 
-        );
+        self.sample_latent_from_noise_with_options(conditioning, noise, guidance_scale, cfg_rescale, n_steps, &options)
+    }
 
-        let conditional_latent = self.diffusion.forward(
-            latent, 
-            timestep, 
-            conditioning.context, 
-            conditioning.channel_context, 
-        );
+    /// Samples a latent deterministically from `seed`.
+    /// 
+    /// The seed controls the backend RNG via `B::seed`. The initial latent is the first draw after seeding: 
+    /// `random_normal([n_batch, 4, height / 8, width / 8], device)`, generated on the backend's default device 
+    /// and only then moved to the conditioning's device, so the same seed yields the same latent whether the 
+    /// model runs on the CPU or on CUDA.
+    /// 
+    /// A nonzero `noise_offset` adds `noise_offset * randn([n_batch, 4, 1, 1])`, drawn right after the initial latent, 
+    /// to every pixel of a channel. This shifts the mean brightness of the noise, which lets SDXL produce very dark or 
+    /// very bright images; values around 0.05 to 0.1 are typical. An offset of 0.0 draws nothing and changes nothing.
+    pub fn sample_latent_with_seed(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, seed: u64, noise_offset: f32) -> Tensor<B, 4> {
+        let noise = self.seeded_noise(&conditioning, seed, noise_offset);
+        self.sample_latent_from_noise(conditioning, noise, guidance_scale, cfg_rescale, n_steps)
+    }
 
-        /*let latent = self.diffusion.forward(
-            latent.repeat(0, 2), 
-            timestep.repeat(0, 2), 
-            Tensor::cat(vec![unconditional_context.unsqueeze::<3>(), context], 0)
-        );
+    fn seeded_noise(&self, conditioning: &Conditioning<B>, seed: u64, noise_offset: f32) -> Tensor<B, 4> {
+        let device = conditioning.context.device();
 
-        let unconditional_latent = latent.clone().slice([0..n_batch]);
-        let conditional_latent = latent.slice([n_batch..2 * n_batch]);*/
+        let [n_batches, _, _] = conditioning.context.dims();
+        let [height, width] = conditioning.resolution;
 
-        unconditional_latent.clone() + (conditional_latent - unconditional_latent) * unconditional_guidance_scale
+        B::seed(seed);
+        let noise = random_normal([n_batches, 4, height / 8, width / 8], &device);
+        offset_noise(noise, noise_offset)
     }
-}
 
+    /// Samples a latent starting from the caller's `noise` instead of a random draw, e.g. the noise of a 
+    /// previous run or structured noise. `noise` is the full-noise latent of shape 
+    /// `[n_batch, 4, height / 8, width / 8]` for the conditioning's batch size and resolution; it is denoised 
+    /// with the deterministic DDIM sampler, so the same noise always gives the same latent.
+    pub fn sample_latent_from_noise(&self, conditioning: Conditioning<B>, noise: Tensor<B, 4>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize) -> Tensor<B, 4> {
+        self.sample_latent_from_noise_with_options(conditioning, noise, guidance_scale, cfg_rescale, n_steps, &SamplingOptions::new())
+    }
 
-#[derive(Clone, Debug)]
-pub struct Conditioning<B: Backend> {
-    pub unconditional_context: Tensor<B, 2>, 
-    pub context: Tensor<B, 3>, 
-    pub unconditional_channel_context: Tensor<B, 1>, 
-    pub channel_context: Tensor<B, 2>, 
-    pub resolution: [usize; 2], // (height, width)
-}
-
-/// These are the resolutions (height, width) Stable Diffusion XL was trained on.
-pub const RESOLUTIONS: [[i32; 2]; 40] = [
-    [512, 2048],
-    [512, 1984],
-    [512, 1920],
-    [512, 1856],
-    [576, 1792],
-    [576, 1728],
-    [576, 1664],
-    [640, 1600],
-    [640, 1536],
-    [704, 1472],
-    [704, 1408],
-    [704, 1344],
-    [768, 1344],
-    [768, 1280],
-    [832, 1216],
-    [832, 1152],
-    [896, 1152],
-    [896, 1088],
-    [960, 1088],
-    [960, 1024],
-    [1024, 1024],
-    [1024, 960],
-    [1088, 960],
-    [1088, 896],
-    [1152, 896],
-    [1152, 832],
-    [1216, 832],
-    [1280, 768],
-    [1344, 768],
-    [1408, 704],
-    [1472, 704],
-    [1536, 640],
-    [1600, 640],
-    [1664, 576],
-    [1728, 576],
-    [1792, 576],
-    [1856, 512],
-    [1920, 512],
-    [1984, 512],
-    [2048, 512]
-];
+    fn sample_latent_from_noise_with_options(&self, conditioning: Conditioning<B>, noise: Tensor<B, 4>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, options: &SamplingOptions) -> Tensor<B, 4> {
+        let device = conditioning.context.device();
 
+        let [n_batches, _, _] = conditioning.context.dims();
+        let [height, width] = conditioning.resolution;
+        let expected = [n_batches, 4, height / 8, width / 8];
+        assert!(noise.dims() == expected, "Expected initial noise of shape {:?} for the conditioning but got {:?}.", expected, noise.dims());
 
+        let guidance_scale = guidance_scale.into();
+        let timesteps = self.timestep_schedule(n_steps);
+        let microbatch = options.microbatch.unwrap_or(n_batches).max(1);
 
-#[derive(Config, Debug)]
-pub struct EmbedderConfig {
-    clip_config: CLIPConfig, 
-    open_clip_config: CLIPConfig, 
-}
+        let latents = (0..n_batches)
+            .step_by(microbatch)
+            .map(|start| {
+                let batch = start..(start + microbatch).min(n_batches);
 
-impl EmbedderConfig {
-    pub fn init<B: Backend>(&self) -> Embedder<B> {
-        /*let clip = CLIPConfig::new(49408, 768, 768, 12, 77, 12, true).init();
-        let open_clip = CLIPConfig::new(49408, 1024, 1024, 16, 77, 24, false).init();*/
+                let mut chunk = conditioning.clone();
+                chunk.context = conditioning.context.clone().slice([batch.clone()]);
+                chunk.channel_context = conditioning.channel_context.clone().slice([batch.clone()]);
 
-        let clip = self.clip_config.init();
-        let open_clip = self.open_clip_config.init();
+                let sampler = self.ddim_sampler(0.0); // Use deterministic diffusion
+                let mut sampler: Box<dyn Sampler<B>> = match options.dynamic_threshold {
+                    Some(percentile) => Box::new( DynamicThresholdSampler::new(sampler, &self.scheduler(), percentile) ), 
+                    None => Box::new(sampler), 
+                };
 
-        let clip_tokenizer = SimpleTokenizer::new().unwrap();
-        let open_clip_tokenizer = OpenClipTokenizer::new().unwrap();
+                self.denoise(noise.clone().slice([batch]).to_device(&device), chunk, guidance_scale, cfg_rescale, &timesteps, sampler.as_mut(), |_, _, _, _| {})
+            })
+            .collect();
 
-        Embedder {
-            clip, 
-            open_clip, 
-            clip_tokenizer, 
-            open_clip_tokenizer, 
-        }
+        Tensor::cat(latents, 0)
     }
-}
 
-#[derive(Module, Debug)]
-pub struct Embedder<B: Backend> {
-    clip: CLIP<B>, 
-    open_clip: CLIP<B>, 
-    clip_tokenizer: SimpleTokenizer, 
-    open_clip_tokenizer: OpenClipTokenizer, 
-}
+    /// Samples one latent per seed, with `seeds[i]` giving the initial noise of the `i`-th batch entry 
+    /// exactly as `sample_latent_with_seed` would for a batch of one, so any image can be regenerated alone. 
+    /// The conditioning's batch size must equal `seeds.len()`, e.g. a prompt embedded `seeds.len()` times.
+    pub fn sample_latent_batch_seeds(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, seeds: &[u64]) -> Tensor<B, 4> {
+        let device = conditioning.context.device();
 
-impl<B: Backend> Embedder<B> {
-    pub fn text_to_conditioning(&self, text: &str, size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, ar: Tensor<B, 1, Int>) -> Conditioning<B> {
-        let [n_batch, _] = size.dims();
-        let ar_data = ar.clone().into_data();
-        let resolution = [ar_data.value[0].to_usize().unwrap(), ar_data.value[1].to_usize().unwrap()];
-        let batched_ar = ar.unsqueeze().repeat(0, n_batch);
+        let [n_batches, _, _] = conditioning.context.dims();
+        let [height, width] = conditioning.resolution;
+        assert!(seeds.len() == n_batches, "Expected {} seeds for the batch but got {}.", n_batches, seeds.len());
+
+        let noise = seeds
+            .iter()
+            .map(|&seed| {
+                B::seed(seed);
+                random_normal([1, 4, height / 8, width / 8], &device)
+            })
+            .collect();
+        let noise = Tensor::cat(noise, 0);
+
+        self.sample_latent_from_noise(conditioning, noise, guidance_scale, cfg_rescale, n_steps)
+    }
 
-        let (unconditional_context, unconditional_channel_context) = self.unconditional_context(size.clone(), crop.clone(), batched_ar.clone());
-        let (context, channel_context) = self.context(text, size, crop, batched_ar);
+    /// Like `sample_latent`, but gets the conditioning from `provider`, e.g. an `Embedder` or image embeddings, 
+    /// and checks it against the UNet before sampling, see `ConditioningProvider` for the shapes it must have.
+    pub fn sample_latent_with_provider(&self, provider: &dyn ConditioningProvider<B>, prompt: &str, negative: &str, resolution: [i32; 2], guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize) -> Result<Tensor<B, 4>, Box<dyn Error>> {
+        let conditioning = provider.provide(prompt, negative, resolution)?;
+        self.check_conditioning(&conditioning)?;
 
-        Conditioning {
-            unconditional_context, 
-            context, 
-            unconditional_channel_context, 
-            channel_context, 
-            resolution, 
-        }
+        Ok( self.sample_latent(conditioning, guidance_scale, cfg_rescale, n_steps) )
     }
 
-    fn unconditional_context(&self, size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, ar: Tensor<B, 2, Int>) -> (Tensor<B, 2>, Tensor<B, 1>) {
-        let clip_context = text_to_context_clip("", &self.clip, &self.clip_tokenizer);
-        let (open_clip_context, pooled_text_embed) = text_to_context_open_clip("", &self.open_clip, &self.open_clip_tokenizer);
+    /// Samples a variation of the image `seed` gives: the initial noise is spherically interpolated from `seed`'s 
+    /// noise towards `variation_seed`'s by `variation_strength`, so small strengths slightly perturb the composition 
+    /// and 1.0 gives `variation_seed`'s image. A strength of 0.0 is exactly `sample_latent_with_seed` with `seed`. 
+    /// Slerp rather than lerp keeps the blended noise at unit variance, which the sampler expects; 
+    /// a linear blend of two independent noises halfway through would have a variance of only 0.5.
+    pub fn sample_latent_with_variation(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, seed: u64, variation_seed: u64, variation_strength: f32) -> Tensor<B, 4> {
+        let device = conditioning.context.device();
 
-        (
-            Tensor::cat(vec![clip_context, open_clip_context], 2).squeeze(0), 
-            conditioning_embedding(pooled_text_embed, 256, size, crop, ar).squeeze(0), 
-        )
-    }
+        let [n_batches, _, _] = conditioning.context.dims();
+        let [height, width] = conditioning.resolution;
+        let shape = [n_batches, 4, height / 8, width / 8];
 
-    fn context(&self, text: &str, size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, ar: Tensor<B, 2, Int>) -> (Tensor<B, 3>, Tensor<B, 2>) {
-        let clip_context = text_to_context_clip(text, &self.clip, &self.clip_tokenizer);
-        let (open_clip_context, pooled_text_embed) = text_to_context_open_clip(text, &self.open_clip, &self.open_clip_tokenizer);
+        B::seed(seed);
+        let noise = random_normal(shape, &device);
 
-        (
-            Tensor::cat(vec![clip_context, open_clip_context], 2), 
-            conditioning_embedding(pooled_text_embed, 256, size, crop, ar), 
-        )
+        let noise = if variation_strength != 0.0 {
+            B::seed(variation_seed);
+            let variation_noise = random_normal(shape, &device);
+
+            slerp(noise, variation_noise, variation_strength as f64)
+        } else {
+            noise
+        };
+
+        self.sample_latent_from_noise(conditioning, noise, guidance_scale, cfg_rescale, n_steps)
     }
-}
 
+    /// Seeds the backend RNG before sampling so that both the initial latent and any noise 
+    /// injected by the sampler (e.g. `EulerAncestralSampler`) are reproducible.
+    pub fn sample_latent_with_sampler_and_seed(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, sampler: &mut dyn Sampler<B>, spacing: TimestepSpacing, seed: u64) -> Tensor<B, 4> {
+        self.sample_latent_with_noise_seed(conditioning, guidance_scale, cfg_rescale, n_steps, sampler, spacing, seed, None)
+    }
 
-pub fn text_to_context_clip<B: Backend, T: Tokenizer>(text: &str, clip: &CLIP<B>, tokenizer: &T) -> Tensor<B, 3> {
-    let device = &clip.devices()[0];
+    /// Samples with two seeds: `seed` draws the initial latent, which fixes the composition, and `noise_seed` 
+    /// drives the noise that ancestral samplers (`EulerAncestralSampler`, `DdimSampler` with a positive `eta`) 
+    /// inject at every step, which varies the details. The backend RNG is reseeded with `noise_seed` right after 
+    /// the initial latent is drawn, so fixing `seed` and changing `noise_seed` gives variations of one composition. 
+    /// Without a `noise_seed` the step noise continues the `seed` stream, as in `sample_latent_with_sampler_and_seed`. 
+    /// Deterministic samplers draw no step noise and ignore `noise_seed`.
+    pub fn sample_latent_with_noise_seed(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, sampler: &mut dyn Sampler<B>, spacing: TimestepSpacing, seed: u64, noise_seed: Option<u64>) -> Tensor<B, 4> {
+        let device = conditioning.context.device();
 
-    let tokens = tokenize_text(text, tokenizer, clip.max_sequence_length(), device);
+        let [n_batches, _, _] = conditioning.context.dims();
+        let [height, width] = conditioning.resolution;
 
-    let n_layers = clip.num_layers();
-    clip.forward_hidden(tokens, n_layers - 1) // penultimate layer
-}
+        B::seed(seed);
+        let latent = random_normal([n_batches, 4, height / 8, width / 8], &device);
 
-pub fn text_to_context_open_clip<B: Backend, T: Tokenizer>(text: &str, clip: &CLIP<B>, tokenizer: &T) -> (Tensor<B, 3>, Tensor<B, 2>) {
-    let device = &clip.devices()[0];
+        if let Some(noise_seed) = noise_seed {
+            B::seed(noise_seed);
+        }
 
-    let tokens = tokenize_text(text, tokenizer, clip.max_sequence_length(), device);
+        let timesteps = self.timestep_schedule_with_spacing(n_steps, spacing);
+        self.denoise(latent, conditioning, guidance_scale.into(), cfg_rescale, &timesteps, sampler, |_, _, _, _| {})
+    }
 
-    let n_layers = clip.num_layers();
-    clip.forward_hidden_pooled(tokens, n_layers - 1) // penultimate layer
-}
+    /// `spacing` picks how the `n_steps` timesteps are spread over the training schedule, see `TimestepSpacing`.
+    pub fn sample_latent_with_sampler(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, sampler: &mut dyn Sampler<B>, spacing: TimestepSpacing) -> Tensor<B, 4> {
+        self.sample_latent_with_sampler_and_callback(conditioning, guidance_scale, cfg_rescale, n_steps, sampler, spacing, |_, _, _| {})
+    }
 
-pub fn tokenize_text<B: Backend, T: Tokenizer>(text: &str, tokenizer: &T, seq_len: usize, device: &B::Device) -> Tensor<B, 2, Int> {
-    let mut tokenized: Vec<_> = tokenizer
-        .encode(text, true, true)
-        .into_iter()
-        .map(|v| v as i32)
-        .collect();
+    /// Samples a latent from `seed` with a separate prompt per region, see `RegionalConditioning`. 
+    /// The UNet's cross-attention uses each region's context inside its hard rectangular mask; 
+    /// the negative prompt of `base` applies to the whole image.
+    pub fn sample_latent_regional(&self, conditioning: RegionalConditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, seed: u64) -> Result<Tensor<B, 4>, Box<dyn Error>> {
+        self.check_conditioning(&conditioning.base)?;
+        conditioning.check()?;
 
-    tokenized.resize(seq_len, tokenizer.padding_token() as i32);
+        let noise = self.seeded_noise(&conditioning.base, seed, 0.0);
 
-    Tensor::from_ints(&tokenized[..]).to_device(device).unsqueeze()
-}
+        let mut sampler = self.ddim_sampler(0.0);
+        let timesteps = self.timestep_schedule(n_steps);
+        let predict = |latent: Tensor<B, 4>, timestep: Tensor<B, 1, Int>, unconditional_guidance_scale: f64| {
+            self.forward_diffuser_regional(latent, timestep, &conditioning, unconditional_guidance_scale, cfg_rescale)
+        };
+
+        Ok( self.denoise_with(noise, guidance_scale.into(), &timesteps, &mut sampler, predict, |_, _, _, _| {}) )
+    }
+
+    /// Samples a latent from `seed`, switching conditionings over the steps as `conditioning` schedules, 
+    /// see `ScheduledConditioning`. With a single stage this is `sample_latent_with_seed`.
+    pub fn sample_latent_scheduled(&self, conditioning: ScheduledConditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, seed: u64) -> Result<Tensor<B, 4>, Box<dyn Error>> {
+        conditioning.check()?;
+        for (_, stage) in &conditioning.stages {
+            self.check_conditioning(stage)?;
+        }
+
+        let first = &conditioning.stages[0].1;
+        let device = first.context.device();
+
+        let [n_batches, _, _] = first.context.dims();
+        let [height, width] = first.resolution;
+
+        B::seed(seed);
+        let mut latent = random_normal([n_batches, 4, height / 8, width / 8], &device);
+
+        let guidance_scale = guidance_scale.into();
+        let mut sampler = self.ddim_sampler(0.0);
+        let timesteps = self.timestep_schedule(n_steps);
+        let n_total_steps = timesteps.len();
+
+        for (i, &t) in timesteps.iter().enumerate() {
+            let prev_t = timesteps.get(i + 1).cloned();
+
+            let unconditional_guidance_scale = guidance_scale.scale(i, n_total_steps);
+            let stage = conditioning.at(i, n_total_steps).clone();
+            latent = self.denoise_step(latent, t, prev_t, stage, unconditional_guidance_scale, cfg_rescale, &mut sampler);
+        }
+
+        Ok(latent)
+    }
+
+    /// Like `sample_latent`, but calls `callback(step, n_total_steps, &latent)` after every denoising step.
+    pub fn sample_latent_with_callback(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, callback: impl FnMut(usize, usize, &Tensor<B, 4>)) -> Tensor<B, 4> {
+        B::seed(random_seed());
+
+        let mut sampler = self.ddim_sampler(0.0);
+        self.sample_latent_with_sampler_and_callback(conditioning, guidance_scale, cfg_rescale, n_steps, &mut sampler, TimestepSpacing::Uniform, callback)
+    }
+
+    /// Like `sample_latent`, but also returns the latent after every `stride`-th step (every step if `None`), 
+    /// starting with the first, so the denoising trajectory can be decoded with `LatentDecoder::latent_to_image`. 
+    /// 
+    /// The kept latents stay on the model's device. Each one is `n_batch * 4 * (height / 8) * (width / 8)` elements, 
+    /// i.e. 256 KiB in f32 for a single 1024x1024 image, so 30 steps keep about 7.5 MiB alive per image. 
+    /// Use a stride to reduce this for large batches or long runs.
+    pub fn sample_latent_collect(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, stride: Option<usize>) -> (Tensor<B, 4>, Vec<Tensor<B, 4>>) {
+        let stride = stride.unwrap_or(1).max(1);
+
+        let mut intermediates = Vec::new();
+        let latent = self.sample_latent_with_callback(conditioning, guidance_scale, cfg_rescale, n_steps, |step, _, latent| {
+            if step % stride == 0 {
+                intermediates.push(latent.clone());
+            }
+        });
+
+        (latent, intermediates)
+    }
+
+    /// Yields the latent after each denoising step, only running a step when the iterator is polled, 
+    /// so callers can drive the loop themselves, e.g. to stream previews from an async server. 
+    /// Dropping the iterator cancels sampling; the sampler state lives in the iterator, so the diffuser 
+    /// is unaffected. The last item is the same latent `sample_latent` returns.
+    pub fn sample_iter(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize) -> impl Iterator<Item = Tensor<B, 4>> + '_ {
+        B::seed(random_seed());
+
+        let device = conditioning.context.device();
+
+        let [n_batches, _, _] = conditioning.context.dims();
+        let [height, width] = conditioning.resolution;
+
+        let mut latent = random_normal([n_batches, 4, height / 8, width / 8], &device);
+
+        let guidance_scale = guidance_scale.into();
+        let mut sampler = self.ddim_sampler(0.0);
+        let timesteps = self.timestep_schedule(n_steps);
+        let n_total_steps = timesteps.len();
+
+        (0..n_total_steps).map(move |i| {
+            let t = timesteps[i];
+            let prev_t = timesteps.get(i + 1).cloned();
+
+            let unconditional_guidance_scale = guidance_scale.scale(i, n_total_steps);
+            latent = self.denoise_step(latent.clone(), t, prev_t, conditioning.clone(), unconditional_guidance_scale, cfg_rescale, &mut sampler);
+
+            latent.clone()
+        })
+    }
+
+    /// Like `sample_latent`, but checks `cancel` before every step and stops with 
+    /// `Aborted` once it is set, e.g. by a server whose client disconnected. The check is a single relaxed 
+    /// atomic load per step.
+    pub fn sample_latent_cancellable(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, cancel: Arc<AtomicBool>) -> Result<Tensor<B, 4>, Aborted<B>> {
+        let n_total_steps = self.timestep_schedule(n_steps).len();
+        let mut steps = self.sample_iter(conditioning, guidance_scale, cfg_rescale, n_steps);
+
+        let mut latent = None;
+        for completed_steps in 0..n_total_steps {
+            if cancel.load(Ordering::Relaxed) {
+                return Err(Aborted {
+                    latent, 
+                    completed_steps, 
+                    n_steps: n_total_steps, 
+                });
+            }
+
+            latent = steps.next();
+        }
+
+        Ok( latent.expect("Sampling needs at least one step.") )
+    }
+
+    pub fn sample_latent_with_sampler_and_callback(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, sampler: &mut dyn Sampler<B>, spacing: TimestepSpacing, mut callback: impl FnMut(usize, usize, &Tensor<B, 4>)) -> Tensor<B, 4> {
+        self.sample_latent_with_sampler_and_x0_callback(conditioning, guidance_scale, cfg_rescale, n_steps, sampler, spacing, |step, n_total_steps, latent, _| callback(step, n_total_steps, latent))
+    }
+
+    /// Like `sample_latent_with_callback`, but the callback also gets the sampler's estimate of the fully denoised 
+    /// latent (x0) at each step, see `Sampler::step_with_x0`. Decoding x0 with `LatentDecoder::latent_to_image` 
+    /// gives a much sharper progress preview than the noisy latent, especially early on.
+    pub fn sample_latent_with_x0_callback(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, callback: impl FnMut(usize, usize, &Tensor<B, 4>, Option<&Tensor<B, 4>>)) -> Tensor<B, 4> {
+        B::seed(random_seed());
+
+        let mut sampler = self.ddim_sampler(0.0);
+        self.sample_latent_with_sampler_and_x0_callback(conditioning, guidance_scale, cfg_rescale, n_steps, &mut sampler, TimestepSpacing::Uniform, callback)
+    }
+
+    /// Calls `callback(step, n_total_steps, &latent, x0)` after every step, where `x0` is `None` for samplers 
+    /// without an x0 estimate.
+    pub fn sample_latent_with_sampler_and_x0_callback(&self, conditioning: Conditioning<B>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, sampler: &mut dyn Sampler<B>, spacing: TimestepSpacing, callback: impl FnMut(usize, usize, &Tensor<B, 4>, Option<&Tensor<B, 4>>)) -> Tensor<B, 4> {
+        let device = conditioning.context.device();
+
+        let [n_batches, _, _] = conditioning.context.dims();
+        let [height, width] = conditioning.resolution;
+
+        let latent = random_normal([n_batches, 4, height / 8, width / 8], &device);
+
+        let timesteps = self.timestep_schedule_with_spacing(n_steps, spacing);
+        self.denoise(latent, conditioning, guidance_scale.into(), cfg_rescale, &timesteps, sampler, callback)
+    }
+
+    /// Image-to-image sampling starting from `init_latent` (see `LatentDecoder::image_to_latent`). 
+    /// The init latent is noised to the timestep at which the last `strength` fraction of the `n_steps` steps begins 
+    /// and only those steps are run. A strength of 1.0 starts from pure noise, which is equivalent to text-to-image.
+    pub fn sample_latent_from_image(&self, conditioning: Conditioning<B>, init_latent: Tensor<B, 4>, strength: f32, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize) -> Tensor<B, 4> {
+        let mut sampler = self.ddim_sampler(0.0);
+        self.sample_latent_from_image_with_sampler(conditioning, init_latent, strength, guidance_scale, cfg_rescale, n_steps, &mut sampler)
+    }
+
+    pub fn sample_latent_from_image_with_sampler(&self, conditioning: Conditioning<B>, init_latent: Tensor<B, 4>, strength: f32, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, sampler: &mut dyn Sampler<B>) -> Tensor<B, 4> {
+        let device = conditioning.context.device();
+        let init_latent = init_latent.to_device(&device);
+
+        let timesteps = self.timestep_schedule(n_steps);
+        let n_skip = img2img_skipped_steps(timesteps.len(), strength);
+
+        let noise = random_normal(init_latent.shape(), &device);
+        let latent = if n_skip == 0 {
+            noise
+        } else {
+            self.add_noise(init_latent, noise, timesteps[n_skip])
+        };
+
+        self.denoise(latent, conditioning, guidance_scale.into(), cfg_rescale, &timesteps[n_skip..], sampler, |_, _, _, _| {})
+    }
+
+    /// Hi-res fix: upscales `base_latent` by `scale_factor` with bilinear interpolation (see `upsample_bilinear`), 
+    /// noises it per `strength` and runs `added_steps` more denoising steps at the new size, which restores the 
+    /// detail that interpolation blurs. The steps are taken from a schedule of `added_steps / strength` steps, 
+    /// so the result matches `sample_latent_from_image` on the upscaled latent. 
+    /// 
+    /// Typical settings are a `scale_factor` of 1.5 to 2.0 with a `strength` of 0.5 to 0.7 and 10 to 20 steps. 
+    /// Lower strengths keep the base composition but leave interpolation blur, higher ones sharpen more but 
+    /// start to duplicate subjects, since the model then effectively composes at a size it wasn't trained on. 
+    /// `conditioning` can be re-embedded at the upscaled size, but the base conditioning works well too. 
+    /// A `strength` of 0 (or no `added_steps`) skips the refinement pass and returns the interpolated latent.
+    pub fn hires_fix(&self, conditioning: Conditioning<B>, base_latent: Tensor<B, 4>, scale_factor: f64, added_steps: usize, strength: f32, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32) -> Tensor<B, 4> {
+        let [_, _, height, width] = base_latent.dims();
+        let upscaled = upsample_bilinear(
+            base_latent, 
+            (height as f64 * scale_factor).round() as usize, 
+            (width as f64 * scale_factor).round() as usize, 
+        );
+
+        if strength <= 0.0 || added_steps == 0 {
+            return upscaled;
+        }
+
+        let n_steps = ((added_steps as f32 / strength.min(1.0)).round() as usize).max(added_steps);
+        self.sample_latent_from_image(conditioning, upscaled, strength, guidance_scale, cfg_rescale, n_steps)
+    }
+
+    /// Diffuses a clean latent forward to `timestep`.
+    pub fn add_noise(&self, latent: Tensor<B, 4>, noise: Tensor<B, 4>, timestep: usize) -> Tensor<B, 4> {
+        self.scheduler().add_noise(latent, noise, timestep)
+    }
+
+    /// Inpaints the regions of `init_latent` where `mask` is 1. 
+    /// `mask` is a binary tensor at latent resolution with shape `[n_batch, 1, height / 8, width / 8]` (or 4 channels). 
+    /// After every step the unmasked region is replaced by `init_latent` noised to the current timestep, 
+    /// so only the masked region is free to change.
+    pub fn sample_latent_inpaint(&self, conditioning: Conditioning<B>, init_latent: Tensor<B, 4>, mask: Tensor<B, 4>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize) -> Tensor<B, 4> {
+        let mut sampler = self.ddim_sampler(0.0);
+        self.sample_latent_inpaint_with_sampler(conditioning, init_latent, mask, guidance_scale, cfg_rescale, n_steps, &mut sampler)
+    }
+
+    pub fn sample_latent_inpaint_with_sampler(&self, conditioning: Conditioning<B>, init_latent: Tensor<B, 4>, mask: Tensor<B, 4>, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize, sampler: &mut dyn Sampler<B>) -> Tensor<B, 4> {
+        let device = conditioning.context.device();
+        let init_latent = init_latent.to_device(&device);
+
+        let [_, n_channel, _, _] = init_latent.dims();
+        let mask = mask.to_device(&device);
+        let mask = if mask.dims()[1] == 1 {
+            mask.repeat(1, n_channel)
+        } else {
+            mask
+        };
+        let keep = mask.clone().mul_scalar(-1.0).add_scalar(1.0);
+
+        let noise = random_normal(init_latent.shape(), &device);
+        let mut latent = noise.clone();
+
+        let guidance_scale = guidance_scale.into();
+        let timesteps = self.timestep_schedule(n_steps);
+        for (i, &t) in timesteps.iter().enumerate() {
+            let prev_t = timesteps.get(i + 1).cloned();
+            let unconditional_guidance_scale = guidance_scale.scale(i, timesteps.len());
+
+            latent = self.denoise_step(latent, t, prev_t, conditioning.clone(), unconditional_guidance_scale, cfg_rescale, sampler);
+
+            let known = if let Some(prev_t) = prev_t {
+                self.add_noise(init_latent.clone(), noise.clone(), prev_t)
+            } else {
+                init_latent.clone()
+            };
+
+            latent = latent * mask.clone() + known * keep.clone();
+        }
+
+        latent
+    }
+
+    /// Samples with the base model and finishes with the SDXL refiner. 
+    /// The `base_steps + refiner_steps` timesteps are scheduled as a single run, the base model denoises the first 
+    /// `base_steps` of them and the latent is handed to the refiner at that point. The switch point is therefore at the 
+    /// fraction `base_steps / (base_steps + refiner_steps)` of the schedule; the reference SDXL pipeline switches at 0.8, 
+    /// e.g. 32 base steps and 8 refiner steps. The refiner uses the same conditioning, see `Refiner` for how it is adapted.
+    pub fn sample_latent_with_refiner(&self, conditioning: Conditioning<B>, refiner: &Refiner<B>, base_steps: usize, refiner_steps: usize, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32) -> Tensor<B, 4> {
+        let device = conditioning.context.device();
+
+        let [n_batches, _, _] = conditioning.context.dims();
+        let [height, width] = conditioning.resolution;
+
+        let mut latent = random_normal([n_batches, 4, height / 8, width / 8], &device);
+
+        let mut sampler = self.ddim_sampler(0.0);
+        let guidance_scale = guidance_scale.into();
+        let timesteps = self.timestep_schedule(base_steps + refiner_steps);
+        let n_base_steps = base_steps.min(timesteps.len());
+
+        for (i, &t) in timesteps.iter().enumerate() {
+            let prev_t = timesteps.get(i + 1).cloned();
+            let unconditional_guidance_scale = guidance_scale.scale(i, timesteps.len());
+
+            latent = if i < n_base_steps {
+                self.denoise_step(latent, t, prev_t, conditioning.clone(), unconditional_guidance_scale, cfg_rescale, &mut sampler)
+            } else {
+                refiner.denoise_step(latent, t, prev_t, conditioning.clone(), unconditional_guidance_scale, cfg_rescale, &mut sampler)
+            };
+        }
+
+        latent
+    }
+
+    fn denoise(&self, latent: Tensor<B, 4>, conditioning: Conditioning<B>, guidance_scale: GuidanceSchedule, cfg_rescale: f32, timesteps: &[usize], sampler: &mut dyn Sampler<B>, callback: impl FnMut(usize, usize, &Tensor<B, 4>, Option<&Tensor<B, 4>>)) -> Tensor<B, 4> {
+        let predict = |latent: Tensor<B, 4>, timestep: Tensor<B, 1, Int>, unconditional_guidance_scale: f64| {
+            self.forward_diffuser(latent, timestep, conditioning.clone(), unconditional_guidance_scale, cfg_rescale)
+        };
+
+        self.denoise_with(latent, guidance_scale, timesteps, sampler, predict, callback)
+    }
+
+    /// Like `denoise`, but with the guided noise predictions `predict(latent, timestep, unconditional_guidance_scale)`, 
+    /// e.g. `forward_diffuser_regional`.
+    fn denoise_with(&self, latent: Tensor<B, 4>, guidance_scale: GuidanceSchedule, timesteps: &[usize], sampler: &mut dyn Sampler<B>, predict: impl Fn(Tensor<B, 4>, Tensor<B, 1, Int>, f64) -> Tensor<B, 4>, mut callback: impl FnMut(usize, usize, &Tensor<B, 4>, Option<&Tensor<B, 4>>)) -> Tensor<B, 4> {
+        let n_total_steps = timesteps.len();
+
+        let mut latent = latent;
+        for (i, &t) in timesteps.iter().enumerate() {
+            let prev_t = timesteps.get(i + 1).cloned();
+
+            let unconditional_guidance_scale = guidance_scale.scale(i, n_total_steps);
+            let (next_latent, x0) = sampler_step(latent, t, prev_t, sampler, |latent, timestep| predict(latent, timestep, unconditional_guidance_scale));
+            latent = next_latent;
+
+            callback(i, n_total_steps, &latent, x0.as_ref());
+        }
+
+        latent
+    }
+
+    fn denoise_step(&self, latent: Tensor<B, 4>, t: usize, prev_t: Option<usize>, conditioning: Conditioning<B>, unconditional_guidance_scale: f64, cfg_rescale: f32, sampler: &mut dyn Sampler<B>) -> Tensor<B, 4> {
+        self.denoise_step_with_x0(latent, t, prev_t, conditioning, unconditional_guidance_scale, cfg_rescale, sampler).0
+    }
+
+    fn denoise_step_with_x0(&self, latent: Tensor<B, 4>, t: usize, prev_t: Option<usize>, conditioning: Conditioning<B>, unconditional_guidance_scale: f64, cfg_rescale: f32, sampler: &mut dyn Sampler<B>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        sampler_step(latent, t, prev_t, sampler, |latent, timestep| {
+            self.forward_diffuser(latent, timestep, conditioning.clone(), unconditional_guidance_scale, cfg_rescale)
+        })
+    }
+
+    /// The timesteps `sample_latent` visits for `n_steps`, in the order they are run.
+    pub fn timesteps(&self, n_steps: usize) -> Vec<usize> {
+        self.timestep_schedule(n_steps)
+    }
+
+    /// The timesteps the samplers visit with the given `spacing`, see `sample_latent_with_sampler`.
+    pub fn timesteps_with_spacing(&self, n_steps: usize, spacing: TimestepSpacing) -> Vec<usize> {
+        self.timestep_schedule_with_spacing(n_steps, spacing)
+    }
+
+    /// The noise levels `sqrt((1 - alpha_cumprod) / alpha_cumprod)` of `timesteps`, which the sigma space samplers 
+    /// (`EulerAncestralSampler`, `DpmPlusPlus2mSampler`) step between, followed by the final 0.
+    pub fn sigmas(&self, n_steps: usize) -> Vec<f32> {
+        self.sigmas_with_spacing(n_steps, TimestepSpacing::Uniform)
+    }
+
+    pub fn sigmas_with_spacing(&self, n_steps: usize, spacing: TimestepSpacing) -> Vec<f32> {
+        let scheduler = self.scheduler();
+
+        self.timestep_schedule_with_spacing(n_steps, spacing)
+            .into_iter()
+            .map(|t| scheduler.sigma(t) as f32)
+            .chain(iter::once(0.0))
+            .collect()
+    }
+
+    fn timestep_schedule(&self, n_steps: usize) -> Vec<usize> {
+        let step_size = self.n_steps / n_steps;
+        (0..self.n_steps).rev().step_by(step_size).collect()
+    }
+
+    fn timestep_schedule_with_spacing(&self, n_steps: usize, spacing: TimestepSpacing) -> Vec<usize> {
+        match spacing {
+            TimestepSpacing::Uniform => self.timestep_schedule(n_steps), 
+            TimestepSpacing::Leading | TimestepSpacing::Trailing | TimestepSpacing::Linspace => spaced_timesteps(self.n_steps, n_steps, spacing), 
+            TimestepSpacing::Karras => {
+                let scheduler = self.scheduler();
+                let sigmas = scheduler.sigmas();
+
+                let sigma_min = sigmas[0];
+                let sigma_max = sigmas[sigmas.len() - 1];
+                let karras = karras_sigmas(n_steps, sigma_min, sigma_max, 7.0);
+
+                sigmas_to_timesteps(&karras, sigmas)
+            }
+        }
+    }
+
+    /// The noise schedule of this diffuser, which its samplers step through.
+    pub fn scheduler(&self) -> Scheduler {
+        Scheduler::from_tensor(self.alpha_cumulative_products.val())
+    }
+
+    pub fn ddim_sampler(&self, eta: f64) -> DdimSampler {
+        DdimSampler::new(&self.scheduler(), eta)
+    }
+
+    pub fn euler_ancestral_sampler(&self) -> EulerAncestralSampler {
+        EulerAncestralSampler::new(&self.scheduler())
+    }
+
+    pub fn dpm_plus_plus_2m_sampler(&self) -> DpmPlusPlus2mSampler<B> {
+        DpmPlusPlus2mSampler::new(&self.scheduler())
+    }
+
+    pub fn heun_sampler(&self) -> HeunSampler {
+        HeunSampler::new(&self.scheduler())
+    }
+
+    pub fn lms_sampler(&self, order: usize) -> LmsSampler<B> {
+        LmsSampler::new(&self.scheduler(), order)
+    }
+
+    /// The sampler of the given kind for this diffuser's schedule, e.g. 
+    /// `diffuser.sampler("heun".parse()?)` for `sample_latent_with_sampler`.
+    pub fn sampler(&self, kind: SamplerKind) -> Box<dyn Sampler<B>> {
+        match kind {
+            SamplerKind::Ddim => Box::new( self.ddim_sampler(0.0) ), 
+            SamplerKind::EulerAncestral => Box::new( self.euler_ancestral_sampler() ), 
+            SamplerKind::DpmPlusPlus2m => Box::new( self.dpm_plus_plus_2m_sampler() ), 
+            SamplerKind::Heun => Box::new( self.heun_sampler() ), 
+            SamplerKind::Lms => Box::new( self.lms_sampler(4) ), 
+        }
+    }
+
+    fn forward_diffuser(&self, latent: Tensor<B, 4>, timestep: Tensor<B, 1, Int>, conditioning: Conditioning<B>, unconditional_guidance_scale: f64, cfg_rescale: f32) -> Tensor<B, 4> {
+        guided_prediction(
+            latent, 
+            timestep, 
+            conditioning, 
+            unconditional_guidance_scale, 
+            cfg_rescale, 
+            |latent, timestep, context, channel_context| self.diffusion.forward(latent, timestep, context, channel_context), 
+        )
+    }
+
+    fn forward_diffuser_regional(&self, latent: Tensor<B, 4>, timestep: Tensor<B, 1, Int>, conditioning: &RegionalConditioning<B>, unconditional_guidance_scale: f64, cfg_rescale: f32) -> Tensor<B, 4> {
+        let [n_batch, _, _, _] = latent.dims();
+        let base = &conditioning.base;
+
+        let conditional_latent = self.diffusion.forward_regional(
+            latent.clone(), 
+            timestep.clone(), 
+            conditioning.context(), 
+            base.channel_context.clone(), 
+        );
+
+        if unconditional_guidance_scale == 1.0 {
+            return conditional_latent;
+        }
+
+        let unconditional_latent = self.diffusion.forward(
+            latent, 
+            timestep, 
+            base.unconditional_context.clone().unsqueeze().repeat(0, n_batch), 
+            base.unconditional_channel_context.clone().unsqueeze().repeat(0, n_batch), 
+        );
+
+        let guided = unconditional_latent.clone() + (conditional_latent.clone() - unconditional_latent) * unconditional_guidance_scale;
+        rescale_guidance(guided, conditional_latent, cfg_rescale)
+    }
+}
+
+
+/// Aesthetic scores the refiner is conditioned on when `Conditioning` leaves them unset.
+pub const DEFAULT_AESTHETIC_SCORE: f32 = 6.0;
+pub const DEFAULT_NEGATIVE_AESTHETIC_SCORE: f32 = 2.5;
+
+const CONDITIONING_EMBEDDING_DIM: usize = 256;
+
+/// The refiner's channel context: the pooled text embedding followed by the size, crop and aesthetic score embeddings.
+fn refiner_channel_context<B: Backend>(pooled: Tensor<B, 2>, size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, aesthetic_score: f32) -> Tensor<B, 2> {
+    let device = pooled.device();
+    let [n_batch, _] = pooled.dims();
+
+    let size_crop = Tensor::cat(vec![size, crop], 1);
+    let size_crop_embedding = timestep_embedding(size_crop.reshape([n_batch * 4]), CONDITIONING_EMBEDDING_DIM, 10000)
+        .reshape([n_batch, 4 * CONDITIONING_EMBEDDING_DIM]);
+
+    Tensor::cat(vec![
+        pooled, 
+        size_crop_embedding, 
+        aesthetic_embedding(aesthetic_score, n_batch, &device), 
+    ], 1)
+}
+
+fn aesthetic_embedding<B: Backend>(score: f32, n_batch: usize, device: &B::Device) -> Tensor<B, 2> {
+    let score: Tensor<B, 1> = Tensor::from_floats([score]).to_device(device);
+    float_timestep_embedding(score, CONDITIONING_EMBEDDING_DIM, 10000).repeat(0, n_batch)
+}
+
+#[derive(Config, Debug)]
+pub struct RefinerConfig {
+    adm_in_channels: usize, 
+    model_channels: usize, 
+    num_head_channels: usize, 
+    context_dim: usize, 
+}
+
+impl RefinerConfig {
+    pub fn init<B: Backend>(&self) -> Refiner<B> {
+        let n_steps = 1000;
+        let alpha_cumulative_products = offset_cosine_schedule_cumprod::<B>(n_steps, &B::Device::default()).into();
+        //let diffusion = RefinerUNetConfig::new(2560, 4, 4, 384, 64, 1280).init();
+        let diffusion = RefinerUNetConfig::new(
+            self.adm_in_channels, 
+            4, 
+            4, 
+            self.model_channels, 
+            self.num_head_channels, 
+            self.context_dim
+        ).init();
+
+        Refiner {
+            n_steps, 
+            alpha_cumulative_products, 
+            diffusion, 
+        }
+    }
+}
+
+/// The SDXL refiner, a second diffusion model specialised on the low-noise end of the schedule. 
+/// It takes the base model's `Conditioning` as is: the context is cut down to its OpenCLIP part, and the 
+/// aspect-ratio embedding at the end of the channel context is replaced by an embedding of the aesthetic score 
+/// (`DEFAULT_AESTHETIC_SCORE` and `DEFAULT_NEGATIVE_AESTHETIC_SCORE` unless set on the conditioning). 
+/// Conditioning already in the refiner's layout, see `Embedder::text_to_refiner_conditioning`, is used unchanged.
+#[derive(Module, Debug)]
+pub struct Refiner<B: Backend> {
+    n_steps: usize, 
+    pub alpha_cumulative_products: Param<Tensor<B, 1>>, 
+    pub diffusion: RefinerUNet<B>, 
+}
+
+impl<B: Backend> Refiner<B> {
+    /// The noise schedule of this refiner, which `refine` steps through.
+    pub fn scheduler(&self) -> Scheduler {
+        Scheduler::from_tensor(self.alpha_cumulative_products.val())
+    }
+
+    /// Refines `latent` on its own, e.g. a finished base model latent or an encoded image: the latent is noised 
+    /// to the last `strength` fraction of an `n_steps` schedule and denoised by the refiner with DDIM, 
+    /// like `Diffuser::sample_latent_from_image`. A `strength` of 0.2 to 0.3 matches how SDXL uses the refiner.
+    pub fn refine(&self, conditioning: Conditioning<B>, latent: Tensor<B, 4>, strength: f32, guidance_scale: impl Into<GuidanceSchedule>, cfg_rescale: f32, n_steps: usize) -> Tensor<B, 4> {
+        let device = conditioning.context.device();
+        let latent = latent.to_device(&device);
+
+        let step_size = self.n_steps / n_steps;
+        let timesteps: Vec<usize> = (0..self.n_steps).rev().step_by(step_size).collect();
+        let n_skip = img2img_skipped_steps(timesteps.len(), strength);
+
+        let scheduler = self.scheduler();
+        let noise = random_normal(latent.shape(), &device);
+        let mut latent = if n_skip == 0 {
+            noise
+        } else {
+            scheduler.add_noise(latent, noise, timesteps[n_skip])
+        };
+
+        let mut sampler = DdimSampler::new(&scheduler, 0.0);
+        let guidance_scale = guidance_scale.into();
+        let timesteps = &timesteps[n_skip..];
+
+        for (i, &t) in timesteps.iter().enumerate() {
+            let prev_t = timesteps.get(i + 1).cloned();
+            let unconditional_guidance_scale = guidance_scale.scale(i, timesteps.len());
+            latent = self.denoise_step(latent, t, prev_t, conditioning.clone(), unconditional_guidance_scale, cfg_rescale, &mut sampler);
+        }
+
+        latent
+    }
+
+    fn denoise_step(&self, latent: Tensor<B, 4>, t: usize, prev_t: Option<usize>, conditioning: Conditioning<B>, unconditional_guidance_scale: f64, cfg_rescale: f32, sampler: &mut dyn Sampler<B>) -> Tensor<B, 4> {
+        let conditioning = self.refiner_conditioning(conditioning);
+
+        let (latent, _) = sampler_step(latent, t, prev_t, sampler, |latent, timestep| {
+            guided_prediction(
+                latent, 
+                timestep, 
+                conditioning.clone(), 
+                unconditional_guidance_scale, 
+                cfg_rescale, 
+                |latent, timestep, context, channel_context| self.diffusion.forward(latent, timestep, context, channel_context), 
+            )
+        });
+
+        latent
+    }
+
+    fn refiner_conditioning(&self, conditioning: Conditioning<B>) -> Conditioning<B> {
+        let device = conditioning.context.device();
+
+        let [n_batch, n_ctx, n_context_dim] = conditioning.context.dims();
+        let [_, n_channel_dim] = conditioning.channel_context.dims();
+        let context_dim = self.diffusion.context_dim();
+        let adm_in_channels = self.diffusion.adm_in_channels();
+
+        if n_context_dim == context_dim && n_channel_dim == adm_in_channels {
+            return conditioning;
+        }
+
+        let context_range = n_context_dim - context_dim..n_context_dim;
+
+        // pooled text embedding and the size and crop embeddings, without the aspect ratio
+        let n_kept = adm_in_channels - CONDITIONING_EMBEDDING_DIM;
+
+        let aesthetic_score = conditioning.aesthetic_score.unwrap_or(DEFAULT_AESTHETIC_SCORE);
+        let negative_aesthetic_score = conditioning.negative_aesthetic_score.unwrap_or(DEFAULT_NEGATIVE_AESTHETIC_SCORE);
+
+        let channel_context = Tensor::cat(vec![
+            conditioning.channel_context.slice([0..n_batch, 0..n_kept]), 
+            aesthetic_embedding(aesthetic_score, n_batch, &device), 
+        ], 1);
+
+        let unconditional_channel_context = Tensor::cat(vec![
+            conditioning.unconditional_channel_context.slice([0..n_kept]), 
+            aesthetic_embedding(negative_aesthetic_score, 1, &device).squeeze(0), 
+        ], 0);
+
+        Conditioning {
+            unconditional_context: conditioning.unconditional_context.slice([0..n_ctx, context_range.clone()]), 
+            context: conditioning.context.slice([0..n_batch, 0..n_ctx, context_range]), 
+            unconditional_channel_context, 
+            channel_context, 
+            resolution: conditioning.resolution, 
+            aesthetic_score: conditioning.aesthetic_score, 
+            negative_aesthetic_score: conditioning.negative_aesthetic_score, 
+        }
+    }
+}
+
+
+/// Returned by `Diffuser::sample_latent_cancellable` when sampling was cancelled. 
+/// `latent` is the partially denoised latent, or `None` if no step had run yet.
+#[derive(Clone, Debug)]
+pub struct Aborted<B: Backend> {
+    pub latent: Option<Tensor<B, 4>>, 
+    pub completed_steps: usize, 
+    pub n_steps: usize, 
+}
+
+impl<B: Backend> fmt::Display for Aborted<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sampling was cancelled after {} of {} steps.", self.completed_steps, self.n_steps)
+    }
+}
+
+impl<B: Backend> Error for Aborted<B> {}
+
+
+#[derive(Clone, Debug)]
+pub struct Conditioning<B: Backend> {
+    pub unconditional_context: Tensor<B, 2>, 
+    pub context: Tensor<B, 3>, 
+    pub unconditional_channel_context: Tensor<B, 1>, 
+    pub channel_context: Tensor<B, 2>, 
+    /// The (height, width) to generate. The embedder sets it to the target resolution rounded to multiples of 
+    /// `LATENT_SCALE` by `round_resolution`, so it can differ from the requested one.
+    pub resolution: [usize; 2], 
+    /// Only used by the refiner, see `Refiner`.
+    pub aesthetic_score: Option<f32>, 
+    pub negative_aesthetic_score: Option<f32>, 
+}
+
+impl<B: Backend> Conditioning<B> {
+    /// Moves the conditioning to another device of the same backend, e.g. from an embedder on one GPU to a 
+    /// diffuser on another. This copies a few MiB at most: the contexts are `77 * 2048` values per prompt for SDXL.
+    pub fn to_device(self, device: &B::Device) -> Conditioning<B> {
+        Conditioning {
+            unconditional_context: self.unconditional_context.to_device(device), 
+            context: self.context.to_device(device), 
+            unconditional_channel_context: self.unconditional_channel_context.to_device(device), 
+            channel_context: self.channel_context.to_device(device), 
+            ..self
+        }
+    }
+
+    /// Converts the conditioning to another backend, e.g. from the f32 embedder to an f16 diffuser.
+    pub fn to_backend<B2: Backend>(self, device: &B2::Device) -> Conditioning<B2> {
+        Conditioning {
+            unconditional_context: tensor_to_backend(self.unconditional_context, device), 
+            context: tensor_to_backend(self.context, device), 
+            unconditional_channel_context: tensor_to_backend(self.unconditional_channel_context, device), 
+            channel_context: tensor_to_backend(self.channel_context, device), 
+            resolution: self.resolution, 
+            aesthetic_score: self.aesthetic_score, 
+            negative_aesthetic_score: self.negative_aesthetic_score, 
+        }
+    }
+
+    /// Blends two prompts' conditionings: `t = 0.0` gives `self` and `t = 1.0` gives `other`. All four contexts are 
+    /// interpolated linearly, so both must come from prompts embedded with the same batch size and context length 
+    /// (chunked prompts need the same number of chunks) and at the same resolution. Combined with a fixed seed, 
+    /// stepping `t` gives a smooth walk between the prompts. Aesthetic scores are interpolated when both are set.
+    pub fn lerp(&self, other: &Conditioning<B>, t: f32) -> Result<Conditioning<B>, Box<dyn Error>> {
+        if self.context.dims() != other.context.dims() || self.unconditional_context.dims() != other.unconditional_context.dims() {
+            return Err( format!("Cannot interpolate contexts of shapes {:?} and {:?}.", self.context.dims(), other.context.dims()).into() );
+        }
+
+        if self.channel_context.dims() != other.channel_context.dims() || self.unconditional_channel_context.dims() != other.unconditional_channel_context.dims() {
+            return Err( format!("Cannot interpolate channel contexts of shapes {:?} and {:?}.", self.channel_context.dims(), other.channel_context.dims()).into() );
+        }
+
+        if self.resolution != other.resolution {
+            return Err( format!("Cannot interpolate conditionings for resolutions {:?} and {:?}.", self.resolution, other.resolution).into() );
+        }
+
+        let t = t as f64;
+        let lerp_score = |a: Option<f32>, b: Option<f32>| match (a, b) {
+            (Some(a), Some(b)) => Some(a + (b - a) * t as f32), 
+            (a, _) => a, 
+        };
+
+        Ok(Conditioning {
+            unconditional_context: lerp(self.unconditional_context.clone(), other.unconditional_context.clone(), t), 
+            context: lerp(self.context.clone(), other.context.clone(), t), 
+            unconditional_channel_context: lerp(self.unconditional_channel_context.clone(), other.unconditional_channel_context.clone(), t), 
+            channel_context: lerp(self.channel_context.clone(), other.channel_context.clone(), t), 
+            resolution: self.resolution, 
+            aesthetic_score: lerp_score(self.aesthetic_score, other.aesthetic_score), 
+            negative_aesthetic_score: lerp_score(self.negative_aesthetic_score, other.negative_aesthetic_score), 
+        })
+    }
+}
+
+/// A source of `Conditioning` for `Diffuser::sample_latent_with_provider`, e.g. the text `Embedder`, precomputed 
+/// embeddings or image embeddings for IP-Adapter style workflows. Providers that don't condition on text ignore 
+/// the prompts. The conditioning must be on the diffuser's device and shaped like the embedder's: 
+/// 
+/// - `context`: `[n_batch, n_tokens, context_dim]`, 2048 wide for SDXL, with any number of tokens 
+/// - `unconditional_context`: `[n_tokens, context_dim]` 
+/// - `channel_context`: `[n_batch, adm_in_channels]`, 2816 wide for SDXL: the 1280 pooled embedding followed by 
+///   the Fourier embeddings of size, crop and target resolution 
+/// - `unconditional_channel_context`: `[adm_in_channels]` 
+/// - `resolution`: the (height, width) to generate, multiples of `LATENT_SCALE` 
+/// 
+/// `Diffuser::check_conditioning` reports mismatched widths.
+pub trait ConditioningProvider<B: Backend> {
+    /// The conditioning for `prompt`, with `negative` as the unconditional prompt, at the (height, width) `resolution`.
+    fn provide(&self, prompt: &str, negative: &str, resolution: [i32; 2]) -> Result<Conditioning<B>, Box<dyn Error>>;
+}
+
+/// A precomputed conditioning provides itself, whatever the prompts and resolution.
+impl<B: Backend> ConditioningProvider<B> for Conditioning<B> {
+    fn provide(&self, _prompt: &str, _negative: &str, _resolution: [i32; 2]) -> Result<Conditioning<B>, Box<dyn Error>> {
+        Ok( self.clone() )
+    }
+}
+
+/// Different prompts for rectangular regions of the image (regional prompting), e.g. a castle on the left 
+/// and a dragon on the right, see `Diffuser::sample_latent_regional`. Only the regions' contexts are used: 
+/// the channel context, the unconditional (negative) contexts and the resolution come from `base`, whose prompt 
+/// fills the rest of the image. Where regions overlap, the one added last wins.
+#[derive(Clone, Debug)]
+pub struct RegionalConditioning<B: Backend> {
+    pub base: Conditioning<B>, 
+    /// `([top, left, bottom, right], conditioning)` with the rectangle in latent pixels (1/8 of the image resolution), 
+    /// bottom and right exclusive.
+    pub regions: Vec<([usize; 4], Conditioning<B>)>, 
+}
+
+impl<B: Backend> RegionalConditioning<B> {
+    pub fn new(base: Conditioning<B>) -> Self {
+        Self {
+            base, 
+            regions: Vec::new(), 
+        }
+    }
+
+    pub fn with_region(mut self, rect: [usize; 4], conditioning: Conditioning<B>) -> Self {
+        self.regions.push((rect, conditioning));
+        self
+    }
+
+    /// Checks that every region is a non-empty rectangle inside the latent and that its context 
+    /// has the batch size and width of the base context.
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        let [height, width] = self.base.resolution;
+        let [latent_height, latent_width] = [height / LATENT_SCALE, width / LATENT_SCALE];
+        let [n_batch, _, context_dim] = self.base.context.dims();
+
+        for ([top, left, bottom, right], conditioning) in &self.regions {
+            if top >= bottom || left >= right || *bottom > latent_height || *right > latent_width {
+                return Err( format!("The region {:?} is empty or not inside the {}x{} latent.", [top, left, bottom, right], latent_width, latent_height).into() );
+            }
+
+            let [n_region_batch, _, n_region_context_dim] = conditioning.context.dims();
+            if n_region_batch != n_batch || n_region_context_dim != context_dim {
+                return Err( format!("The region {:?} has a context of batch size {} and width {} but the base has {} and {}.", [top, left, bottom, right], n_region_batch, n_region_context_dim, n_batch, context_dim).into() );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn context(&self) -> RegionalContext<B> {
+        let [height, width] = self.base.resolution;
+        let device = self.base.context.device();
+
+        self.regions.iter().fold(
+            RegionalContext::new(self.base.context.clone(), [height / LATENT_SCALE, width / LATENT_SCALE]), 
+            |context, (rect, conditioning)| context.with_region(*rect, conditioning.context.clone().to_device(&device))
+        )
+    }
+}
+
+/// Conditionings that take over from each other partway through denoising (prompt editing), e.g. a cat for the 
+/// first half of the steps and a dog for the rest, see `Diffuser::sample_latent_scheduled`. Usually built from the 
+/// `[from:to:when]` syntax with `Embedder::text_to_scheduled_conditioning`.
+#[derive(Clone, Debug)]
+pub struct ScheduledConditioning<B: Backend> {
+    /// `(start, conditioning)` with `start` the fraction of the steps from which the conditioning is used, 
+    /// in increasing order and starting at 0.0.
+    pub stages: Vec<(f32, Conditioning<B>)>, 
+}
+
+impl<B: Backend> ScheduledConditioning<B> {
+    pub fn new(conditioning: Conditioning<B>) -> Self {
+        Self {
+            stages: vec![(0.0, conditioning)], 
+        }
+    }
+
+    /// Switches to `conditioning` once the fraction `start` of the steps is done.
+    pub fn with_stage(mut self, start: f32, conditioning: Conditioning<B>) -> Self {
+        self.stages.push((start, conditioning));
+        self
+    }
+
+    /// The conditioning used at `step`, counting from 0, of a run of `n_steps` steps: 
+    /// the last stage starting at or before `step / n_steps`.
+    pub fn at(&self, step: usize, n_steps: usize) -> &Conditioning<B> {
+        let progress = step as f32 / n_steps.max(1) as f32;
+
+        self.stages
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= progress)
+            .map(|(_, conditioning)| conditioning)
+            .unwrap_or(&self.stages[0].1)
+    }
+
+    /// Checks that the stages start at 0.0 in increasing order and share the batch size and resolution.
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        let (first_start, first) = self.stages.first().ok_or("A scheduled conditioning needs at least one stage.")?;
+        if *first_start != 0.0 {
+            return Err( format!("The first stage must start at 0.0 but starts at {}.", first_start).into() );
+        }
+
+        let [n_batch, _, _] = first.context.dims();
+        for window in self.stages.windows(2) {
+            let (start, conditioning) = &window[1];
+            if *start < window[0].0 {
+                return Err( format!("The stage starting at {} comes after one starting at {}.", start, window[0].0).into() );
+            }
+
+            let [n_stage_batch, _, _] = conditioning.context.dims();
+            if n_stage_batch != n_batch || conditioning.resolution != first.resolution {
+                return Err( format!("The stage starting at {} has batch size {} at {:?} but the first has {} at {:?}.", start, n_stage_batch, conditioning.resolution, n_batch, first.resolution).into() );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Steps `sampler` from `t` to `prev_t` with the noise predictions `predict(latent, timestep)`, e.g. the 
+/// `guided_prediction` of the base or the refiner UNet. Returns the next latent and the sampler's x0 estimate.
+fn sampler_step<B: Backend>(latent: Tensor<B, 4>, t: usize, prev_t: Option<usize>, sampler: &mut dyn Sampler<B>, predict: impl Fn(Tensor<B, 4>, Tensor<B, 1, Int>) -> Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+    let device = latent.device();
+    let mut model = |latent: Tensor<B, 4>, t: usize| {
+        let timestep = Tensor::from_ints([t as i32]).to_device(&device);
+        predict(latent, timestep)
+    };
+
+    sampler.step_with_model_and_x0(&mut model, t, prev_t, latent)
+}
+
+/// The classifier-free guided noise prediction of `unet(latent, timestep, context, channel_context)`, 
+/// the base or the refiner UNet, with `cfg_rescale` applied, see `rescale_guidance`.
+fn guided_prediction<B: Backend>(latent: Tensor<B, 4>, timestep: Tensor<B, 1, Int>, conditioning: Conditioning<B>, unconditional_guidance_scale: f64, cfg_rescale: f32, unet: impl Fn(Tensor<B, 4>, Tensor<B, 1, Int>, Tensor<B, 3>, Tensor<B, 2>) -> Tensor<B, 4>) -> Tensor<B, 4> {
+    // the unconditional prediction cancels out at a guidance scale of 1, so skip its UNet pass
+    if unconditional_guidance_scale == 1.0 {
+        return unet(latent, timestep, conditioning.context, conditioning.channel_context);
+    }
+
+    let (unconditional_latent, conditional_latent) = guidance_predictions(latent, timestep, conditioning, unet);
+
+    let guided = unconditional_latent.clone() + (conditional_latent.clone() - unconditional_latent) * unconditional_guidance_scale;
+    rescale_guidance(guided, conditional_latent, cfg_rescale)
+}
+
+/// The unconditional and conditional predictions of `unet` for classifier-free guidance. Both go through a single 
+/// UNet pass batched as `[unconditional; conditional]`, which uses the GPU better than two passes, unless the 
+/// contexts differ in length (e.g. a chunked prompt with an unchunked negative), which falls back to two passes.
+fn guidance_predictions<B: Backend>(latent: Tensor<B, 4>, timestep: Tensor<B, 1, Int>, conditioning: Conditioning<B>, unet: impl Fn(Tensor<B, 4>, Tensor<B, 1, Int>, Tensor<B, 3>, Tensor<B, 2>) -> Tensor<B, 4>) -> (Tensor<B, 4>, Tensor<B, 4>) {
+    let [n_batch, _, _, _] = latent.dims();
+
+    let unconditional_context = conditioning.unconditional_context.unsqueeze::<3>().repeat(0, n_batch);
+    let unconditional_channel_context = conditioning.unconditional_channel_context.unsqueeze::<2>().repeat(0, n_batch);
+
+    if unconditional_context.dims() != conditioning.context.dims() {
+        let unconditional_latent = unet(latent.clone(), timestep.clone(), unconditional_context, unconditional_channel_context);
+        let conditional_latent = unet(latent, timestep, conditioning.context, conditioning.channel_context);
+        return (unconditional_latent, conditional_latent);
+    }
+
+    let latents = unet(
+        Tensor::cat(vec![latent.clone(), latent], 0), 
+        timestep, 
+        Tensor::cat(vec![unconditional_context, conditioning.context], 0), 
+        Tensor::cat(vec![unconditional_channel_context, conditioning.channel_context], 0), 
+    );
+
+    let [_, n_channel, height, width] = latents.dims();
+    let unconditional_latent = latents.clone().slice([0..n_batch, 0..n_channel, 0..height, 0..width]);
+    let conditional_latent = latents.slice([n_batch..2 * n_batch, 0..n_channel, 0..height, 0..width]);
+
+    (unconditional_latent, conditional_latent)
+}
+
+fn lerp<B: Backend, const D: usize>(a: Tensor<B, D>, b: Tensor<B, D>, t: f64) -> Tensor<B, D> {
+    a.clone() + (b - a) * t
+}
+
+/// Spherical interpolation between `a` and `b` per batch entry, falling back to `lerp` for nearly parallel entries.
+fn slerp<B: Backend>(a: Tensor<B, 4>, b: Tensor<B, 4>, t: f64) -> Tensor<B, 4> {
+    let [n_batch, n_channel, height, width] = a.dims();
+    let n_per_sample = n_channel * height * width;
+
+    let a_values = tensor_to_vec(a.clone());
+    let b_values = tensor_to_vec(b.clone());
+
+    let (a_weights, b_weights): (Vec<f32>, Vec<f32>) = a_values
+        .chunks(n_per_sample)
+        .zip(b_values.chunks(n_per_sample))
+        .map(|(a, b)| {
+            let norm = |x: &[f64]| x.iter().map(|v| v * v).sum::<f64>().sqrt();
+            let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f64>() / (norm(a) * norm(b));
+
+            if dot.abs() > 0.9995 {
+                return ((1.0 - t) as f32, t as f32);
+            }
+
+            let omega = dot.acos();
+            let a_weight = ((1.0 - t) * omega).sin() / omega.sin();
+            let b_weight = (t * omega).sin() / omega.sin();
+            (a_weight as f32, b_weight as f32)
+        })
+        .unzip();
+
+    let device = a.device();
+    let weights = |weights: Vec<f32>| -> Tensor<B, 4> {
+        Tensor::from_data_device(Data::new(weights, Shape::new([n_batch, 1, 1, 1])).convert(), &device)
+    };
+
+    a * weights(a_weights) + b * weights(b_weights)
+}
+
+/// CFG rescale (Lin et al., "Common Diffusion Noise Schedules and Sample Steps are Flawed"). 
+/// Rescales the guided prediction to the per-sample standard deviation of the conditional prediction 
+/// and blends by `cfg_rescale`: 0.0 leaves the guided prediction untouched, 1.0 fully rescales it.
+fn rescale_guidance<B: Backend>(guided: Tensor<B, 4>, conditional: Tensor<B, 4>, cfg_rescale: f32) -> Tensor<B, 4> {
+    if cfg_rescale == 0.0 {
+        return guided;
+    }
+
+    let std_conditional = per_sample_std(conditional);
+    let std_guided = per_sample_std(guided.clone());
+
+    let rescaled = guided.clone() * (std_conditional / std_guided);
+    rescaled * (cfg_rescale as f64) + guided * (1.0 - cfg_rescale as f64)
+}
+
+/// Standard deviation over the channel and spatial dimensions, shaped `[n_batch, 1, 1, 1]` for broadcasting.
+fn per_sample_std<B: Backend>(x: Tensor<B, 4>) -> Tensor<B, 4> {
+    let [n_batch, n_channel, height, width] = x.dims();
+
+    let x = x.reshape([n_batch, n_channel * height * width]);
+    let centered = x.clone() - x.mean_dim(1);
+    let var = (centered.clone() * centered).mean_dim(1);
+
+    var.sqrt().reshape([n_batch, 1, 1, 1])
+}
+
+/// Adds `noise_offset * randn([n_batch, n_channel, 1, 1])` to `noise`, shifting each channel by a constant. 
+/// Draws nothing from the RNG when the offset is 0.0.
+fn offset_noise<B: Backend>(noise: Tensor<B, 4>, noise_offset: f32) -> Tensor<B, 4> {
+    if noise_offset == 0.0 {
+        return noise;
+    }
+
+    let [n_batch, n_channel, _, _] = noise.dims();
+    let offset = random_normal([n_batch, n_channel, 1, 1], &noise.device());
+
+    noise + offset * (noise_offset as f64)
+}
+
+/// The resolutions Stable Diffusion XL was trained on, named by width x height, 
+/// e.g. `SdxlResolution::Portrait832x1216` is 832 pixels wide and 1216 high.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SdxlResolution {
+    Landscape2048x512, 
+    Landscape1984x512, 
+    Landscape1920x512, 
+    Landscape1856x512, 
+    Landscape1792x576, 
+    Landscape1728x576, 
+    Landscape1664x576, 
+    Landscape1600x640, 
+    Landscape1536x640, 
+    Landscape1472x704, 
+    Landscape1408x704, 
+    Landscape1344x704, 
+    Landscape1344x768, 
+    Landscape1280x768, 
+    Landscape1216x832, 
+    Landscape1152x832, 
+    Landscape1152x896, 
+    Landscape1088x896, 
+    Landscape1088x960, 
+    Landscape1024x960, 
+    Square1024, 
+    Portrait960x1024, 
+    Portrait960x1088, 
+    Portrait896x1088, 
+    Portrait896x1152, 
+    Portrait832x1152, 
+    Portrait832x1216, 
+    Portrait768x1280, 
+    Portrait768x1344, 
+    Portrait704x1408, 
+    Portrait704x1472, 
+    Portrait640x1536, 
+    Portrait640x1600, 
+    Portrait576x1664, 
+    Portrait576x1728, 
+    Portrait576x1792, 
+    Portrait512x1856, 
+    Portrait512x1920, 
+    Portrait512x1984, 
+    Portrait512x2048, 
+}
+
+impl SdxlResolution {
+    /// All training resolutions, from the widest to the tallest, in the order of `RESOLUTIONS`.
+    pub const ALL: [SdxlResolution; 40] = [
+        SdxlResolution::Landscape2048x512,
+        SdxlResolution::Landscape1984x512,
+        SdxlResolution::Landscape1920x512,
+        SdxlResolution::Landscape1856x512,
+        SdxlResolution::Landscape1792x576,
+        SdxlResolution::Landscape1728x576,
+        SdxlResolution::Landscape1664x576,
+        SdxlResolution::Landscape1600x640,
+        SdxlResolution::Landscape1536x640,
+        SdxlResolution::Landscape1472x704,
+        SdxlResolution::Landscape1408x704,
+        SdxlResolution::Landscape1344x704,
+        SdxlResolution::Landscape1344x768,
+        SdxlResolution::Landscape1280x768,
+        SdxlResolution::Landscape1216x832,
+        SdxlResolution::Landscape1152x832,
+        SdxlResolution::Landscape1152x896,
+        SdxlResolution::Landscape1088x896,
+        SdxlResolution::Landscape1088x960,
+        SdxlResolution::Landscape1024x960,
+        SdxlResolution::Square1024,
+        SdxlResolution::Portrait960x1024,
+        SdxlResolution::Portrait960x1088,
+        SdxlResolution::Portrait896x1088,
+        SdxlResolution::Portrait896x1152,
+        SdxlResolution::Portrait832x1152,
+        SdxlResolution::Portrait832x1216,
+        SdxlResolution::Portrait768x1280,
+        SdxlResolution::Portrait768x1344,
+        SdxlResolution::Portrait704x1408,
+        SdxlResolution::Portrait704x1472,
+        SdxlResolution::Portrait640x1536,
+        SdxlResolution::Portrait640x1600,
+        SdxlResolution::Portrait576x1664,
+        SdxlResolution::Portrait576x1728,
+        SdxlResolution::Portrait576x1792,
+        SdxlResolution::Portrait512x1856,
+        SdxlResolution::Portrait512x1920,
+        SdxlResolution::Portrait512x1984,
+        SdxlResolution::Portrait512x2048, 
+    ];
+
+    /// The (height, width) of the resolution, as in `RESOLUTIONS`.
+    pub const fn dims(&self) -> [i32; 2] {
+        match self {
+            SdxlResolution::Landscape2048x512 => [512, 2048], 
+            SdxlResolution::Landscape1984x512 => [512, 1984], 
+            SdxlResolution::Landscape1920x512 => [512, 1920], 
+            SdxlResolution::Landscape1856x512 => [512, 1856], 
+            SdxlResolution::Landscape1792x576 => [576, 1792], 
+            SdxlResolution::Landscape1728x576 => [576, 1728], 
+            SdxlResolution::Landscape1664x576 => [576, 1664], 
+            SdxlResolution::Landscape1600x640 => [640, 1600], 
+            SdxlResolution::Landscape1536x640 => [640, 1536], 
+            SdxlResolution::Landscape1472x704 => [704, 1472], 
+            SdxlResolution::Landscape1408x704 => [704, 1408], 
+            SdxlResolution::Landscape1344x704 => [704, 1344], 
+            SdxlResolution::Landscape1344x768 => [768, 1344], 
+            SdxlResolution::Landscape1280x768 => [768, 1280], 
+            SdxlResolution::Landscape1216x832 => [832, 1216], 
+            SdxlResolution::Landscape1152x832 => [832, 1152], 
+            SdxlResolution::Landscape1152x896 => [896, 1152], 
+            SdxlResolution::Landscape1088x896 => [896, 1088], 
+            SdxlResolution::Landscape1088x960 => [960, 1088], 
+            SdxlResolution::Landscape1024x960 => [960, 1024], 
+            SdxlResolution::Square1024 => [1024, 1024], 
+            SdxlResolution::Portrait960x1024 => [1024, 960], 
+            SdxlResolution::Portrait960x1088 => [1088, 960], 
+            SdxlResolution::Portrait896x1088 => [1088, 896], 
+            SdxlResolution::Portrait896x1152 => [1152, 896], 
+            SdxlResolution::Portrait832x1152 => [1152, 832], 
+            SdxlResolution::Portrait832x1216 => [1216, 832], 
+            SdxlResolution::Portrait768x1280 => [1280, 768], 
+            SdxlResolution::Portrait768x1344 => [1344, 768], 
+            SdxlResolution::Portrait704x1408 => [1408, 704], 
+            SdxlResolution::Portrait704x1472 => [1472, 704], 
+            SdxlResolution::Portrait640x1536 => [1536, 640], 
+            SdxlResolution::Portrait640x1600 => [1600, 640], 
+            SdxlResolution::Portrait576x1664 => [1664, 576], 
+            SdxlResolution::Portrait576x1728 => [1728, 576], 
+            SdxlResolution::Portrait576x1792 => [1792, 576], 
+            SdxlResolution::Portrait512x1856 => [1856, 512], 
+            SdxlResolution::Portrait512x1920 => [1920, 512], 
+            SdxlResolution::Portrait512x1984 => [1984, 512], 
+            SdxlResolution::Portrait512x2048 => [2048, 512], 
+        }
+    }
+
+    pub const fn height(&self) -> i32 {
+        self.dims()[0]
+    }
+
+    pub const fn width(&self) -> i32 {
+        self.dims()[1]
+    }
+}
+
+/// These are the resolutions (height, width) Stable Diffusion XL was trained on, see `SdxlResolution`.
+pub const RESOLUTIONS: [[i32; 2]; 40] = {
+    let mut resolutions = [[0; 2]; 40];
+
+    let mut i = 0;
+    while i < resolutions.len() {
+        resolutions[i] = SdxlResolution::ALL[i].dims();
+        i += 1;
+    }
+
+    resolutions
+};
+
+/// Picks the training resolution (height, width) closest to the requested size, 
+/// first by aspect ratio and then by area. Zero sizes count as 1 pixel.
+pub fn nearest_resolution(width: u32, height: u32) -> [i32; 2] {
+    let (width, height) = (width.max(1), height.max(1));
+    let aspect_ratio = (height as f64 / width as f64).ln();
+    let area = (height as f64 * width as f64).ln();
+
+    let distance = |[h, w]: [i32; 2]| {
+        (
+            ( (h as f64 / w as f64).ln() - aspect_ratio ).abs(), 
+            ( (h as f64 * w as f64).ln() - area ).abs(), 
+        )
+    };
+
+    RESOLUTIONS
+        .iter()
+        .cloned()
+        .min_by(|&a, &b| {
+            let (a, b) = (distance(a), distance(b));
+            a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1))
+        })
+        .unwrap()
+}
+
+
+/// Latents are 8 times smaller than images on each side.
+pub const LATENT_SCALE: usize = 8;
+
+/// Rounds a (height, width) resolution to the nearest multiple of `LATENT_SCALE` on each side, 
+/// at least one latent pixel, so that decoding the sampled latent gives exactly that size. 
+/// Halfway sizes round down, e.g. 513x769 becomes 512x768 and 1004x1004 becomes 1000x1000. 
+/// The embedder applies this to the target resolution of every `Conditioning`.
+pub fn round_resolution([height, width]: [usize; 2]) -> [usize; 2] {
+    let round = |x: usize| ( (x + (LATENT_SCALE - 1) / 2) / LATENT_SCALE ).max(1) * LATENT_SCALE;
+    [round(height), round(width)]
+}
+
+/// The crop (top, left) to condition on for a (height, width) `source` image that was scaled to cover the (height, width) 
+/// `target` and center cropped to it, the way SDXL's training images were. Offsets are in pixels of the scaled image, 
+/// e.g. a 1080x1920 (height x width) photo cropped to 1024x1024 gives `[0, 398]`. 
+/// 
+/// The crop conditioning tells the model the image was cut out of a larger one at that offset. At `[0, 0]` it frames 
+/// subjects fully, while larger offsets make it generate subjects cut off at the top or left edge, like the cropped 
+/// training images. Text to image generation should therefore keep `[0, 0]`, as `text_to_conditioning_for_size` does. 
+/// Use this to match an actual cropped source instead, e.g. for image to image, together with `source` as the size.
+pub fn crop_coords_for(source: [u32; 2], target: [u32; 2]) -> [i32; 2] {
+    let [source_height, source_width] = source.map(|x| x.max(1) as f64);
+    let [target_height, target_width] = target.map(|x| x as f64);
+    let scale = (target_height / source_height).max(target_width / source_width);
+
+    let offset = |source: f64, target: f64| ( ((source * scale).round() - target).max(0.0) / 2.0 ).floor() as i32;
+    [offset(source_height, target_height), offset(source_width, target_width)]
+}
+
+
+#[derive(Config, Debug)]
+pub struct EmbedderConfig {
+    clip_config: CLIPConfig, 
+    open_clip_config: CLIPConfig, 
+}
+
+impl EmbedderConfig {
+    pub fn init<B: Backend>(&self) -> Embedder<B> {
+        /*let clip = CLIPConfig::new(49408, 768, 768, 12, 77, 12, true).init();
+        let open_clip = CLIPConfig::new(49408, 1024, 1024, 16, 77, 24, false).init();*/
+
+        let clip = self.clip_config.init();
+        let open_clip = self.open_clip_config.init();
+
+        let clip_tokenizer = SimpleTokenizer::new().unwrap();
+        let open_clip_tokenizer = OpenClipTokenizer::new().unwrap();
+
+        Embedder {
+            clip, 
+            open_clip, 
+            clip_tokenizer, 
+            open_clip_tokenizer, 
+            cache: None, 
+        }
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct Embedder<B: Backend> {
+    clip: CLIP<B>, 
+    open_clip: CLIP<B>, 
+    clip_tokenizer: SimpleTokenizer, 
+    open_clip_tokenizer: OpenClipTokenizer, 
+    cache: Option<EmbeddingCache>, 
+}
+
+impl<B: Backend> Embedder<B> {
+    /// Caches the text encoder outputs of the last `capacity` prompt batches, see `EmbeddingCache`. 
+    /// Conditioning on a prompt that is in the cache, typically the same negative prompt on every generation 
+    /// of an interactive session, then costs a copy to the device instead of a forward pass through both text encoders. 
+    /// Adding a textual inversion clears the cache, since it changes how prompts are tokenized.
+    pub fn with_cache(self, capacity: usize) -> Self {
+        Self {
+            cache: Some( EmbeddingCache::new(capacity) ), 
+            ..self
+        }
+    }
+
+    pub fn cache(&self) -> Option<&EmbeddingCache> {
+        self.cache.as_ref()
+    }
+
+    /// Embeds an empty prompt, see `Diffuser::warmup`.
+    pub fn warmup(&self, device: &B::Device) -> Result<(), Box<dyn Error>> {
+        let size = Tensor::from_ints([1024, 1024]).to_device(device).unsqueeze();
+        let crop = Tensor::from_ints([0, 0]).to_device(device).unsqueeze();
+        let ar = Tensor::from_ints([1024, 1024]).to_device(device);
+
+        let _ = self.text_to_conditioning("", size, crop, ar, 1)?.context.into_data();
+        Ok(())
+    }
+
+    /// Registers a textual inversion embedding with both text encoders. Prompts containing `trigger` are 
+    /// then encoded with the learned vectors in its place; embeddings trained with several vectors per 
+    /// encoder take up several tokens. SDXL embeddings store the vectors as `clip_l` and `clip_g`, 
+    /// see `load::load_textual_inversion`. Fails for an empty trigger or an encoder without vectors.
+    pub fn add_textual_inversion(&mut self, trigger: &str, clip_vectors: Vec<Vec<f32>>, open_clip_vectors: Vec<Vec<f32>>) -> Result<(), Box<dyn Error>> {
+        if clip_vectors.is_empty() || open_clip_vectors.is_empty() {
+            return Err( format!("The textual inversion embedding for {} needs vectors for both text encoders.", trigger).into() );
+        }
+
+        self.clip_tokenizer.add_multi_vector_embedding(trigger, clip_vectors)?;
+        self.open_clip_tokenizer.add_multi_vector_embedding(trigger, open_clip_vectors)?;
+
+        if let Some(cache) = self.cache.as_ref() {
+            cache.clear();
+        }
+
+        Ok(())
+    }
+
+    /// `clip_skip` selects which hidden layer the text encoders' context is taken from, counting back 
+    /// from the last layer. SDXL is trained with a `clip_skip` of 1, i.e. the penultimate layer. 
+    /// Fails with `TooManyTokens` if the prompt doesn't fit in the context window, see `text_to_conditioning_chunked`. 
+    /// An empty `text` is valid: it encodes to the start and end of text tokens followed by padding, exactly like 
+    /// the unconditional context, so guidance has no effect and sampling follows the size conditioning alone.
+    pub fn text_to_conditioning(&self, text: &str, size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, ar: Tensor<B, 1, Int>, clip_skip: usize) -> Result<Conditioning<B>, Box<dyn Error>> {
+        self.text_to_conditioning_with_negative(text, "", size, crop, ar, clip_skip)
+    }
+
+    /// Like `text_to_conditioning`, but the unconditional context used for classifier-free 
+    /// guidance is built from the `negative` prompt instead of the empty string.
+    pub fn text_to_conditioning_with_negative(&self, text: &str, negative: &str, size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, ar: Tensor<B, 1, Int>, clip_skip: usize) -> Result<Conditioning<B>, Box<dyn Error>> {
+        let [n_batch, _] = size.dims();
+        let texts = vec![text; n_batch];
+        let batched_ar = ar.unsqueeze().repeat(0, n_batch);
+
+        self.texts_to_conditioning_with_negative(&texts, negative, size, crop, batched_ar, clip_skip)
+    }
+
+    /// Like `text_to_conditioning_with_negative`, but with several negative prompts whose embeddings are averaged 
+    /// into the unconditional context, see `texts_to_unconditional`.
+    pub fn text_to_conditioning_with_negatives(&self, text: &str, negatives: &[&str], size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, ar: Tensor<B, 1, Int>, clip_skip: usize) -> Result<Conditioning<B>, Box<dyn Error>> {
+        let [n_batch, _] = size.dims();
+        let texts = vec![text; n_batch];
+        let batched_ar = ar.unsqueeze().repeat(0, n_batch);
+
+        self.conditioning(&texts, negatives, size, crop, batched_ar, clip_skip, false)
+    }
+
+    /// Embeds every negative prompt and averages the results into one `[1, n_ctx, D]` unconditional context. 
+    /// The averaging happens after the text encoders, on their hidden states, not on the token embeddings, so 
+    /// e.g. "blurry" and "watermark" each pull the guidance away from what they encode on their own, which 
+    /// differs from the single prompt "blurry, watermark". Fails if `negatives` is empty.
+    pub fn texts_to_unconditional(&self, negatives: &[&str], clip_skip: usize) -> Result<Tensor<B, 3>, Box<dyn Error>> {
+        if negatives.is_empty() {
+            return Err( "At least one negative prompt is required.".into() );
+        }
+
+        let (context, _) = self.text_embeddings(negatives, clip_skip, false)?;
+        Ok( context.mean_dim(0) )
+    }
+
+    /// Embeds every prompt of a `[from:to:when]` prompt edit, see `parse_prompt_schedule`, into a schedule for 
+    /// `Diffuser::sample_latent_scheduled`. The negative prompt is the same for all stages.
+    pub fn text_to_scheduled_conditioning(&self, text: &str, negative: &str, size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, ar: Tensor<B, 1, Int>, clip_skip: usize) -> Result<ScheduledConditioning<B>, Box<dyn Error>> {
+        let stages = parse_prompt_schedule(text)?
+            .into_iter()
+            .map(|(start, prompt)| {
+                let conditioning = self.text_to_conditioning_with_negative(&prompt, negative, size.clone(), crop.clone(), ar.clone(), clip_skip)?;
+                Ok((start, conditioning))
+            })
+            .collect::<Result<_, Box<dyn Error>>>()?;
+
+        Ok( ScheduledConditioning { stages } )
+    }
+
+    /// Embeds a batch of prompts at once. `sizes`, `crops` and `ars` hold one `[2]` row per prompt. 
+    /// All prompts share a single output resolution, which is taken from the first row of `ars`.
+    pub fn texts_to_conditioning(&self, texts: &[&str], sizes: Tensor<B, 2, Int>, crops: Tensor<B, 2, Int>, ars: Tensor<B, 2, Int>, clip_skip: usize) -> Result<Conditioning<B>, Box<dyn Error>> {
+        self.texts_to_conditioning_with_negative(texts, "", sizes, crops, ars, clip_skip)
+    }
+
+    pub fn texts_to_conditioning_with_negative(&self, texts: &[&str], negative: &str, sizes: Tensor<B, 2, Int>, crops: Tensor<B, 2, Int>, ars: Tensor<B, 2, Int>, clip_skip: usize) -> Result<Conditioning<B>, Box<dyn Error>> {
+        self.conditioning(texts, &[negative], sizes, crops, ars, clip_skip, false)
+    }
+
+    /// Long-prompt mode of `text_to_conditioning_with_negative`. Prompts are split into chunks of 75 tokens, 
+    /// each chunk is encoded separately with its own start and end tokens, and the hidden states are 
+    /// concatenated so the context is `[n_batch, 77 * n_chunks, D]`. The pooled embedding is taken from the first chunk.
+    pub fn text_to_conditioning_chunked(&self, text: &str, negative: &str, size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, ar: Tensor<B, 1, Int>, clip_skip: usize) -> Result<Conditioning<B>, Box<dyn Error>> {
+        let [n_batch, _] = size.dims();
+        let texts = vec![text; n_batch];
+        let batched_ar = ar.unsqueeze().repeat(0, n_batch);
+
+        self.texts_to_conditioning_chunked(&texts, negative, size, crop, batched_ar, clip_skip)
+    }
+
+    pub fn texts_to_conditioning_chunked(&self, texts: &[&str], negative: &str, sizes: Tensor<B, 2, Int>, crops: Tensor<B, 2, Int>, ars: Tensor<B, 2, Int>, clip_skip: usize) -> Result<Conditioning<B>, Box<dyn Error>> {
+        self.conditioning(texts, &[negative], sizes, crops, ars, clip_skip, true)
+    }
+
+    /// Conditions on the training resolution nearest to `width` x `height` (see `nearest_resolution`), 
+    /// which is used for both the size and the target resolution with no crop. 
+    /// Returns the chosen (height, width) alongside the conditioning; the generated image will have that size.
+    pub fn text_to_conditioning_for_size(&self, text: &str, negative: &str, width: u32, height: u32, clip_skip: usize) -> Result<(Conditioning<B>, [i32; 2]), Box<dyn Error>> {
+        let resolution = nearest_resolution(width, height);
+
+        let conditioning = self.text_to_conditioning_at(text, negative, resolution, clip_skip)?;
+        Ok( (conditioning, resolution) )
+    }
+
+    /// Conditions on a training resolution, used for both the size and the target resolution with no crop, 
+    /// e.g. `SdxlResolution::Square1024`. The generated image will have that size.
+    pub fn text_to_conditioning_for_resolution(&self, text: &str, negative: &str, resolution: SdxlResolution, clip_skip: usize) -> Result<Conditioning<B>, Box<dyn Error>> {
+        self.text_to_conditioning_at(text, negative, resolution.dims(), clip_skip)
+    }
+
+    fn text_to_conditioning_at(&self, text: &str, negative: &str, resolution: [i32; 2], clip_skip: usize) -> Result<Conditioning<B>, Box<dyn Error>> {
+        let device = &self.devices()[0];
+
+        let size = Tensor::from_ints(resolution).to_device(device).unsqueeze();
+        let crop = Tensor::from_ints([0, 0]).to_device(device).unsqueeze();
+        let ar = Tensor::from_ints(resolution).to_device(device);
+
+        self.text_to_conditioning_with_negative(text, negative, size, crop, ar, clip_skip)
+    }
+
+    /// The pooled text embedding of `text`, a `[1, 1280]` tensor for SDXL. It comes from the OpenCLIP encoder alone: 
+    /// its final layer's features at the end of text token, passed through the text projection. This is the 
+    /// embedding that, together with the size, crop and target resolution embeddings, makes up `channel_context`, 
+    /// so interpolating the pooled embeddings of two prompts mixes their overall style. 
+    /// It doesn't depend on `clip_skip` or on attention weighting syntax.
+    pub fn text_to_pooled(&self, text: &str) -> Result<Tensor<B, 2>, Box<dyn Error>> {
+        let (_, pooled) = texts_to_context_open_clip(&[text], &self.open_clip, &self.open_clip_tokenizer, 1)?;
+        Ok(pooled)
+    }
+
+    /// Conditioning in the layout the refiner is trained on, for `Refiner::refine`. The context is the OpenCLIP 
+    /// penultimate layer alone, and the channel context is the pooled embedding followed by the embeddings of 
+    /// `size`, `crop` and `aesthetic_score`, with no target resolution. The unconditional side embeds the empty 
+    /// prompt with `negative_aesthetic_score`; lowering it (e.g. to 2.5) steers away from poorly rated images. 
+    /// The base model can't use this conditioning, but `Refiner` also accepts the base model's.
+    pub fn text_to_refiner_conditioning(&self, text: &str, size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, aesthetic_score: f32, negative_aesthetic_score: f32) -> Result<Conditioning<B>, Box<dyn Error>> {
+        let [n_batch, _] = size.dims();
+        let texts = vec![text; n_batch];
+
+        let size_data = size.clone().slice([0..1]).into_data();
+        let resolution = round_resolution([size_data.value[0].to_usize().unwrap(), size_data.value[1].to_usize().unwrap()]);
+
+        let (unconditional_context, unconditional_pooled) = texts_to_context_open_clip(&[""], &self.open_clip, &self.open_clip_tokenizer, 1)?;
+        let unconditional_channel_context = refiner_channel_context(
+            unconditional_pooled, 
+            size.clone().slice([0..1]), 
+            crop.clone().slice([0..1]), 
+            negative_aesthetic_score, 
+        );
+
+        let (context, pooled) = texts_to_context_open_clip(&texts, &self.open_clip, &self.open_clip_tokenizer, 1)?;
+        let channel_context = refiner_channel_context(pooled, size, crop, aesthetic_score);
+
+        Ok(Conditioning {
+            unconditional_context: unconditional_context.squeeze(0), 
+            context, 
+            unconditional_channel_context: unconditional_channel_context.squeeze(0), 
+            channel_context, 
+            resolution, 
+            aesthetic_score: Some(aesthetic_score), 
+            negative_aesthetic_score: Some(negative_aesthetic_score), 
+        })
+    }
+
+    fn conditioning(&self, texts: &[&str], negatives: &[&str], sizes: Tensor<B, 2, Int>, crops: Tensor<B, 2, Int>, ars: Tensor<B, 2, Int>, clip_skip: usize, chunked: bool) -> Result<Conditioning<B>, Box<dyn Error>> {
+        let [n_batch, _] = sizes.dims();
+        if texts.len() != n_batch {
+            return Err( format!("Expected {} prompts but got {}.", n_batch, texts.len()).into() );
+        }
+
+        let ar_data = ars.clone().slice([0..1]).into_data();
+        let requested = [ar_data.value[0].to_usize().unwrap(), ar_data.value[1].to_usize().unwrap()];
+        let resolution = round_resolution(requested);
+
+        let (unconditional_context, unconditional_channel_context) = self.unconditional_context(
+            negatives, 
+            sizes.clone().slice([0..1]), 
+            crops.clone().slice([0..1]), 
+            ars.clone().slice([0..1]), 
+            clip_skip, 
+            chunked, 
+        )?;
+        let (context, channel_context) = self.context(texts, sizes, crops, ars, clip_skip, chunked)?;
+
+        Ok(Conditioning {
+            unconditional_context, 
+            context, 
+            unconditional_channel_context, 
+            channel_context, 
+            resolution, 
+            aesthetic_score: None, 
+            negative_aesthetic_score: None, 
+        })
+    }
+
+    /// Several negatives are averaged after embedding, see `texts_to_unconditional`.
+    fn unconditional_context(&self, negatives: &[&str], size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, ar: Tensor<B, 2, Int>, clip_skip: usize, chunked: bool) -> Result<(Tensor<B, 2>, Tensor<B, 1>), Box<dyn Error>> {
+        let n_negatives = negatives.len();
+        if n_negatives == 0 {
+            return Err( "At least one negative prompt is required.".into() );
+        }
+
+        let (context, channel_context) = self.context(negatives, size.repeat(0, n_negatives), crop.repeat(0, n_negatives), ar.repeat(0, n_negatives), clip_skip, chunked)?;
+
+        Ok((
+            context.mean_dim(0).squeeze(0), 
+            channel_context.mean_dim(0).squeeze(0), 
+        ))
+    }
+
+    fn context(&self, texts: &[&str], size: Tensor<B, 2, Int>, crop: Tensor<B, 2, Int>, ar: Tensor<B, 2, Int>, clip_skip: usize, chunked: bool) -> Result<(Tensor<B, 3>, Tensor<B, 2>), Box<dyn Error>> {
+        let device = size.device();
+
+        let cached = self.cache
+            .as_ref()
+            .and_then(|cache| cache.get(texts, clip_skip, chunked, &device));
+        let (context, pooled_text_embed) = match cached {
+            Some(embedding) => embedding, 
+            None => {
+                let (context, pooled_text_embed) = self.text_embeddings(texts, clip_skip, chunked)?;
+                if let Some(cache) = self.cache.as_ref() {
+                    cache.insert(texts, clip_skip, chunked, &context, &pooled_text_embed);
+                }
+
+                (context, pooled_text_embed)
+            }
+        };
+
+        Ok((
+            context, 
+            conditioning_embedding(pooled_text_embed, 256, size, crop, ar), 
+        ))
+    }
+
+    fn text_embeddings(&self, texts: &[&str], clip_skip: usize, chunked: bool) -> Result<(Tensor<B, 3>, Tensor<B, 2>), Box<dyn Error>> {
+        let (clip_context, (open_clip_context, pooled_text_embed)) = if chunked {
+            (
+                texts_to_context_clip_chunked(texts, &self.clip, &self.clip_tokenizer, clip_skip)?, 
+                texts_to_context_open_clip_chunked(texts, &self.open_clip, &self.open_clip_tokenizer, clip_skip)?, 
+            )
+        } else {
+            (
+                texts_to_context_clip(texts, &self.clip, &self.clip_tokenizer, clip_skip)?, 
+                texts_to_context_open_clip(texts, &self.open_clip, &self.open_clip_tokenizer, clip_skip)?, 
+            )
+        };
+
+        Ok((
+            Tensor::cat(vec![clip_context, open_clip_context], 2), 
+            pooled_text_embed, 
+        ))
+    }
+}
+
+impl<B: Backend> ConditioningProvider<B> for Embedder<B> {
+    /// Encodes `prompt` and `negative` with both text encoders at the penultimate layer, see 
+    /// `text_to_conditioning_for_resolution`.
+    fn provide(&self, prompt: &str, negative: &str, resolution: [i32; 2]) -> Result<Conditioning<B>, Box<dyn Error>> {
+        self.text_to_conditioning_at(prompt, negative, resolution, 1)
+    }
+}
+
+
+pub fn text_to_context_clip<B: Backend, T: Tokenizer>(text: &str, clip: &CLIP<B>, tokenizer: &T, clip_skip: usize) -> Result<Tensor<B, 3>, Box<dyn Error>> {
+    texts_to_context_clip(&[text], clip, tokenizer, clip_skip)
+}
+
+pub fn texts_to_context_clip<B: Backend, T: Tokenizer>(texts: &[&str], clip: &CLIP<B>, tokenizer: &T, clip_skip: usize) -> Result<Tensor<B, 3>, Box<dyn Error>> {
+    let device = &clip.devices()[0];
+
+    let hidden_idx = clip_skip_layer(clip, clip_skip)?;
+    let (tokens, weights) = tokenize_texts_weighted(texts, tokenizer, clip.max_sequence_length(), device)?;
+
+    let clip = clip_with_textual_inversions(clip, tokenizer)?;
+    let context = clip.forward_hidden(tokens, hidden_idx);
+    Ok( apply_token_weights(context, weights) )
+}
+
+pub fn text_to_context_open_clip<B: Backend, T: Tokenizer>(text: &str, clip: &CLIP<B>, tokenizer: &T, clip_skip: usize) -> Result<(Tensor<B, 3>, Tensor<B, 2>), Box<dyn Error>> {
+    texts_to_context_open_clip(&[text], clip, tokenizer, clip_skip)
+}
+
+pub fn texts_to_context_open_clip<B: Backend, T: Tokenizer>(texts: &[&str], clip: &CLIP<B>, tokenizer: &T, clip_skip: usize) -> Result<(Tensor<B, 3>, Tensor<B, 2>), Box<dyn Error>> {
+    let device = &clip.devices()[0];
+
+    let hidden_idx = clip_skip_layer(clip, clip_skip)?;
+    let (tokens, weights) = tokenize_texts_weighted(texts, tokenizer, clip.max_sequence_length(), device)?;
+
+    let clip = clip_with_textual_inversions(clip, tokenizer)?;
+    let eot_indices = end_of_text_indices(tokens.clone(), tokenizer);
+    let (context, pooled) = clip.forward_hidden_pooled_at(tokens, hidden_idx, eot_indices);
+    Ok( (apply_token_weights(context, weights), pooled) )
+}
+
+/// Encodes prompts of any length by splitting them into chunks, see `tokenize_texts_chunked`. 
+/// Returns a `[n_texts, seq_len * n_chunks, D]` context.
+pub fn texts_to_context_clip_chunked<B: Backend, T: Tokenizer>(texts: &[&str], clip: &CLIP<B>, tokenizer: &T, clip_skip: usize) -> Result<Tensor<B, 3>, Box<dyn Error>> {
+    let device = &clip.devices()[0];
+
+    let hidden_idx = clip_skip_layer(clip, clip_skip)?;
+    let clip = clip_with_textual_inversions(clip, tokenizer)?;
+    let contexts = tokenize_texts_chunked(texts, tokenizer, clip.max_sequence_length(), device)
+        .into_iter()
+        .map(|(tokens, weights)| apply_token_weights(clip.forward_hidden(tokens, hidden_idx), weights))
+        .collect();
+
+    Ok( Tensor::cat(contexts, 1) )
+}
+
+/// Chunked version of `texts_to_context_open_clip`. The pooled embedding comes from the first chunk.
+pub fn texts_to_context_open_clip_chunked<B: Backend, T: Tokenizer>(texts: &[&str], clip: &CLIP<B>, tokenizer: &T, clip_skip: usize) -> Result<(Tensor<B, 3>, Tensor<B, 2>), Box<dyn Error>> {
+    let device = &clip.devices()[0];
+
+    let hidden_idx = clip_skip_layer(clip, clip_skip)?;
+    let clip = clip_with_textual_inversions(clip, tokenizer)?;
+
+    let mut pooled = None;
+    let mut contexts = Vec::new();
+    for (tokens, weights) in tokenize_texts_chunked(texts, tokenizer, clip.max_sequence_length(), device) {
+        let eot_indices = end_of_text_indices(tokens.clone(), tokenizer);
+        let (context, chunk_pooled) = clip.forward_hidden_pooled_at(tokens, hidden_idx, eot_indices);
+
+        contexts.push( apply_token_weights(context, weights) );
+        pooled.get_or_insert(chunk_pooled);
+    }
+
+    Ok( (Tensor::cat(contexts, 1), pooled.unwrap()) )
+}
+
+/// Returns `clip` with the tokenizer's textual inversion vectors appended to its token embedding table, 
+/// so the reserved ids the tokenizer emits for trigger words embed to the learned vectors.
+fn clip_with_textual_inversions<'a, B: Backend, T: Tokenizer>(clip: &'a CLIP<B>, tokenizer: &T) -> Result<Cow<'a, CLIP<B>>, Box<dyn Error>> {
+    let inversions = match tokenizer.textual_inversions() {
+        Some(inversions) if !inversions.is_empty() => inversions, 
+        _ => return Ok( Cow::Borrowed(clip) ), 
+    };
+
+    let [n_vocab, n_state] = clip.token_embedding_dims();
+    if inversions.first_id() as usize != n_vocab {
+        return Err( format!("The tokenizer reserves textual inversion ids from {} but the text encoder has {} tokens.", inversions.first_id(), n_vocab).into() );
+    }
+
+    let vectors = inversions.vectors();
+    if let Some(vector) = vectors.iter().find(|vector| vector.len() != n_state) {
+        return Err( format!("A textual inversion vector has {} values but the text encoder embeds tokens into {}.", vector.len(), n_state).into() );
+    }
+
+    let values: Vec<B::FloatElem> = vectors.iter().flatten().map(|&v| v.elem()).collect();
+    let vectors = Tensor::from_data_device(Data::new(values, Shape::new([vectors.len(), n_state])), &clip.devices()[0]);
+
+    Ok( Cow::Owned(clip.with_added_token_embeddings(vectors)) )
+}
+
+/// Positions of the first end of text token in each sequence, so neither the padding after it nor textual 
+/// inversion ids above the vocabulary are mistaken for it.
+fn end_of_text_indices<B: Backend, T: Tokenizer>(tokens: Tensor<B, 2, Int>, tokenizer: &T) -> Tensor<B, 1, Int> {
+    first_token_indices(tokens, tokenizer.end_of_text_token())
+}
+
+/// Index of the hidden layer `clip_skip` layers before the output. A `clip_skip` of 1 is the penultimate layer.
+fn clip_skip_layer<B: Backend>(clip: &CLIP<B>, clip_skip: usize) -> Result<usize, Box<dyn Error>> {
+    let n_layers = clip.num_layers();
+    if clip_skip == 0 || clip_skip > n_layers {
+        return Err( format!("clip_skip must be between 1 and {} for this text encoder but was {}.", n_layers, clip_skip).into() );
+    }
+
+    Ok(n_layers - clip_skip)
+}
+
+pub fn tokenize_text<B: Backend, T: Tokenizer>(text: &str, tokenizer: &T, seq_len: usize, device: &B::Device) -> Tensor<B, 2, Int> {
+    tokenize_texts(&[text], tokenizer, seq_len, device)
+}
+
+/// Tokenizes each text and pads it to `seq_len` with the padding token, returning a `[n_texts, seq_len]` tensor.
+pub fn tokenize_texts<B: Backend, T: Tokenizer>(texts: &[&str], tokenizer: &T, seq_len: usize, device: &B::Device) -> Tensor<B, 2, Int> {
+    let tokenized: Vec<_> = texts
+        .iter()
+        .flat_map(|text| {
+            let mut tokenized: Vec<_> = tokenizer
+                .encode(text, true, true)
+                .into_iter()
+                .map(|v| v as i32)
+                .collect();
+
+            tokenized.resize(seq_len, tokenizer.padding_token() as i32);
+            tokenized
+        }).collect();
+
+    Tensor::from_ints(&tokenized[..]).to_device(device).reshape([texts.len(), seq_len])
+}
+
+/// Like `tokenize_texts`, but parses attention weighting syntax such as `(word:1.3)`. 
+/// The per-token weights are only returned if some prompt actually uses weighting. 
+/// Prompts longer than `seq_len` are rejected with `TooManyTokens` instead of being truncated.
+pub fn tokenize_texts_weighted<B: Backend, T: Tokenizer>(texts: &[&str], tokenizer: &T, seq_len: usize, device: &B::Device) -> Result<(Tensor<B, 2, Int>, Option<Tensor<B, 2>>), TooManyTokens> {
+    let encoded: Vec<_> = texts
+        .iter()
+        .map(|text| tokenizer.encode_weighted(text, true, true))
+        .collect();
+
+    if let Some(n_tokens) = encoded.iter().map(|(tokens, _)| tokens.len()).filter(|&n| n > seq_len).max() {
+        return Err(TooManyTokens {
+            n_tokens, 
+            max_tokens: seq_len, 
+        });
+    }
+
+    Ok( encoded_to_tensors(encoded, seq_len, tokenizer.padding_token(), device) )
+}
+
+/// Splits each prompt into chunks of `seq_len - 2` tokens which each get their own start and end tokens 
+/// and are padded to `seq_len`. Prompts with fewer chunks are filled out with empty chunks so that every 
+/// prompt has the same number. Returns the `[n_texts, seq_len]` tokens and optional weights of each chunk.
+pub fn tokenize_texts_chunked<B: Backend, T: Tokenizer>(texts: &[&str], tokenizer: &T, seq_len: usize, device: &B::Device) -> Vec<(Tensor<B, 2, Int>, Option<Tensor<B, 2>>)> {
+    let chunk_len = seq_len - 2;
+
+    let encoded: Vec<_> = texts
+        .iter()
+        .map(|text| tokenizer.encode_weighted(text, false, false))
+        .collect();
+
+    let n_chunks = encoded
+        .iter()
+        .map(|(tokens, _)| div_roundup(tokens.len(), chunk_len))
+        .max()
+        .unwrap_or(0)
+        .max(1);
+
+    (0..n_chunks).map(|i| {
+        let chunk = encoded.iter().map(|(tokens, weights)| {
+            let start = (i * chunk_len).min(tokens.len());
+            let end = ((i + 1) * chunk_len).min(tokens.len());
+
+            let chunk_tokens = iter::once(tokenizer.start_of_text_token())
+                .chain(tokens[start..end].iter().cloned())
+                .chain(iter::once(tokenizer.end_of_text_token()))
+                .collect();
+            let chunk_weights = iter::once(1.0)
+                .chain(weights[start..end].iter().cloned())
+                .chain(iter::once(1.0))
+                .collect();
+
+            (chunk_tokens, chunk_weights)
+        }).collect();
+
+        encoded_to_tensors(chunk, seq_len, tokenizer.padding_token(), device)
+    }).collect()
+}
+
+fn encoded_to_tensors<B: Backend>(encoded: Vec<(Vec<u32>, Vec<f32>)>, seq_len: usize, padding_token: u32, device: &B::Device) -> (Tensor<B, 2, Int>, Option<Tensor<B, 2>>) {
+    let n_texts = encoded.len();
+
+    let mut tokenized = Vec::new();
+    let mut weights = Vec::new();
+    for (mut text_tokens, mut text_weights) in encoded {
+        text_tokens.resize(seq_len, padding_token);
+        text_weights.resize(seq_len, 1.0);
+
+        tokenized.extend(text_tokens.into_iter().map(|v| v as i32));
+        weights.extend(text_weights);
+    }
+
+    let tokens = Tensor::from_ints(&tokenized[..]).to_device(device).reshape([n_texts, seq_len]);
+
+    let weights = if weights.iter().all(|&w| w == 1.0) {
+        None
+    } else {
+        let weights: Vec<B::FloatElem> = weights.into_iter().map(|w| w.elem()).collect();
+        Some( Tensor::from_data_device(Data::new(weights, Shape::new([n_texts, seq_len])), device) )
+    };
+
+    (tokens, weights)
+}
+
+/// Scales each token's hidden state by its weight, then rescales each prompt's context so its mean is unchanged.
+fn apply_token_weights<B: Backend>(context: Tensor<B, 3>, weights: Option<Tensor<B, 2>>) -> Tensor<B, 3> {
+    let weights = match weights {
+        Some(weights) => weights, 
+        None => return context, 
+    };
+
+    let [n_batch, seq_len, _] = context.dims();
+
+    let original_mean = context.clone().mean_dim(2).mean_dim(1);
+    let weighted = context * weights.reshape([n_batch, seq_len, 1]);
+    let weighted_mean = weighted.clone().mean_dim(2).mean_dim(1);
+
+    weighted * (original_mean / weighted_mean)
+}
 
 
 
@@ -541,6 +2662,322 @@ pub fn tokenize_text<B: Backend, T: Tokenizer>(text: &str, tokenizer: &T, seq_le
 
 use crate::helper::to_float;
 use std::f64::consts::PI;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Interleaved RGB buffers to a `[n_batch, 3, height, width]` tensor in the decoder's range of -1 to 1.
+fn raw_images_to_tensor<B: Backend>(images: &[Vec<u8>], width: usize, height: usize, device: &B::Device) -> Tensor<B, 4> {
+    let n_batch = images.len();
+
+    let values: Vec<B::FloatElem> = images
+        .iter()
+        .flat_map(|image| {
+            assert!(image.len() == height * width * 3, "Image buffer must contain {} bytes.", height * width * 3);
+            image.iter().map(|&v| (v as f32).elem())
+        }).collect();
+
+    let image: Tensor<B, 4> = Tensor::from_data_device(Data::new(values, Shape::new([n_batch, height, width, 3])), device);
+    let image = image
+        .swap_dims(2, 3)
+        .swap_dims(1, 2);
+
+    image / 255.0 * 2.0 - 1.0
+}
+
+/// Reinhard-style color transfer: shifts and scales every channel of every image in `image` so that its mean 
+/// and standard deviation match the same channel of `reference`. `reference` has the same batch size as `image` 
+/// or a batch of one that is matched by every image; the spatial sizes may differ.
+pub fn match_color<B: Backend>(image: Tensor<B, 4>, reference: Tensor<B, 4>) -> Tensor<B, 4> {
+    let [n_batch, n_channel, height, width] = image.dims();
+
+    let stats = |x: Tensor<B, 4>| {
+        let [n, c, h, w] = x.dims();
+        let x = x.reshape([n, c, h * w]);
+        let mean = x.clone().mean_dim(2);
+        let centered = x - mean.clone();
+        let std = (centered.clone() * centered).mean_dim(2).sqrt();
+        (mean, std)
+    };
+
+    let (mean, std) = stats(image.clone());
+    let (ref_mean, ref_std) = stats(reference);
+    let (ref_mean, ref_std) = if ref_mean.dims()[0] == 1 && n_batch > 1 {
+        (ref_mean.repeat(0, n_batch), ref_std.repeat(0, n_batch))
+    } else {
+        (ref_mean, ref_std)
+    };
+
+    let x = image.reshape([n_batch, n_channel, height * width]);
+    let normalized = (x - mean) / std.add_scalar(1e-5);
+    (normalized * ref_std + ref_mean).reshape([n_batch, n_channel, height, width])
+}
+
+fn decoded_to_raw_images<B: Backend>(image: Tensor<B, 4>, gamma: f64, clamp: bool) -> RawImages {
+    let [_, n_channel, height, width] = image.dims();
+    let num_elements_per_image = n_channel * height * width;
+
+    let mut pixels = Vec::new();
+    decoded_to_pixels(image, gamma, clamp, &mut pixels);
+
+    let buffer = pixels
+        .chunks(num_elements_per_image)
+        .map(|image| image.to_vec())
+        .collect();
+
+    RawImages {
+        buffer: buffer, 
+        width: width, 
+        height: height, 
+        channels: n_channel, 
+    }
+}
+
+/// Writes the pixels of the whole batch to `out`, replacing its contents.
+fn decoded_to_pixels<B: Backend>(image: Tensor<B, 4>, gamma: f64, clamp: bool, out: &mut Vec<u8>) {
+    // correct size and scale and reorder to 
+    let image = (image + 1.0) / 2.0;
+    let image = if clamp {
+        tensor_min_scalar(tensor_max_scalar(image, 0.0), 1.0)
+    } else {
+        image
+    };
+    let image = if gamma != 1.0 {
+        image.powf(1.0 / gamma as f32)
+    } else {
+        image
+    };
+    let image = image
+        .swap_dims(1, 2)
+        .swap_dims(2, 3)
+        .mul_scalar(255.0);
+
+    let flattened: Vec<_> = image.
+        into_data().
+        value;
+
+    out.clear();
+    out.extend(flattened.into_iter().map(|v| pixel_to_u8(v.to_f64().unwrap())));
+}
+
+/// Rec. 709 luminance weights of the red, green and blue channels.
+const LUMA_WEIGHTS: [f64; 3] = [0.2126, 0.7152, 0.0722];
+
+/// Saturates instead of wrapping, and maps NaN to 0.
+fn pixel_to_u8(value: f64) -> u8 {
+    if value.is_nan() {
+        0
+    } else {
+        value.round().min(255.0).max(0.0) as u8
+    }
+}
+
+fn check_tiling(tile_size: usize, overlap: usize) -> Result<(), Box<dyn Error>> {
+    if overlap >= tile_size {
+        return Err( format!("The tile overlap {} must be smaller than the tile size {}.", overlap, tile_size).into() );
+    }
+
+    Ok(())
+}
+
+/// Runs `process` on the `[rows, cols]` of overlapping `tile_size` tiles covering a `[height, width]` grid and 
+/// blends the `[n_batch, n_channel, rows * scale, cols * scale]` outputs with a linear feather over the overlap. 
+/// Tiles are clamped to the grid, so a side shorter than `tile_size` is covered by a single tile.
+fn blend_tiles<B: Backend>(output_dims: [usize; 2], size: [usize; 2], tile_size: usize, overlap: usize, scale: usize, device: &B::Device, mut process: impl FnMut(Range<usize>, Range<usize>) -> Tensor<B, 4>) -> Tensor<B, 4> {
+    let [n_batch, n_channel] = output_dims;
+    let [height, width] = size;
+    let tile_height = tile_size.min(height);
+    let tile_width = tile_size.min(width);
+
+    let mut output = Tensor::zeros_device([n_batch, n_channel, height * scale, width * scale], device);
+    let mut weights = Tensor::zeros_device([1, 1, height * scale, width * scale], device);
+
+    for y in tile_starts(height, tile_height, overlap) {
+        for x in tile_starts(width, tile_width, overlap) {
+            let tile = process(y..y + tile_height, x..x + tile_width);
+
+            let weight_y = feather_weights(tile_height * scale, overlap * scale, y > 0, y + tile_height < height);
+            let weight_x = feather_weights(tile_width * scale, overlap * scale, x > 0, x + tile_width < width);
+            let weight: Vec<B::FloatElem> = weight_y
+                .iter()
+                .flat_map(|wy| weight_x.iter().map(move |wx| (wy * wx).elem()))
+                .collect();
+            let weight: Tensor<B, 4> = Tensor::from_data_device(
+                Data::new(weight, Shape::new([1, 1, tile_height * scale, tile_width * scale])), 
+                device
+            );
+
+            let rows = y * scale..(y + tile_height) * scale;
+            let cols = x * scale..(x + tile_width) * scale;
+
+            let output_range = [0..n_batch, 0..n_channel, rows.clone(), cols.clone()];
+            let blended = output.clone().slice(output_range.clone()) + tile * weight.clone();
+            output = output.slice_assign(output_range, blended);
+
+            let weight_range = [0..1, 0..1, rows, cols];
+            let summed = weights.clone().slice(weight_range.clone()) + weight;
+            weights = weights.slice_assign(weight_range, summed);
+        }
+    }
+
+    output / weights
+}
+
+fn tile_starts(length: usize, tile_size: usize, overlap: usize) -> Vec<usize> {
+    let tile_size = tile_size.min(length);
+    let stride = tile_size.saturating_sub(overlap).max(1);
+
+    let mut starts: Vec<_> = (0..length - tile_size).step_by(stride).collect();
+    starts.push(length - tile_size);
+    starts
+}
+
+fn feather_weights(length: usize, ramp: usize, feather_start: bool, feather_end: bool) -> Vec<f32> {
+    (0..length).map(|i| {
+        let mut weight = 1.0f32;
+        if ramp > 0 {
+            if feather_start {
+                weight = weight.min( (i as f32 + 0.5) / ramp as f32 );
+            }
+            if feather_end {
+                weight = weight.min( ((length - i) as f32 - 0.5) / ramp as f32 );
+            }
+        }
+        weight
+    }).collect()
+}
+
+fn img2img_skipped_steps(n_steps: usize, strength: f32) -> usize {
+    let n_run = (n_steps as f32 * strength.max(0.0).min(1.0)).round() as usize;
+    n_steps - n_run.max(1)
+}
+
+fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// How the classifier-free guidance scale changes over the denoising steps. Plain numbers convert to `Constant`, 
+/// so `sample_latent(conditioning, 7.5, ...)` keeps working. Ramps start at `start` on the first step and reach 
+/// `end` on the last step that is actually run, e.g. the last `strength` fraction of the steps for image-to-image. 
+/// High guidance early and low guidance late tends to keep the composition while reducing oversaturation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GuidanceSchedule {
+    Constant(f64), 
+    Linear { start: f64, end: f64 }, 
+    /// Half a cosine period from `start` to `end`, so the scale changes slowly near both ends.
+    Cosine { start: f64, end: f64 }, 
+}
+
+impl GuidanceSchedule {
+    /// The guidance scale at `step`, counting from 0, of a run of `n_steps` steps.
+    pub fn scale(&self, step: usize, n_steps: usize) -> f64 {
+        let progress = if n_steps > 1 {
+            step as f64 / (n_steps - 1) as f64
+        } else {
+            0.0
+        };
+
+        match *self {
+            GuidanceSchedule::Constant(scale) => scale, 
+            GuidanceSchedule::Linear { start, end } => start + (end - start) * progress, 
+            GuidanceSchedule::Cosine { start, end } => {
+                let ramp = (1.0 - (progress * std::f64::consts::PI).cos()) / 2.0;
+                start + (end - start) * ramp
+            }
+        }
+    }
+}
+
+impl From<f64> for GuidanceSchedule {
+    fn from(scale: f64) -> Self {
+        GuidanceSchedule::Constant(scale)
+    }
+}
+
+impl From<f32> for GuidanceSchedule {
+    fn from(scale: f32) -> Self {
+        GuidanceSchedule::Constant(scale as f64)
+    }
+}
+
+/// How the sampling timesteps are spread over the training schedule.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TimestepSpacing {
+    /// Evenly spaced timesteps counting down from the last training timestep in steps of `n_train_steps / n_steps`, 
+    /// which runs an extra step when that doesn't divide evenly. The default used by `sample_latent`.
+    Uniform, 
+    /// `n_steps` timesteps in steps of `n_train_steps / n_steps` counting up from 0, like diffusers' "leading" 
+    /// spacing. The noisiest timestep falls short of the end of the schedule.
+    Leading, 
+    /// `n_steps` timesteps evenly spaced down from the last training timestep, like diffusers' "trailing" spacing. 
+    /// Starting at the noisiest timestep notably improves quality at low step counts.
+    Trailing, 
+    /// `n_steps` timesteps evenly spaced between the first and the last training timestep, inclusive.
+    Linspace, 
+    /// Timesteps nearest to `karras_sigmas` with `rho = 7`, spending more steps at low noise levels. 
+    /// `sigma_min` and `sigma_max` are the endpoints of the alpha schedule. Since the diffusion model is 
+    /// conditioned on integer timesteps, sigmas that land on the same timestep are merged, so very small 
+    /// step counts may run fewer steps than requested.
+    Karras, 
+}
+
+/// The timesteps for the `Leading`, `Trailing` and `Linspace` spacings in decreasing order. 
+/// Timesteps that coincide when `n_steps` exceeds `n_train_steps` are merged.
+fn spaced_timesteps(n_train_steps: usize, n_steps: usize, spacing: TimestepSpacing) -> Vec<usize> {
+    let n_steps = n_steps.max(1);
+
+    let mut timesteps: Vec<usize> = match spacing {
+        TimestepSpacing::Leading => {
+            let step_size = (n_train_steps / n_steps).max(1);
+            (0..n_steps).rev().map(|i| (i * step_size).min(n_train_steps - 1)).collect()
+        }, 
+        TimestepSpacing::Trailing => {
+            let step_size = n_train_steps as f64 / n_steps as f64;
+            (0..n_steps).map(|i| (n_train_steps as f64 - i as f64 * step_size).round().max(1.0) as usize - 1).collect()
+        }, 
+        TimestepSpacing::Linspace => {
+            let step_size = (n_train_steps - 1) as f64 / (n_steps - 1).max(1) as f64;
+            (0..n_steps).rev().map(|i| (i as f64 * step_size).round() as usize).collect()
+        }, 
+        TimestepSpacing::Uniform | TimestepSpacing::Karras => unreachable!("Not a spacing of spaced_timesteps."), 
+    };
+
+    timesteps.dedup();
+    timesteps
+}
+
+/// Noise levels from Karras et al. (2022), "Elucidating the Design Space of Diffusion-Based Generative Models", 
+/// in decreasing order from `sigma_max` to `sigma_min`.
+pub fn karras_sigmas(n_steps: usize, sigma_min: f64, sigma_max: f64, rho: f64) -> Vec<f64> {
+    let min_inv_rho = sigma_min.powf(1.0 / rho);
+    let max_inv_rho = sigma_max.powf(1.0 / rho);
+
+    (0..n_steps).map(|i| {
+        let ramp = if n_steps > 1 { i as f64 / (n_steps - 1) as f64 } else { 0.0 };
+        (max_inv_rho + ramp * (min_inv_rho - max_inv_rho)).powf(rho)
+    }).collect()
+}
+
+/// Maps each sigma to the timestep whose sigma is closest in log space, dropping repeated timesteps.
+fn sigmas_to_timesteps(sigmas: &[f64], schedule_sigmas: &[f64]) -> Vec<usize> {
+    let mut timesteps: Vec<usize> = Vec::new();
+    for sigma in sigmas {
+        let t = schedule_sigmas
+            .iter()
+            .map(|s| (s.ln() - sigma.ln()).abs())
+            .enumerate()
+            .min_by(|(_, a), (_, b)| a.partial_cmp(b).unwrap())
+            .map(|(t, _)| t)
+            .unwrap();
+
+        if timesteps.last() != Some(&t) {
+            timesteps.push(t);
+        }
+    }
+
+    timesteps
+}
 
 fn cosine_schedule<B: Backend>(n_steps: usize) -> Tensor<B, 1> {
     to_float(Tensor::arange(1..n_steps + 1))
@@ -562,4 +2999,1004 @@ fn offset_cosine_schedule<B: Backend>(n_steps: usize, device: &B::Device) -> Ten
 
 pub fn offset_cosine_schedule_cumprod<B: Backend>(n_steps: usize, device: &B::Device) -> Tensor<B, 1> {
     offset_cosine_schedule::<B>(n_steps, device).powf(2.0)
-}
\ No newline at end of file
+}
+
+/// The cumulative alpha products of the "scaled_linear" beta schedule SDXL is trained with, where the square roots 
+/// of the betas are evenly spaced from `beta_start` to `beta_end` (0.00085 and 0.012 for SDXL). 
+/// The products are accumulated in f64.
+pub fn scaled_linear_schedule_cumprod<B: Backend>(n_steps: usize, beta_start: f64, beta_end: f64, device: &B::Device) -> Tensor<B, 1> {
+    let (start, end) = (beta_start.sqrt(), beta_end.sqrt());
+    let step = if n_steps > 1 { (end - start) / (n_steps - 1) as f64 } else { 0.0 };
+
+    let alphas_cumprod: Vec<B::FloatElem> = (0..n_steps)
+        .scan(1.0, |alpha_cumprod: &mut f64, i| {
+            let beta = (start + step * i as f64).powi(2);
+            *alpha_cumprod *= 1.0 - beta;
+            Some(*alpha_cumprod)
+        })
+        .map(|a| a.elem())
+        .collect();
+
+    Tensor::from_data_device(Data::new(alphas_cumprod, Shape::new([n_steps])), device)
+}
+
+/// `offset_cosine_schedule_cumprod` rescaled to zero terminal SNR, see `rescale_zero_terminal_snr`.
+pub fn offset_cosine_schedule_cumprod_zero_snr<B: Backend>(n_steps: usize, device: &B::Device) -> Tensor<B, 1> {
+    rescale_zero_terminal_snr(offset_cosine_schedule_cumprod::<B>(n_steps, device))
+}
+
+/// The smallest alpha-bar the zero SNR schedule ends on. An exact zero would make the noise prediction 
+/// of the final timestep carry no information about the image, so, like diffusers, it is clamped to 2^-24.
+pub const ZERO_SNR_MIN_ALPHA_CUMPROD: f64 = 1.0 / (1 << 24) as f64;
+
+/// Shifts and scales `sqrt(alphas_cumprod)` so that the first value is kept and the last reaches zero, i.e. zero 
+/// signal-to-noise ratio at the final timestep (Lin et al. 2023, "Common Diffusion Noise Schedules and Sample Steps 
+/// are Flawed", Algorithm 1). Without it the last timestep still leaks the mean of the training image, which biases 
+/// generations toward medium brightness and keeps very dark or bright prompts from working.
+pub fn rescale_zero_terminal_snr<B: Backend>(alphas_cumprod: Tensor<B, 1>) -> Tensor<B, 1> {
+    let device = alphas_cumprod.device();
+
+    let sqrt_alphas_cumprod: Vec<f64> = tensor_to_vec(alphas_cumprod).into_iter().map(f64::sqrt).collect();
+    let first = sqrt_alphas_cumprod[0];
+    let last = sqrt_alphas_cumprod[sqrt_alphas_cumprod.len() - 1];
+
+    let rescaled: Vec<B::FloatElem> = sqrt_alphas_cumprod
+        .iter()
+        .map(|a| ((a - last) * first / (first - last)).powi(2).max(ZERO_SNR_MIN_ALPHA_CUMPROD).elem())
+        .collect();
+    let n_steps = rescaled.len();
+
+    Tensor::from_data_device(Data::new(rescaled, Shape::new([n_steps])), &device)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::tensor_max_element;
+    use burn::tensor::Distribution;
+    use burn_tch::TchBackend;
+
+    type TestBackend = TchBackend<f32>;
+
+    fn tiny_diffuser() -> Diffuser<TestBackend> {
+        DiffuserConfig::new(8, 32, 16, 16).init()
+    }
+
+    fn tiny_conditioning() -> Conditioning<TestBackend> {
+        Conditioning {
+            unconditional_context: Tensor::random([7, 16], Distribution::Normal(0.0, 1.0)), 
+            context: Tensor::random([1, 7, 16], Distribution::Normal(0.0, 1.0)), 
+            unconditional_channel_context: Tensor::random([8], Distribution::Normal(0.0, 1.0)), 
+            channel_context: Tensor::random([1, 8], Distribution::Normal(0.0, 1.0)), 
+            resolution: [64, 64], 
+            aesthetic_score: None, 
+            negative_aesthetic_score: None, 
+        }
+    }
+
+    #[test]
+    fn test_inpaint_preserves_unmasked_latent() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+
+        let init_latent: Tensor<TestBackend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let mask: Tensor<TestBackend, 4> = Tensor::zeros([1, 1, 8, 8])
+            .slice_assign([0..1, 0..1, 0..8, 0..4], Tensor::ones([1, 1, 8, 4]));
+
+        let latent = diffuser.sample_latent_inpaint(conditioning, init_latent.clone(), mask.clone(), 7.5, 0.0, 4);
+
+        let keep = mask.mul_scalar(-1.0).add_scalar(1.0).repeat(1, 4);
+        let diff = ( (latent - init_latent) * keep ).powf(2.0);
+        assert!(tensor_max_element(diff) < 1e-6);
+    }
+
+    #[test]
+    fn test_outpaint_canvas_and_mask() {
+        let image = RgbImage::from_fn(16, 8, |x, y| Rgb([x as u8, y as u8, 0]));
+
+        let (canvas, known) = outpaint_canvas(&image, OutpaintDirection::Left, 8).unwrap();
+        assert_eq!(canvas.dimensions(), (24, 8));
+        assert_eq!(known, [0, 8, 8, 24]);
+        assert_eq!(canvas.get_pixel(8, 3), image.get_pixel(0, 3));
+        assert_eq!(canvas.get_pixel(0, 3), image.get_pixel(0, 3));
+        assert_eq!(canvas.get_pixel(23, 7), image.get_pixel(15, 7));
+
+        let (canvas, known) = outpaint_canvas(&image, OutpaintDirection::Down, 16).unwrap();
+        assert_eq!(canvas.dimensions(), (16, 24));
+        assert_eq!(known, [0, 0, 8, 16]);
+
+        let mask: Tensor<TestBackend, 4> = outpaint_mask([24, 16], known, &Default::default()).unwrap();
+        assert_eq!(mask.dims(), [1, 1, 3, 2]);
+        assert_eq!(tensor_to_vec(mask), vec![0.0, 0.0, 1.0, 1.0, 1.0, 1.0]);
+
+        assert!(outpaint_canvas(&RgbImage::new(0, 8), OutpaintDirection::Right, 8).is_err());
+        assert!(outpaint_mask::<TestBackend>([24, 16], [0, 0, 4, 16], &Default::default()).is_err());
+        assert!(outpaint_mask::<TestBackend>([24, 16], [8, 0, 8, 16], &Default::default()).is_err());
+        assert!(outpaint_mask::<TestBackend>([24, 16], [0, 0, 32, 16], &Default::default()).is_err());
+
+        // the known sub-rectangle survives inpainting
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+        let init_latent: Tensor<TestBackend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let mask = outpaint_mask([64, 64], [16, 0, 64, 40], &Default::default()).unwrap();
+
+        let latent = diffuser.sample_latent_inpaint(conditioning, init_latent.clone(), mask, 7.5, 0.0, 3);
+        let diff = (latent - init_latent).slice([0..1, 0..4, 2..8, 0..5]).abs();
+        assert!(tensor_max_element(diff) < 1e-3);
+    }
+
+    #[test]
+    fn test_sample_latent_scheduled() {
+        let diffuser = tiny_diffuser();
+        let cat = tiny_conditioning();
+        let mut dog = tiny_conditioning();
+        dog.context = dog.context.mul_scalar(-1.0);
+
+        let schedule = ScheduledConditioning::new(cat.clone()).with_stage(0.5, dog.clone());
+        assert!(schedule.check().is_ok());
+        assert_eq!(tensor_to_vec(schedule.at(1, 4).context.clone()), tensor_to_vec(cat.context.clone()));
+        assert_eq!(tensor_to_vec(schedule.at(2, 4).context.clone()), tensor_to_vec(dog.context.clone()));
+        assert!(ScheduledConditioning::new(cat.clone()).with_stage(0.5, dog.clone()).with_stage(0.2, cat.clone()).check().is_err());
+
+        // a single stage samples like the plain conditioning
+        let expected = diffuser.sample_latent_with_seed(cat.clone(), 7.0, 0.0, 2, 4, 0.0);
+        let latent = diffuser.sample_latent_scheduled(ScheduledConditioning::new(cat), 7.0, 0.0, 2, 4).unwrap();
+        assert!(tensor_max_element((latent - expected).abs()) < 1e-5);
+
+        let latent = diffuser.sample_latent_scheduled(schedule, 7.0, 0.0, 2, 4).unwrap();
+        assert_eq!(latent.dims(), [1, 4, 8, 8]);
+    }
+
+    #[test]
+    fn test_guidance_one_skips_unconditional_pass() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+
+        let latent: Tensor<TestBackend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let timestep = Tensor::from_ints([500]);
+
+        let unconditional = diffuser.diffusion.forward(
+            latent.clone(), 
+            timestep.clone(), 
+            conditioning.unconditional_context.clone().unsqueeze(), 
+            conditioning.unconditional_channel_context.clone().unsqueeze(), 
+        );
+        let conditional = diffuser.diffusion.forward(latent.clone(), timestep.clone(), conditioning.context.clone(), conditioning.channel_context.clone());
+        let expected = unconditional.clone() + (conditional - unconditional) * 1.0;
+
+        for cfg_rescale in [0.0, 0.7] {
+            let guided = diffuser.forward_diffuser(latent.clone(), timestep.clone(), conditioning.clone(), 1.0, cfg_rescale);
+            assert!(tensor_max_element((guided - expected.clone()).abs()) < 1e-5);
+        }
+    }
+
+    #[test]
+    fn test_batched_guidance_matches_two_passes() {
+        let diffuser = tiny_diffuser();
+        let mut conditioning = tiny_conditioning();
+        conditioning.context = Tensor::cat(vec![conditioning.context.clone(), conditioning.context.mul_scalar(-1.0)], 0);
+        conditioning.channel_context = conditioning.channel_context.repeat(0, 2);
+
+        let latent: Tensor<TestBackend, 4> = Tensor::random([2, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let timestep = Tensor::from_ints([500]);
+
+        let unconditional = diffuser.diffusion.forward(
+            latent.clone(), 
+            timestep.clone(), 
+            conditioning.unconditional_context.clone().unsqueeze().repeat(0, 2), 
+            conditioning.unconditional_channel_context.clone().unsqueeze().repeat(0, 2), 
+        );
+        let conditional = diffuser.diffusion.forward(latent.clone(), timestep.clone(), conditioning.context.clone(), conditioning.channel_context.clone());
+        let expected = unconditional.clone() + (conditional - unconditional) * 7.5;
+
+        let guided = diffuser.forward_diffuser(latent, timestep, conditioning, 7.5, 0.0);
+        assert!(tensor_max_element((guided - expected).abs()) < 1e-4);
+    }
+
+    #[test]
+    fn test_hires_fix_upscales_latent() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+
+        let base_latent = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let latent = diffuser.hires_fix(conditioning.clone(), base_latent.clone(), 1.5, 2, 0.5, 7.0, 0.0);
+
+        assert_eq!(latent.dims(), [1, 4, 12, 12]);
+
+        let upscaled = diffuser.hires_fix(conditioning, base_latent.clone(), 1.5, 2, 0.0, 7.0, 0.0);
+        let expected = upsample_bilinear(base_latent, 12, 12);
+        assert!(tensor_max_element((upscaled - expected).abs()) < 1e-6);
+    }
+
+    #[test]
+    fn test_sample_iter_is_lazy() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+
+        let mut steps = diffuser.sample_iter(conditioning.clone(), 7.0, 0.0, 4);
+        let first = steps.next().unwrap();
+        assert_eq!(first.dims(), [1, 4, 8, 8]);
+        assert_eq!(steps.count(), 3);
+
+        // dropping an iterator early leaves the diffuser usable
+        let _ = diffuser.sample_iter(conditioning.clone(), 7.0, 0.0, 4).next();
+        assert_eq!(diffuser.sample_iter(conditioning, 7.0, 0.0, 4).last().unwrap().dims(), [1, 4, 8, 8]);
+    }
+
+    #[test]
+    fn test_sample_latent_cancellable() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+
+        let latent = diffuser.sample_latent_cancellable(conditioning.clone(), 7.0, 0.0, 2, Arc::new(AtomicBool::new(false)));
+        assert_eq!(latent.unwrap().dims(), [1, 4, 8, 8]);
+
+        let aborted = diffuser.sample_latent_cancellable(conditioning, 7.0, 0.0, 2, Arc::new(AtomicBool::new(true))).unwrap_err();
+        assert_eq!(aborted.completed_steps, 0);
+        assert!(aborted.latent.is_none());
+    }
+
+    #[test]
+    fn test_latent_decoder_scale_factor() {
+        let sdxl: LatentDecoder<TestBackend> = LatentDecoderConfig::new().init();
+        assert_eq!(sdxl.scale_factor(), SDXL_VAE_SCALE_FACTOR);
+
+        let sd15: LatentDecoder<TestBackend> = LatentDecoderConfig::new().with_scale_factor(0.18215).init();
+        assert_eq!(sd15.scale_factor(), 0.18215);
+
+        // same weights, so both directions must differ only by the ratio of the factors
+        let sd15 = LatentDecoder { scale_factor: 0.18215, ..sdxl.clone() };
+        let ratio = 0.18215 / 0.13025;
+
+        let image: Tensor<TestBackend, 4> = Tensor::random([1, 3, 16, 16], Distribution::Uniform(-1.0, 1.0));
+        let encoded = sd15.encode_image(image.clone()) - sdxl.encode_image(image) * ratio;
+        assert!(tensor_max_element(encoded.abs()) < 1e-4);
+
+        let latent: Tensor<TestBackend, 4> = Tensor::random([1, 4, 2, 2], Distribution::Normal(0.0, 1.0));
+        let decoded = sd15.decode_latent(latent.clone() * ratio) - sdxl.decode_latent(latent);
+        assert!(tensor_max_element(decoded.abs()) < 1e-4);
+    }
+
+    #[test]
+    fn test_sample_latent_batch_seeds() {
+        let diffuser = tiny_diffuser();
+        let single = tiny_conditioning();
+
+        let mut batch = single.clone();
+        batch.context = single.context.clone().repeat(0, 2);
+        batch.channel_context = single.channel_context.clone().repeat(0, 2);
+
+        let latents = diffuser.sample_latent_batch_seeds(batch, 7.0, 0.0, 2, &[3, 7]);
+        assert_eq!(latents.dims(), [2, 4, 8, 8]);
+
+        let second = diffuser.sample_latent_with_seed(single, 7.0, 0.0, 2, 7, 0.0);
+        let diff = latents.slice([1..2]) - second;
+        assert!(tensor_max_element(diff.abs()) < 1e-4);
+    }
+
+    #[test]
+    fn test_conditioning_to_device() {
+        let conditioning = Conditioning {
+            aesthetic_score: Some(7.0), 
+            ..tiny_conditioning()
+        };
+        let moved = conditioning.clone().to_device(&burn_tch::TchDevice::Cpu);
+
+        assert_eq!(moved.context.device(), burn_tch::TchDevice::Cpu);
+        assert_eq!(moved.context.into_data(), conditioning.context.into_data());
+        assert_eq!(moved.channel_context.into_data(), conditioning.channel_context.into_data());
+        assert_eq!(moved.resolution, conditioning.resolution);
+        assert_eq!(moved.aesthetic_score, Some(7.0));
+    }
+
+    #[test]
+    fn test_sample_latent_microbatched() {
+        let diffuser = tiny_diffuser();
+
+        let mut conditioning = tiny_conditioning();
+        conditioning.context = Tensor::random([3, 7, 16], Distribution::Normal(0.0, 1.0));
+        conditioning.channel_context = Tensor::random([3, 8], Distribution::Normal(0.0, 1.0));
+
+        let chunked = diffuser.sample_latent_microbatched(conditioning.clone(), 7.0, 0.0, 2, 9, 2);
+        let whole = diffuser.sample_latent_with_seed(conditioning.clone(), 7.0, 0.0, 2, 9, 0.0);
+        assert_eq!(chunked.dims(), [3, 4, 8, 8]);
+        assert!(tensor_max_element((chunked - whole).abs()) < 1e-3);
+
+        assert_eq!(diffuser.sample_latent_with_options(conditioning, 7.0, 0.0, 2, &SamplingOptions::new().with_microbatch(Some(2)).with_dynamic_threshold(Some(0.995))).dims(), [3, 4, 8, 8]);
+    }
+
+    #[test]
+    fn test_sample_latent_from_noise() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+        let device = conditioning.context.device();
+
+        TestBackend::seed(5);
+        let noise = random_normal([1, 4, 8, 8], &device);
+
+        let from_noise = diffuser.sample_latent_from_noise(conditioning.clone(), noise, 7.0, 0.0, 2);
+        let from_seed = diffuser.sample_latent_with_seed(conditioning, 7.0, 0.0, 2, 5, 0.0);
+        assert!(tensor_max_element((from_noise - from_seed).abs()) < 1e-4);
+    }
+
+    #[test]
+    #[should_panic(expected = "Expected initial noise of shape")]
+    fn test_sample_latent_from_noise_checks_shape() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+
+        let noise = Tensor::random([1, 4, 16, 16], Distribution::Normal(0.0, 1.0));
+        diffuser.sample_latent_from_noise(conditioning, noise, 7.0, 0.0, 2);
+    }
+
+    #[test]
+    fn test_heun_sampling() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+
+        for kind in SamplerKind::ALL {
+            let mut sampler = diffuser.sampler(kind);
+            let latent = diffuser.sample_latent_with_sampler_and_seed(conditioning.clone(), 7.0, 0.0, 2, sampler.as_mut(), TimestepSpacing::Uniform, 3);
+            assert_eq!(latent.dims(), [1, 4, 8, 8]);
+        }
+
+        // DDIM through the registry is the default sampler
+        let mut ddim = diffuser.sampler("ddim".parse().unwrap());
+        let registry = diffuser.sample_latent_with_sampler_and_seed(conditioning.clone(), 7.0, 0.0, 2, ddim.as_mut(), TimestepSpacing::Uniform, 3);
+        let default = diffuser.sample_latent_with_seed(conditioning, 7.0, 0.0, 2, 3, 0.0);
+        assert!(tensor_max_element((registry - default).abs()) < 1e-4);
+    }
+
+    #[test]
+    fn test_slerp() {
+        let a: Tensor<TestBackend, 4> = Tensor::random([2, 4, 16, 16], Distribution::Normal(0.0, 1.0));
+        let b: Tensor<TestBackend, 4> = Tensor::random([2, 4, 16, 16], Distribution::Normal(0.0, 1.0));
+
+        assert!(tensor_max_element((slerp(a.clone(), b.clone(), 0.0) - a.clone()).abs()) < 1e-5);
+        assert!(tensor_max_element((slerp(a.clone(), b.clone(), 1.0) - b.clone()).abs()) < 1e-5);
+
+        // independent noises are nearly orthogonal, so the halfway point keeps unit variance where lerp halves it
+        let variance = |x: Tensor<TestBackend, 4>| tensor_to_vec(x.clone() * x).iter().sum::<f64>() / (2 * 4 * 16 * 16) as f64;
+        assert!((variance(slerp(a.clone(), b.clone(), 0.5)) - 1.0).abs() < 0.1);
+        assert!((variance(lerp(a, b, 0.5)) - 0.5).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_sample_latent_with_provider() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+
+        let latent = diffuser.sample_latent_with_provider(&conditioning, "ignored", "", [64, 64], 7.0, 0.0, 2).unwrap();
+        assert_eq!(latent.dims(), [1, 4, 8, 8]);
+
+        let mut too_wide = conditioning;
+        too_wide.channel_context = Tensor::random([1, 9], Distribution::Normal(0.0, 1.0));
+        assert!(diffuser.sample_latent_with_provider(&too_wide, "", "", [64, 64], 7.0, 0.0, 2).is_err());
+    }
+
+    #[test]
+    fn test_sample_latent_with_variation() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+
+        let base = diffuser.sample_latent_with_seed(conditioning.clone(), 7.0, 0.0, 2, 3, 0.0);
+        let unvaried = diffuser.sample_latent_with_variation(conditioning.clone(), 7.0, 0.0, 2, 3, 8, 0.0);
+        assert!(tensor_max_element((base.clone() - unvaried).abs()) < 1e-6);
+
+        let varied = diffuser.sample_latent_with_variation(conditioning, 7.0, 0.0, 2, 3, 8, 0.3);
+        assert!(tensor_max_element((base - varied).abs()) > 1e-3);
+    }
+
+    #[test]
+    fn test_sample_latent_with_noise_seed() {
+        let diffuser = tiny_diffuser();
+        let conditioning = tiny_conditioning();
+
+        let sample = |seed: u64, noise_seed: Option<u64>| {
+            let mut sampler = diffuser.euler_ancestral_sampler();
+            diffuser.sample_latent_with_noise_seed(conditioning.clone(), 7.0, 0.0, 3, &mut sampler, TimestepSpacing::Uniform, seed, noise_seed)
+        };
+
+        let a = sample(3, Some(5));
+        assert!(tensor_max_element((a.clone() - sample(3, Some(5))).abs()) < 1e-6);
+        assert!(tensor_max_element((a.clone() - sample(3, Some(6))).abs()) > 1e-3);
+        assert!(tensor_max_element((a - sample(4, Some(5))).abs()) > 1e-3);
+
+        // without a noise seed the step noise continues the init seed's stream
+        let mut sampler = diffuser.euler_ancestral_sampler();
+        let single = diffuser.sample_latent_with_sampler_and_seed(conditioning.clone(), 7.0, 0.0, 3, &mut sampler, TimestepSpacing::Uniform, 3);
+        assert!(tensor_max_element((single - sample(3, None)).abs()) < 1e-6);
+    }
+
+    #[test]
+    fn test_sample_latent_regional() {
+        let diffuser = tiny_diffuser();
+        let base = tiny_conditioning();
+
+        // a region covering the whole latent with the base prompt is the same as no regions
+        let full = RegionalConditioning::new(base.clone()).with_region([0, 0, 8, 8], base.clone());
+        let regional = diffuser.sample_latent_regional(full.clone(), 7.0, 0.0, 2, 11).unwrap();
+        let plain = diffuser.sample_latent_with_seed(base.clone(), 7.0, 0.0, 2, 11, 0.0);
+        assert!(tensor_max_element((regional - plain.clone()).abs()) < 1e-4);
+
+        let regional = diffuser.sample_latent_regional(full, 7.0, 0.7, 2, 11).unwrap();
+        let rescaled = diffuser.sample_latent_with_seed(base.clone(), 7.0, 0.7, 2, 11, 0.0);
+        assert!(tensor_max_element((regional - rescaled).abs()) < 1e-4);
+
+        let mut other = base.clone();
+        other.context = Tensor::random([1, 7, 16], Distribution::Normal(0.0, 1.0));
+        let left = RegionalConditioning::new(base.clone()).with_region([0, 0, 8, 4], other.clone());
+        let regional = diffuser.sample_latent_regional(left, 7.0, 0.0, 2, 11).unwrap();
+        assert_eq!(regional.dims(), [1, 4, 8, 8]);
+        assert!(tensor_max_element((regional - plain).abs()) > 1e-4);
+
+        let outside = RegionalConditioning::new(base.clone()).with_region([0, 4, 8, 12], other.clone());
+        assert!(diffuser.sample_latent_regional(outside, 7.0, 0.0, 2, 11).is_err());
+
+        let empty = RegionalConditioning::new(base).with_region([4, 0, 4, 8], other);
+        assert!(empty.check().is_err());
+    }
+
+    #[test]
+    fn test_warmup() {
+        let device = Default::default();
+        tiny_diffuser().warmup([64, 64], &device);
+
+        let latent_decoder: LatentDecoder<TestBackend> = LatentDecoderConfig::new().init();
+        latent_decoder.warmup([16, 16], &device);
+    }
+
+    #[test]
+    fn test_latent_to_grayscale() {
+        let latent_decoder: LatentDecoder<TestBackend> = LatentDecoderConfig::new().init();
+        let latent: Tensor<TestBackend, 4> = Tensor::random([1, 4, 2, 2], Distribution::Normal(0.0, 1.0));
+
+        let gray = latent_decoder.latent_to_grayscale(latent.clone());
+        let rgb = latent_decoder.latent_to_image(latent);
+        assert_eq!([gray.width, gray.height], [16, 16]);
+        assert_eq!(gray.buffer[0].len(), 16 * 16);
+
+        // clamping before and after the conversion only agrees for unsaturated pixels
+        let unsaturated = gray.buffer[0].iter().zip(rgb.buffer[0].chunks(3)).filter(|(_, pixel)| pixel.iter().all(|&v| v > 0 && v < 255));
+        for (luma, pixel) in unsaturated {
+            let expected: f64 = pixel.iter().zip(LUMA_WEIGHTS).map(|(&v, w)| v as f64 * w).sum();
+            assert!((*luma as f64 - expected).abs() <= 1.5);
+        }
+    }
+
+    #[test]
+    fn test_latent_to_image_chunked() {
+        let latent_decoder: LatentDecoder<TestBackend> = LatentDecoderConfig::new().init();
+        let latent: Tensor<TestBackend, 4> = Tensor::random([3, 4, 2, 2], Distribution::Normal(0.0, 1.0));
+
+        let whole = latent_decoder.latent_to_image(latent.clone());
+        let chunked = latent_decoder.latent_to_image_chunked(latent, 2);
+        assert_eq!([chunked.width, chunked.height], [16, 16]);
+        assert_eq!(chunked.buffer.len(), 3);
+
+        for (a, b) in whole.buffer.iter().zip(&chunked.buffer) {
+            assert!(a.iter().zip(b).all(|(&a, &b)| (a as i32 - b as i32).abs() <= 1));
+        }
+    }
+
+    #[test]
+    fn test_offset_noise() {
+        let device = Default::default();
+        let noise: Tensor<TestBackend, 4> = random_normal([2, 4, 8, 8], &device);
+
+        let unchanged = offset_noise(noise.clone(), 0.0);
+        assert_eq!(tensor_to_vec(unchanged), tensor_to_vec(noise.clone()));
+
+        // the offset is constant over the spatial dimensions of each channel
+        let shift = offset_noise(noise.clone(), 0.1) - noise;
+        let per_channel = shift.clone().mean_dim(3).mean_dim(2);
+        let spread = (shift - per_channel).abs();
+        assert!(tensor_max_element(spread) < 1e-5);
+    }
+
+    #[test]
+    fn test_quick_preview() {
+        let latent_decoder: LatentDecoder<TestBackend> = LatentDecoderConfig::new().init();
+
+        let latent: Tensor<TestBackend, 4> = Tensor::zeros([2, 4, 3, 5]);
+        let preview = latent_decoder.quick_preview(latent);
+        assert_eq!(preview.dimensions(), (5, 3));
+        assert_eq!(preview.get_pixel(4, 2).0, [141, 125, 127]);
+
+        // only channel 0 is set, so the color is its factors plus the bias
+        let latent = Tensor::cat(vec![Tensor::ones([1, 1, 1, 1]), Tensor::zeros([1, 3, 1, 1])], 1);
+        let preview = latent_decoder.quick_preview(latent);
+        assert_eq!(preview.get_pixel(0, 0).0, [188, 179, 183]);
+    }
+
+    #[test]
+    fn test_visualize_latent() {
+        let latent_decoder: LatentDecoder<TestBackend> = LatentDecoderConfig::new().init();
+
+        let ramp: Tensor<TestBackend, 4> = Tensor::from_floats([[[[-2.0, 0.0], [1.0, 2.0]]]]);
+        let latent = Tensor::cat(vec![Tensor::zeros([1, 1, 2, 2]), ramp], 1);
+
+        let image = latent_decoder.visualize_latent(latent, 1);
+        assert_eq!(image.dimensions(), (16, 16));
+        assert_eq!(image.get_pixel(0, 0).0, [0, 0, 0]);
+        assert_eq!(image.get_pixel(8, 0).0, [128, 128, 128]);
+        assert_eq!(image.get_pixel(7, 8).0, [191, 191, 191]);
+        assert_eq!(image.get_pixel(15, 15).0, [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_scaled_linear_schedule() {
+        let device = Default::default();
+        let alphas_cumprod = tensor_to_vec(scaled_linear_schedule_cumprod::<TestBackend>(1000, 0.00085, 0.012, &device));
+
+        assert_eq!(alphas_cumprod.len(), 1000);
+        assert!((alphas_cumprod[0] - (1.0 - 0.00085)).abs() < 1e-6);
+        let beta_1 = (0.00085f64.sqrt() + (0.012f64.sqrt() - 0.00085f64.sqrt()) / 999.0).powi(2);
+        assert!((alphas_cumprod[1] - (1.0 - 0.00085) * (1.0 - beta_1)).abs() < 1e-6);
+        assert!(alphas_cumprod.windows(2).all(|w| w[1] < w[0]));
+        // diffusers' SDXL schedule ends at about 0.0047
+        assert!((alphas_cumprod[999] - 0.0047).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_zero_terminal_snr() {
+        let device = Default::default();
+        let plain = tensor_to_vec(offset_cosine_schedule_cumprod::<TestBackend>(1000, &device));
+        let zero_snr = tensor_to_vec(offset_cosine_schedule_cumprod_zero_snr::<TestBackend>(1000, &device));
+
+        assert!((zero_snr[0] - plain[0]).abs() < 1e-5);
+        assert!(plain[999] > 1e-4);
+        assert!((zero_snr[999] - ZERO_SNR_MIN_ALPHA_CUMPROD).abs() < 1e-9);
+        assert!(zero_snr.windows(2).all(|w| w[1] <= w[0]));
+
+        let diffuser = tiny_diffuser().with_zero_terminal_snr();
+        let sigmas = diffuser.sigmas(4);
+        assert!(sigmas[0] > 4000.0);
+
+        let diffuser: Diffuser<TestBackend> = DiffuserConfig::new(8, 32, 16, 16).with_zero_terminal_snr(true).init();
+        assert_eq!(tensor_to_vec(diffuser.alpha_cumulative_products.val()).len(), 1000);
+        assert!(diffuser.sigmas(4)[0] > 4000.0);
+    }
+
+    #[test]
+    fn test_check_conditioning() {
+        let diffuser = tiny_diffuser();
+        assert_eq!(diffuser.diffusion.context_dim(), 16);
+        assert_eq!(diffuser.diffusion.adm_in_channels(), 8);
+
+        let conditioning = tiny_conditioning();
+        assert!(diffuser.check_conditioning(&conditioning).is_ok());
+
+        let mut wide = conditioning.clone();
+        wide.context = Tensor::random([1, 7, 32], Distribution::Normal(0.0, 1.0));
+        assert!(diffuser.check_conditioning(&wide).is_err());
+
+        let mut narrow = conditioning;
+        narrow.unconditional_channel_context = Tensor::random([4], Distribution::Normal(0.0, 1.0));
+        assert!(diffuser.check_conditioning(&narrow).is_err());
+    }
+
+    #[test]
+    fn test_color_matched_decode_keeps_tint() {
+        let latent_decoder: LatentDecoder<TestBackend> = LatentDecoderConfig::new().init();
+
+        // a strongly red tinted init image with some texture
+        let (width, height) = (32, 32);
+        let init: Vec<u8> = (0..width * height)
+            .flat_map(|i| [200 + (i % 7) as u8 * 5, 40 + (i % 5) as u8 * 4, 60])
+            .collect();
+
+        let latent = latent_decoder.image_to_latent(&[init.clone()], width, height);
+        let images = latent_decoder.latent_to_image_color_matched(latent, &[init.clone()], width, height);
+
+        let channel_means = |buffer: &[u8]| {
+            let mut means = [0.0; 3];
+            for (i, &v) in buffer.iter().enumerate() {
+                means[i % 3] += v as f64 / (width * height) as f64;
+            }
+            means
+        };
+
+        let expected = channel_means(&init);
+        let actual = channel_means(&images.buffer[0]);
+        for c in 0..3 {
+            assert!((expected[c] - actual[c]).abs() < 3.0, "channel {}: {} vs {}", c, actual[c], expected[c]);
+        }
+    }
+
+    #[test]
+    fn test_encode_image_tiled_uniform_image() {
+        let latent_decoder: LatentDecoder<TestBackend> = LatentDecoderConfig::new().init();
+        let image: Tensor<TestBackend, 4> = Tensor::ones([1, 3, 64, 64]).mul_scalar(0.3);
+
+        // a tile covering the whole image is a plain encode
+        let tiled = latent_decoder.encode_image_tiled(image.clone(), 64, 16).unwrap();
+        let full = latent_decoder.encode_image(image.clone());
+        assert!(tensor_max_element((tiled - full).abs()) < 1e-6);
+
+        // every tile of a uniform image encodes to the same latent, so the blend must stay within its range 
+        // rather than introduce seams
+        let tiled = latent_decoder.encode_image_tiled(image.clone(), 48, 16).unwrap();
+        let tile = latent_decoder.encode_image(image.slice([0..1, 0..3, 0..48, 0..48]));
+        assert_eq!(tiled.dims(), [1, 4, 8, 8]);
+
+        let tile_max = tensor_max_element(tile.clone());
+        let tile_min = -tensor_max_element(-tile);
+        assert!(tensor_max_element(tiled.clone()) <= tile_max + 1e-4);
+        assert!(-tensor_max_element(-tiled) >= tile_min - 1e-4);
+
+        assert!(latent_decoder.encode_image_tiled(image.clone(), 16, 16).is_err());
+        assert!(latent_decoder.encode_image_tiled(image, 20, 8).is_err());
+    }
+
+    #[test]
+    fn test_tile_starts() {
+        assert_eq!(tile_starts(10, 4, 1), vec![0, 3, 6]);
+        assert_eq!(tile_starts(10, 4, 2), vec![0, 2, 4, 6]);
+        // a tile larger than the input is clamped to a single tile
+        assert_eq!(tile_starts(5, 8, 2), vec![0]);
+        assert_eq!(tile_starts(5, 8, 6), vec![0]);
+    }
+
+    #[test]
+    fn test_make_grid_leaves_trailing_cells_blank() {
+        let images: Vec<_> = [10, 20, 30]
+            .iter()
+            .map(|&v| RgbImage::from_pixel(2, 3, Rgb([v, v, v])))
+            .collect();
+
+        let grid = make_grid(&images, 2, 1, [255, 0, 0]);
+        assert_eq!(grid.dimensions(), (7, 9));
+
+        assert_eq!(grid.get_pixel(0, 0), &Rgb([255, 0, 0]));
+        assert_eq!(grid.get_pixel(1, 1), &Rgb([10, 10, 10]));
+        assert_eq!(grid.get_pixel(4, 3), &Rgb([20, 20, 20]));
+        assert_eq!(grid.get_pixel(3, 3), &Rgb([255, 0, 0]));
+        assert_eq!(grid.get_pixel(2, 7), &Rgb([30, 30, 30]));
+        assert_eq!(grid.get_pixel(5, 7), &Rgb([255, 0, 0]));
+    }
+
+    #[test]
+    fn test_decoded_image_saturates() {
+        let decoded: Tensor<TestBackend, 4> = Tensor::from_floats([[[[-1e6, -1.0, 0.0], [1.0, 3.0, 1e6]]]]).repeat(1, 3);
+
+        for clamp in [true, false] {
+            let images = decoded_to_raw_images(decoded.clone(), 1.0, clamp);
+            assert_eq!(images.buffer[0][..], [0, 0, 0, 0, 0, 0, 128, 128, 128, 255, 255, 255, 255, 255, 255, 255, 255, 255]);
+        }
+
+        let images = decoded_to_raw_images(decoded, 2.2, true);
+        assert_eq!(images.buffer[0][..3], [0, 0, 0]);
+        assert_eq!(images.buffer[0][15..], [255, 255, 255]);
+    }
+
+    #[test]
+    fn test_raw_images_channels() {
+        let gray = decoded_to_raw_images(Tensor::<TestBackend, 4>::zeros([2, 1, 4, 5]), 1.0, true);
+        assert_eq!(gray.channels, 1);
+        assert_eq!(gray.color_type(), Some(ColorType::L8));
+        assert!(gray.into_rgb_images().is_err());
+
+        let rgb = decoded_to_raw_images(Tensor::<TestBackend, 4>::zeros([2, 3, 4, 5]), 1.0, true);
+        assert_eq!(rgb.color_type(), Some(ColorType::Rgb8));
+        assert_eq!(rgb.into_rgb_images().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_decoded_to_pixels_reuses_buffer() {
+        let decoded: Tensor<TestBackend, 4> = Tensor::random([2, 3, 4, 5], Distribution::Normal(0.0, 1.0));
+        let images = decoded_to_raw_images(decoded.clone(), 1.0, true);
+
+        let mut out = Vec::with_capacity(1000);
+        out.extend([1, 2, 3]);
+        let capacity = out.capacity();
+
+        decoded_to_pixels(decoded.clone(), 1.0, true, &mut out);
+        assert_eq!(out.len(), 2 * 4 * 5 * 3);
+        assert_eq!(out.capacity(), capacity);
+        assert_eq!(out, images.buffer.concat());
+
+        // a smaller batch shrinks the contents but keeps the allocation
+        decoded_to_pixels(decoded.slice([0..1]), 1.0, true, &mut out);
+        assert_eq!(out.len(), 4 * 5 * 3);
+        assert_eq!(out.capacity(), capacity);
+    }
+
+    #[test]
+    fn test_round_resolution() {
+        assert_eq!(round_resolution([1024, 1024]), [1024, 1024]);
+        // 513x769 (width x height)
+        assert_eq!(round_resolution([769, 513]), [768, 512]);
+        assert_eq!(round_resolution([1004, 1005]), [1000, 1008]);
+        assert_eq!(round_resolution([3, 0]), [8, 8]);
+    }
+
+    #[test]
+    fn test_nearest_resolution() {
+        assert_eq!(nearest_resolution(1024, 1024), [1024, 1024]);
+        assert_eq!(nearest_resolution(512, 512), [1024, 1024]);
+        assert_eq!(nearest_resolution(1920, 1080), [768, 1344]);
+        assert_eq!(nearest_resolution(832, 1216), [1216, 832]);
+        assert_eq!(nearest_resolution(0, 0), nearest_resolution(1, 1));
+        assert_eq!(nearest_resolution(0, 1024), nearest_resolution(1, 1024));
+    }
+
+    #[test]
+    fn test_crop_coords_for() {
+        assert_eq!(crop_coords_for([1024, 1024], [1024, 1024]), [0, 0]);
+        assert_eq!(crop_coords_for([512, 512], [1024, 1024]), [0, 0]);
+        assert_eq!(crop_coords_for([1080, 1920], [1024, 1024]), [0, 398]);
+        assert_eq!(crop_coords_for([2000, 1000], [1024, 1024]), [512, 0]);
+        assert_eq!(crop_coords_for([1216, 832], [1216, 832]), [0, 0]);
+        // a 3:2 landscape photo in the 1152x896 (width x height) bucket
+        assert_eq!(crop_coords_for([1000, 1500], [896, 1152]), [0, 96]);
+    }
+
+    #[test]
+    fn test_sdxl_resolution() {
+        assert_eq!(RESOLUTIONS[20], SdxlResolution::Square1024.dims());
+        assert_eq!(SdxlResolution::Portrait832x1216.dims(), [1216, 832]);
+        assert_eq!(SdxlResolution::Landscape1216x832.width(), 1216);
+
+        for (resolution, dims) in SdxlResolution::ALL.iter().zip(RESOLUTIONS) {
+            assert_eq!(resolution.dims(), dims);
+
+            let name = format!("{:?}", resolution);
+            let expected = match resolution.height().cmp(&resolution.width()) {
+                std::cmp::Ordering::Equal => format!("Square{}", resolution.width()), 
+                std::cmp::Ordering::Greater => format!("Portrait{}x{}", resolution.width(), resolution.height()), 
+                std::cmp::Ordering::Less => format!("Landscape{}x{}", resolution.width(), resolution.height()), 
+            };
+            assert_eq!(name, expected);
+        }
+    }
+
+    #[test]
+    fn test_conditioning_to_backend() {
+        let conditioning = tiny_conditioning();
+        let device = conditioning.context.device();
+
+        let half = conditioning.clone().to_backend::<TchBackend<burn::tensor::f16>>(&device);
+        assert_eq!(half.context.dims(), [1, 7, 16]);
+        assert_eq!(half.resolution, conditioning.resolution);
+
+        let back = half.to_backend::<TestBackend>(&device);
+        assert!(tensor_max_element((back.context - conditioning.context).abs()) < 1e-2);
+    }
+
+    #[test]
+    fn test_conditioning_lerp() {
+        let a = tiny_conditioning();
+        let b = tiny_conditioning();
+
+        let start = a.lerp(&b, 0.0).unwrap();
+        let mid = a.lerp(&b, 0.5).unwrap();
+        let end = a.lerp(&b, 1.0).unwrap();
+
+        assert!(tensor_max_element((start.context - a.context.clone()).abs()) < 1e-6);
+        assert!(tensor_max_element((end.channel_context - b.channel_context.clone()).abs()) < 1e-6);
+
+        let expected_mid = (a.unconditional_context.clone() + b.unconditional_context.clone()) / 2.0;
+        assert!(tensor_max_element((mid.unconditional_context - expected_mid).abs()) < 1e-6);
+
+        let mut other_resolution = tiny_conditioning();
+        other_resolution.resolution = [32, 32];
+        assert!(a.lerp(&other_resolution, 0.5).is_err());
+
+        let mut longer = tiny_conditioning();
+        longer.context = Tensor::random([1, 14, 16], Distribution::Normal(0.0, 1.0));
+        assert!(a.lerp(&longer, 0.5).is_err());
+    }
+
+    #[test]
+    fn test_refiner_conditioning_dims() {
+        let pooled_dim = 8;
+        let refiner: Refiner<TestBackend> = RefinerConfig::new(pooled_dim + 5 * CONDITIONING_EMBEDDING_DIM, 32, 16, 16).init();
+
+        let channel_dim = pooled_dim + 6 * CONDITIONING_EMBEDDING_DIM;
+        let conditioning = Conditioning {
+            unconditional_context: Tensor::random([7, 24], Distribution::Normal(0.0, 1.0)), 
+            context: Tensor::random([2, 7, 24], Distribution::Normal(0.0, 1.0)), 
+            unconditional_channel_context: Tensor::random([channel_dim], Distribution::Normal(0.0, 1.0)), 
+            channel_context: Tensor::random([2, channel_dim], Distribution::Normal(0.0, 1.0)), 
+            resolution: [64, 64], 
+            aesthetic_score: None, 
+            negative_aesthetic_score: None, 
+        };
+
+        let refined = refiner.refiner_conditioning(conditioning.clone());
+        assert_eq!(refined.unconditional_context.dims(), [7, 16]);
+        assert_eq!(refined.context.dims(), [2, 7, 16]);
+        assert_eq!(refined.unconditional_channel_context.dims(), [pooled_dim + 5 * CONDITIONING_EMBEDDING_DIM]);
+        assert_eq!(refined.channel_context.dims(), [2, pooled_dim + 5 * CONDITIONING_EMBEDDING_DIM]);
+
+        // the context keeps its trailing (OpenCLIP) part
+        let expected = conditioning.context.slice([0..2, 0..7, 8..24]);
+        assert!(tensor_max_element((refined.context - expected).abs()) < 1e-6);
+    }
+
+    #[test]
+    fn test_refiner_native_conditioning() {
+        let pooled_dim = 8;
+        let adm_in_channels = pooled_dim + 5 * CONDITIONING_EMBEDDING_DIM;
+        let refiner: Refiner<TestBackend> = RefinerConfig::new(adm_in_channels, 32, 16, 16).init();
+
+        let size: Tensor<TestBackend, 2, Int> = Tensor::from_ints([[64, 64], [64, 64]]);
+        let crop: Tensor<TestBackend, 2, Int> = Tensor::from_ints([[0, 0], [0, 0]]);
+        let channel_context = refiner_channel_context(Tensor::random([2, pooled_dim], Distribution::Normal(0.0, 1.0)), size.clone(), crop.clone(), 6.0);
+        let unconditional_channel_context = refiner_channel_context(Tensor::random([1, pooled_dim], Distribution::Normal(0.0, 1.0)), size.slice([0..1]), crop.slice([0..1]), 2.5);
+        assert_eq!(channel_context.dims(), [2, adm_in_channels]);
+
+        let conditioning = Conditioning {
+            unconditional_context: Tensor::random([7, 16], Distribution::Normal(0.0, 1.0)), 
+            context: Tensor::random([2, 7, 16], Distribution::Normal(0.0, 1.0)), 
+            unconditional_channel_context: unconditional_channel_context.squeeze(0), 
+            channel_context, 
+            resolution: [64, 64], 
+            aesthetic_score: Some(6.0), 
+            negative_aesthetic_score: Some(2.5), 
+        };
+
+        // already in the refiner's layout, so it passes through unchanged
+        let refined = refiner.refiner_conditioning(conditioning.clone());
+        assert_eq!(refined.channel_context.into_data(), conditioning.channel_context.clone().into_data());
+        assert_eq!(refined.context.into_data(), conditioning.context.clone().into_data());
+
+        let latent: Tensor<TestBackend, 4> = Tensor::random([2, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let refined = refiner.refine(conditioning, latent, 0.5, 5.0, 0.0, 4);
+        assert_eq!(refined.dims(), [2, 4, 8, 8]);
+    }
+
+    #[test]
+    fn test_guidance_schedule() {
+        let constant: GuidanceSchedule = 7.5f32.into();
+        assert_eq!(constant.scale(3, 10), 7.5);
+
+        let linear = GuidanceSchedule::Linear { start: 9.0, end: 3.0 };
+        assert_eq!(linear.scale(0, 4), 9.0);
+        assert!((linear.scale(1, 4) - 7.0).abs() < 1e-9);
+        assert_eq!(linear.scale(3, 4), 3.0);
+
+        let cosine = GuidanceSchedule::Cosine { start: 9.0, end: 3.0 };
+        assert!((cosine.scale(0, 5) - 9.0).abs() < 1e-9);
+        assert!((cosine.scale(2, 5) - 6.0).abs() < 1e-9);
+        assert!((cosine.scale(4, 5) - 3.0).abs() < 1e-9);
+        assert!(cosine.scale(1, 5) > linear.scale(1, 5));
+
+        assert_eq!(linear.scale(0, 1), 9.0);
+    }
+
+    #[test]
+    fn test_timesteps_and_sigmas() {
+        let diffuser = tiny_diffuser();
+
+        assert_eq!(diffuser.timesteps(4), vec![999, 749, 499, 249]);
+        assert_eq!(diffuser.timesteps_with_spacing(4, TimestepSpacing::Leading), vec![750, 500, 250, 0]);
+
+        let sigmas = diffuser.sigmas(4);
+        assert_eq!(sigmas.len(), 5);
+        assert_eq!(sigmas[4], 0.0);
+        assert!(sigmas.windows(2).all(|w| w[0] > w[1]));
+    }
+
+    #[test]
+    fn test_spaced_timesteps() {
+        assert_eq!(spaced_timesteps(1000, 4, TimestepSpacing::Leading), vec![750, 500, 250, 0]);
+        assert_eq!(spaced_timesteps(1000, 4, TimestepSpacing::Trailing), vec![999, 749, 499, 249]);
+        assert_eq!(spaced_timesteps(1000, 4, TimestepSpacing::Linspace), vec![999, 666, 333, 0]);
+
+        // every spacing yields exactly the requested number of steps
+        for spacing in [TimestepSpacing::Leading, TimestepSpacing::Trailing, TimestepSpacing::Linspace] {
+            assert_eq!(spaced_timesteps(1000, 30, spacing).len(), 30);
+            assert_eq!(spaced_timesteps(1000, 1, spacing).len(), 1);
+        }
+
+        assert_eq!(spaced_timesteps(1000, 1, TimestepSpacing::Trailing), vec![999]);
+    }
+
+    #[test]
+    fn test_spaced_timesteps_more_steps_than_training_steps() {
+        for spacing in [TimestepSpacing::Leading, TimestepSpacing::Trailing, TimestepSpacing::Linspace] {
+            let timesteps = spaced_timesteps(1000, 2500, spacing);
+
+            assert!(timesteps.windows(2).all(|pair| pair[0] > pair[1]), "{:?} timesteps aren't strictly decreasing", spacing);
+            assert!(timesteps.iter().all(|&t| t < 1000), "{:?} timesteps are out of range", spacing);
+        }
+    }
+
+    #[test]
+    fn test_karras_sigmas() {
+        let sigmas = karras_sigmas(10, 0.03, 15.0, 7.0);
+
+        assert_eq!(sigmas.len(), 10);
+        assert!((sigmas[0] - 15.0).abs() < 1e-9);
+        assert!((sigmas[9] - 0.03).abs() < 1e-9);
+        assert!(sigmas.windows(2).all(|w| w[0] > w[1]));
+    }
+
+    /// Encodes every word as token 1.
+    struct WordTokenizer;
+
+    impl Tokenizer for WordTokenizer {
+        fn encode(&self, text: &str, add_sot: bool, add_eot: bool) -> Vec<u32> {
+            iter::once(self.start_of_text_token()).filter(|_| add_sot)
+                .chain(text.split_whitespace().map(|_| 1))
+                .chain(iter::once(self.end_of_text_token()).filter(|_| add_eot))
+                .collect()
+        }
+
+        fn decode_raw(&self, tokens: &[u32]) -> String {
+            vec!["word"; tokens.len()].join(" ")
+        }
+
+        fn start_of_text_token(&self) -> u32 {
+            2
+        }
+
+        fn end_of_text_token(&self) -> u32 {
+            3
+        }
+
+        fn padding_token(&self) -> u32 {
+            0
+        }
+    }
+
+    #[test]
+    fn test_empty_prompt_tokens() {
+        let device = Default::default();
+
+        let tokens = tokenize_text::<TestBackend, _>("", &WordTokenizer, 77, &device);
+        let mut expected = vec![0; 77];
+        expected[0] = 2;
+        expected[1] = 3;
+        assert_eq!(tokens.into_data().value, expected);
+
+        // the weighted and chunked paths lay the empty prompt out the same way, without weights
+        let (tokens, weights) = tokenize_texts_weighted::<TestBackend, _>(&[""], &WordTokenizer, 77, &device).unwrap();
+        assert_eq!(tokens.into_data().value, expected);
+        assert!(weights.is_none());
+
+        let chunks = tokenize_texts_chunked::<TestBackend, _>(&[""], &WordTokenizer, 77, &device);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].0.clone().into_data().value, expected);
+
+        // CLIP pads with the end of text token
+        let tokens = tokenize_text::<TestBackend, _>("", &SimpleTokenizer::new().unwrap(), 77, &device);
+        let mut expected = vec![49407; 77];
+        expected[0] = 49406;
+        assert_eq!(tokens.into_data().value, expected);
+    }
+
+    #[test]
+    fn test_long_prompt_is_rejected() {
+        let prompt = vec!["word"; 76].join(" ");
+        assert_eq!(
+            WordTokenizer.encode_checked(&prompt, true, true), 
+            Err(TooManyTokens { n_tokens: 78, max_tokens: 77 })
+        );
+
+        let result = tokenize_texts_weighted::<TestBackend, _>(&["word", &prompt], &WordTokenizer, 77, &Default::default());
+        assert_eq!(result.err(), Some(TooManyTokens { n_tokens: 78, max_tokens: 77 }));
+
+        let prompt = vec!["word"; 75].join(" ");
+        assert_eq!(WordTokenizer.encode_checked(&prompt, true, true).unwrap().len(), 77);
+    }
+
+    #[test]
+    fn test_chunked_context_long_prompt() {
+        let clip: CLIP<TestBackend> = CLIPConfig::new(8, 16, 16, 2, 77, 2, true).init();
+        let prompt = vec!["word"; 200].join(" ");
+
+        let chunks = tokenize_texts_chunked::<TestBackend, _>(&[&prompt], &WordTokenizer, 77, &Default::default());
+        assert_eq!(chunks.len(), 3);
+        for (tokens, weights) in &chunks {
+            assert_eq!(tokens.dims(), [1, 77]);
+            assert!(weights.is_none());
+        }
+
+        let context = texts_to_context_clip_chunked(&[&prompt], &clip, &WordTokenizer, 1).unwrap();
+        assert_eq!(context.dims(), [1, 77 * 3, 16]);
+    }
+}
@@ -0,0 +1,88 @@
+use std::error::Error;
+
+use burn::{
+    module::Module,
+    tensor::backend::Backend,
+};
+
+use super::{Embedder, Diffuser, LatentDecoder, RawImages, random_seed};
+use super::metadata::GenerationParams;
+
+/// Runs the pipeline with sequential offloading: all three models are kept on `storage_device` (usually the CPU) 
+/// and each is only moved to `compute_device` for its own stage, so at most one model occupies GPU memory at a time. 
+/// Moving the weights costs some time every run but lets SDXL fit on cards that cannot hold every stage at once.
+pub struct StagedPipeline<B: Backend> {
+    embedder: Option<Embedder<B>>, 
+    diffuser: Option<Diffuser<B>>, 
+    latent_decoder: Option<LatentDecoder<B>>, 
+    compute_device: B::Device, 
+    storage_device: B::Device, 
+}
+
+impl<B: Backend> StagedPipeline<B> {
+    pub fn new(embedder: Embedder<B>, diffuser: Diffuser<B>, latent_decoder: LatentDecoder<B>, compute_device: B::Device, storage_device: B::Device) -> Self {
+        Self {
+            embedder: Some( embedder.to_device(&storage_device) ), 
+            diffuser: Some( diffuser.to_device(&storage_device) ), 
+            latent_decoder: Some( latent_decoder.to_device(&storage_device) ), 
+            compute_device, 
+            storage_device, 
+        }
+    }
+
+    /// Generates images for `text` at the training resolution nearest to `width` x `height`.
+    pub fn run(&mut self, text: &str, negative: &str, width: u32, height: u32, unconditional_guidance_scale: f64, n_steps: usize) -> Result<RawImages, Box<dyn Error>> {
+        let (images, _) = self.run_with_seed(text, negative, width, height, unconditional_guidance_scale, n_steps, random_seed())?;
+        Ok(images)
+    }
+
+    /// Like `run`, but samples deterministically from `seed` and also returns the settings that were used, 
+    /// ready to be saved with `metadata::save_images_with_metadata`.
+    pub fn run_with_seed(&mut self, text: &str, negative: &str, width: u32, height: u32, unconditional_guidance_scale: f64, n_steps: usize, seed: u64) -> Result<(RawImages, GenerationParams), Box<dyn Error>> {
+        let (conditioning, [resolution_height, resolution_width]) = run_offloaded(&mut self.embedder, &self.compute_device, &self.storage_device, |embedder| {
+            embedder.text_to_conditioning_for_size(text, negative, width, height, 1)
+        })?;
+
+        let latent = run_offloaded(&mut self.diffuser, &self.compute_device, &self.storage_device, |diffuser| {
+            diffuser.sample_latent_with_seed(conditioning, unconditional_guidance_scale, 0.0, n_steps, seed, 0.0)
+        });
+
+        let images = run_offloaded(&mut self.latent_decoder, &self.compute_device, &self.storage_device, |latent_decoder| {
+            latent_decoder.latent_to_image(latent)
+        });
+
+        let params = GenerationParams {
+            prompt: text.to_string(), 
+            negative_prompt: negative.to_string(), 
+            seed, 
+            n_steps, 
+            guidance_scale: unconditional_guidance_scale, 
+            sampler: "DDIM".to_string(), 
+            width: resolution_width as u32, 
+            height: resolution_height as u32, 
+        };
+
+        Ok( (images, params) )
+    }
+
+    pub fn into_parts(self) -> (Embedder<B>, Diffuser<B>, LatentDecoder<B>) {
+        (
+            self.embedder.expect("Embedder is always present outside of a stage."), 
+            self.diffuser.expect("Diffuser is always present outside of a stage."), 
+            self.latent_decoder.expect("Latent decoder is always present outside of a stage."), 
+        )
+    }
+}
+
+/// Moves the module in `slot` to `compute_device`, runs `stage` with it and moves it back to `storage_device`.
+fn run_offloaded<B: Backend, M: Module<B>, T>(slot: &mut Option<M>, compute_device: &B::Device, storage_device: &B::Device, stage: impl FnOnce(&M) -> T) -> T {
+    let module = slot
+        .take()
+        .expect("Module is always present outside of a stage.")
+        .to_device(compute_device);
+
+    let output = stage(&module);
+
+    *slot = Some( module.to_device(storage_device) );
+    output
+}
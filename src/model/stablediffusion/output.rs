@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+
+use image::{
+    codecs::jpeg::JpegEncoder,
+    codecs::webp::{WebPEncoder, WebPQuality},
+};
+
+use super::RawImages;
+
+/// The file format `save_images_format` writes. Lossy formats carry a quality from 1 (smallest) to 100 (best).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Png, 
+    Jpeg(u8), 
+    WebpLossless, 
+    WebpLossy(u8), 
+}
+
+impl OutputFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "png", 
+            OutputFormat::Jpeg(_) => "jpg", 
+            OutputFormat::WebpLossless | OutputFormat::WebpLossy(_) => "webp", 
+        }
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        match self {
+            OutputFormat::Jpeg(quality) | OutputFormat::WebpLossy(quality) if !(1..=100).contains(quality) => {
+                Err( format!("The quality of {:?} must be between 1 and 100.", self).into() )
+            }, 
+            _ => Ok(()), 
+        }
+    }
+}
+
+/// Like `save_images` in the binaries, writing `{basepath}{index}.{extension}` for each RGB image, 
+/// or single-channel one such as those of `LatentDecoder::latent_to_grayscale`, but in any `OutputFormat`.
+/// Lossless WebP is typically a good deal smaller than PNG for the same pixels.
+pub fn save_images_format(images: &RawImages, basepath: &str, format: OutputFormat) -> Result<(), Box<dyn Error>> {
+    format.validate()?;
+
+    let color = images.color_type().ok_or_else(|| format!("Can't save images with {} channels.", images.channels))?;
+    let (width, height) = (images.width as u32, images.height as u32);
+
+    for (index, img_data) in images.buffer.iter().enumerate() {
+        let path = format!("{}{}.{}", basepath, index, format.extension());
+
+        match format {
+            OutputFormat::Png => image::save_buffer(path, &img_data[..], width, height, color)?, 
+            OutputFormat::Jpeg(quality) => {
+                let file = BufWriter::new( File::create(path)? );
+                JpegEncoder::new_with_quality(file, quality).encode(&img_data[..], width, height, color)?;
+            }, 
+            OutputFormat::WebpLossless => {
+                let file = BufWriter::new( File::create(path)? );
+                WebPEncoder::new_with_quality(file, WebPQuality::lossless()).encode(&img_data[..], width, height, color)?;
+            }, 
+            OutputFormat::WebpLossy(quality) => {
+                let file = BufWriter::new( File::create(path)? );
+                WebPEncoder::new_with_quality(file, WebPQuality::lossy(quality)).encode(&img_data[..], width, height, color)?;
+            }, 
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_save_images_format() {
+        let (width, height) = (16, 8);
+        let images = RawImages {
+            buffer: vec![(0..width * height * 3).map(|i| i as u8).collect()], 
+            width, 
+            height, 
+            channels: 3, 
+        };
+        let basepath = std::env::temp_dir().join(format!("sdxl_output_format_{}_", std::process::id()));
+        let basepath = basepath.to_str().unwrap();
+
+        for format in [OutputFormat::Png, OutputFormat::Jpeg(90), OutputFormat::WebpLossless, OutputFormat::WebpLossy(80)] {
+            save_images_format(&images, basepath, format).unwrap();
+
+            let path = format!("{}0.{}", basepath, format.extension());
+            let image = image::open(&path).unwrap();
+            assert_eq!((image.width() as usize, image.height() as usize), (width, height));
+
+            if format == OutputFormat::WebpLossless {
+                assert_eq!(image.to_rgb8().into_raw(), images.buffer[0]);
+            }
+
+            std::fs::remove_file(path).ok();
+        }
+
+        assert!(save_images_format(&images, basepath, OutputFormat::Jpeg(0)).is_err());
+        assert!(save_images_format(&images, basepath, OutputFormat::WebpLossy(101)).is_err());
+    }
+}
@@ -0,0 +1,348 @@
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+use burn::{
+    config::Config, 
+    module::Module,
+    tensor::{
+        backend::Backend,
+        Tensor,
+    },
+};
+
+use image::RgbImage;
+
+use super::{Conditioning, Embedder, EmbedderConfig, Diffuser, DiffuserConfig, LatentDecoder, LatentDecoderConfig, OutpaintDirection, TimestepSpacing, outpaint_canvas, outpaint_mask, random_seed, round_resolution};
+use crate::model::load::{load_config, load_record};
+
+/// What to generate with `Pipeline::generate`. Only the prompt is required, e.g.
+/// `GenerationRequest::new("a photo of a cat".into()).with_seed(Some(42))`.
+#[derive(Config, Debug)]
+pub struct GenerationRequest {
+    pub prompt: String, 
+    /// Defaults to an empty negative prompt.
+    pub negative_prompt: Option<String>, 
+    #[config(default = 30)]
+    pub n_steps: usize, 
+    #[config(default = 7.5)]
+    pub guidance_scale: f64, 
+    /// Defaults to a random seed.
+    pub seed: Option<u64>, 
+    /// Rounded to multiples of 8, see `round_resolution`.
+    #[config(default = 1024)]
+    pub width: u32, 
+    #[config(default = 1024)]
+    pub height: u32, 
+}
+
+/// Which device each stage of a `Pipeline` runs on, e.g. the embedder on a second GPU to leave the first one 
+/// to the diffuser. Between stages only the `Conditioning` (a few MiB) and the latent (256 KiB per 1024x1024 
+/// image in f32) are copied, which is negligible next to a denoising step.
+#[derive(Clone, Debug)]
+pub struct StageDevices<B: Backend> {
+    pub embedder: B::Device, 
+    pub diffuser: B::Device, 
+    pub latent_decoder: B::Device, 
+}
+
+impl<B: Backend> StageDevices<B> {
+    /// Every stage on `device`.
+    pub fn single(device: &B::Device) -> Self {
+        Self {
+            embedder: device.clone(), 
+            diffuser: device.clone(), 
+            latent_decoder: device.clone(), 
+        }
+    }
+}
+
+/// Wall clock timings of a `Pipeline::generate_with_telemetry` run, for tuning step counts and backends. 
+/// GPU backends queue work asynchronously, so every stage and step waits for its result before it is timed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Telemetry {
+    /// Embedding the prompts, including moving the conditioning to the diffuser's device.
+    pub embed: Duration, 
+    /// The whole denoising loop.
+    pub diffuse: Duration, 
+    /// Decoding the latent to images.
+    pub decode: Duration, 
+    /// Each denoising step in order, summing to about `diffuse`.
+    pub steps: Vec<Duration>, 
+}
+
+impl Telemetry {
+    pub fn total(&self) -> Duration {
+        self.embed + self.diffuse + self.decode
+    }
+
+    /// The mean denoising step time, zero without steps.
+    pub fn mean_step(&self) -> Duration {
+        match self.steps.len() {
+            0 => Duration::ZERO, 
+            n_steps => self.steps.iter().sum::<Duration>() / n_steps as u32, 
+        }
+    }
+}
+
+/// The embedder, diffuser and latent decoder together, for generating images in a single call. The stages can be 
+/// placed on different devices, see `StageDevices`. See `offload::StagedPipeline` for a pipeline that only keeps 
+/// one model on the GPU at a time.
+pub struct Pipeline<B: Backend> {
+    embedder: Embedder<B>, 
+    diffuser: Diffuser<B>, 
+    latent_decoder: LatentDecoder<B>, 
+}
+
+impl<B: Backend> Pipeline<B> {
+    pub fn new(embedder: Embedder<B>, diffuser: Diffuser<B>, latent_decoder: LatentDecoder<B>) -> Self {
+        Self {
+            embedder, 
+            diffuser, 
+            latent_decoder, 
+        }
+    }
+
+    /// Loads the `embedder`, `diffuser` and `latent_decoder` configs and records converted into `model_dir`, 
+    /// as used by the sample binary.
+    pub fn load(model_dir: &str, device: &B::Device) -> Result<Self, Box<dyn Error>> {
+        Self::load_with_devices(model_dir, &StageDevices::single(device))
+    }
+
+    /// Like `load`, but puts every stage on its own device.
+    pub fn load_with_devices(model_dir: &str, devices: &StageDevices<B>) -> Result<Self, Box<dyn Error>> {
+        let embedder_path = format!("{}/embedder", model_dir);
+        let embedder: EmbedderConfig = load_config(&format!("{}.cfg", embedder_path))?;
+        let embedder = load_record(embedder.init(), &embedder_path)?;
+
+        let diffuser_path = format!("{}/diffuser", model_dir);
+        let diffuser: DiffuserConfig = load_config(&format!("{}.cfg", diffuser_path))?;
+        let diffuser = load_record(diffuser.init(), &diffuser_path)?;
+
+        let latent_decoder_path = format!("{}/latent_decoder", model_dir);
+        let latent_decoder: LatentDecoderConfig = load_config(&format!("{}.cfg", latent_decoder_path))?;
+        let latent_decoder = load_record(latent_decoder.init(), &latent_decoder_path)?;
+
+        Ok( Self::new(embedder, diffuser, latent_decoder).to_devices(devices) )
+    }
+
+    /// Moves every stage to its device.
+    pub fn to_devices(self, devices: &StageDevices<B>) -> Self {
+        Self::new(
+            self.embedder.to_device(&devices.embedder), 
+            self.diffuser.to_device(&devices.diffuser), 
+            self.latent_decoder.to_device(&devices.latent_decoder), 
+        )
+    }
+
+    /// The devices the stages are currently on.
+    pub fn devices(&self) -> StageDevices<B> {
+        StageDevices {
+            embedder: self.embedder.devices()[0].clone(), 
+            diffuser: self.diffuser.devices()[0].clone(), 
+            latent_decoder: self.latent_decoder.devices()[0].clone(), 
+        }
+    }
+
+    pub fn generate(&self, request: GenerationRequest) -> Result<Vec<RgbImage>, Box<dyn Error>> {
+        self.generate_timed(request, None)
+    }
+
+    /// Like `generate`, but also returns how long each stage and each denoising step took. `generate` skips 
+    /// the timing entirely, including the synchronization after every step.
+    pub fn generate_with_telemetry(&self, request: GenerationRequest) -> Result<(Vec<RgbImage>, Telemetry), Box<dyn Error>> {
+        let mut telemetry = Telemetry::default();
+        let images = self.generate_timed(request, Some(&mut telemetry))?;
+
+        Ok( (images, telemetry) )
+    }
+
+    fn generate_timed(&self, request: GenerationRequest, mut telemetry: Option<&mut Telemetry>) -> Result<Vec<RgbImage>, Box<dyn Error>> {
+        let start = Instant::now();
+        let conditioning = self.conditioning(&request, [request.height as usize, request.width as usize])?;
+
+        let devices = self.devices();
+
+        let seed = request.seed.unwrap_or_else(random_seed);
+        let latent = match telemetry.as_deref_mut() {
+            Some(telemetry) => {
+                sync(&conditioning.context);
+                telemetry.embed = start.elapsed();
+
+                // the same initial latent and deterministic DDIM steps as `sample_latent_with_seed`
+                let start = Instant::now();
+                let mut step_start = start;
+                let mut sampler = self.diffuser.ddim_sampler(0.0);
+
+                B::seed(seed);
+                let latent = self.diffuser.sample_latent_with_sampler_and_callback(conditioning, request.guidance_scale, 0.0, request.n_steps, &mut sampler, TimestepSpacing::Uniform, |_, _, latent| {
+                    sync(latent);
+                    telemetry.steps.push(step_start.elapsed());
+                    step_start = Instant::now();
+                });
+                telemetry.diffuse = start.elapsed();
+
+                latent
+            }, 
+            None => self.diffuser.sample_latent_with_seed(conditioning, request.guidance_scale, 0.0, request.n_steps, seed, 0.0), 
+        };
+
+        let start = Instant::now();
+        let images = self.latent_decoder.latent_to_rgb_images(latent.to_device(&devices.latent_decoder))?;
+        if let Some(telemetry) = telemetry {
+            telemetry.decode = start.elapsed();
+        }
+
+        Ok(images)
+    }
+
+    /// Image-to-image generation from `init_image`, see `Diffuser::sample_latent_from_image`. The request's 
+    /// width and height are ignored in favor of the init image's size, whose sides must be multiples of 8. 
+    /// With `color_match` the output's per-channel color statistics are matched to the init image, 
+    /// see `LatentDecoder::latent_to_image_color_matched`, which prevents drift when iterating img2img.
+    pub fn img2img(&self, request: GenerationRequest, init_image: &RgbImage, strength: f32, color_match: bool) -> Result<Vec<RgbImage>, Box<dyn Error>> {
+        let (width, height) = (init_image.width() as usize, init_image.height() as usize);
+        if round_resolution([height, width]) != [height, width] {
+            return Err( format!("The init image size {}x{} must be a multiple of 8 on both sides.", width, height).into() );
+        }
+
+        let conditioning = self.conditioning(&request, [height, width])?;
+
+        let devices = self.devices();
+
+        let init = vec![init_image.as_raw().clone()];
+        let init_latent = self.latent_decoder.image_to_latent(&init, width, height).to_device(&devices.diffuser);
+
+        B::seed(request.seed.unwrap_or_else(random_seed));
+        let latent = self.diffuser.sample_latent_from_image(conditioning, init_latent, strength, request.guidance_scale, 0.0, request.n_steps);
+        let latent = latent.to_device(&devices.latent_decoder);
+
+        let images = if color_match {
+            self.latent_decoder.latent_to_image_color_matched(latent, &init, width, height)
+        } else {
+            self.latent_decoder.latent_to_image(latent)
+        };
+
+        images.into_rgb_images()
+    }
+
+    /// Extends `init_image` by `pixels` towards `direction` and generates the new border, see `outpaint_canvas`. 
+    /// This is inpainting with the mask covering the border: the original image's latents are re-noised to every 
+    /// step's timestep and pasted back, so only the border is free to change. The init image sides and `pixels` 
+    /// must be multiples of 8; the request's width and height are ignored in favor of the extended canvas size.
+    pub fn outpaint(&self, request: GenerationRequest, init_image: &RgbImage, direction: OutpaintDirection, pixels: u32) -> Result<Vec<RgbImage>, Box<dyn Error>> {
+        let (width, height) = (init_image.width() as usize, init_image.height() as usize);
+        if round_resolution([height, width]) != [height, width] || pixels % 8 != 0 {
+            return Err( format!("The init image size {}x{} and the outpainted {} pixels must be multiples of 8.", width, height, pixels).into() );
+        }
+
+        let (canvas, known) = outpaint_canvas(init_image, direction, pixels)?;
+        let (width, height) = (canvas.width() as usize, canvas.height() as usize);
+
+        let conditioning = self.conditioning(&request, [height, width])?;
+
+        let devices = self.devices();
+
+        let init_latent = self.latent_decoder.image_to_latent(&[canvas.into_raw()], width, height).to_device(&devices.diffuser);
+        let mask = outpaint_mask([height, width], known, &devices.diffuser)?;
+
+        B::seed(request.seed.unwrap_or_else(random_seed));
+        let latent = self.diffuser.sample_latent_inpaint(conditioning, init_latent, mask, request.guidance_scale, 0.0, request.n_steps);
+
+        self.latent_decoder.latent_to_rgb_images(latent.to_device(&devices.latent_decoder))
+    }
+
+    /// Embeds the request's prompts on the embedder's device and moves the conditioning to the diffuser's.
+    fn conditioning(&self, request: &GenerationRequest, resolution: [usize; 2]) -> Result<Conditioning<B>, Box<dyn Error>> {
+        let devices = self.devices();
+        let device = devices.embedder;
+
+        let [height, width] = round_resolution(resolution);
+        let resolution = [height as i32, width as i32];
+
+        let size = Tensor::from_ints(resolution).to_device(&device).unsqueeze();
+        let crop = Tensor::from_ints([0, 0]).to_device(&device).unsqueeze();
+        let ar = Tensor::from_ints(resolution).to_device(&device);
+
+        let negative = request.negative_prompt.as_deref().unwrap_or("");
+        let conditioning = self.embedder
+            .text_to_conditioning_with_negative(&request.prompt, negative, size, crop, ar, 1)?
+            .to_device(&devices.diffuser);
+        self.diffuser.check_conditioning(&conditioning)?;
+
+        Ok(conditioning)
+    }
+
+    pub fn into_parts(self) -> (Embedder<B>, Diffuser<B>, LatentDecoder<B>) {
+        (self.embedder, self.diffuser, self.latent_decoder)
+    }
+}
+
+/// Waits for `x` to be computed by reading back a single element.
+fn sync<B: Backend, const D: usize>(x: &Tensor<B, D>) {
+    let _ = x.clone().flatten::<1>(0, D - 1).slice([0..1]).into_data();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn::tensor::Distribution;
+
+    #[test]
+    fn test_generation_request_defaults() {
+        let request = GenerationRequest::new("a photo of a cat".into());
+        assert_eq!(request.negative_prompt, None);
+        assert_eq!(request.n_steps, 30);
+        assert_eq!(request.guidance_scale, 7.5);
+        assert_eq!(request.seed, None);
+        assert_eq!([request.width, request.height], [1024, 1024]);
+
+        let request = request.with_seed(Some(42)).with_width(832).with_height(1216);
+        assert_eq!(request.seed, Some(42));
+        assert_eq!([request.width, request.height], [832, 1216]);
+    }
+
+    #[test]
+    fn test_telemetry() {
+        let telemetry = Telemetry {
+            embed: Duration::from_millis(100), 
+            diffuse: Duration::from_millis(300), 
+            decode: Duration::from_millis(50), 
+            steps: vec![Duration::from_millis(100), Duration::from_millis(200)], 
+        };
+
+        assert_eq!(telemetry.total(), Duration::from_millis(450));
+        assert_eq!(telemetry.mean_step(), Duration::from_millis(150));
+        assert_eq!(Telemetry::default().mean_step(), Duration::ZERO);
+    }
+
+    #[test]
+    fn test_ndarray_pipeline() {
+        type TestBackend = burn_ndarray::NdArrayBackend<f32>;
+
+        // the embedder needs the OpenCLIP vocabulary generated by tokenizer/convert.py, so the conditioning 
+        // is random, shaped like a tiny embedder's: two 16 wide text encoders and six 256 wide size embeddings
+        let context_dim = 2 * 16;
+        let adm_in_channels = 16 + 6 * 256;
+        let conditioning = Conditioning {
+            unconditional_context: Tensor::random([77, context_dim], Distribution::Normal(0.0, 1.0)), 
+            context: Tensor::random([1, 77, context_dim], Distribution::Normal(0.0, 1.0)), 
+            unconditional_channel_context: Tensor::random([adm_in_channels], Distribution::Normal(0.0, 1.0)), 
+            channel_context: Tensor::random([1, adm_in_channels], Distribution::Normal(0.0, 1.0)), 
+            resolution: [32, 32], 
+            aesthetic_score: None, 
+            negative_aesthetic_score: None, 
+        };
+
+        // a tiny UNet, but the real autoencoder
+        let diffuser: Diffuser<TestBackend> = DiffuserConfig::new(adm_in_channels, 32, 16, context_dim).init();
+        let latent_decoder: LatentDecoder<TestBackend> = LatentDecoderConfig::new().init();
+        diffuser.check_conditioning(&conditioning).unwrap();
+
+        let latent = diffuser.sample_latent_with_seed(conditioning, 7.5, 0.0, 2, 0, 0.0);
+        let images = latent_decoder.latent_to_image(latent);
+
+        assert_eq!([images.width, images.height], [32, 32]);
+        assert_eq!(images.buffer.len(), 1);
+        assert_eq!(images.buffer[0].len(), 32 * 32 * 3);
+    }
+}
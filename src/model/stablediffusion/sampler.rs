@@ -0,0 +1,751 @@
+use std::error::Error;
+use std::str::FromStr;
+
+use burn::tensor::{
+    backend::Backend,
+    Tensor,
+    Data,
+    Shape,
+};
+
+use super::scheduler::Scheduler;
+use crate::helper::{random_normal, tensor_to_vec, tensor_max_scalar, tensor_min_scalar};
+
+pub trait Sampler<B: Backend> {
+    /// Moves `latent` from `timestep` to `prev_timestep` given the noise predicted by the diffusion model at `timestep`. 
+    /// `prev_timestep` is `None` for the final step, which fully denoises the latent.
+    fn step(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4>;
+
+    /// Like `step`, but also returns the sampler's estimate of the fully denoised latent (x0) at `timestep`, 
+    /// if it has one. For a noise prediction `eps` this is `x0 = (x - sqrt(1 - alpha_cumprod) * eps) / sqrt(alpha_cumprod)`, 
+    /// see `Scheduler::predict_x0`. Decoding it gives a sharper preview than the noisy latent. 
+    /// By default there is no estimate.
+    fn step_with_x0(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        (self.step(model_output, timestep, prev_timestep, latent), None)
+    }
+
+    /// Like `step`, but gets the diffusion model itself as `model(latent, timestep)`, returning the predicted noise, 
+    /// so that samplers can evaluate it more than once per step. By default it evaluates the model once at `latent` 
+    /// and calls `step`.
+    fn step_with_model(&mut self, model: &mut dyn FnMut(Tensor<B, 4>, usize) -> Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        let model_output = model(latent.clone(), timestep);
+        self.step(model_output, timestep, prev_timestep, latent)
+    }
+
+    /// Like `step_with_model`, but also returns the x0 estimate of `step_with_x0`. The diffusers sample through 
+    /// this method, so samplers overriding `step_with_model` should override it too.
+    fn step_with_model_and_x0(&mut self, model: &mut dyn FnMut(Tensor<B, 4>, usize) -> Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        let model_output = model(latent.clone(), timestep);
+        self.step_with_x0(model_output, timestep, prev_timestep, latent)
+    }
+}
+
+/// The samplers by name, for picking one from the command line or a config, see `Diffuser::sampler`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SamplerKind {
+    /// Deterministic DDIM, `DdimSampler` with an `eta` of 0.
+    Ddim, 
+    EulerAncestral, 
+    DpmPlusPlus2m, 
+    Heun, 
+    /// `LmsSampler` of order 4.
+    Lms, 
+}
+
+impl SamplerKind {
+    pub const ALL: [SamplerKind; 5] = [SamplerKind::Ddim, SamplerKind::EulerAncestral, SamplerKind::DpmPlusPlus2m, SamplerKind::Heun, SamplerKind::Lms];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SamplerKind::Ddim => "ddim", 
+            SamplerKind::EulerAncestral => "euler_a", 
+            SamplerKind::DpmPlusPlus2m => "dpmpp_2m", 
+            SamplerKind::Heun => "heun", 
+            SamplerKind::Lms => "lms", 
+        }
+    }
+}
+
+impl FromStr for SamplerKind {
+    type Err = Box<dyn Error>;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        SamplerKind::ALL
+            .into_iter()
+            .find(|kind| kind.name() == name)
+            .ok_or_else(|| {
+                let names: Vec<_> = SamplerKind::ALL.iter().map(|kind| kind.name()).collect();
+                format!("Unknown sampler '{}', expected one of {}.", name, names.join(", ")).into()
+            })
+    }
+}
+
+
+/// Denoising Diffusion Implicit Models sampler. 
+/// `eta` scales the noise injected each step: 0.0 is fully deterministic, 1.0 matches DDPM.
+pub struct DdimSampler {
+    scheduler: Scheduler, 
+    eta: f64, 
+}
+
+impl DdimSampler {
+    pub fn new(scheduler: &Scheduler, eta: f64) -> Self {
+        Self {
+            scheduler: scheduler.clone(), 
+            eta, 
+        }
+    }
+}
+
+impl<B: Backend> Sampler<B> for DdimSampler {
+    fn step(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        self.step_with_x0(model_output, timestep, prev_timestep, latent).0
+    }
+
+    fn step_with_x0(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        let current_alpha = self.scheduler.alpha_cumprod(timestep);
+        let prev_alpha = self.scheduler.alpha_cumprod_or_clean(prev_timestep);
+
+        let sigma = self.eta * ( (1.0 - prev_alpha) / (1.0 - current_alpha) * (1.0 - current_alpha / prev_alpha) ).sqrt();
+
+        let predx0 = self.scheduler.predict_x0(latent.clone(), model_output.clone(), timestep);
+        let dir_latent = model_output * (1.0 - prev_alpha - sigma * sigma).sqrt();
+
+        let prev_latent = predx0.clone() * prev_alpha.sqrt() + dir_latent;
+
+        let prev_latent = if sigma > 0.0 {
+            prev_latent + gen_noise(&latent) * sigma
+        } else {
+            prev_latent
+        };
+
+        (prev_latent, Some(predx0))
+    }
+}
+
+
+/// Euler ancestral sampler operating in sigma space, where `sigma = sqrt((1 - alpha_cumprod) / alpha_cumprod)`. 
+/// Each step takes an Euler step down to `sigma_down` and then adds fresh noise of scale `sigma_up`. 
+/// The noise is drawn from the backend RNG, so seeding the run with `B::seed` makes it reproducible; 
+/// `Diffuser::sample_latent_with_noise_seed` seeds it independently of the initial latent.
+pub struct EulerAncestralSampler {
+    scheduler: Scheduler, 
+}
+
+impl EulerAncestralSampler {
+    pub fn new(scheduler: &Scheduler) -> Self {
+        Self {
+            scheduler: scheduler.clone(), 
+        }
+    }
+}
+
+impl<B: Backend> Sampler<B> for EulerAncestralSampler {
+    fn step(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        self.step_with_x0(model_output, timestep, prev_timestep, latent).0
+    }
+
+    fn step_with_x0(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        let current_alpha = self.scheduler.alpha_cumprod(timestep);
+        let prev_alpha = self.scheduler.alpha_cumprod_or_clean(prev_timestep);
+
+        let sigma_from = self.scheduler.sigma(timestep);
+        let sigma_to = self.scheduler.sigma_or_clean(prev_timestep);
+
+        let sigma_up = (sigma_to * sigma_to * (sigma_from * sigma_from - sigma_to * sigma_to) / (sigma_from * sigma_from))
+            .sqrt()
+            .min(sigma_to);
+        let sigma_down = (sigma_to * sigma_to - sigma_up * sigma_up).sqrt();
+
+        // move into sigma space, where the latent is x0 + sigma * noise
+        let x = latent.clone() / current_alpha.sqrt();
+        let denoised = x.clone() - model_output * sigma_from;
+
+        let derivative = (x.clone() - denoised.clone()) / sigma_from;
+        let x = x + derivative * (sigma_down - sigma_from);
+
+        let x = if sigma_up > 0.0 {
+            x + gen_noise(&latent) * sigma_up
+        } else {
+            x
+        };
+
+        // x0 is the same in sigma space and in the latent's own scale
+        (x * prev_alpha.sqrt(), Some(denoised))
+    }
+}
+
+
+/// DPM-Solver++(2M) sampler operating in sigma space. 
+/// It reuses the previous step's denoised estimate to form a second order update, 
+/// falling back to a first order update on the first step and on the final step to sigma = 0.
+pub struct DpmPlusPlus2mSampler<B: Backend> {
+    scheduler: Scheduler, 
+    prev_denoised: Option<Tensor<B, 4>>, 
+    prev_sigma: Option<f64>, 
+}
+
+impl<B: Backend> DpmPlusPlus2mSampler<B> {
+    pub fn new(scheduler: &Scheduler) -> Self {
+        Self {
+            scheduler: scheduler.clone(), 
+            prev_denoised: None, 
+            prev_sigma: None, 
+        }
+    }
+}
+
+impl<B: Backend> Sampler<B> for DpmPlusPlus2mSampler<B> {
+    fn step(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        self.step_with_x0(model_output, timestep, prev_timestep, latent).0
+    }
+
+    fn step_with_x0(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        let current_alpha = self.scheduler.alpha_cumprod(timestep);
+        let prev_alpha = self.scheduler.alpha_cumprod_or_clean(prev_timestep);
+
+        let sigma_from = self.scheduler.sigma(timestep);
+        let sigma_to = self.scheduler.sigma_or_clean(prev_timestep);
+
+        let x = latent / current_alpha.sqrt();
+        let denoised = x.clone() - model_output * sigma_from;
+
+        let x = if sigma_to == 0.0 {
+            denoised.clone()
+        } else {
+            let t_from = -sigma_from.ln();
+            let t_to = -sigma_to.ln();
+            let h = t_to - t_from;
+
+            let denoised_d = match (self.prev_denoised.take(), self.prev_sigma) {
+                (Some(prev_denoised), Some(prev_sigma)) => {
+                    let h_last = t_from + prev_sigma.ln();
+                    let r = h_last / h;
+                    denoised.clone() * (1.0 + 1.0 / (2.0 * r)) - prev_denoised * (1.0 / (2.0 * r))
+                }, 
+                _ => denoised.clone(), 
+            };
+
+            x * (sigma_to / sigma_from) - denoised_d * (-h).exp_m1()
+        };
+
+        self.prev_denoised = Some(denoised.clone());
+        self.prev_sigma = Some(sigma_from);
+
+        (x * prev_alpha.sqrt(), Some(denoised))
+    }
+}
+
+
+/// Heun's second order sampler (Karras et al. 2022, Algorithm 1 without churn) operating in sigma space. 
+/// Each step takes an Euler step, evaluates the model again at the predicted point and redoes the step with the 
+/// average of both derivatives, so it costs two UNet evaluations per step: 15 Heun steps take about as long as 
+/// 30 Euler steps. The final step to sigma = 0 skips the correction. Only `step_with_model` can correct; 
+/// `step` on its own falls back to a plain Euler step.
+pub struct HeunSampler {
+    scheduler: Scheduler, 
+}
+
+impl HeunSampler {
+    pub fn new(scheduler: &Scheduler) -> Self {
+        Self {
+            scheduler: scheduler.clone(), 
+        }
+    }
+
+    fn sigmas(&self, timestep: usize, prev_timestep: Option<usize>) -> (f64, f64, f64, f64) {
+        let current_alpha = self.scheduler.alpha_cumprod(timestep);
+        let prev_alpha = self.scheduler.alpha_cumprod_or_clean(prev_timestep);
+
+        (current_alpha, prev_alpha, self.scheduler.sigma(timestep), self.scheduler.sigma_or_clean(prev_timestep))
+    }
+}
+
+impl<B: Backend> Sampler<B> for HeunSampler {
+    fn step(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        self.step_with_x0(model_output, timestep, prev_timestep, latent).0
+    }
+
+    fn step_with_x0(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        let (current_alpha, prev_alpha, sigma_from, sigma_to) = self.sigmas(timestep, prev_timestep);
+
+        // in sigma space the derivative dx/dsigma is the predicted noise
+        let x = latent / current_alpha.sqrt();
+        let denoised = x.clone() - model_output.clone() * sigma_from;
+        let x = x + model_output * (sigma_to - sigma_from);
+
+        (x * prev_alpha.sqrt(), Some(denoised))
+    }
+
+    fn step_with_model(&mut self, model: &mut dyn FnMut(Tensor<B, 4>, usize) -> Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        self.step_with_model_and_x0(model, timestep, prev_timestep, latent).0
+    }
+
+    /// The x0 estimate is the one from the first model evaluation at `timestep`.
+    fn step_with_model_and_x0(&mut self, model: &mut dyn FnMut(Tensor<B, 4>, usize) -> Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        let model_output = model(latent.clone(), timestep);
+
+        let prev_timestep = match prev_timestep {
+            Some(prev_timestep) => prev_timestep, 
+            None => return self.step_with_x0(model_output, timestep, None, latent), 
+        };
+
+        let (current_alpha, prev_alpha, sigma_from, sigma_to) = self.sigmas(timestep, Some(prev_timestep));
+
+        let x = latent / current_alpha.sqrt();
+        let denoised = x.clone() - model_output.clone() * sigma_from;
+        let x_euler = x.clone() + model_output.clone() * (sigma_to - sigma_from);
+
+        let corrected_output = model(x_euler * prev_alpha.sqrt(), prev_timestep);
+        let x = x + (model_output + corrected_output) * (0.5 * (sigma_to - sigma_from));
+
+        (x * prev_alpha.sqrt(), Some(denoised))
+    }
+}
+
+
+/// Linear multistep sampler (k-diffusion's `sample_lms`) operating in sigma space. It keeps the derivatives of the 
+/// last `order` steps and integrates the polynomial through them over the next sigma interval, so it costs one 
+/// model evaluation per step like Euler while being higher order. The order ramps up from 1 over the first steps 
+/// as the history fills.
+pub struct LmsSampler<B: Backend> {
+    scheduler: Scheduler, 
+    order: usize, 
+    history: Vec<(f64, Tensor<B, 4>)>, 
+}
+
+impl<B: Backend> LmsSampler<B> {
+    /// `order` is clamped to between 1 and 4; k-diffusion's default is 4.
+    pub fn new(scheduler: &Scheduler, order: usize) -> Self {
+        Self {
+            scheduler: scheduler.clone(), 
+            order: order.clamp(1, 4), 
+            history: Vec::new(), 
+        }
+    }
+}
+
+impl<B: Backend> Sampler<B> for LmsSampler<B> {
+    fn step(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        self.step_with_x0(model_output, timestep, prev_timestep, latent).0
+    }
+
+    fn step_with_x0(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        let current_alpha = self.scheduler.alpha_cumprod(timestep);
+        let prev_alpha = self.scheduler.alpha_cumprod_or_clean(prev_timestep);
+
+        let sigma_from = self.scheduler.sigma(timestep);
+        let sigma_to = self.scheduler.sigma_or_clean(prev_timestep);
+
+        // in sigma space the derivative dx/dsigma is the predicted noise
+        let x = latent / current_alpha.sqrt();
+        let denoised = x.clone() - model_output.clone() * sigma_from;
+
+        self.history.push((sigma_from, model_output));
+        if self.history.len() > self.order {
+            self.history.remove(0);
+        }
+
+        let sigmas: Vec<f64> = self.history.iter().rev().map(|(sigma, _)| *sigma).collect();
+        let x = self.history
+            .iter()
+            .rev()
+            .enumerate()
+            .fold(x, |x, (j, (_, derivative))| {
+                x + derivative.clone() * linear_multistep_coeff(&sigmas, j, sigma_from, sigma_to)
+            });
+
+        (x * prev_alpha.sqrt(), Some(denoised))
+    }
+}
+
+/// The integral from `from` to `to` of the Lagrange basis polynomial that is 1 at `sigmas[j]` and 0 at the 
+/// other `sigmas`, the weight of the `j`-th most recent derivative in a linear multistep update.
+fn linear_multistep_coeff(sigmas: &[f64], j: usize, from: f64, to: f64) -> f64 {
+    // polynomial coefficients in increasing powers
+    let basis = sigmas
+        .iter()
+        .enumerate()
+        .filter(|&(k, _)| k != j)
+        .fold(vec![1.0], |poly, (_, &sigma_k)| {
+            let scale = 1.0 / (sigmas[j] - sigma_k);
+
+            let mut product = vec![0.0; poly.len() + 1];
+            for (power, &c) in poly.iter().enumerate() {
+                product[power + 1] += c * scale;
+                product[power] -= c * sigma_k * scale;
+            }
+            product
+        });
+
+    let antiderivative = |x: f64| -> f64 {
+        basis
+            .iter()
+            .enumerate()
+            .map(|(power, c)| c * x.powi(power as i32 + 1) / (power + 1) as f64)
+            .sum()
+    };
+
+    antiderivative(to) - antiderivative(from)
+}
+
+
+/// Where `RestartSampler` restarts: after the main step that reaches or passes timestep `t_min`, the latent is 
+/// noised forward to `t_max` and denoised back in `n_steps` steps, `n_repeats` times. The noise levels are 
+/// `Scheduler::sigma` of the timesteps.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RestartInterval {
+    pub t_min: usize, 
+    pub t_max: usize, 
+    pub n_steps: usize, 
+    pub n_repeats: usize, 
+}
+
+/// Restart sampling (Xu et al. 2023) around any sampler: at the restart intervals the latent is re-noised to a 
+/// higher noise level and denoised again, so the fresh noise contracts the errors accumulated by earlier steps. 
+/// Each interval costs `n_steps * n_repeats` extra steps of the base sampler. Restarts need the model, so only 
+/// `step_with_model` restarts; `step` on its own is the base sampler's step. Samplers with a history, such as 
+/// `DpmPlusPlus2mSampler` and `LmsSampler`, carry it across the jumps in noise level, so deterministic single 
+/// step samplers like `DdimSampler` or `HeunSampler` make the best base.
+pub struct RestartSampler<S> {
+    sampler: S, 
+    scheduler: Scheduler, 
+    intervals: Vec<RestartInterval>, 
+}
+
+impl<S> RestartSampler<S> {
+    pub fn new(sampler: S, scheduler: &Scheduler, intervals: Vec<RestartInterval>) -> Self {
+        Self {
+            sampler, 
+            scheduler: scheduler.clone(), 
+            intervals, 
+        }
+    }
+}
+
+impl<B: Backend, S: Sampler<B>> Sampler<B> for RestartSampler<S> {
+    fn step(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        self.sampler.step(model_output, timestep, prev_timestep, latent)
+    }
+
+    fn step_with_x0(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        self.sampler.step_with_x0(model_output, timestep, prev_timestep, latent)
+    }
+
+    fn step_with_model(&mut self, model: &mut dyn FnMut(Tensor<B, 4>, usize) -> Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        self.step_with_model_and_x0(model, timestep, prev_timestep, latent).0
+    }
+
+    fn step_with_model_and_x0(&mut self, model: &mut dyn FnMut(Tensor<B, 4>, usize) -> Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        let (mut latent, x0) = self.sampler.step_with_model_and_x0(model, timestep, prev_timestep, latent);
+
+        // no restarts from the clean latent at sigma 0
+        let landed = match prev_timestep {
+            Some(landed) => landed, 
+            None => return (latent, x0), 
+        };
+
+        let max_timestep = self.scheduler.n_steps() - 1;
+        let intervals: Vec<_> = self.intervals
+            .iter()
+            .filter(|interval| timestep > interval.t_min && interval.t_min >= landed && interval.t_max > landed)
+            .cloned()
+            .collect();
+
+        for interval in intervals {
+            let t_max = interval.t_max.min(max_timestep);
+            let timesteps = restart_timesteps(t_max, landed, interval.n_steps);
+
+            for _ in 0..interval.n_repeats {
+                latent = renoise(&self.scheduler, latent, landed, t_max);
+                for pair in timesteps.windows(2) {
+                    latent = self.sampler.step_with_model(model, pair[0], Some(pair[1]), latent);
+                }
+            }
+        }
+
+        (latent, x0)
+    }
+}
+
+/// Noises a latent at `timestep` forward to `to_timestep`, adding noise of variance `sigma_to^2 - sigma^2` in sigma space.
+fn renoise<B: Backend>(scheduler: &Scheduler, latent: Tensor<B, 4>, timestep: usize, to_timestep: usize) -> Tensor<B, 4> {
+    let sigma = scheduler.sigma(timestep);
+    let sigma_to = scheduler.sigma(to_timestep);
+
+    let x = latent.clone() / scheduler.alpha_cumprod(timestep).sqrt();
+    let x = x + gen_noise(&latent) * (sigma_to * sigma_to - sigma * sigma).sqrt();
+
+    x * scheduler.alpha_cumprod(to_timestep).sqrt()
+}
+
+/// `n_steps + 1` timesteps evenly spaced from `t_max` down to `t_min`, inclusive.
+fn restart_timesteps(t_max: usize, t_min: usize, n_steps: usize) -> Vec<usize> {
+    let n_steps = n_steps.max(1);
+
+    (0..=n_steps)
+        .map(|i| t_max - ( (t_max - t_min) as f64 * i as f64 / n_steps as f64 ).round() as usize)
+        .collect()
+}
+
+
+/// Imagen's dynamic thresholding (Saharia et al. 2022) around any sampler: every step the x0 prediction is 
+/// clamped to the `percentile` (e.g. 0.995) of its absolute values per sample, floored at 1, and divided by it, 
+/// which keeps high guidance scales from blowing out colors. The sampler then steps with the noise prediction 
+/// matching the thresholded x0, see `Scheduler::predict_x0`.
+pub struct DynamicThresholdSampler<S> {
+    sampler: S, 
+    scheduler: Scheduler, 
+    percentile: f32, 
+}
+
+impl<S> DynamicThresholdSampler<S> {
+    pub fn new(sampler: S, scheduler: &Scheduler, percentile: f32) -> Self {
+        Self {
+            sampler, 
+            scheduler: scheduler.clone(), 
+            percentile, 
+        }
+    }
+}
+
+impl<B: Backend, S: Sampler<B>> Sampler<B> for DynamicThresholdSampler<S> {
+    fn step(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        let model_output = threshold_model_output(&self.scheduler, self.percentile, model_output, timestep, latent.clone());
+        self.sampler.step(model_output, timestep, prev_timestep, latent)
+    }
+
+    fn step_with_x0(&mut self, model_output: Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        let model_output = threshold_model_output(&self.scheduler, self.percentile, model_output, timestep, latent.clone());
+        self.sampler.step_with_x0(model_output, timestep, prev_timestep, latent)
+    }
+
+    fn step_with_model(&mut self, model: &mut dyn FnMut(Tensor<B, 4>, usize) -> Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+        self.step_with_model_and_x0(model, timestep, prev_timestep, latent).0
+    }
+
+    fn step_with_model_and_x0(&mut self, model: &mut dyn FnMut(Tensor<B, 4>, usize) -> Tensor<B, 4>, timestep: usize, prev_timestep: Option<usize>, latent: Tensor<B, 4>) -> (Tensor<B, 4>, Option<Tensor<B, 4>>) {
+        let (scheduler, percentile) = (&self.scheduler, self.percentile);
+        let mut model = |latent: Tensor<B, 4>, timestep: usize| {
+            let model_output = model(latent.clone(), timestep);
+            threshold_model_output(scheduler, percentile, model_output, timestep, latent)
+        };
+
+        self.sampler.step_with_model_and_x0(&mut model, timestep, prev_timestep, latent)
+    }
+}
+
+/// The noise prediction whose x0 estimate is the dynamically thresholded one of `model_output`.
+fn threshold_model_output<B: Backend>(scheduler: &Scheduler, percentile: f32, model_output: Tensor<B, 4>, timestep: usize, latent: Tensor<B, 4>) -> Tensor<B, 4> {
+    let x0 = scheduler.predict_x0(latent.clone(), model_output, timestep);
+    let x0 = dynamic_threshold(x0, percentile);
+
+    (latent - x0 * scheduler.sqrt_alpha_cumprod(timestep)) / scheduler.sqrt_one_minus_alpha_cumprod(timestep)
+}
+
+/// Clamps `x0` to the `percentile` of its absolute values per batch entry, but at least to [-1, 1], 
+/// and rescales it by that value, so the result lies in [-1, 1].
+pub fn dynamic_threshold<B: Backend>(x0: Tensor<B, 4>, percentile: f32) -> Tensor<B, 4> {
+    let [n_batch, n_channel, height, width] = x0.dims();
+    let n_per_sample = n_channel * height * width;
+
+    let values = tensor_to_vec(x0.clone().abs());
+    let scales: Vec<f32> = values
+        .chunks(n_per_sample)
+        .map(|sample| {
+            let mut sample = sample.to_vec();
+
+            // total_cmp orders NaNs instead of panicking on them, so a NaN x0 passes through
+            let index = ( (n_per_sample - 1) as f32 * percentile.clamp(0.0, 1.0) ).round() as usize;
+            let (_, quantile, _) = sample.select_nth_unstable_by(index, |a, b| a.total_cmp(b));
+            quantile.max(1.0) as f32
+        })
+        .collect();
+    let scales = Tensor::from_data_device(Data::new(scales, Shape::new([n_batch, 1, 1, 1])).convert(), &x0.device());
+
+    tensor_min_scalar(tensor_max_scalar(x0 / scales, -1.0), 1.0)
+}
+
+
+/// Converts a cumulative alpha product to the noise level of the equivalent sigma-space (variance exploding) latent.
+pub fn alpha_to_sigma(alpha_cumprod: f64) -> f64 {
+    ( (1.0 - alpha_cumprod) / alpha_cumprod ).sqrt()
+}
+
+fn gen_noise<B: Backend>(like: &Tensor<B, 4>) -> Tensor<B, 4> {
+    random_normal(like.shape(), &like.device())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::tensor_max_element;
+    use burn::tensor::Distribution;
+    use burn_tch::TchBackend;
+
+    type Backend = TchBackend<f32>;
+
+    #[test]
+    fn test_sampler_kind_names() {
+        for kind in SamplerKind::ALL {
+            assert_eq!(kind.name().parse::<SamplerKind>().unwrap(), kind);
+        }
+        assert!("euler".parse::<SamplerKind>().is_err());
+    }
+
+    #[test]
+    fn test_heun() {
+        let device = Default::default();
+        let mut sampler = HeunSampler::new(&Scheduler::offset_cosine::<Backend>(1000, &device));
+        let latent: Tensor<Backend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let noise: Tensor<Backend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+
+        // with a constant noise prediction both derivatives agree, so the correction changes nothing
+        let mut n_evaluations = 0;
+        let mut model = |_: Tensor<Backend, 4>, _: usize| {
+            n_evaluations += 1;
+            noise.clone()
+        };
+        let heun = sampler.step_with_model(&mut model, 999, Some(749), latent.clone());
+        let euler = Sampler::<Backend>::step(&mut sampler, noise.clone(), 999, Some(749), latent.clone());
+        assert!(tensor_max_element((heun - euler).abs()) < 1e-4);
+
+        // the final step skips the correction
+        sampler.step_with_model(&mut model, 249, None, latent);
+        assert_eq!(n_evaluations, 3);
+    }
+
+    #[test]
+    fn test_x0_estimates_agree() {
+        let device = Default::default();
+        let scheduler = Scheduler::offset_cosine::<Backend>(1000, &device);
+        let latent: Tensor<Backend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let noise: Tensor<Backend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+
+        let expected = scheduler.predict_x0(latent.clone(), noise.clone(), 749);
+
+        let mut samplers: Vec<Box<dyn Sampler<Backend>>> = vec![
+            Box::new(DdimSampler::new(&scheduler, 0.0)), 
+            Box::new(EulerAncestralSampler::new(&scheduler)), 
+            Box::new(DpmPlusPlus2mSampler::new(&scheduler)), 
+            Box::new(HeunSampler::new(&scheduler)), 
+        ];
+        for sampler in samplers.iter_mut() {
+            let mut model = |_: Tensor<Backend, 4>, _: usize| noise.clone();
+            let (_, x0) = sampler.step_with_model_and_x0(&mut model, 749, Some(499), latent.clone());
+            assert!(tensor_max_element((x0.unwrap() - expected.clone()).abs()) < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_linear_multistep_coeff() {
+        // order 1 is an Euler step
+        assert!((linear_multistep_coeff(&[2.0], 0, 2.0, 1.5) + 0.5).abs() < 1e-12);
+
+        // the weights integrate a constant exactly and a line through the history points exactly
+        let sigmas = [2.0, 3.0, 5.0, 8.0];
+        let coeffs: Vec<f64> = (0..4).map(|j| linear_multistep_coeff(&sigmas, j, 2.0, 1.0)).collect();
+        assert!((coeffs.iter().sum::<f64>() + 1.0).abs() < 1e-9);
+        let line: f64 = coeffs.iter().zip(sigmas).map(|(c, sigma)| c * sigma).sum();
+        assert!((line + 1.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_lms_ramps_up_order() {
+        let device = Default::default();
+        let scheduler = Scheduler::offset_cosine::<Backend>(1000, &device);
+        let mut sampler = LmsSampler::<Backend>::new(&scheduler, 4);
+        let mut euler = HeunSampler::new(&scheduler);
+
+        let latent: Tensor<Backend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let noise: Tensor<Backend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+
+        // the first step has no history and is an Euler step
+        let lms = sampler.step(noise.clone(), 999, Some(749), latent.clone());
+        let expected = Sampler::<Backend>::step(&mut euler, noise.clone(), 999, Some(749), latent.clone());
+        assert!(tensor_max_element((lms - expected).abs()) < 1e-4);
+
+        // a constant derivative is integrated exactly at any order
+        for (t, prev_t) in [(749, Some(499)), (499, Some(249)), (249, Some(99)), (99, None)] {
+            let lms = sampler.step(noise.clone(), t, prev_t, latent.clone());
+            let expected = Sampler::<Backend>::step(&mut euler, noise.clone(), t, prev_t, latent.clone());
+            assert!(tensor_max_element((lms - expected).abs()) < 1e-3);
+        }
+        assert_eq!(sampler.history.len(), 4);
+    }
+
+    #[test]
+    fn test_restart_model_evaluations() {
+        let device = Default::default();
+        let scheduler = Scheduler::offset_cosine::<Backend>(1000, &device);
+        let intervals = vec![
+            RestartInterval { t_min: 400, t_max: 800, n_steps: 3, n_repeats: 2 }, 
+            // never reached by a step
+            RestartInterval { t_min: 10, t_max: 300, n_steps: 5, n_repeats: 1 }, 
+        ];
+        let mut sampler = RestartSampler::new(DdimSampler::new(&scheduler, 0.0), &scheduler, intervals);
+
+        let noise: Tensor<Backend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Normal(0.0, 1.0));
+        let mut latent = noise.clone();
+
+        let mut timesteps_seen = Vec::new();
+        let mut model = |_: Tensor<Backend, 4>, t: usize| {
+            timesteps_seen.push(t);
+            noise.clone()
+        };
+
+        let schedule = [999, 749, 499, 249];
+        for (i, &t) in schedule.iter().enumerate() {
+            latent = sampler.step_with_model(&mut model, t, schedule.get(i + 1).cloned(), latent);
+        }
+
+        // 4 main steps and 2 repeats of 3 restart steps after the step from 499 to 249 passes 400
+        assert_eq!(timesteps_seen.len(), 4 + 2 * 3);
+        assert_eq!(timesteps_seen, vec![999, 749, 499, 800, 616, 433, 800, 616, 433, 249]);
+        assert_eq!(latent.dims(), [1, 4, 8, 8]);
+        assert_eq!(restart_timesteps(800, 249, 3), vec![800, 616, 433, 249]);
+    }
+
+    #[test]
+    fn test_dynamic_threshold() {
+        let small: Tensor<Backend, 4> = Tensor::random([1, 4, 8, 8], Distribution::Uniform(-0.5, 0.5));
+        let large = small.clone() * 10.0;
+        let x0 = Tensor::cat(vec![small.clone(), large], 0);
+
+        let thresholded = dynamic_threshold(x0, 0.995);
+        assert!(tensor_max_element(thresholded.clone().abs()) <= 1.0);
+
+        // entries within [-1, 1] keep their values, the others are rescaled on their own
+        let [first, second] = [0, 1].map(|i| thresholded.clone().slice([i..i + 1]));
+        assert!(tensor_max_element((first - small).abs()) < 1e-6);
+        assert!(tensor_max_element(second.abs()) > 0.9);
+    }
+
+    #[test]
+    fn test_dynamic_threshold_nan() {
+        let zeros: Tensor<Backend, 4> = Tensor::zeros([1, 4, 8, 8]);
+        let nan = zeros.clone() / zeros;
+
+        let thresholded = dynamic_threshold(nan, 0.995);
+        assert!(tensor_to_vec(thresholded).iter().all(|v| v.is_nan()));
+    }
+
+    #[test]
+    fn test_dpm_plus_plus_2m_preserves_shape() {
+        let device = Default::default();
+        let n_train_steps = 1000;
+        let n_steps = 4;
+        let step_size = n_train_steps / n_steps;
+
+        let mut sampler = DpmPlusPlus2mSampler::<Backend>::new(&Scheduler::offset_cosine::<Backend>(n_train_steps, &device));
+        let mut latent: Tensor<Backend, 4> = Tensor::random([1, 4, 16, 16], Distribution::Normal(0.0, 1.0));
+
+        for t in (0..n_train_steps).rev().step_by(step_size) {
+            let prev_t = if t >= step_size { Some(t - step_size) } else { None };
+            let model_output = Tensor::random([1, 4, 16, 16], Distribution::Normal(0.0, 1.0));
+            latent = sampler.step(model_output, t, prev_t, latent);
+
+            assert_eq!(latent.dims(), [1, 4, 16, 16]);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use burn::tensor::{
+    backend::Backend,
+    Tensor,
+};
+
+use super::offset_cosine_schedule_cumprod;
+use super::sampler::alpha_to_sigma;
+use crate::helper::tensor_to_vec;
+
+/// A diffusion noise schedule over the training timesteps. The cumulative alpha products and the quantities
+/// derived from them are computed once, so the samplers and noising code index into them by timestep instead
+/// of redoing the schedule math.
+#[derive(Clone, Debug)]
+pub struct Scheduler {
+    alphas_cumprod: Vec<f64>, 
+    sqrt_alphas_cumprod: Vec<f64>, 
+    sqrt_one_minus_alphas_cumprod: Vec<f64>, 
+    sigmas: Vec<f64>, 
+}
+
+impl Scheduler {
+    pub fn new(alphas_cumprod: Vec<f64>) -> Self {
+        let sqrt_alphas_cumprod = alphas_cumprod.iter().map(|a| a.sqrt()).collect();
+        let sqrt_one_minus_alphas_cumprod = alphas_cumprod.iter().map(|a| (1.0 - a).sqrt()).collect();
+        let sigmas = alphas_cumprod.iter().cloned().map(alpha_to_sigma).collect();
+
+        Self {
+            alphas_cumprod, 
+            sqrt_alphas_cumprod, 
+            sqrt_one_minus_alphas_cumprod, 
+            sigmas, 
+        }
+    }
+
+    pub fn from_tensor<B: Backend>(alphas_cumprod: Tensor<B, 1>) -> Self {
+        Self::new(tensor_to_vec(alphas_cumprod))
+    }
+
+    pub fn offset_cosine<B: Backend>(n_steps: usize, device: &B::Device) -> Self {
+        Self::from_tensor(offset_cosine_schedule_cumprod::<B>(n_steps, device))
+    }
+
+    /// Number of training timesteps.
+    pub fn n_steps(&self) -> usize {
+        self.alphas_cumprod.len()
+    }
+
+    pub fn alpha_cumprod(&self, timestep: usize) -> f64 {
+        self.alphas_cumprod[timestep]
+    }
+
+    pub fn sqrt_alpha_cumprod(&self, timestep: usize) -> f64 {
+        self.sqrt_alphas_cumprod[timestep]
+    }
+
+    pub fn sqrt_one_minus_alpha_cumprod(&self, timestep: usize) -> f64 {
+        self.sqrt_one_minus_alphas_cumprod[timestep]
+    }
+
+    /// The noise level `sqrt((1 - alpha_cumprod) / alpha_cumprod)` of `timestep` in sigma space.
+    pub fn sigma(&self, timestep: usize) -> f64 {
+        self.sigmas[timestep]
+    }
+
+    /// The alpha of the single step into `timestep`, `alpha_cumprod(t) / alpha_cumprod(t - 1)`.
+    pub fn alpha(&self, timestep: usize) -> f64 {
+        match timestep {
+            0 => self.alphas_cumprod[0], 
+            t => self.alphas_cumprod[t] / self.alphas_cumprod[t - 1], 
+        }
+    }
+
+    pub fn beta(&self, timestep: usize) -> f64 {
+        1.0 - self.alpha(timestep)
+    }
+
+    /// Like `alpha_cumprod`, but `None` is the clean end of sampling with an alpha product of 1,
+    /// as the samplers' `prev_timestep` of the final step.
+    pub fn alpha_cumprod_or_clean(&self, timestep: Option<usize>) -> f64 {
+        timestep.map(|t| self.alpha_cumprod(t)).unwrap_or(1.0)
+    }
+
+    /// Like `sigma`, but `None` is the clean end of sampling at sigma 0.
+    pub fn sigma_or_clean(&self, timestep: Option<usize>) -> f64 {
+        timestep.map(|t| self.sigma(t)).unwrap_or(0.0)
+    }
+
+    pub fn alphas_cumprod(&self) -> &[f64] {
+        &self.alphas_cumprod
+    }
+
+    pub fn sigmas(&self) -> &[f64] {
+        &self.sigmas
+    }
+
+    /// Diffuses a clean latent forward to `timestep`.
+    pub fn add_noise<B: Backend>(&self, latent: Tensor<B, 4>, noise: Tensor<B, 4>, timestep: usize) -> Tensor<B, 4> {
+        latent * self.sqrt_alpha_cumprod(timestep) + noise * self.sqrt_one_minus_alpha_cumprod(timestep)
+    }
+
+    /// Inverts `add_noise` for a noise prediction `eps`, estimating the clean latent as 
+    /// `x0 = (x - sqrt(1 - alpha_cumprod) * eps) / sqrt(alpha_cumprod)`.
+    pub fn predict_x0<B: Backend>(&self, latent: Tensor<B, 4>, eps: Tensor<B, 4>, timestep: usize) -> Tensor<B, 4> {
+        (latent - eps * self.sqrt_one_minus_alpha_cumprod(timestep)) / self.sqrt_alpha_cumprod(timestep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use burn_tch::TchBackend;
+
+    type TestBackend = TchBackend<f32>;
+
+    #[test]
+    fn test_scheduler() {
+        let device = Default::default();
+        let scheduler = Scheduler::offset_cosine::<TestBackend>(1000, &device);
+        assert_eq!(scheduler.n_steps(), 1000);
+
+        let product: f64 = (0..=500).map(|t| scheduler.alpha(t)).product();
+        assert!((product - scheduler.alpha_cumprod(500)).abs() < 1e-6);
+
+        for t in [0, 499, 999] {
+            let alpha = scheduler.alpha_cumprod(t);
+            assert!((scheduler.sqrt_alpha_cumprod(t).powi(2) - alpha).abs() < 1e-9);
+            assert!((scheduler.sqrt_one_minus_alpha_cumprod(t).powi(2) - (1.0 - alpha)).abs() < 1e-9);
+            assert!((scheduler.sigma(t) - alpha_to_sigma(alpha)).abs() < 1e-9);
+        }
+
+        assert!(scheduler.sigma(999) > scheduler.sigma(0));
+        assert_eq!(scheduler.alpha_cumprod_or_clean(None), 1.0);
+        assert_eq!(scheduler.sigma_or_clean(None), 0.0);
+    }
+}
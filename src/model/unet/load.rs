@@ -1,7 +1,6 @@
 use super::GroupNorm;
 use crate::model::load::*;
 
-use std::error::Error;
 
 use burn::{
     config::Config, 
@@ -14,10 +13,11 @@ use burn::{
 };
 
 use super::*;
+use super::quantize::quantize_int8;
 use crate::model::groupnorm::load::load_group_norm;
 use crate::model::layernorm::load::load_layer_norm;
 
-pub fn load_res_block<B: Backend>(path: &str, device: &B::Device) -> Result<ResBlock<B>, Box<dyn Error>> {
+pub fn load_res_block<B: Backend>(path: &str, device: &B::Device) -> Result<ResBlock<B>, LoadError> {
     let norm_in = load_group_norm::<B>(&format!("{}/{}", path, "norm_in"), device)?;
     let conv_in = load_conv2d::<B>(&format!("{}/{}", path, "conv_in"), device)?;
     let lin_embed = load_linear::<B>(&format!("{}/{}", path, "lin_embed"), device)?;
@@ -40,7 +40,7 @@ pub fn load_res_block<B: Backend>(path: &str, device: &B::Device) -> Result<ResB
     Ok(res_block)
 }
 
-pub fn load_multi_head_attention<B: Backend>(path: &str, device: &B::Device) -> Result<MultiHeadAttention<B>, Box<dyn Error>> {
+pub fn load_multi_head_attention<B: Backend>(path: &str, device: &B::Device) -> Result<MultiHeadAttention<B>, LoadError> {
     let n_head = load_usize::<B>("n_head", path, device)?;
     let query = load_linear::<B>(&format!("{}/{}", path, "query"), device)?;
     let key = load_linear::<B>(&format!("{}/{}", path, "key"), device)?;
@@ -53,13 +53,16 @@ pub fn load_multi_head_attention<B: Backend>(path: &str, device: &B::Device) ->
         key: key,
         value: value,
         out: out,
+        attention_slice_size: None, 
+        flash_attention_block_size: None, 
+        upcast_attention: false, 
     };
     
     Ok(multi_head_attention)
 }
 
 
-pub fn load_geglu<B: Backend>(path: &str, device: &B::Device) -> Result<GEGLU<B>, Box<dyn Error>> {
+pub fn load_geglu<B: Backend>(path: &str, device: &B::Device) -> Result<GEGLU<B>, LoadError> {
     let proj = load_linear::<B>(&format!("{}/{}", path, "proj"), device)?;
 
     let geglue = GEGLU {
@@ -71,7 +74,7 @@ pub fn load_geglu<B: Backend>(path: &str, device: &B::Device) -> Result<GEGLU<B>
 }
 
 
-pub fn load_mlp<B: Backend>(path: &str, device: &B::Device) -> Result<MLP<B>, Box<dyn Error>> {
+pub fn load_mlp<B: Backend>(path: &str, device: &B::Device) -> Result<MLP<B>, LoadError> {
     let geglu = load_geglu::<B>(&format!("{}/{}", path, "geglu"), device)?;
     let lin = load_linear::<B>(&format!("{}/{}", path, "lin"), device)?;
 
@@ -84,7 +87,7 @@ pub fn load_mlp<B: Backend>(path: &str, device: &B::Device) -> Result<MLP<B>, Bo
 }
 
 
-pub fn load_transformer_block<B: Backend>(path: &str, device: &B::Device) -> Result<TransformerBlock<B>, Box<dyn Error>> {
+pub fn load_transformer_block<B: Backend>(path: &str, device: &B::Device) -> Result<TransformerBlock<B>, LoadError> {
     let norm1 = load_layer_norm::<B>(&format!("{}/{}", path, "norm1"), device)?;
     let attn1 = load_multi_head_attention::<B>(&format!("{}/{}", path, "attn1"), device)?;
     let norm2 = load_layer_norm::<B>(&format!("{}/{}", path, "norm2"), device)?;
@@ -105,7 +108,7 @@ pub fn load_transformer_block<B: Backend>(path: &str, device: &B::Device) -> Res
 }
 
 
-pub fn load_spatial_transformer<B: Backend>(path: &str, device: &B::Device) -> Result<SpatialTransformer<B>, Box<dyn Error>> {
+pub fn load_spatial_transformer<B: Backend>(path: &str, device: &B::Device) -> Result<SpatialTransformer<B>, LoadError> {
     let norm = load_group_norm::<B>(&format!("{}/{}", path, "norm"), device)?;
     //let proj_in = load_conv2d::<B>(&format!("{}/{}", path, "proj_in"), device)?;
     let proj_in = load_linear::<B>(&format!("{}/{}", path, "proj_in"), device)?;
@@ -129,7 +132,7 @@ pub fn load_spatial_transformer<B: Backend>(path: &str, device: &B::Device) -> R
 }
 
 
-pub fn load_upsample<B: Backend>(path: &str, device: &B::Device) -> Result<Upsample<B>, Box<dyn Error>> {
+pub fn load_upsample<B: Backend>(path: &str, device: &B::Device) -> Result<Upsample<B>, LoadError> {
     let conv = load_conv2d::<B>(&format!("{}/{}", path, "conv"), device)?;
 
     let upsample = Upsample {
@@ -139,11 +142,11 @@ pub fn load_upsample<B: Backend>(path: &str, device: &B::Device) -> Result<Upsam
     Ok(upsample)
 }
 
-pub fn load_downsample<B: Backend>(path: &str, device: &B::Device) -> Result<Downsample<B>, Box<dyn Error>> {
+pub fn load_downsample<B: Backend>(path: &str, device: &B::Device) -> Result<Downsample<B>, LoadError> {
     load_conv2d(path, device)
 }
 
-pub fn load_res_transformer_res<B: Backend>(path: &str, device: &B::Device) -> Result<ResTransformerRes<B>, Box<dyn Error>> {
+pub fn load_res_transformer_res<B: Backend>(path: &str, device: &B::Device) -> Result<ResTransformerRes<B>, LoadError> {
     let res1 = load_res_block::<B>(&format!("{}/{}", path, "res1"), device)?; // Assuming load_res_block function
     let transformer = load_spatial_transformer::<B>(&format!("{}/{}", path, "transformer"), device)?;
     let res2 = load_res_block::<B>(&format!("{}/{}", path, "res2"), device)?;
@@ -157,7 +160,7 @@ pub fn load_res_transformer_res<B: Backend>(path: &str, device: &B::Device) -> R
     Ok(res_transformer_res)
 }
 
-pub fn load_res_transformer_upsample<B: Backend>(path: &str, device: &B::Device) -> Result<ResTransformerUpsample<B>, Box<dyn Error>> {
+pub fn load_res_transformer_upsample<B: Backend>(path: &str, device: &B::Device) -> Result<ResTransformerUpsample<B>, LoadError> {
     let res = load_res_block::<B>(&format!("{}/{}", path, "res"), device)?;
     let transformer = load_spatial_transformer::<B>(&format!("{}/{}", path, "transformer"), device)?;
     let upsample = load_upsample::<B>(&format!("{}/{}", path, "upsample"), device)?;
@@ -172,7 +175,7 @@ pub fn load_res_transformer_upsample<B: Backend>(path: &str, device: &B::Device)
 }
 
 
-pub fn load_res_upsample<B: Backend>(path: &str, device: &B::Device) -> Result<ResUpSample<B>, Box<dyn Error>> {
+pub fn load_res_upsample<B: Backend>(path: &str, device: &B::Device) -> Result<ResUpSample<B>, LoadError> {
     let res = load_res_block::<B>(&format!("{}/{}", path, "res"), device)?;
     let upsample = load_upsample::<B>(&format!("{}/{}", path, "upsample"), device)?;
 
@@ -185,7 +188,7 @@ pub fn load_res_upsample<B: Backend>(path: &str, device: &B::Device) -> Result<R
 }
 
 
-pub fn load_res_transformer<B: Backend>(path: &str, device: &B::Device) -> Result<ResTransformer<B>, Box<dyn Error>> {
+pub fn load_res_transformer<B: Backend>(path: &str, device: &B::Device) -> Result<ResTransformer<B>, LoadError> {
     let res = load_res_block::<B>(&format!("{}/{}", path, "res"), device)?;
     let transformer = load_spatial_transformer::<B>(&format!("{}/{}", path, "transformer"), device)?;
 
@@ -198,7 +201,7 @@ pub fn load_res_transformer<B: Backend>(path: &str, device: &B::Device) -> Resul
 }
 
 
-/*pub fn load_unet_input_blocks<B: Backend>(path: &str, device: &B::Device) -> Result<UNetInputBlocks<B>, Box<dyn Error>> {
+/*pub fn load_unet_input_blocks<B: Backend>(path: &str, device: &B::Device) -> Result<UNetInputBlocks<B>, LoadError> {
     let conv = load_conv2d::<B>(&format!("{}/{}", path, "conv"), device)?;
     let rt1 = load_res_transformer::<B>(&format!("{}/{}", path, "rt1"), device)?;
     let rt2 = load_res_transformer::<B>(&format!("{}/{}", path, "rt2"), device)?;
@@ -230,7 +233,7 @@ pub fn load_res_transformer<B: Backend>(path: &str, device: &B::Device) -> Resul
     Ok(unet_input_blocks)
 }
 
-pub fn load_unet_output_blocks<B: Backend>(path: &str, device: &B::Device) -> Result<UNetOutputBlocks<B>, Box<dyn Error>> {
+pub fn load_unet_output_blocks<B: Backend>(path: &str, device: &B::Device) -> Result<UNetOutputBlocks<B>, LoadError> {
     let r1 = load_res_block::<B>(&format!("{}/{}", path, "r1"), device)?;
     let r2 = load_res_block::<B>(&format!("{}/{}", path, "r2"), device)?;
     let ru = load_res_upsample::<B>(&format!("{}/{}", path, "ru"), device)?;
@@ -262,7 +265,7 @@ pub fn load_unet_output_blocks<B: Backend>(path: &str, device: &B::Device) -> Re
 
 
 
-pub fn load_unet_input_blocks<B: Backend>(path: &str, device: &B::Device) -> Result<UNetInputBlocks<B>, Box<dyn Error>> {
+pub fn load_unet_input_blocks<B: Backend>(path: &str, device: &B::Device) -> Result<UNetInputBlocks<B>, LoadError> {
     let conv = load_conv2d::<B>(&format!("{}/{}", path, "conv"), device)?;
     let r1 = load_res_block::<B>(&format!("{}/{}", path, "r1"), device)?;
     let r2 = load_res_block::<B>(&format!("{}/{}", path, "r2"), device)?;
@@ -286,7 +289,7 @@ pub fn load_unet_input_blocks<B: Backend>(path: &str, device: &B::Device) -> Res
     })
 }
 
-pub fn load_unet_output_blocks<B: Backend>(path: &str, device: &B::Device) -> Result<UNetOutputBlocks<B>, Box<dyn Error>> {
+pub fn load_unet_output_blocks<B: Backend>(path: &str, device: &B::Device) -> Result<UNetOutputBlocks<B>, LoadError> {
     let rt1 = load_res_transformer::<B>(&format!("{}/{}", path, "rt1"), device)?;
     let rt2 = load_res_transformer::<B>(&format!("{}/{}", path, "rt2"), device)?;
     let rtu1 = load_res_transformer_upsample::<B>(&format!("{}/{}", path, "rtu1"), device)?;
@@ -312,7 +315,7 @@ pub fn load_unet_output_blocks<B: Backend>(path: &str, device: &B::Device) -> Re
 
 
 
-pub fn load_unet<B: Backend>(path: &str, device: &B::Device) -> Result<UNet<B>, Box<dyn Error>> {
+pub fn load_unet<B: Backend>(path: &str, device: &B::Device) -> Result<UNet<B>, LoadError> {
     let model_channels = load_usize::<B>("model_channels", path, device)?;
     let lin1_time_embed = load_linear::<B>(&format!("{}/{}", path, "lin1_time_embed"), device)?;
     let silu_time_embed = SILU::new(); // Assuming SILU::new() initializes a new SILU struct
@@ -341,5 +344,340 @@ pub fn load_unet<B: Backend>(path: &str, device: &B::Device) -> Result<UNet<B>,
         norm_out,
         silu_out,
         conv_out,
+        tileable: false,
     })
 }
+
+/// Loads the UNet like `load_unet` and quantizes its linear and convolution weights to int8 at load time, 
+/// see `quantize::quantize_int8` for what that does and doesn't save.
+pub fn load_unet_int8<B: Backend>(path: &str, device: &B::Device) -> Result<UNet<B>, LoadError> {
+    load_unet(path, device).map(quantize_int8)
+}
+
+pub fn load_refiner_unet_input_blocks<B: Backend>(path: &str, device: &B::Device) -> Result<RefinerUNetInputBlocks<B>, LoadError> {
+    let conv = load_conv2d::<B>(&format!("{}/{}", path, "conv"), device)?;
+    let r1 = load_res_block::<B>(&format!("{}/{}", path, "r1"), device)?;
+    let r2 = load_res_block::<B>(&format!("{}/{}", path, "r2"), device)?;
+    let d1 = load_downsample::<B>(&format!("{}/{}", path, "d1"), device)?;
+    let rt1 = load_res_transformer::<B>(&format!("{}/{}", path, "rt1"), device)?;
+    let rt2 = load_res_transformer::<B>(&format!("{}/{}", path, "rt2"), device)?;
+    let d2 = load_downsample::<B>(&format!("{}/{}", path, "d2"), device)?;
+    let rt3 = load_res_transformer::<B>(&format!("{}/{}", path, "rt3"), device)?;
+    let rt4 = load_res_transformer::<B>(&format!("{}/{}", path, "rt4"), device)?;
+    let d3 = load_downsample::<B>(&format!("{}/{}", path, "d3"), device)?;
+    let r3 = load_res_block::<B>(&format!("{}/{}", path, "r3"), device)?;
+    let r4 = load_res_block::<B>(&format!("{}/{}", path, "r4"), device)?;
+
+    Ok(RefinerUNetInputBlocks {
+        conv,
+        r1,
+        r2,
+        d1,
+        rt1,
+        rt2,
+        d2,
+        rt3,
+        rt4,
+        d3,
+        r3,
+        r4,
+    })
+}
+
+pub fn load_refiner_unet_output_blocks<B: Backend>(path: &str, device: &B::Device) -> Result<RefinerUNetOutputBlocks<B>, LoadError> {
+    let r1 = load_res_block::<B>(&format!("{}/{}", path, "r1"), device)?;
+    let r2 = load_res_block::<B>(&format!("{}/{}", path, "r2"), device)?;
+    let ru = load_res_upsample::<B>(&format!("{}/{}", path, "ru"), device)?;
+    let rt1 = load_res_transformer::<B>(&format!("{}/{}", path, "rt1"), device)?;
+    let rt2 = load_res_transformer::<B>(&format!("{}/{}", path, "rt2"), device)?;
+    let rtu1 = load_res_transformer_upsample::<B>(&format!("{}/{}", path, "rtu1"), device)?;
+    let rt3 = load_res_transformer::<B>(&format!("{}/{}", path, "rt3"), device)?;
+    let rt4 = load_res_transformer::<B>(&format!("{}/{}", path, "rt4"), device)?;
+    let rtu2 = load_res_transformer_upsample::<B>(&format!("{}/{}", path, "rtu2"), device)?;
+    let r3 = load_res_block::<B>(&format!("{}/{}", path, "r3"), device)?;
+    let r4 = load_res_block::<B>(&format!("{}/{}", path, "r4"), device)?;
+    let r5 = load_res_block::<B>(&format!("{}/{}", path, "r5"), device)?;
+
+    Ok(RefinerUNetOutputBlocks {
+        r1,
+        r2,
+        ru,
+        rt1,
+        rt2,
+        rtu1,
+        rt3,
+        rt4,
+        rtu2,
+        r3,
+        r4,
+        r5,
+    })
+}
+
+pub fn load_refiner_unet<B: Backend>(path: &str, device: &B::Device) -> Result<RefinerUNet<B>, LoadError> {
+    let model_channels = load_usize::<B>("model_channels", path, device)?;
+    let lin1_time_embed = load_linear::<B>(&format!("{}/{}", path, "lin1_time_embed"), device)?;
+    let silu_time_embed = SILU::new();
+    let lin2_time_embed = load_linear::<B>(&format!("{}/{}", path, "lin2_time_embed"), device)?;
+    let lin1_label_embed = load_linear::<B>(&format!("{}/{}", path, "lin1_label_embed"), device)?;
+    let silu_label_embed = SILU::new();
+    let lin2_label_embed = load_linear::<B>(&format!("{}/{}", path, "lin2_label_embed"), device)?;
+    let input_blocks = load_refiner_unet_input_blocks::<B>(&format!("{}/{}", path, "input_blocks"), device)?;
+    let middle_block = load_res_transformer_res::<B>(&format!("{}/{}", path, "middle_block"), device)?;
+    let output_blocks = load_refiner_unet_output_blocks::<B>(&format!("{}/{}", path, "output_blocks"), device)?;
+    let norm_out = load_group_norm::<B>(&format!("{}/{}", path, "norm_out"), device)?;
+    let silu_out = SILU::new();
+    let conv_out = load_conv2d::<B>(&format!("{}/{}", path, "conv_out"), device)?;
+
+    Ok(RefinerUNet {
+        model_channels, 
+        lin1_time_embed,
+        silu_time_embed,
+        lin2_time_embed,
+        lin1_label_embed, 
+        silu_label_embed, 
+        lin2_label_embed, 
+        input_blocks,
+        middle_block,
+        output_blocks,
+        norm_out,
+        silu_out,
+        conv_out,
+    })
+}
+
+
+
+use crate::model::safetensors::{SafeTensorsFile, join, names::{self, lookup}};
+use crate::model::groupnorm::load::load_group_norm_safetensors;
+use crate::model::layernorm::load::load_layer_norm_safetensors;
+
+const SAFETENSORS_HEAD_DIM: usize = 64;
+
+pub fn load_res_block_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, device: &B::Device) -> Result<ResBlock<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::UNET_RES_BLOCK, n)?) ) };
+
+    let norm_in = load_group_norm_safetensors::<B>(st, &name("norm_in")?, 32, 1e-5, device)?;
+    let conv_in = load_conv2d_safetensors::<B>(st, &name("conv_in")?, 1, 1, device)?;
+    let lin_embed = load_linear_safetensors::<B>(st, &name("lin_embed")?, device)?;
+    let norm_out = load_group_norm_safetensors::<B>(st, &name("norm_out")?, 32, 1e-5, device)?;
+    let conv_out = load_conv2d_safetensors::<B>(st, &name("conv_out")?, 1, 1, device)?;
+    let skip_connection = if st.contains(&join(&name("skip_connection")?, "weight")) {
+        Some( load_conv2d_safetensors::<B>(st, &name("skip_connection")?, 1, 0, device)? )
+    } else {
+        None
+    };
+
+    Ok(ResBlock {
+        norm_in,
+        silu_in: SILU::new(), 
+        conv_in,
+        silu_embed: SILU::new(), 
+        lin_embed,
+        norm_out,
+        silu_out: SILU::new(), 
+        conv_out,
+        skip_connection,
+    })
+}
+
+pub fn load_multi_head_attention_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, n_head: usize, device: &B::Device) -> Result<MultiHeadAttention<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::UNET_ATTENTION, n)?) ) };
+
+    let query = load_linear_safetensors::<B>(st, &name("query")?, device)?;
+    let key = load_linear_safetensors::<B>(st, &name("key")?, device)?;
+    let value = load_linear_safetensors::<B>(st, &name("value")?, device)?;
+    let out = load_linear_safetensors::<B>(st, &name("out")?, device)?;
+
+    Ok(MultiHeadAttention {
+        n_head,
+        query,
+        key,
+        value,
+        out,
+        attention_slice_size: None,
+        flash_attention_block_size: None,
+        upcast_attention: false,
+    })
+}
+
+pub fn load_transformer_block_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, n_head: usize, device: &B::Device) -> Result<TransformerBlock<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::UNET_TRANSFORMER_BLOCK, n)?) ) };
+
+    let norm1 = load_layer_norm_safetensors::<B>(st, &name("norm1")?, 1e-5, device)?;
+    let attn1 = load_multi_head_attention_safetensors::<B>(st, &name("attn1")?, n_head, device)?;
+    let norm2 = load_layer_norm_safetensors::<B>(st, &name("norm2")?, 1e-5, device)?;
+    let attn2 = load_multi_head_attention_safetensors::<B>(st, &name("attn2")?, n_head, device)?;
+    let norm3 = load_layer_norm_safetensors::<B>(st, &name("norm3")?, 1e-5, device)?;
+
+    let mlp = MLP {
+        geglu: GEGLU {
+            proj: load_linear_safetensors::<B>(st, &name("mlp/geglu/proj")?, device)?,
+            gelu: GELU::new(),
+        },
+        lin: load_linear_safetensors::<B>(st, &name("mlp/lin")?, device)?,
+    };
+
+    Ok(TransformerBlock {
+        norm1,
+        attn1,
+        norm2,
+        attn2,
+        norm3,
+        mlp,
+    })
+}
+
+pub fn load_spatial_transformer_safetensors<B: Backend>(st: &SafeTensorsFile, prefix: &str, device: &B::Device) -> Result<SpatialTransformer<B>, LoadError> {
+    let name = |n: &str| -> Result<String, LoadError> { Ok( join(prefix, lookup(names::UNET_SPATIAL_TRANSFORMER, n)?) ) };
+
+    let norm = load_group_norm_safetensors::<B>(st, &name("norm")?, 32, 1e-6, device)?;
+    let proj_in = load_linear_safetensors::<B>(st, &name("proj_in")?, device)?;
+
+    let [_, n_channels] = proj_in.weight.val().dims();
+    let n_head = n_channels / SAFETENSORS_HEAD_DIM;
+
+    let blocks_name = name("blocks")?;
+    let block_name = |i: usize| join(&blocks_name, &i.to_string());
+    let n_blocks = (0..)
+        .take_while(|&i| st.contains(&join(&block_name(i), "norm1.weight")))
+        .count();
+
+    let blocks = (0..n_blocks).into_iter().map(|i| {
+        load_transformer_block_safetensors::<B>(st, &block_name(i), n_head, device)
+    }).collect::<Result<_, _>>()?;
+
+    let proj_out = load_linear_safetensors::<B>(st, &name("proj_out")?, device)?;
+
+    Ok(SpatialTransformer {
+        norm,
+        proj_in,
+        blocks,
+        proj_out,
+    })
+}
+
+pub fn load_res_transformer_safetensors<B: Backend>(st: &SafeTensorsFile, res: &str, transformer: &str, device: &B::Device) -> Result<ResTransformer<B>, LoadError> {
+    Ok(ResTransformer {
+        res: load_res_block_safetensors::<B>(st, res, device)?,
+        transformer: load_spatial_transformer_safetensors::<B>(st, transformer, device)?,
+    })
+}
+
+/// Loads the UNet from a diffusers-format `.safetensors` file, e.g. `unet/diffusion_pytorch_model.safetensors`.
+pub fn load_unet_safetensors<B: Backend>(path: &str, device: &B::Device) -> Result<UNet<B>, LoadError> {
+    let st = SafeTensorsFile::open(path)?;
+    let st = &st;
+    let name = |n: &str| lookup(names::UNET, n);
+
+    let res = |n: &str| load_res_block_safetensors::<B>(st, name(n)?, device);
+    let res_transformer = |n: &str| load_res_transformer_safetensors::<B>(
+        st, 
+        name(&format!("{}/res", n))?, 
+        name(&format!("{}/transformer", n))?, 
+        device, 
+    );
+    let res_transformer_upsample = |n: &str| -> Result<ResTransformerUpsample<B>, LoadError> {
+        Ok(ResTransformerUpsample {
+            res: load_res_block_safetensors::<B>(st, name(&format!("{}/res", n))?, device)?,
+            transformer: load_spatial_transformer_safetensors::<B>(st, name(&format!("{}/transformer", n))?, device)?,
+            upsample: Upsample {
+                conv: load_conv2d_safetensors::<B>(st, name(&format!("{}/upsample/conv", n))?, 1, 1, device)?, 
+            },
+        })
+    };
+
+    let input_blocks = UNetInputBlocks {
+        conv: load_conv2d_safetensors::<B>(st, name("input_blocks/conv")?, 1, 1, device)?,
+        r1: res("input_blocks/r1")?,
+        r2: res("input_blocks/r2")?,
+        d1: load_conv2d_safetensors::<B>(st, name("input_blocks/d1")?, 2, 1, device)?,
+        rt1: res_transformer("input_blocks/rt1")?,
+        rt2: res_transformer("input_blocks/rt2")?,
+        d2: load_conv2d_safetensors::<B>(st, name("input_blocks/d2")?, 2, 1, device)?,
+        rt3: res_transformer("input_blocks/rt3")?,
+        rt4: res_transformer("input_blocks/rt4")?,
+    };
+
+    let middle_block = ResTransformerRes {
+        res1: res("middle_block/res1")?,
+        transformer: load_spatial_transformer_safetensors::<B>(st, name("middle_block/transformer")?, device)?,
+        res2: res("middle_block/res2")?,
+    };
+
+    let output_blocks = UNetOutputBlocks {
+        rt1: res_transformer("output_blocks/rt1")?,
+        rt2: res_transformer("output_blocks/rt2")?,
+        rtu1: res_transformer_upsample("output_blocks/rtu1")?,
+        rt3: res_transformer("output_blocks/rt3")?,
+        rt4: res_transformer("output_blocks/rt4")?,
+        rtu2: res_transformer_upsample("output_blocks/rtu2")?,
+        r1: res("output_blocks/r1")?,
+        r2: res("output_blocks/r2")?,
+        r3: res("output_blocks/r3")?,
+    };
+
+    let lin1_time_embed = load_linear_safetensors::<B>(st, name("lin1_time_embed")?, device)?;
+    let [model_channels, _] = lin1_time_embed.weight.val().dims();
+
+    Ok(UNet {
+        model_channels, 
+        lin1_time_embed,
+        silu_time_embed: SILU::new(),
+        lin2_time_embed: load_linear_safetensors::<B>(st, name("lin2_time_embed")?, device)?,
+        lin1_label_embed: load_linear_safetensors::<B>(st, name("lin1_label_embed")?, device)?, 
+        silu_label_embed: SILU::new(), 
+        lin2_label_embed: load_linear_safetensors::<B>(st, name("lin2_label_embed")?, device)?, 
+        input_blocks,
+        middle_block,
+        output_blocks,
+        norm_out: load_group_norm_safetensors::<B>(st, name("norm_out")?, 32, 1e-5, device)?,
+        silu_out: SILU::new(),
+        conv_out: load_conv2d_safetensors::<B>(st, name("conv_out")?, 1, 1, device)?,
+        tileable: false,
+    })
+}
+
+
+
+use super::lora::{LoraLayer, LoraWeights, normalize_layer_name};
+use std::collections::HashMap;
+
+/// Loads a LoRA `.safetensors` file using the `{layer}.lora_down.weight`/`{layer}.lora_up.weight`/`{layer}.alpha` naming.
+pub fn load_lora<B: Backend>(path: &str, device: &B::Device) -> Result<LoraWeights<B>, LoadError> {
+    let st = SafeTensorsFile::open(path)?;
+
+    let mut layers = HashMap::new();
+    for name in st.names() {
+        let layer_name = match name.strip_suffix(".lora_down.weight") {
+            Some(layer_name) => layer_name, 
+            None => continue, 
+        };
+
+        let down = lora_matrix(&st, &name, device)?;
+        let up = lora_matrix(&st, &format!("{}.lora_up.weight", layer_name), device)?;
+
+        let alpha_name = format!("{}.alpha", layer_name);
+        let alpha = if st.contains(&alpha_name) {
+            Some( st.scalar(&alpha_name)? )
+        } else {
+            None
+        };
+
+        layers.insert(normalize_layer_name(layer_name), LoraLayer { down, up, alpha });
+    }
+
+    if layers.is_empty() {
+        return Err( LoadError::read_failed(path, "no LoRA layers found") );
+    }
+
+    Ok(LoraWeights { layers })
+}
+
+// linear LoRAs are stored as 2D weights, conv-style ones as [out, in, 1, 1]
+fn lora_matrix<B: Backend>(st: &SafeTensorsFile, name: &str, device: &B::Device) -> Result<Tensor<B, 2>, LoadError> {
+    match st.shape(name).as_deref() {
+        Some(&[n_out, n_in, 1, 1]) => Ok( st.tensor::<B, 4>(name, device)?.reshape([n_out, n_in]) ), 
+        _ => st.tensor::<B, 2>(name, device), 
+    }
+}
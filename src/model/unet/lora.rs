@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+
+use burn::{
+    module::Param,
+    nn,
+    tensor::{
+        backend::Backend,
+        Tensor,
+    },
+};
+
+use super::{UNet, SpatialTransformer, MultiHeadAttention};
+use crate::model::safetensors::names::{self, lookup};
+
+/// One low-rank update `up @ down`, scaled by `alpha / rank` when the file provides an alpha.
+pub struct LoraLayer<B: Backend> {
+    pub down: Tensor<B, 2>, // [rank, n_in]
+    pub up: Tensor<B, 2>, // [n_out, rank]
+    pub alpha: Option<f32>, 
+}
+
+impl<B: Backend> LoraLayer<B> {
+    /// The weight delta in burn's `[n_in, n_out]` linear layout.
+    fn delta(&self, scale: f32) -> Tensor<B, 2> {
+        let [rank, _] = self.down.dims();
+        let alpha_scale = self.alpha.map(|alpha| alpha / rank as f32).unwrap_or(1.0);
+
+        self.up.clone()
+            .matmul(self.down.clone())
+            .transpose() * (alpha_scale * scale) as f64
+    }
+}
+
+/// The layers of a LoRA file, keyed by their module path with the `lora_unet_`/`unet.` prefix removed 
+/// and dots replaced by underscores, so kohya-style and diffusers-style names look the same.
+pub struct LoraWeights<B: Backend> {
+    pub layers: HashMap<String, LoraLayer<B>>, 
+}
+
+pub fn normalize_layer_name(name: &str) -> String {
+    let name = name
+        .strip_prefix("lora_unet_")
+        .or_else(|| name.strip_prefix("unet."))
+        .unwrap_or(name);
+
+    name.replace('.', "_")
+}
+
+// attention modules of the original (SGM) SDXL checkpoint layout, which kohya SDXL LoRAs are named after
+const SGM_TRANSFORMERS: names::NameTable = &[
+    ("input_blocks/rt1/transformer", "input_blocks.4.1"), 
+    ("input_blocks/rt2/transformer", "input_blocks.5.1"), 
+    ("input_blocks/rt3/transformer", "input_blocks.7.1"), 
+    ("input_blocks/rt4/transformer", "input_blocks.8.1"), 
+    ("middle_block/transformer", "middle_block.1"), 
+    ("output_blocks/rt1/transformer", "output_blocks.0.1"), 
+    ("output_blocks/rt2/transformer", "output_blocks.1.1"), 
+    ("output_blocks/rtu1/transformer", "output_blocks.2.1"), 
+    ("output_blocks/rt3/transformer", "output_blocks.3.1"), 
+    ("output_blocks/rt4/transformer", "output_blocks.4.1"), 
+    ("output_blocks/rtu2/transformer", "output_blocks.5.1"), 
+];
+
+impl<B: Backend> UNet<B> {
+    /// Folds `lora` into the UNet's weights, scaled by `scale`. Call it repeatedly to stack several LoRAs. 
+    /// Only the query, key, value and output projections of the self- and cross-attention layers in every 
+    /// transformer block are patched; LoRA layers for anything else (e.g. feed-forward or ResNet convolutions) 
+    /// are ignored. Layers may be named after either the diffusers or the original SDXL checkpoint layout. 
+    /// Returns the number of patched layers.
+    pub fn apply_lora(&mut self, lora: &LoraWeights<B>, scale: f32) -> usize {
+        let mut n_patched = 0;
+
+        for (name, transformer) in self.transformers_mut() {
+            let prefixes = [names::UNET, SGM_TRANSFORMERS].map(|table| lookup(table, name).ok());
+
+            for (i, block) in transformer.blocks.iter_mut().enumerate() {
+                for (attn_name, attn) in [("attn1", &mut block.attn1), ("attn2", &mut block.attn2)] {
+                    for (linear_name, linear) in attention_linears_mut(attn) {
+                        let layer = prefixes.iter().flatten().find_map(|prefix| {
+                            let linear_name = lookup(names::UNET_ATTENTION, linear_name).ok()?;
+                            let path = format!("{}.transformer_blocks.{}.{}.{}", prefix, i, attn_name, linear_name);
+                            lora.layers.get(&normalize_layer_name(&path))
+                        });
+
+                        if let Some(layer) = layer {
+                            let weight = linear.weight.val() + layer.delta(scale).to_device(&linear.weight.val().device());
+                            linear.weight = Param::from(weight);
+                            n_patched += 1;
+                        }
+                    }
+                }
+            }
+        }
+
+        n_patched
+    }
+
+    fn transformers_mut(&mut self) -> Vec<(&'static str, &mut SpatialTransformer<B>)> {
+        vec![
+            ("input_blocks/rt1/transformer", &mut self.input_blocks.rt1.transformer), 
+            ("input_blocks/rt2/transformer", &mut self.input_blocks.rt2.transformer), 
+            ("input_blocks/rt3/transformer", &mut self.input_blocks.rt3.transformer), 
+            ("input_blocks/rt4/transformer", &mut self.input_blocks.rt4.transformer), 
+            ("middle_block/transformer", &mut self.middle_block.transformer), 
+            ("output_blocks/rt1/transformer", &mut self.output_blocks.rt1.transformer), 
+            ("output_blocks/rt2/transformer", &mut self.output_blocks.rt2.transformer), 
+            ("output_blocks/rtu1/transformer", &mut self.output_blocks.rtu1.transformer), 
+            ("output_blocks/rt3/transformer", &mut self.output_blocks.rt3.transformer), 
+            ("output_blocks/rt4/transformer", &mut self.output_blocks.rt4.transformer), 
+            ("output_blocks/rtu2/transformer", &mut self.output_blocks.rtu2.transformer), 
+        ]
+    }
+}
+
+fn attention_linears_mut<B: Backend>(attn: &mut MultiHeadAttention<B>) -> [(&'static str, &mut nn::Linear<B>); 4] {
+    [
+        ("query", &mut attn.query), 
+        ("key", &mut attn.key), 
+        ("value", &mut attn.value), 
+        ("out", &mut attn.out), 
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::unet::UNetConfig;
+    use crate::helper::tensor_max_element;
+    use burn::tensor::Distribution;
+    use burn_tch::TchBackend;
+
+    type TestBackend = TchBackend<f32>;
+
+    #[test]
+    fn test_apply_lora() {
+        let mut unet: UNet<TestBackend> = UNetConfig::new(8, 4, 4, 32, 16, 16).init();
+        let original = unet.input_blocks.rt1.transformer.blocks[0].attn2.key.weight.val();
+        let [n_in, n_out] = original.dims();
+
+        let layer = LoraLayer {
+            down: Tensor::random([2, n_in], Distribution::Normal(0.0, 1.0)), 
+            up: Tensor::random([n_out, 2], Distribution::Normal(0.0, 1.0)), 
+            alpha: Some(1.0), 
+        };
+        let expected_delta = layer.delta(0.5);
+
+        let layers = HashMap::from([
+            (normalize_layer_name("lora_unet_input_blocks_4_1_transformer_blocks_0_attn2_to_k"), layer), 
+        ]);
+        let lora = LoraWeights { layers };
+
+        assert_eq!(unet.apply_lora(&lora, 0.5), 1);
+
+        let patched = unet.input_blocks.rt1.transformer.blocks[0].attn2.key.weight.val();
+        assert!(tensor_max_element((patched - original - expected_delta).abs()) < 1e-5);
+    }
+}
@@ -1,4 +1,10 @@
 pub mod load;
+pub mod lora;
+pub mod named;
+pub mod quantize;
+
+use std::error::Error;
+use std::iter;
 
 use burn::{
     config::Config, 
@@ -11,22 +17,30 @@ use burn::{
         Tensor,
         Distribution, 
         Int, 
+        Data, 
+        Shape, 
+        ElementConversion, 
     },
 };
 
 use super::silu::*;
 use super::groupnorm::*;
-use crate::helper::to_float;
+use crate::helper::{to_float, upsample_bilinear, conv2d_forward};
 use crate::model::layernorm::{LayerNorm, LayerNormConfig};
-use super::attention::qkv_attention;
+use super::attention::{qkv_attention, qkv_attention_weights, qkv_attention_sliced, qkv_attention_upcast, flash_attention};
 
 
 pub fn timestep_embedding<B: Backend>(timesteps: Tensor<B, 1, Int>, dim: usize, max_period: usize) -> Tensor<B, 2> {
+    float_timestep_embedding(to_float(timesteps), dim, max_period)
+}
+
+/// Sinusoidal embedding of non-integer values such as the refiner's aesthetic score.
+pub fn float_timestep_embedding<B: Backend>(timesteps: Tensor<B, 1>, dim: usize, max_period: usize) -> Tensor<B, 2> {
     let [n_batch] = timesteps.dims();
 
     let half = dim / 2;
     let freqs = ( to_float(Tensor::arange_device(0..half, &timesteps.device())) * (-(max_period as f64).ln() / half as f64 ) ).exp();
-    let args = to_float(timesteps).unsqueeze::<2>().transpose().repeat(1, half) * freqs.unsqueeze();
+    let args = timesteps.unsqueeze::<2>().transpose().repeat(1, half) * freqs.unsqueeze();
     Tensor::cat(vec![args.clone().cos(), args.sin()], 1)
 }
 
@@ -43,19 +57,95 @@ pub fn conditioning_embedding<B: Backend>(pooled_text_enc: Tensor<B, 2>, dim: us
 }
 
 
+/// How the transformer blocks of a `UNet` compute attention, passed down unchanged to every block.
+#[derive(Config, Debug)]
+pub struct AttentionOptions {
+    /// Computes attention in chunks of this many queries instead of materializing the full 
+    /// `[n_head, n_ctx, n_ctx]` score matrix at once, trading some speed for much lower peak memory. 
+    /// Applies to both self- and cross-attention unless overridden by the two settings below.
+    pub attention_slice_size: Option<usize>, 
+    /// Slice size of the self-attention over the spatial tokens, whose `[n_ctx, n_ctx]` scores dominate 
+    /// memory at high resolutions (16384 tokens in the first attention level at 1024x1024).
+    pub self_attention_slice_size: Option<usize>, 
+    /// Slice size of the cross-attention over the text tokens. Its scores are only `[n_ctx, 77]`, 
+    /// so it rarely needs slicing when self-attention is sliced.
+    pub cross_attention_slice_size: Option<usize>, 
+    /// Uses `flash_attention` with this block size, which never materializes more than a block of attention 
+    /// scores. Takes precedence over `attention_slice_size`.
+    pub flash_attention_block_size: Option<usize>, 
+    /// Computes the scores and softmax with `qkv_attention_upcast`, which rescales `q` and `k` so the scores 
+    /// can't overflow in f16 the way `q·kᵀ` does for some prompts at high guidance, turning the whole image 
+    /// into NaNs. Despite the name nothing is cast to f32. Takes precedence over `flash_attention_block_size`.
+    #[config(default = false)]
+    pub upcast_attention: bool, 
+}
+
+impl AttentionOptions {
+    fn self_slice_size(&self) -> Option<usize> {
+        self.self_attention_slice_size.or(self.attention_slice_size)
+    }
+
+    fn cross_slice_size(&self) -> Option<usize> {
+        self.cross_attention_slice_size.or(self.attention_slice_size)
+    }
+}
+
 #[derive(Config)]
 pub struct UNetConfig {
     adm_in_channels: usize, 
     in_channels: usize, 
     out_channels: usize, 
     model_channels: usize, 
+    /// The width of each attention head. The transformer blocks are `2 * model_channels` wide in the first 
+    /// attention level and `4 * model_channels` wide in the second and the middle block (640 and 1280 for SDXL), 
+    /// each split into `width / n_head_channels` heads, so both widths must be multiples of it.
     n_head_channels: usize, 
     context_dim: usize, 
+    /// Uses this many heads in every transformer block instead of deriving the count from `n_head_channels`, 
+    /// as some non-standard variants do. Both transformer widths must then be multiples of `n_head`.
+    n_head: Option<usize>, 
+    /// How every transformer block computes attention.
+    #[config(default = "AttentionOptions::new()")]
+    attention: AttentionOptions, 
+    /// Pads every convolution circularly instead of with zeros, so the generated image tiles seamlessly, 
+    /// see `UNet::with_tileable`.
+    #[config(default = false)]
+    tileable: bool, 
 }
 
 impl UNetConfig {
+    /// Checks that the attention heads evenly split every transformer block, which `init` would otherwise 
+    /// only report as a reshape panic in `forward`.
+    pub fn check(&self) -> Result<(), Box<dyn Error>> {
+        if self.model_channels == 0 || self.model_channels % 32 != 0 {
+            return Err( format!("The model channels {} must be a positive multiple of the 32 group norm groups.", self.model_channels).into() );
+        }
+
+        for width in [2 * self.model_channels, 4 * self.model_channels] {
+            match self.n_head {
+                Some(n_head) if n_head == 0 || width % n_head != 0 => {
+                    return Err( format!("The transformer width {} can't be split into {} heads.", width, n_head).into() );
+                }, 
+                None if self.n_head_channels == 0 || width % self.n_head_channels != 0 => {
+                    return Err( format!("The transformer width {} can't be split into heads of {} channels.", width, self.n_head_channels).into() );
+                }, 
+                _ => {}, 
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like `init`, but fails with the error from `check` instead of panicking on a bad head configuration.
+    pub fn try_init<B: Backend>(&self) -> Result<UNet<B>, Box<dyn Error>> {
+        self.check()?;
+        Ok( self.init() )
+    }
+
     pub fn init<B: Backend>(&self) -> UNet<B> {
-        assert!(self.model_channels % self.n_head_channels == 0, "The number of head channels must evenly divide the model channels.");
+        if let Err(e) = self.check() {
+            panic!("{}", e);
+        }
 
         let time_embed_dim = self.model_channels * 4;
 
@@ -91,7 +181,7 @@ impl UNetConfig {
         ##Appended layer*/
 
         let n_head = |channels| {
-            channels / self.n_head_channels
+            self.n_head.unwrap_or(channels / self.n_head_channels)
         };
 
         let input_blocks = UNetInputBlocks {
@@ -99,11 +189,11 @@ impl UNetConfig {
             r1: ResBlockConfig::new(self.model_channels, time_embed_dim, self.model_channels).init(), 
             r2: ResBlockConfig::new(self.model_channels, time_embed_dim, self.model_channels).init(),
             d1: DownsampleConfig::new(self.model_channels).init(), 
-            rt1: ResTransformerConfig::new(self.model_channels, time_embed_dim, 2 * self.model_channels, self.context_dim, n_head(2 * self.model_channels), 2).init(), 
-            rt2: ResTransformerConfig::new(2 * self.model_channels, time_embed_dim, 2 * self.model_channels, self.context_dim, n_head(2 * self.model_channels), 2).init(), 
+            rt1: ResTransformerConfig::new(self.model_channels, time_embed_dim, 2 * self.model_channels, self.context_dim, n_head(2 * self.model_channels), 2).with_attention(self.attention.clone()).init(), 
+            rt2: ResTransformerConfig::new(2 * self.model_channels, time_embed_dim, 2 * self.model_channels, self.context_dim, n_head(2 * self.model_channels), 2).with_attention(self.attention.clone()).init(), 
             d2: DownsampleConfig::new(2 * self.model_channels).init(), 
-            rt3: ResTransformerConfig::new(2 * self.model_channels, time_embed_dim, 4 * self.model_channels, self.context_dim, n_head(4 * self.model_channels), 10).init(), 
-            rt4: ResTransformerConfig::new(4 * self.model_channels, time_embed_dim, 4 * self.model_channels, self.context_dim, n_head(4 * self.model_channels), 10).init(), 
+            rt3: ResTransformerConfig::new(2 * self.model_channels, time_embed_dim, 4 * self.model_channels, self.context_dim, n_head(4 * self.model_channels), 10).with_attention(self.attention.clone()).init(), 
+            rt4: ResTransformerConfig::new(4 * self.model_channels, time_embed_dim, 4 * self.model_channels, self.context_dim, n_head(4 * self.model_channels), 10).with_attention(self.attention.clone()).init(), 
         };
 
         /*let input_blocks = UNetInputBlocks {
@@ -128,15 +218,15 @@ impl UNetConfig {
             self.context_dim, 
             n_head(4 * self.model_channels), 
             10
-        ).init();
+        ).with_attention(self.attention.clone()).init();
 
         let output_blocks = UNetOutputBlocks {
-            rt1: ResTransformerConfig::new(8 * self.model_channels, time_embed_dim, 4 * self.model_channels, self.context_dim, n_head(4 * self.model_channels), 10).init(), 
-            rt2: ResTransformerConfig::new(8 * self.model_channels, time_embed_dim, 4 * self.model_channels, self.context_dim, n_head(4 * self.model_channels), 10).init(), 
-            rtu1: ResTransformerUpsampleConfig::new(6 * self.model_channels, time_embed_dim, 4 * self.model_channels, self.context_dim, n_head(4 * self.model_channels), 10).init(), 
-            rt3: ResTransformerConfig::new(6 * self.model_channels, time_embed_dim, 2 * self.model_channels, self.context_dim, n_head(2 * self.model_channels), 2).init(), 
-            rt4: ResTransformerConfig::new(4 * self.model_channels, time_embed_dim, 2 * self.model_channels, self.context_dim, n_head(2 * self.model_channels), 2).init(), 
-            rtu2: ResTransformerUpsampleConfig::new(3 * self.model_channels, time_embed_dim, 2 * self.model_channels, self.context_dim, n_head(2 * self.model_channels), 2).init(),
+            rt1: ResTransformerConfig::new(8 * self.model_channels, time_embed_dim, 4 * self.model_channels, self.context_dim, n_head(4 * self.model_channels), 10).with_attention(self.attention.clone()).init(), 
+            rt2: ResTransformerConfig::new(8 * self.model_channels, time_embed_dim, 4 * self.model_channels, self.context_dim, n_head(4 * self.model_channels), 10).with_attention(self.attention.clone()).init(), 
+            rtu1: ResTransformerUpsampleConfig::new(6 * self.model_channels, time_embed_dim, 4 * self.model_channels, self.context_dim, n_head(4 * self.model_channels), 10).with_attention(self.attention.clone()).init(), 
+            rt3: ResTransformerConfig::new(6 * self.model_channels, time_embed_dim, 2 * self.model_channels, self.context_dim, n_head(2 * self.model_channels), 2).with_attention(self.attention.clone()).init(), 
+            rt4: ResTransformerConfig::new(4 * self.model_channels, time_embed_dim, 2 * self.model_channels, self.context_dim, n_head(2 * self.model_channels), 2).with_attention(self.attention.clone()).init(), 
+            rtu2: ResTransformerUpsampleConfig::new(3 * self.model_channels, time_embed_dim, 2 * self.model_channels, self.context_dim, n_head(2 * self.model_channels), 2).with_attention(self.attention.clone()).init(),
             r1: ResBlockConfig::new(3 * self.model_channels, time_embed_dim, self.model_channels).init(), 
             r2: ResBlockConfig::new(2 * self.model_channels, time_embed_dim, self.model_channels).init(), 
             r3: ResBlockConfig::new(2 * self.model_channels, time_embed_dim, self.model_channels).init(), 
@@ -175,6 +265,7 @@ impl UNetConfig {
             norm_out, 
             silu_out, 
             conv_out, 
+            tileable: self.tileable, 
         }
     }
 }
@@ -194,10 +285,60 @@ pub struct UNet<B: Backend> {
     norm_out: GroupNorm<B>, 
     silu_out: SILU, 
     conv_out: Conv2d<B>, 
+    tileable: bool, 
 }
 
 impl<B: Backend> UNet<B> {
+    /// Width of the label input `y`: the pooled text embedding followed by the size, crop and target size embeddings.
+    pub fn adm_in_channels(&self) -> usize {
+        let [n_in, _] = self.lin1_label_embed.weight.val().dims();
+        n_in
+    }
+
+    /// Width of the cross-attention context, i.e. of the concatenated text encoder hidden states.
+    pub fn context_dim(&self) -> usize {
+        let [n_in, _] = self.middle_block.transformer.blocks[0].attn2.key.weight.val().dims();
+        n_in
+    }
+
+    /// Pads every convolution circularly instead of with zeros, so that the latent wraps around at its edges 
+    /// and the generated image tiles seamlessly, e.g. for textures. Decode it with a tileable `Decoder`.
+    pub fn with_tileable(self, tileable: bool) -> Self {
+        Self {
+            tileable, 
+            ..self
+        }
+    }
+
     pub fn forward(&self, x: Tensor<B, 4>, timesteps: Tensor<B, 1, Int>, context: Tensor<B, 3>, label: Tensor<B, 2>) -> Tensor<B, 4> {
+        self.forward_with_context(x, timesteps, &Context::Global(context), label)
+    }
+
+    /// Like `forward`, additionally returning the cross-attention probabilities of every text token (as in DAAM). 
+    /// The head-averaged maps of each cross-attention layer are resized bilinearly to `resolution` (`[height, width]`, 
+    /// e.g. the latent size or that of the 32x32 transformers of a 1024x1024 image) and averaged over all layers. 
+    /// The maps have shape `[n_batch, n_ctx, height, width]`: `maps[b, t]` is where token `t` of the context of 
+    /// batch entry `b` was attended to, and sums to 1 over `t` at every position.
+    pub fn forward_with_attention(&self, x: Tensor<B, 4>, timesteps: Tensor<B, 1, Int>, context: Tensor<B, 3>, label: Tensor<B, 2>, resolution: [usize; 2]) -> (Tensor<B, 4>, Tensor<B, 4>) {
+        let mut maps = AttentionMaps::new(resolution);
+        let x = self.forward_collecting(x, timesteps, &Context::Global(context), label, Some(&mut maps));
+
+        (x, maps.average())
+    }
+
+    /// Like `forward`, but with a separate cross-attention context per latent region, see `RegionalContext`.
+    pub fn forward_regional(&self, x: Tensor<B, 4>, timesteps: Tensor<B, 1, Int>, context: RegionalContext<B>, label: Tensor<B, 2>) -> Tensor<B, 4> {
+        let [_, _, height, width] = x.dims();
+        assert!(context.latent_size == [height, width], "The regional context is for a {:?} latent but the input is {:?}.", context.latent_size, [height, width]);
+
+        self.forward_with_context(x, timesteps, &Context::Regional(context), label)
+    }
+
+    fn forward_with_context(&self, x: Tensor<B, 4>, timesteps: Tensor<B, 1, Int>, context: &Context<B>, label: Tensor<B, 2>) -> Tensor<B, 4> {
+        self.forward_collecting(x, timesteps, context, label, None)
+    }
+
+    fn forward_collecting(&self, x: Tensor<B, 4>, timesteps: Tensor<B, 1, Int>, context: &Context<B>, label: Tensor<B, 2>, maps: Option<&mut AttentionMaps<B>>) -> Tensor<B, 4> {
         // embed the timestep
         let t_emb = timestep_embedding(timesteps, self.model_channels, 10000);
         let t_emb = self.lin1_time_embed.forward(t_emb);
@@ -211,28 +352,92 @@ impl<B: Backend> UNet<B> {
 
         let emb = t_emb + label_emb;
 
-        let mut saved_inputs = Vec::new();
-        let mut x = x;
+        let x = forward_blocks(
+            x, 
+            emb, 
+            context, 
+            &self.input_blocks.as_array(), 
+            &self.middle_block, 
+            &self.output_blocks.as_array(), 
+            maps, 
+            self.tileable, 
+        );
 
-        // input blocks
-        for block in self.input_blocks.as_array() {
-            x = block.forward(x, emb.clone(), context.clone());
-            saved_inputs.push(x.clone())
-        }
+        let x = self.norm_out.forward(x);
+        let x = self.silu_out.forward(x);
+        let x = conv2d_forward(&self.conv_out, x, self.tileable);
+        x
+    }
+}
+
+fn forward_blocks<B: Backend>(x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, input_blocks: &[&dyn UNetBlock<B>], middle_block: &dyn UNetBlock<B>, output_blocks: &[&dyn UNetBlock<B>], mut maps: Option<&mut AttentionMaps<B>>, tileable: bool) -> Tensor<B, 4> {
+    let mut saved_inputs = Vec::new();
+    let mut x = x;
+
+    // input blocks
+    for block in input_blocks {
+        x = forward_block(*block, x, emb.clone(), context, maps.as_deref_mut(), tileable);
+        saved_inputs.push(x.clone())
+    }
+
+    // middle block
+    x = forward_block(middle_block, x, emb.clone(), context, maps.as_deref_mut(), tileable);
+
+    // output blocks
+    for block in output_blocks {
+        x = Tensor::cat(vec![x, saved_inputs.pop().unwrap()], 1);
+        x = forward_block(*block, x, emb.clone(), context, maps.as_deref_mut(), tileable);
+    }
+
+    x
+}
+
+fn forward_block<B: Backend>(block: &dyn UNetBlock<B>, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, maps: Option<&mut AttentionMaps<B>>, tileable: bool) -> Tensor<B, 4> {
+    match maps {
+        Some(maps) => block.forward_with_attention(x, emb, context, maps, tileable), 
+        None => block.forward(x, emb, context, tileable), 
+    }
+}
 
-        // middle block
-        x = self.middle_block.forward(x, emb.clone(), context.clone());
+/// Running average of cross-attention maps resized to one resolution, see `UNet::forward_with_attention`.
+struct AttentionMaps<B: Backend> {
+    resolution: [usize; 2], 
+    sum: Option<Tensor<B, 4>>, 
+    n_maps: usize, 
+}
 
-        // output blocks
-        for block in self.output_blocks.as_array() {
-            x = Tensor::cat(vec![x, saved_inputs.pop().unwrap()], 1);
-            x = block.forward(x, emb.clone(), context.clone());
+impl<B: Backend> AttentionMaps<B> {
+    fn new(resolution: [usize; 2]) -> Self {
+        Self {
+            resolution, 
+            sum: None, 
+            n_maps: 0, 
         }
+    }
 
-        let x = self.norm_out.forward(x);
-        let x = self.silu_out.forward(x);
-        let x = self.conv_out.forward(x);
-        x
+    /// Adds the head-averaged `[n_batch, height * width, n_ctx]` probabilities of a transformer of `size`.
+    fn add(&mut self, weights: Tensor<B, 3>, size: [usize; 2]) {
+        let [n_batch, _, n_ctx] = weights.dims();
+        let [height, width] = size;
+        let [out_height, out_width] = self.resolution;
+
+        let map = weights.swap_dims(1, 2).reshape([n_batch, n_ctx, height, width]);
+        let map = if size == self.resolution {
+            map
+        } else {
+            upsample_bilinear(map, out_height, out_width)
+        };
+
+        self.sum = Some( match self.sum.take() {
+            Some(sum) => sum + map, 
+            None => map, 
+        });
+        self.n_maps += 1;
+    }
+
+    fn average(self) -> Tensor<B, 4> {
+        let sum = self.sum.expect("The UNet has no cross-attention layers.");
+        sum.div_scalar(self.n_maps as f64)
     }
 }
 
@@ -300,8 +505,319 @@ impl<B: Backend> UNetOutputBlocks<B> {
 
 
 
+/// The SDXL refiner UNet. It has four resolution levels (channel multipliers 1, 2, 4, 4) 
+/// with transformers of depth 4 only on the middle two, and its context is OpenCLIP alone.
+#[derive(Config)]
+pub struct RefinerUNetConfig {
+    adm_in_channels: usize, 
+    in_channels: usize, 
+    out_channels: usize, 
+    model_channels: usize, 
+    n_head_channels: usize, 
+    context_dim: usize, 
+}
+
+impl RefinerUNetConfig {
+    pub fn init<B: Backend>(&self) -> RefinerUNet<B> {
+        assert!(self.model_channels % self.n_head_channels == 0, "The number of head channels must evenly divide the model channels.");
+
+        let time_embed_dim = self.model_channels * 4;
+
+        let lin1_time_embed = nn::LinearConfig::new(self.model_channels, time_embed_dim).init();
+        let silu_time_embed = SILU::new();
+        let lin2_time_embed = nn::LinearConfig::new(time_embed_dim, time_embed_dim).init();
+
+        let lin1_label_embed = nn::LinearConfig::new(self.adm_in_channels, time_embed_dim).init();
+        let silu_label_embed = SILU::new();
+        let lin2_label_embed = nn::LinearConfig::new(time_embed_dim, time_embed_dim).init();
+
+        let model_channels = self.model_channels;
+        let c = self.model_channels;
+        let depth = 4;
+
+        let n_head = |channels| {
+            channels / self.n_head_channels
+        };
+
+        let input_blocks = RefinerUNetInputBlocks {
+            conv: Conv2dConfig::new([self.in_channels, c], [3, 3]).with_padding(PaddingConfig2d::Explicit(1, 1)).init(),
+            r1: ResBlockConfig::new(c, time_embed_dim, c).init(), 
+            r2: ResBlockConfig::new(c, time_embed_dim, c).init(),
+            d1: DownsampleConfig::new(c).init(), 
+            rt1: ResTransformerConfig::new(c, time_embed_dim, 2 * c, self.context_dim, n_head(2 * c), depth).init(), 
+            rt2: ResTransformerConfig::new(2 * c, time_embed_dim, 2 * c, self.context_dim, n_head(2 * c), depth).init(), 
+            d2: DownsampleConfig::new(2 * c).init(), 
+            rt3: ResTransformerConfig::new(2 * c, time_embed_dim, 4 * c, self.context_dim, n_head(4 * c), depth).init(), 
+            rt4: ResTransformerConfig::new(4 * c, time_embed_dim, 4 * c, self.context_dim, n_head(4 * c), depth).init(), 
+            d3: DownsampleConfig::new(4 * c).init(), 
+            r3: ResBlockConfig::new(4 * c, time_embed_dim, 4 * c).init(), 
+            r4: ResBlockConfig::new(4 * c, time_embed_dim, 4 * c).init(), 
+        };
+
+        let middle_block = ResTransformerResConfig::new(
+            4 * c, 
+            time_embed_dim, 
+            4 * c, 
+            self.context_dim, 
+            n_head(4 * c), 
+            depth
+        ).init();
+
+        let output_blocks = RefinerUNetOutputBlocks {
+            r1: ResBlockConfig::new(8 * c, time_embed_dim, 4 * c).init(), 
+            r2: ResBlockConfig::new(8 * c, time_embed_dim, 4 * c).init(), 
+            ru: ResUpSampleConfig::new(8 * c, time_embed_dim, 4 * c).init(), 
+            rt1: ResTransformerConfig::new(8 * c, time_embed_dim, 4 * c, self.context_dim, n_head(4 * c), depth).init(), 
+            rt2: ResTransformerConfig::new(8 * c, time_embed_dim, 4 * c, self.context_dim, n_head(4 * c), depth).init(), 
+            rtu1: ResTransformerUpsampleConfig::new(6 * c, time_embed_dim, 4 * c, self.context_dim, n_head(4 * c), depth).init(), 
+            rt3: ResTransformerConfig::new(6 * c, time_embed_dim, 2 * c, self.context_dim, n_head(2 * c), depth).init(), 
+            rt4: ResTransformerConfig::new(4 * c, time_embed_dim, 2 * c, self.context_dim, n_head(2 * c), depth).init(), 
+            rtu2: ResTransformerUpsampleConfig::new(3 * c, time_embed_dim, 2 * c, self.context_dim, n_head(2 * c), depth).init(), 
+            r3: ResBlockConfig::new(3 * c, time_embed_dim, c).init(), 
+            r4: ResBlockConfig::new(2 * c, time_embed_dim, c).init(), 
+            r5: ResBlockConfig::new(2 * c, time_embed_dim, c).init(), 
+        };
+
+        let norm_out = GroupNormConfig::new(32, c).init();
+        let silu_out = SILU::new();
+        let conv_out = Conv2dConfig::new([c, self.out_channels], [3, 3]).with_padding(PaddingConfig2d::Explicit(1, 1)).init();
+
+        RefinerUNet {
+            model_channels, 
+            lin1_time_embed, 
+            silu_time_embed, 
+            lin2_time_embed, 
+            lin1_label_embed, 
+            silu_label_embed, 
+            lin2_label_embed, 
+            input_blocks, 
+            middle_block, 
+            output_blocks, 
+            norm_out, 
+            silu_out, 
+            conv_out, 
+        }
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct RefinerUNet<B: Backend> {
+    model_channels: usize, 
+    lin1_time_embed: nn::Linear<B>, 
+    silu_time_embed: SILU, 
+    lin2_time_embed: nn::Linear<B>, 
+    lin1_label_embed: nn::Linear<B>, 
+    silu_label_embed: SILU, 
+    lin2_label_embed: nn::Linear<B>, 
+    input_blocks: RefinerUNetInputBlocks<B>, 
+    middle_block: ResTransformerRes<B>, 
+    output_blocks: RefinerUNetOutputBlocks<B>, 
+    norm_out: GroupNorm<B>, 
+    silu_out: SILU, 
+    conv_out: Conv2d<B>, 
+}
+
+impl<B: Backend> RefinerUNet<B> {
+    /// Width of the label input: the pooled text embedding followed by the size, crop and aesthetic score embeddings.
+    pub fn adm_in_channels(&self) -> usize {
+        let [n_in, _] = self.lin1_label_embed.weight.val().dims();
+        n_in
+    }
+
+    /// Width of the cross-attention context.
+    pub fn context_dim(&self) -> usize {
+        let [n_in, _] = self.middle_block.transformer.blocks[0].attn2.key.weight.val().dims();
+        n_in
+    }
+
+    pub fn forward(&self, x: Tensor<B, 4>, timesteps: Tensor<B, 1, Int>, context: Tensor<B, 3>, label: Tensor<B, 2>) -> Tensor<B, 4> {
+        // embed the timestep
+        let t_emb = timestep_embedding(timesteps, self.model_channels, 10000);
+        let t_emb = self.lin1_time_embed.forward(t_emb);
+        let t_emb = self.silu_time_embed.forward(t_emb);
+        let t_emb = self.lin2_time_embed.forward(t_emb);
+
+        // embed the labels
+        let label_emb = self.lin1_label_embed.forward(label);
+        let label_emb = self.silu_label_embed.forward(label_emb);
+        let label_emb = self.lin2_label_embed.forward(label_emb);
+
+        let emb = t_emb + label_emb;
+
+        let x = forward_blocks(
+            x, 
+            emb, 
+            &Context::Global(context), 
+            &self.input_blocks.as_array(), 
+            &self.middle_block, 
+            &self.output_blocks.as_array(), 
+            None, 
+            false, 
+        );
+
+        let x = self.norm_out.forward(x);
+        let x = self.silu_out.forward(x);
+        let x = self.conv_out.forward(x);
+        x
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct RefinerUNetInputBlocks<B: Backend> {
+    conv: Conv2d<B>, 
+    r1: ResBlock<B>,
+    r2: ResBlock<B>,
+    d1: Downsample<B>, 
+    rt1: ResTransformer<B>, 
+    rt2: ResTransformer<B>, 
+    d2: Downsample<B>, 
+    rt3: ResTransformer<B>, 
+    rt4: ResTransformer<B>, 
+    d3: Downsample<B>, 
+    r3: ResBlock<B>,
+    r4: ResBlock<B>,
+}
+
+impl<B: Backend> RefinerUNetInputBlocks<B> {
+    fn as_array(&self) -> [&dyn UNetBlock<B>; 12] {
+        [
+            &self.conv, 
+            &self.r1, 
+            &self.r2,
+            &self.d1,
+            &self.rt1, 
+            &self.rt2,
+            &self.d2,
+            &self.rt3,
+            &self.rt4,
+            &self.d3,
+            &self.r3,
+            &self.r4,
+        ]
+    }
+}
+
+#[derive(Module, Debug)]
+pub struct RefinerUNetOutputBlocks<B: Backend> {
+    r1: ResBlock<B>,
+    r2: ResBlock<B>,
+    ru: ResUpSample<B>,
+    rt1: ResTransformer<B>,
+    rt2: ResTransformer<B>,
+    rtu1: ResTransformerUpsample<B>,
+    rt3: ResTransformer<B>,
+    rt4: ResTransformer<B>,
+    rtu2: ResTransformerUpsample<B>,
+    r3: ResBlock<B>,
+    r4: ResBlock<B>,
+    r5: ResBlock<B>,
+}
+
+impl<B: Backend> RefinerUNetOutputBlocks<B> {
+    fn as_array(&self) -> [&dyn UNetBlock<B>; 12] {
+        [
+            &self.r1,
+            &self.r2,
+            &self.ru,
+            &self.rt1,
+            &self.rt2,
+            &self.rtu1,
+            &self.rt3,
+            &self.rt4,
+            &self.rtu2,
+            &self.r3,
+            &self.r4,
+            &self.r5,
+        ]
+    }
+}
+
+
+
+
+
+/// The cross-attention context of the UNet's transformers.
+#[derive(Clone, Debug)]
+pub enum Context<B: Backend> {
+    /// One `[n_batch, n_ctx, context_dim]` context for the whole latent.
+    Global(Tensor<B, 3>), 
+    Regional(RegionalContext<B>), 
+}
+
+/// Cross-attention contexts for rectangular regions of the latent (regional prompting). 
+/// Each spatial position attends only to the context of the last region containing it, or to `base` 
+/// if none does. The hard masks are scaled down to every transformer's resolution by position centers.
+#[derive(Clone, Debug)]
+pub struct RegionalContext<B: Backend> {
+    pub base: Tensor<B, 3>, 
+    /// `([top, left, bottom, right], context)` with the rectangle in latent pixels, bottom and right exclusive.
+    pub regions: Vec<([usize; 4], Tensor<B, 3>)>, 
+    /// `[height, width]` of the latent the rectangles refer to.
+    pub latent_size: [usize; 2], 
+}
+
+impl<B: Backend> RegionalContext<B> {
+    pub fn new(base: Tensor<B, 3>, latent_size: [usize; 2]) -> Self {
+        Self {
+            base, 
+            regions: Vec::new(), 
+            latent_size, 
+        }
+    }
+
+    pub fn with_region(mut self, rect: [usize; 4], context: Tensor<B, 3>) -> Self {
+        self.regions.push((rect, context));
+        self
+    }
+
+    /// For every position of a transformer of `size`, the index of the region it belongs to, 
+    /// with 0 for `base` and `r + 1` for `regions[r]`.
+    fn owners(&self, size: [usize; 2]) -> Vec<usize> {
+        let [height, width] = size;
+        let [latent_height, latent_width] = self.latent_size;
+
+        // later regions take precedence, so each position goes to the last region containing its center
+        (0..height * width)
+            .map(|i| {
+                let y = ((i / width) as f64 + 0.5) * latent_height as f64 / height as f64;
+                let x = ((i % width) as f64 + 0.5) * latent_width as f64 / width as f64;
+
+                self.regions
+                    .iter()
+                    .rposition(|([top, left, bottom, right], _)| {
+                        *top as f64 <= y && y < *bottom as f64 && *left as f64 <= x && x < *right as f64
+                    })
+                    .map(|r| r + 1)
+                    .unwrap_or(0)
+            })
+            .collect()
+    }
+
+    /// The contexts used at a transformer of `size` with their `[1, height * width, 1]` hard masks.
+    fn masked_contexts(&self, size: [usize; 2], device: &B::Device) -> Vec<(Tensor<B, 3>, Tensor<B, 3>)> {
+        let [height, width] = size;
+        let owners = self.owners(size);
+
+        iter::once(&self.base)
+            .chain(self.regions.iter().map(|(_, context)| context))
+            .enumerate()
+            .filter(|(r, _)| owners.contains(r))
+            .map(|(r, context)| {
+                let mask: Vec<B::FloatElem> = owners.iter().map(|&o| (if o == r { 1.0f32 } else { 0.0 }).elem()).collect();
+                let mask = Tensor::from_data_device(Data::new(mask, Shape::new([1, height * width, 1])), device);
+                (context.clone(), mask)
+            })
+            .collect()
+    }
+}
+
 trait UNetBlock<B: Backend> {
-    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: Tensor<B, 3>) -> Tensor<B, 4>;
+    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, tileable: bool) -> Tensor<B, 4>;
+
+    /// Like `forward`, adding the cross-attention maps of any transformer in the block to `maps`.
+    fn forward_with_attention(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, maps: &mut AttentionMaps<B>, tileable: bool) -> Tensor<B, 4> {
+        self.forward(x, emb, context, tileable)
+    }
 }
 
 #[derive(Config)]
@@ -312,6 +828,8 @@ pub struct ResTransformerConfig {
     n_context_state: usize, 
     n_head: usize, 
     n_transformer_blocks: usize, 
+    #[config(default = "AttentionOptions::new()")]
+    attention: AttentionOptions, 
 }
 
 impl ResTransformerConfig {
@@ -322,7 +840,7 @@ impl ResTransformerConfig {
             self.n_context_state, 
             self.n_head, 
             self.n_transformer_blocks
-        ).init();
+        ).with_attention(self.attention.clone()).init();
 
         ResTransformer {
             res, 
@@ -338,11 +856,17 @@ pub struct ResTransformer<B: Backend> {
 }
 
 impl<B: Backend> UNetBlock<B> for ResTransformer<B> {
-    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: Tensor<B, 3>) -> Tensor<B, 4> {
-        let x = self.res.forward(x, emb);
+    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, tileable: bool) -> Tensor<B, 4> {
+        let x = self.res.forward(x, emb, tileable);
         let x = self.transformer.forward(x, context);
         x
     }
+
+    fn forward_with_attention(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, maps: &mut AttentionMaps<B>, tileable: bool) -> Tensor<B, 4> {
+        let x = self.res.forward(x, emb, tileable);
+        let x = self.transformer.forward_collecting(x, context, Some(maps));
+        x
+    }
 }
 
 #[derive(Config)]
@@ -371,9 +895,9 @@ pub struct ResUpSample<B: Backend> {
 }
 
 impl<B: Backend> UNetBlock<B> for ResUpSample<B> {
-    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: Tensor<B, 3>) -> Tensor<B, 4> {
-        let x = self.res.forward(x, emb);
-        let x = self.upsample.forward(x);
+    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, tileable: bool) -> Tensor<B, 4> {
+        let x = self.res.forward(x, emb, tileable);
+        let x = self.upsample.forward(x, tileable);
         x
     }
 }
@@ -386,6 +910,8 @@ pub struct ResTransformerUpsampleConfig {
     n_context_state: usize, 
     n_head: usize, 
     n_transformer_blocks: usize, 
+    #[config(default = "AttentionOptions::new()")]
+    attention: AttentionOptions, 
 }
 
 impl ResTransformerUpsampleConfig {
@@ -396,7 +922,7 @@ impl ResTransformerUpsampleConfig {
             self.n_context_state, 
             self.n_head, 
             self.n_transformer_blocks
-        ).init();
+        ).with_attention(self.attention.clone()).init();
         let upsample = UpsampleConfig::new(self.n_channels_out).init();
 
         ResTransformerUpsample {
@@ -415,10 +941,17 @@ pub struct ResTransformerUpsample<B: Backend> {
 }
 
 impl<B: Backend> UNetBlock<B> for ResTransformerUpsample<B> {
-    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: Tensor<B, 3>) -> Tensor<B, 4> {
-        let x = self.res.forward(x, emb);
+    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, tileable: bool) -> Tensor<B, 4> {
+        let x = self.res.forward(x, emb, tileable);
         let x = self.transformer.forward(x, context);
-        let x = self.upsample.forward(x);
+        let x = self.upsample.forward(x, tileable);
+        x
+    }
+
+    fn forward_with_attention(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, maps: &mut AttentionMaps<B>, tileable: bool) -> Tensor<B, 4> {
+        let x = self.res.forward(x, emb, tileable);
+        let x = self.transformer.forward_collecting(x, context, Some(maps));
+        let x = self.upsample.forward(x, tileable);
         x
     }
 }
@@ -431,6 +964,8 @@ pub struct ResTransformerResConfig {
     n_context_state: usize, 
     n_head: usize, 
     n_transformer_blocks: usize, 
+    #[config(default = "AttentionOptions::new()")]
+    attention: AttentionOptions, 
 }
 
 impl ResTransformerResConfig {
@@ -441,7 +976,7 @@ impl ResTransformerResConfig {
             self.n_context_state, 
             self.n_head, 
             self.n_transformer_blocks
-        ).init();
+        ).with_attention(self.attention.clone()).init();
         let res2 = ResBlockConfig::new(self.n_channels_in, self.n_channels_embed, self.n_channels_out).init();
 
         ResTransformerRes {
@@ -460,10 +995,17 @@ pub struct ResTransformerRes<B: Backend> {
 }
 
 impl<B: Backend> UNetBlock<B> for ResTransformerRes<B> {
-    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: Tensor<B, 3>) -> Tensor<B, 4> {
-        let x = self.res1.forward(x, emb.clone());
+    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, tileable: bool) -> Tensor<B, 4> {
+        let x = self.res1.forward(x, emb.clone(), tileable);
         let x = self.transformer.forward(x, context);
-        let x = self.res2.forward(x, emb);
+        let x = self.res2.forward(x, emb, tileable);
+        x
+    }
+
+    fn forward_with_attention(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, maps: &mut AttentionMaps<B>, tileable: bool) -> Tensor<B, 4> {
+        let x = self.res1.forward(x, emb.clone(), tileable);
+        let x = self.transformer.forward_collecting(x, context, Some(maps));
+        let x = self.res2.forward(x, emb, tileable);
         x
     }
 }
@@ -493,20 +1035,20 @@ pub struct Upsample<B: Backend> {
 }
 
 impl<B: Backend> Upsample<B> {
-    fn forward(&self, x: Tensor<B, 4>) -> Tensor<B, 4> {
+    fn forward(&self, x: Tensor<B, 4>, tileable: bool) -> Tensor<B, 4> {
         let [n_batch, n_channel, height, width] = x.dims();
         let x = x
                 .reshape([n_batch, n_channel, height, 1, width, 1])
                 .repeat(3, 2)
                 .repeat(5, 2)
                 .reshape([n_batch, n_channel, 2 * height, 2 * width]);
-        self.conv.forward(x)
+        conv2d_forward(&self.conv, x, tileable)
     }
 }
 
 impl<B: Backend> UNetBlock<B> for Upsample<B> {
-    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: Tensor<B, 3>) -> Tensor<B, 4> {
-        self.forward(x)
+    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, tileable: bool) -> Tensor<B, 4> {
+        self.forward(x, tileable)
     }
 }
 
@@ -527,8 +1069,8 @@ impl DownsampleConfig {
 type Downsample<B> = Conv2d<B>;
 
 impl<B: Backend> UNetBlock<B> for Conv2d<B> {
-    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: Tensor<B, 3>) -> Tensor<B, 4> {
-        self.forward(x)
+    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, tileable: bool) -> Tensor<B, 4> {
+        conv2d_forward(self, x, tileable)
     }
 }
 
@@ -541,6 +1083,8 @@ pub struct SpatialTransformerConfig {
     n_context_state: usize, 
     n_head: usize, 
     n_blocks: usize, 
+    #[config(default = "AttentionOptions::new()")]
+    attention: AttentionOptions, 
 }
 
 impl SpatialTransformerConfig {
@@ -549,7 +1093,7 @@ impl SpatialTransformerConfig {
         let proj_in = nn::LinearConfig::new(self.n_channels, self.n_channels).init(); //Conv2dConfig::new([self.n_channels, self.n_channels], [1, 1]).init();
         let blocks = (0..self.n_blocks)
             .into_iter()
-            .map(|_| TransformerBlockConfig::new(self.n_channels, self.n_context_state, self.n_head).init())
+            .map(|_| TransformerBlockConfig::new(self.n_channels, self.n_context_state, self.n_head).with_attention(self.attention.clone()).init())
             .collect();
         let proj_out = nn::LinearConfig::new(self.n_channels, self.n_channels).init(); //Conv2dConfig::new([self.n_channels, self.n_channels], [1, 1]).init();
 
@@ -571,7 +1115,12 @@ pub struct SpatialTransformer<B: Backend> {
 }
 
 impl<B: Backend> SpatialTransformer<B> {
-    fn forward(&self, x: Tensor<B, 4>, context: Tensor<B, 3>) -> Tensor<B, 4> {
+    fn forward(&self, x: Tensor<B, 4>, context: &Context<B>) -> Tensor<B, 4> {
+        self.forward_collecting(x, context, None)
+    }
+
+    /// Regional contexts are not collected into `maps`.
+    fn forward_collecting(&self, x: Tensor<B, 4>, context: &Context<B>, mut maps: Option<&mut AttentionMaps<B>>) -> Tensor<B, 4> {
         let [n_batch, n_channel, height, width] = x.dims();
 
         let x_in = x.clone();
@@ -581,8 +1130,21 @@ impl<B: Backend> SpatialTransformer<B> {
         let x = self.proj_in.forward(x);
 
         let mut x = x;
-        for block in &self.blocks {
-            x = block.forward(x, context.clone());
+        match context {
+            Context::Global(context) => {
+                for block in &self.blocks {
+                    x = match maps.as_deref_mut() {
+                        Some(maps) => block.forward_with_attention(x, context.clone(), [height, width], maps), 
+                        None => block.forward(x, context.clone()), 
+                    };
+                }
+            }
+            Context::Regional(context) => {
+                let contexts = context.masked_contexts([height, width], &x.device());
+                for block in &self.blocks {
+                    x = block.forward_masked(x, &contexts);
+                }
+            }
         }
 
         let x = self.proj_out.forward(x)
@@ -605,14 +1167,16 @@ pub struct TransformerBlockConfig {
     n_state: usize, 
     n_context_state: usize, 
     n_head: usize, 
+    #[config(default = "AttentionOptions::new()")]
+    attention: AttentionOptions, 
 }
 
 impl TransformerBlockConfig {
     fn init<B: Backend>(&self) -> TransformerBlock<B> {
         let norm1 = LayerNormConfig::new(self.n_state).init();
-        let attn1 = MultiHeadAttentionConfig::new(self.n_state, self.n_state, self.n_head).init();
+        let attn1 = MultiHeadAttentionConfig::new(self.n_state, self.n_state, self.n_head).with_attention_slice_size(self.attention.self_slice_size()).with_flash_attention_block_size(self.attention.flash_attention_block_size).with_upcast_attention(self.attention.upcast_attention).init();
         let norm2 = LayerNormConfig::new(self.n_state).init();
-        let attn2 = MultiHeadAttentionConfig::new(self.n_state, self.n_context_state, self.n_head).init();
+        let attn2 = MultiHeadAttentionConfig::new(self.n_state, self.n_context_state, self.n_head).with_attention_slice_size(self.attention.cross_slice_size()).with_flash_attention_block_size(self.attention.flash_attention_block_size).with_upcast_attention(self.attention.upcast_attention).init();
         let norm3 = LayerNormConfig::new(self.n_state).init();
         let mlp = MLPConfig::new(self.n_state, 4).init();
 
@@ -643,6 +1207,31 @@ impl<B: Backend> TransformerBlock<B> {
         let x = x.clone() + self.attn2.forward( self.norm2.forward(x), Some(context));
         x.clone() + self.mlp.forward( self.norm3.forward(x) )
     }
+
+    fn forward_with_attention(&self, x: Tensor<B, 3>, context: Tensor<B, 3>, size: [usize; 2], maps: &mut AttentionMaps<B>) -> Tensor<B, 3> {
+        let x = x.clone() + self.attn1.forward( self.norm1.forward(x), None);
+
+        let h = self.norm2.forward(x.clone());
+        maps.add(self.attn2.attention_weights(h.clone(), context.clone()), size);
+
+        let x = x + self.attn2.forward(h, Some(context));
+        x.clone() + self.mlp.forward( self.norm3.forward(x) )
+    }
+
+    /// Cross-attends to each `(context, mask)` pair and sums the results weighted by the `[1, n_ctx, 1]` masks.
+    fn forward_masked(&self, x: Tensor<B, 3>, contexts: &[(Tensor<B, 3>, Tensor<B, 3>)]) -> Tensor<B, 3> {
+        let x = x.clone() + self.attn1.forward( self.norm1.forward(x), None);
+
+        let h = self.norm2.forward(x.clone());
+        let cross = contexts
+            .iter()
+            .map(|(context, mask)| self.attn2.forward(h.clone(), Some(context.clone())) * mask.clone())
+            .reduce(|a, b| a + b)
+            .expect("At least one context is required.");
+
+        let x = x + cross;
+        x.clone() + self.mlp.forward( self.norm3.forward(x) )
+    }
 }
 
 
@@ -725,6 +1314,10 @@ pub struct MultiHeadAttentionConfig {
     n_state: usize, 
     n_context_state: usize, 
     n_head: usize, 
+    attention_slice_size: Option<usize>, 
+    flash_attention_block_size: Option<usize>, 
+    #[config(default = false)]
+    upcast_attention: bool, 
 }
 
 impl MultiHeadAttentionConfig {
@@ -742,7 +1335,10 @@ impl MultiHeadAttentionConfig {
             query, 
             key, 
             value, 
-            out 
+            out, 
+            attention_slice_size: self.attention_slice_size, 
+            flash_attention_block_size: self.flash_attention_block_size, 
+            upcast_attention: self.upcast_attention, 
         }
     }
 }
@@ -754,6 +1350,9 @@ pub struct MultiHeadAttention<B: Backend> {
     key: nn::Linear<B>, 
     value: nn::Linear<B>, 
     out: nn::Linear<B>, 
+    attention_slice_size: Option<usize>, 
+    flash_attention_block_size: Option<usize>, 
+    upcast_attention: bool, 
 }
 
 impl<B: Backend> MultiHeadAttention<B> {
@@ -764,10 +1363,26 @@ impl<B: Backend> MultiHeadAttention<B> {
         let k = self.key.forward(xa.clone());
         let v = self.value.forward(xa);
 
-        let wv = qkv_attention(q, k, v, None, self.n_head);
+        let wv = if self.upcast_attention {
+            qkv_attention_upcast(q, k, v, None, self.n_head, self.attention_slice_size)
+        } else {
+            match (self.flash_attention_block_size, self.attention_slice_size) {
+                (Some(block_size), _) => flash_attention(q, k, v, self.n_head, block_size), 
+                (None, Some(slice_size)) => qkv_attention_sliced(q, k, v, None, self.n_head, slice_size), 
+                (None, None) => qkv_attention(q, k, v, None, self.n_head), 
+            }
+        };
 
         self.out.forward(wv)
     }
+
+    /// Attention probabilities of `x` over `context` averaged over the heads, `[n_batch, n_qctx, n_ctx]`.
+    pub fn attention_weights(&self, x: Tensor<B, 3>, context: Tensor<B, 3>) -> Tensor<B, 3> {
+        let q = self.query.forward(x);
+        let k = self.key.forward(context);
+
+        qkv_attention_weights(q, k, None, self.n_head).mean_dim(1).squeeze(1)
+    }
 }
 
 
@@ -841,10 +1456,10 @@ pub struct ResBlock<B: Backend> {
 }
 
 impl<B: Backend> ResBlock<B> {
-    fn forward(&self, x: Tensor<B, 4>, embed: Tensor<B, 2>) -> Tensor<B, 4> {
+    fn forward(&self, x: Tensor<B, 4>, embed: Tensor<B, 2>, tileable: bool) -> Tensor<B, 4> {
         let h = self.norm_in.forward(x.clone());
         let h = self.silu_in.forward(h);
-        let h = self.conv_in.forward(h);
+        let h = conv2d_forward(&self.conv_in, h, tileable);
 
         let embed_out = self.silu_embed.forward(embed);
         let embed_out = self.lin_embed.forward(embed_out);
@@ -854,7 +1469,7 @@ impl<B: Backend> ResBlock<B> {
 
         let h = self.norm_out.forward(h);
         let h = self.silu_out.forward(h);
-        let h = self.conv_out.forward(h);
+        let h = conv2d_forward(&self.conv_out, h, tileable);
 
         let out = if let Some(skipc) = self.skip_connection.as_ref() {
             skipc.forward(x) + h
@@ -867,9 +1482,104 @@ impl<B: Backend> ResBlock<B> {
 }
 
 impl<B: Backend> UNetBlock<B> for ResBlock<B> {
-    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: Tensor<B, 3>) -> Tensor<B, 4> {
-        self.forward(x, emb)
+    fn forward(&self, x: Tensor<B, 4>, emb: Tensor<B, 2>, context: &Context<B>, tileable: bool) -> Tensor<B, 4> {
+        self.forward(x, emb, tileable)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::helper::{tensor_max_element, roll};
+
+    #[test]
+    fn test_check_heads() {
+        // SDXL: 640 and 1280 wide transformers with 64 channel heads
+        assert!(UNetConfig::new(2816, 4, 4, 320, 64, 2048).check().is_ok());
+        assert!(UNetConfig::new(2816, 4, 4, 320, 64, 2048).with_n_head(Some(10)).check().is_ok());
+
+        assert!(UNetConfig::new(2816, 4, 4, 320, 96, 2048).check().is_err());
+        assert!(UNetConfig::new(2816, 4, 4, 320, 64, 2048).with_n_head(Some(3)).check().is_err());
+        assert!(UNetConfig::new(2816, 4, 4, 320, 0, 2048).check().is_err());
+        assert!(UNetConfig::new(2816, 4, 4, 48, 8, 2048).check().is_err());
+
+        assert!(UNetConfig::new(2816, 4, 4, 320, 96, 2048).try_init::<burn_tch::TchBackend<f32>>().is_err());
+    }
+
+    #[test]
+    fn test_separate_attention_slicing() {
+        type TestBackend = burn_tch::TchBackend<f32>;
 
+        let config = TransformerBlockConfig::new(32, 16, 4);
+        let unsliced: TransformerBlock<TestBackend> = config.init();
+
+        let x: Tensor<TestBackend, 3> = Tensor::random([1, 36, 32], Distribution::Normal(0.0, 1.0));
+        let context: Tensor<TestBackend, 3> = Tensor::random([1, 7, 16], Distribution::Normal(0.0, 1.0));
+        let expected = unsliced.forward(x.clone(), context.clone());
+
+        let self_sliced = TransformerBlockConfig::new(32, 16, 4).with_attention(AttentionOptions::new().with_self_attention_slice_size(Some(5)));
+        let cross_sliced = TransformerBlockConfig::new(32, 16, 4).with_attention(AttentionOptions::new().with_cross_attention_slice_size(Some(5)));
+        for config in [self_sliced, cross_sliced] {
+            let block: TransformerBlock<TestBackend> = config.init().load_record(unsliced.clone().into_record());
+            let output = block.forward(x.clone(), context.clone());
+            assert!(tensor_max_element((output - expected.clone()).abs()) < 1e-5);
+        }
+
+        let block: TransformerBlock<TestBackend> = TransformerBlockConfig::new(32, 16, 4).with_attention(AttentionOptions::new().with_cross_attention_slice_size(Some(5))).init();
+        assert_eq!(block.attn1.attention_slice_size, None);
+        assert_eq!(block.attn2.attention_slice_size, Some(5));
+    }
+
+    #[test]
+    fn test_forward_with_attention() {
+        type TestBackend = burn_tch::TchBackend<f32>;
+
+        let unet: UNet<TestBackend> = UNetConfig::new(8, 4, 4, 32, 16, 16).init();
+        let x: Tensor<TestBackend, 4> = Tensor::random([2, 4, 16, 16], Distribution::Normal(0.0, 1.0));
+        let timesteps = Tensor::from_ints([500, 500]);
+        let context: Tensor<TestBackend, 3> = Tensor::random([2, 7, 16], Distribution::Normal(0.0, 1.0));
+        let label: Tensor<TestBackend, 2> = Tensor::random([2, 8], Distribution::Normal(0.0, 1.0));
+
+        let expected = unet.forward(x.clone(), timesteps.clone(), context.clone(), label.clone());
+        let (output, maps) = unet.forward_with_attention(x, timesteps, context, label, [8, 8]);
+        assert!(tensor_max_element((output - expected).abs()) < 1e-5);
+
+        // one map per token, forming a distribution over the tokens at every position
+        assert_eq!(maps.dims(), [2, 7, 8, 8]);
+        let total = maps.sum_dim(1);
+        assert!(tensor_max_element(total.sub_scalar(1.0).abs()) < 1e-4);
+    }
+
+    #[test]
+    fn test_tileable_unet() {
+        type TestBackend = burn_tch::TchBackend<f32>;
+
+        let unet: UNet<TestBackend> = UNetConfig::new(8, 4, 4, 32, 16, 16).with_tileable(true).init();
+        let x: Tensor<TestBackend, 4> = Tensor::random([1, 4, 16, 16], Distribution::Normal(0.0, 1.0));
+        let timesteps = Tensor::from_ints([500]);
+        let context: Tensor<TestBackend, 3> = Tensor::random([1, 7, 16], Distribution::Normal(0.0, 1.0));
+        let label: Tensor<TestBackend, 2> = Tensor::random([1, 8], Distribution::Normal(0.0, 1.0));
+
+        // the latent wraps around, so shifting it by a multiple of the downsampling shifts the prediction
+        let output = unet.forward(x.clone(), timesteps.clone(), context.clone(), label.clone());
+        for (dim, shift) in [(2, 4), (3, 8)] {
+            let shifted = unet.forward(roll(x.clone(), dim, shift), timesteps.clone(), context.clone(), label.clone());
+            assert!(tensor_max_element((shifted - roll(output.clone(), dim, shift)).abs()) < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_regional_context_owners() {
+        let context: Tensor<burn_tch::TchBackend<f32>, 3> = Tensor::zeros([1, 1, 1]);
+        let regional = RegionalContext::new(context.clone(), [4, 4])
+            .with_region([0, 0, 4, 2], context.clone())
+            .with_region([0, 1, 2, 4], context);
+
+        // the later region wins where both contain a position's center, base gets the rest
+        assert_eq!(regional.owners([4, 4]), vec![1, 2, 2, 2, 1, 2, 2, 2, 1, 1, 0, 0, 1, 1, 0, 0]);
+        assert_eq!(regional.owners([2, 2]), vec![2, 2, 1, 0]);
+
+        let contexts = regional.masked_contexts([2, 2], &burn_tch::TchDevice::Cpu);
+        assert_eq!(contexts.len(), 3);
+    }
+}
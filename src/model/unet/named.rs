@@ -0,0 +1,413 @@
+use burn::{
+    module::Param,
+    nn::{self, conv::Conv2d},
+    tensor::{
+        backend::Backend,
+        Tensor,
+    },
+};
+
+use super::{
+    UNet, UNetInputBlocks, UNetOutputBlocks, ResBlock, ResTransformer, ResTransformerUpsample, ResTransformerRes,
+    SpatialTransformer, TransformerBlock, MultiHeadAttention, MLP, GEGLU, Upsample,
+};
+use crate::model::groupnorm::GroupNorm;
+use crate::model::layernorm::LayerNorm;
+
+/// One parameter of `UNet::named_parameters`, by rank. Linear weights are `[n_in, n_out]`, the transpose of
+/// torch's layout; convolution weights are `[n_out, n_in, kernel_height, kernel_width]` as in torch; biases and
+/// the scales and shifts of the normalization layers are `[n_channel]`.
+pub enum ParamRef<'a, B: Backend> {
+    Tensor1(&'a Param<Tensor<B, 1>>), 
+    Tensor2(&'a Param<Tensor<B, 2>>), 
+    Tensor4(&'a Param<Tensor<B, 4>>), 
+}
+
+impl<'a, B: Backend> ParamRef<'a, B> {
+    pub fn dims(&self) -> Vec<usize> {
+        match self {
+            ParamRef::Tensor1(param) => param.val().dims().to_vec(), 
+            ParamRef::Tensor2(param) => param.val().dims().to_vec(), 
+            ParamRef::Tensor4(param) => param.val().dims().to_vec(), 
+        }
+    }
+}
+
+/// Like `ParamRef`, but mutable. Assign e.g. `Param::from(tensor)` to replace a weight in place.
+pub enum ParamMut<'a, B: Backend> {
+    Tensor1(&'a mut Param<Tensor<B, 1>>), 
+    Tensor2(&'a mut Param<Tensor<B, 2>>), 
+    Tensor4(&'a mut Param<Tensor<B, 4>>), 
+}
+
+impl<B: Backend> UNet<B> {
+    /// Every parameter of the UNet with its canonical name, in field order. A name is the module path the
+    /// `load` functions read the layer from, joined by `/`, followed by `weight` or `bias`, e.g.
+    /// `lin1_time_embed/weight`, `input_blocks/r1/conv_in/bias` or
+    /// `input_blocks/rt1/transformer/transformer_0/attn2/key/weight`. Transformer blocks are `transformer_{i}`,
+    /// and the scale and shift of a normalization layer are its `weight` and `bias`. `names::UNET` maps the
+    /// module paths to their diffusers names. Parameters a layer doesn't have, such as missing biases or skip
+    /// connections, are left out.
+    pub fn named_parameters(&self) -> impl Iterator<Item = (String, ParamRef<'_, B>)> {
+        let mut params = Vec::new();
+        self.collect("", &mut params);
+        params.into_iter()
+    }
+
+    /// Like `named_parameters`, but mutable, e.g. to patch weights in memory.
+    pub fn named_parameters_mut(&mut self) -> impl Iterator<Item = (String, ParamMut<'_, B>)> {
+        let mut params = Vec::new();
+        self.collect_mut("", &mut params);
+        params.into_iter()
+    }
+}
+
+fn child(path: &str, name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}/{}", path, name)
+    }
+}
+
+trait NamedParams<B: Backend> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>);
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>);
+}
+
+impl<B: Backend, M: NamedParams<B>> NamedParams<B> for Option<M> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        if let Some(module) = self {
+            module.collect(path, params);
+        }
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        if let Some(module) = self {
+            module.collect_mut(path, params);
+        }
+    }
+}
+
+impl<B: Backend> NamedParams<B> for nn::Linear<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        params.push((child(path, "weight"), ParamRef::Tensor2(&self.weight)));
+        if let Some(bias) = &self.bias {
+            params.push((child(path, "bias"), ParamRef::Tensor1(bias)));
+        }
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        params.push((child(path, "weight"), ParamMut::Tensor2(&mut self.weight)));
+        if let Some(bias) = &mut self.bias {
+            params.push((child(path, "bias"), ParamMut::Tensor1(bias)));
+        }
+    }
+}
+
+impl<B: Backend> NamedParams<B> for Conv2d<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        params.push((child(path, "weight"), ParamRef::Tensor4(&self.weight)));
+        if let Some(bias) = &self.bias {
+            params.push((child(path, "bias"), ParamRef::Tensor1(bias)));
+        }
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        params.push((child(path, "weight"), ParamMut::Tensor4(&mut self.weight)));
+        if let Some(bias) = &mut self.bias {
+            params.push((child(path, "bias"), ParamMut::Tensor1(bias)));
+        }
+    }
+}
+
+impl<B: Backend> NamedParams<B> for GroupNorm<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        for (name, param) in self.params() {
+            params.push((child(path, name), ParamRef::Tensor1(param)));
+        }
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        for (name, param) in self.params_mut() {
+            params.push((child(path, name), ParamMut::Tensor1(param)));
+        }
+    }
+}
+
+impl<B: Backend> NamedParams<B> for LayerNorm<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        for (name, param) in self.params() {
+            params.push((child(path, name), ParamRef::Tensor1(param)));
+        }
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        for (name, param) in self.params_mut() {
+            params.push((child(path, name), ParamMut::Tensor1(param)));
+        }
+    }
+}
+
+impl<B: Backend> NamedParams<B> for ResBlock<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.norm_in.collect(&child(path, "norm_in"), params);
+        self.conv_in.collect(&child(path, "conv_in"), params);
+        self.lin_embed.collect(&child(path, "lin_embed"), params);
+        self.norm_out.collect(&child(path, "norm_out"), params);
+        self.conv_out.collect(&child(path, "conv_out"), params);
+        self.skip_connection.collect(&child(path, "skip_connection"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.norm_in.collect_mut(&child(path, "norm_in"), params);
+        self.conv_in.collect_mut(&child(path, "conv_in"), params);
+        self.lin_embed.collect_mut(&child(path, "lin_embed"), params);
+        self.norm_out.collect_mut(&child(path, "norm_out"), params);
+        self.conv_out.collect_mut(&child(path, "conv_out"), params);
+        self.skip_connection.collect_mut(&child(path, "skip_connection"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for MultiHeadAttention<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.query.collect(&child(path, "query"), params);
+        self.key.collect(&child(path, "key"), params);
+        self.value.collect(&child(path, "value"), params);
+        self.out.collect(&child(path, "out"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.query.collect_mut(&child(path, "query"), params);
+        self.key.collect_mut(&child(path, "key"), params);
+        self.value.collect_mut(&child(path, "value"), params);
+        self.out.collect_mut(&child(path, "out"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for GEGLU<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.proj.collect(&child(path, "proj"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.proj.collect_mut(&child(path, "proj"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for MLP<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.geglu.collect(&child(path, "geglu"), params);
+        self.lin.collect(&child(path, "lin"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.geglu.collect_mut(&child(path, "geglu"), params);
+        self.lin.collect_mut(&child(path, "lin"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for TransformerBlock<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.norm1.collect(&child(path, "norm1"), params);
+        self.attn1.collect(&child(path, "attn1"), params);
+        self.norm2.collect(&child(path, "norm2"), params);
+        self.attn2.collect(&child(path, "attn2"), params);
+        self.norm3.collect(&child(path, "norm3"), params);
+        self.mlp.collect(&child(path, "mlp"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.norm1.collect_mut(&child(path, "norm1"), params);
+        self.attn1.collect_mut(&child(path, "attn1"), params);
+        self.norm2.collect_mut(&child(path, "norm2"), params);
+        self.attn2.collect_mut(&child(path, "attn2"), params);
+        self.norm3.collect_mut(&child(path, "norm3"), params);
+        self.mlp.collect_mut(&child(path, "mlp"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for SpatialTransformer<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.norm.collect(&child(path, "norm"), params);
+        self.proj_in.collect(&child(path, "proj_in"), params);
+        for (i, block) in self.blocks.iter().enumerate() {
+            block.collect(&child(path, &format!("transformer_{}", i)), params);
+        }
+        self.proj_out.collect(&child(path, "proj_out"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.norm.collect_mut(&child(path, "norm"), params);
+        self.proj_in.collect_mut(&child(path, "proj_in"), params);
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            block.collect_mut(&child(path, &format!("transformer_{}", i)), params);
+        }
+        self.proj_out.collect_mut(&child(path, "proj_out"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for Upsample<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.conv.collect(&child(path, "conv"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.conv.collect_mut(&child(path, "conv"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for ResTransformer<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.res.collect(&child(path, "res"), params);
+        self.transformer.collect(&child(path, "transformer"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.res.collect_mut(&child(path, "res"), params);
+        self.transformer.collect_mut(&child(path, "transformer"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for ResTransformerUpsample<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.res.collect(&child(path, "res"), params);
+        self.transformer.collect(&child(path, "transformer"), params);
+        self.upsample.collect(&child(path, "upsample"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.res.collect_mut(&child(path, "res"), params);
+        self.transformer.collect_mut(&child(path, "transformer"), params);
+        self.upsample.collect_mut(&child(path, "upsample"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for ResTransformerRes<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.res1.collect(&child(path, "res1"), params);
+        self.transformer.collect(&child(path, "transformer"), params);
+        self.res2.collect(&child(path, "res2"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.res1.collect_mut(&child(path, "res1"), params);
+        self.transformer.collect_mut(&child(path, "transformer"), params);
+        self.res2.collect_mut(&child(path, "res2"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for UNetInputBlocks<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.conv.collect(&child(path, "conv"), params);
+        self.r1.collect(&child(path, "r1"), params);
+        self.r2.collect(&child(path, "r2"), params);
+        self.d1.collect(&child(path, "d1"), params);
+        self.rt1.collect(&child(path, "rt1"), params);
+        self.rt2.collect(&child(path, "rt2"), params);
+        self.d2.collect(&child(path, "d2"), params);
+        self.rt3.collect(&child(path, "rt3"), params);
+        self.rt4.collect(&child(path, "rt4"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.conv.collect_mut(&child(path, "conv"), params);
+        self.r1.collect_mut(&child(path, "r1"), params);
+        self.r2.collect_mut(&child(path, "r2"), params);
+        self.d1.collect_mut(&child(path, "d1"), params);
+        self.rt1.collect_mut(&child(path, "rt1"), params);
+        self.rt2.collect_mut(&child(path, "rt2"), params);
+        self.d2.collect_mut(&child(path, "d2"), params);
+        self.rt3.collect_mut(&child(path, "rt3"), params);
+        self.rt4.collect_mut(&child(path, "rt4"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for UNetOutputBlocks<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.rt1.collect(&child(path, "rt1"), params);
+        self.rt2.collect(&child(path, "rt2"), params);
+        self.rtu1.collect(&child(path, "rtu1"), params);
+        self.rt3.collect(&child(path, "rt3"), params);
+        self.rt4.collect(&child(path, "rt4"), params);
+        self.rtu2.collect(&child(path, "rtu2"), params);
+        self.r1.collect(&child(path, "r1"), params);
+        self.r2.collect(&child(path, "r2"), params);
+        self.r3.collect(&child(path, "r3"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.rt1.collect_mut(&child(path, "rt1"), params);
+        self.rt2.collect_mut(&child(path, "rt2"), params);
+        self.rtu1.collect_mut(&child(path, "rtu1"), params);
+        self.rt3.collect_mut(&child(path, "rt3"), params);
+        self.rt4.collect_mut(&child(path, "rt4"), params);
+        self.rtu2.collect_mut(&child(path, "rtu2"), params);
+        self.r1.collect_mut(&child(path, "r1"), params);
+        self.r2.collect_mut(&child(path, "r2"), params);
+        self.r3.collect_mut(&child(path, "r3"), params);
+    }
+}
+
+impl<B: Backend> NamedParams<B> for UNet<B> {
+    fn collect<'a>(&'a self, path: &str, params: &mut Vec<(String, ParamRef<'a, B>)>) {
+        self.lin1_time_embed.collect(&child(path, "lin1_time_embed"), params);
+        self.lin2_time_embed.collect(&child(path, "lin2_time_embed"), params);
+        self.lin1_label_embed.collect(&child(path, "lin1_label_embed"), params);
+        self.lin2_label_embed.collect(&child(path, "lin2_label_embed"), params);
+        self.input_blocks.collect(&child(path, "input_blocks"), params);
+        self.middle_block.collect(&child(path, "middle_block"), params);
+        self.output_blocks.collect(&child(path, "output_blocks"), params);
+        self.norm_out.collect(&child(path, "norm_out"), params);
+        self.conv_out.collect(&child(path, "conv_out"), params);
+    }
+
+    fn collect_mut<'a>(&'a mut self, path: &str, params: &mut Vec<(String, ParamMut<'a, B>)>) {
+        self.lin1_time_embed.collect_mut(&child(path, "lin1_time_embed"), params);
+        self.lin2_time_embed.collect_mut(&child(path, "lin2_time_embed"), params);
+        self.lin1_label_embed.collect_mut(&child(path, "lin1_label_embed"), params);
+        self.lin2_label_embed.collect_mut(&child(path, "lin2_label_embed"), params);
+        self.input_blocks.collect_mut(&child(path, "input_blocks"), params);
+        self.middle_block.collect_mut(&child(path, "middle_block"), params);
+        self.output_blocks.collect_mut(&child(path, "output_blocks"), params);
+        self.norm_out.collect_mut(&child(path, "norm_out"), params);
+        self.conv_out.collect_mut(&child(path, "conv_out"), params);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::unet::UNetConfig;
+    use crate::model::params::ParameterCount;
+    use std::collections::HashSet;
+    use burn_tch::TchBackend;
+
+    type TestBackend = TchBackend<f32>;
+
+    #[test]
+    fn test_named_parameters() {
+        let mut unet: UNet<TestBackend> = UNetConfig::new(8, 4, 4, 32, 16, 16).init();
+
+        let names: Vec<String> = unet.named_parameters().map(|(name, _)| name).collect();
+        assert_eq!(names.iter().collect::<HashSet<_>>().len(), names.len());
+        assert_eq!(names[0], "lin1_time_embed/weight");
+        assert!(names.contains(&"input_blocks/rt1/transformer/transformer_0/attn2/key/weight".to_string()));
+        assert!(names.contains(&"middle_block/res1/norm_in/bias".to_string()));
+
+        let n_params: usize = unet.named_parameters().map(|(_, param)| param.dims().iter().product::<usize>()).sum();
+        assert_eq!(n_params, unet.num_parameters());
+
+        match unet.named_parameters().find(|(name, _)| name == "input_blocks/conv/weight") {
+            Some((_, ParamRef::Tensor4(weight))) => assert_eq!(weight.val().dims(), [32, 4, 3, 3]), 
+            _ => panic!("input_blocks/conv/weight is not a convolution weight"), 
+        }
+
+        for (name, param) in unet.named_parameters_mut() {
+            if let ("conv_out/bias", ParamMut::Tensor1(bias)) = (name.as_str(), param) {
+                *bias = Param::from(Tensor::ones([4]));
+            }
+        }
+        assert_eq!(unet.conv_out.bias.as_ref().unwrap().val().into_data().value, vec![1.0; 4]);
+    }
+}
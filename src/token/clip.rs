@@ -1,8 +1,9 @@
-use super::Tokenizer;
+use super::{Tokenizer, textual_inversion::TextualInversions};
 
 use burn::module::Module;
 
 use std::collections::HashMap;
+use std::error::Error;
 use regex::Regex;
 
 use std::fs::File;
@@ -45,24 +46,46 @@ fn whitespace_clean(text: &str) -> String {
     text.split_whitespace().collect::<Vec<&str>>().join(" ")
 }
 
+/// Reads BPE merges, one space separated pair per line in rank order. A `#version` header on the first line 
+/// and blank lines are skipped, any other line that isn't exactly two symbols is an error naming the line.
 fn load_merges(path: &str) -> io::Result<Vec<(String, String)>> {
     let file = File::open(&path)?;
     let reader = io::BufReader::new(file);
     
     let mut merges = Vec::new();
     
-    for line in reader.lines() {
+    for (i, line) in reader.lines().enumerate() {
         let line = line?;
-        let mut words = line.split_whitespace();
-        
-        if let (Some(word1), Some(word2)) = (words.next(), words.next()) {
-            merges.push((word1.into(), word2.into()));
+        if (i == 0 && line.contains("#version")) || line.trim().is_empty() {
+            continue;
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [word1, word2] => merges.push((word1.into(), word2.into())), 
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidData, 
+                format!("{}:{}: expected a merge of two symbols but got {:?}", path, i + 1, line), 
+            )), 
         }
     }
     
     Ok(merges)
 }
 
+fn load_vocab(path: &str) -> io::Result<Vec<String>> {
+    let file = File::open(path)?;
+    io::BufReader::new(file).lines().collect::<io::Result<Vec<String>>>()
+}
+
+/// Looks up the id of a special token, which custom vocabularies must contain too.
+fn special_token(encoder: &HashMap<String, u32>, token: &str) -> io::Result<u32> {
+    encoder.get(token).cloned().ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidData, 
+        format!("The vocabulary has no {} token.", token), 
+    ))
+}
+
 fn construct_vocab(chars: impl Iterator<Item=char> + Clone, merges: &[(String, String)]) -> Vec<String> {
     let iter = chars.map(String::from);
     let mut vocab: Vec<_> = iter.clone().chain( iter.map(|c| c + "</w>") ).collect();
@@ -85,20 +108,39 @@ pub struct SimpleTokenizer {
     bpe_ranks: HashMap<(String, String), u32>,
     cache: HashMap<String, String>,
     pat: Regex, 
+    start_of_text_token: u32, 
+    end_of_text_token: u32, 
+    textual_inversions: TextualInversions, 
 }
 
 impl SimpleTokenizer {
+    /// The tokenizer of the CLIP ViT-L text encoder, built from the bundled merges.
     pub fn new() -> io::Result<Self> {
         let byte_unicode_values = bytes_to_unicode();
 
-        let byte_encoder: HashMap<_, _> = byte_unicode_values.iter().cloned().collect();
-        let byte_decoder = byte_encoder.iter().map(|(k,v)| (*v,*k)).collect();
-
         let merges = load_merges("tokenizer/clip/bpe_simple_vocab_16e6.txt")?;
-        let merges = merges[1..49152-256-2+1].to_vec();
+        let merges = merges[..49152-256-2].to_vec();
 
         let vocab = construct_vocab(byte_unicode_values.into_iter().map(|(_, u)| u), &merges[..]);
 
+        Self::from_vocab_and_merges(vocab, merges)
+    }
+
+    /// Loads a custom BPE vocabulary: `vocab_path` lists one token per line in id order and `merges_path` the 
+    /// merges in rank order, see `load_merges`. The vocabulary must contain `<|startoftext|>` and `<|endoftext|>`.
+    pub fn from_files(vocab_path: &str, merges_path: &str) -> io::Result<Self> {
+        let vocab = load_vocab(vocab_path)?;
+        let merges = load_merges(merges_path)?;
+
+        Self::from_vocab_and_merges(vocab, merges)
+    }
+
+    fn from_vocab_and_merges(vocab: Vec<String>, merges: Vec<(String, String)>) -> io::Result<Self> {
+        let byte_unicode_values = bytes_to_unicode();
+
+        let byte_encoder: HashMap<_, _> = byte_unicode_values.iter().cloned().collect();
+        let byte_decoder = byte_encoder.iter().map(|(k,v)| (*v,*k)).collect();
+
         let encoder: HashMap<String, u32> = vocab.iter().cloned().zip((0..).into_iter()).collect();
         let decoder: HashMap<u32, String> = encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
         let bpe_ranks = merges.iter().cloned().zip((0..).into_iter()).collect();
@@ -107,6 +149,11 @@ impl SimpleTokenizer {
             ("<|endoftext|>".to_string(), "<|endoftext|>".to_string()), 
         ]);
 
+        let start_of_text_token = special_token(&encoder, "<|startoftext|>")?;
+        let end_of_text_token = special_token(&encoder, "<|endoftext|>")?;
+
+        let textual_inversions = TextualInversions::new(encoder.len() as u32);
+
         let pat = Regex::new(r"(?i)<\|startoftext\|>|<\|endoftext\|>|'s|'t|'re|'ve|'m|'ll|'d|\p{L}+|\p{N}|[^\s\p{L}\p{N}]+").unwrap();
 
         Ok( SimpleTokenizer {
@@ -117,9 +164,43 @@ impl SimpleTokenizer {
             bpe_ranks: bpe_ranks,
             cache: cache,
             pat: pat, 
+            start_of_text_token, 
+            end_of_text_token, 
+            textual_inversions: textual_inversions, 
         } )
     }
 
+    /// Registers a textual inversion embedding: `trigger` (e.g. `<my-style>`) then encodes to a reserved 
+    /// token id that the text encoder embeds as `embedding` instead of looking it up in its vocabulary.
+    pub fn add_embedding(&mut self, trigger: &str, embedding: Vec<f32>) -> Result<(), Box<dyn Error>> {
+        self.add_multi_vector_embedding(trigger, vec![embedding])
+    }
+
+    /// Like `add_embedding` for embeddings trained with several vectors, which encode to one token per vector.
+    pub fn add_multi_vector_embedding(&mut self, trigger: &str, vectors: Vec<Vec<f32>>) -> Result<(), Box<dyn Error>> {
+        self.textual_inversions.add(trigger, vectors)?;
+        Ok(())
+    }
+
+    fn encode_vocab(&self, text: &str) -> Vec<u32> {
+        let cleaned_text = whitespace_clean(text.trim()).to_lowercase();
+
+        self.pat.find_iter(&cleaned_text)
+            .flat_map(|m| {
+                let token: String = m.as_str().as_bytes().into_iter().map(|b| self.byte_encoder[b]).collect();
+                self.bpe(&token).split(' ').map(|bpe_token| self.encoder[bpe_token]).collect::<Vec<_>>()
+            }).collect()
+    }
+
+    fn decode_vocab(&self, tokens: &[u32]) -> String {
+        let text: String = tokens.iter().map(|t| self.decoder[t].as_str()).collect();
+        let decoded_bytes: Vec<u8> = text.chars()
+            .map(|c| self.byte_decoder[&c])
+            .collect();
+
+        String::from_utf8_lossy(&decoded_bytes[..]).replace("</w>", " ")
+    }
+
     pub fn bpe(&self, token: &str) -> String {
         if let Some(word) = self.cache.get(token) {
             return word.clone();
@@ -179,19 +260,13 @@ impl SimpleTokenizer {
 
 impl Tokenizer for SimpleTokenizer {
     fn encode(&self, text: &str, add_sot: bool, add_eot: bool) -> Vec<u32> {
-        let cleaned_text = whitespace_clean(text.trim()).to_lowercase();
-
         let mut bpe_tokens: Vec<u32> = Vec::new();
 
         if add_sot {
             bpe_tokens.push(self.start_of_text_token());
         }
 
-        for m in self.pat.find_iter(&cleaned_text) {
-            let token = m.as_str();
-            let token: String = token.as_bytes().into_iter().map(|b| self.byte_encoder[b]).collect();
-            bpe_tokens.extend(self.bpe(&token).split(' ').map(|bpe_token| self.encoder[bpe_token]))
-        }
+        bpe_tokens.extend( self.textual_inversions.encode(text, |segment| self.encode_vocab(segment)) );
 
         if add_eot {
             bpe_tokens.push(self.end_of_text_token());
@@ -200,26 +275,25 @@ impl Tokenizer for SimpleTokenizer {
        return bpe_tokens;
    }
 
-    fn decode(&self, tokens: &[u32]) -> String {
-        let text: String = tokens.iter().map(|t| self.decoder[t].as_str()).collect();
-        let decoded_bytes: Vec<u8> = text.chars()
-            .map(|c| self.byte_decoder[&c])
-            .collect();
-
-        String::from_utf8_lossy(&decoded_bytes[..]).replace("</w>", " ")
+    fn decode_raw(&self, tokens: &[u32]) -> String {
+        self.textual_inversions.decode(tokens, |tokens| self.decode_vocab(tokens))
     }
 
     fn start_of_text_token(&self) -> u32 {
-        49406
+        self.start_of_text_token
     }
 
     fn end_of_text_token(&self) -> u32 {
-        49407
+        self.end_of_text_token
     }
 
     fn padding_token(&self) -> u32 {
         self.end_of_text_token()
     }
+
+    fn textual_inversions(&self) -> Option<&TextualInversions> {
+        Some(&self.textual_inversions)
+    }
 }
 
 #[cfg(test)]
@@ -234,9 +308,86 @@ mod tests {
         let target_encode = [3306, 1002, 256, 49406, 587, 10468, 49406];
         let target_decode = "hello world ! <|startoftext|>asdf <|startoftext|>"; // extra spaces sometimes
 
-        let encoded = tokenizer.encode(&text);
+        let encoded = tokenizer.encode(&text, false, false);
         assert_eq!(&target_encode[..], &encoded[..]);
-        let decoded = tokenizer.decode(&encoded[..]);
+        let decoded = tokenizer.decode_raw(&encoded[..]);
         assert_eq!(target_decode, decoded);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_decode_round_trip() {
+        let tokenizer = SimpleTokenizer::new().unwrap();
+
+        let text = "A  photo of an Astronaut, riding a horse!";
+        let mut ids: Vec<usize> = tokenizer.encode(text, true, true).into_iter().map(|t| t as usize).collect();
+        ids.resize(77, tokenizer.padding_token() as usize);
+
+        assert_eq!(tokenizer.decode(&ids), "a photo of an astronaut , riding a horse !");
+    }
+
+    #[test]
+    fn test_textual_inversion_tokens() {
+        let mut tokenizer = SimpleTokenizer::new().unwrap();
+        tokenizer.add_embedding("<my-style>", vec![0.0; 768]).unwrap();
+        tokenizer.add_multi_vector_embedding("<my-Object>", vec![vec![0.0; 768]; 3]).unwrap();
+
+        let plain = tokenizer.encode("a photo in the style of", false, false);
+        let encoded = tokenizer.encode("a photo in the style of <my-style>, <My-object>", false, false);
+
+        let mut expected = plain.clone();
+        expected.push(49408);
+        expected.extend(tokenizer.encode(",", false, false));
+        expected.extend([49409, 49410, 49411]);
+        assert_eq!(encoded, expected);
+
+        assert_eq!(tokenizer.decode_raw(&encoded), "a photo in the style of <my-style> , <my-object> ");
+
+        assert!(tokenizer.add_embedding("", vec![0.0; 768]).is_err());
+        assert!(tokenizer.add_multi_vector_embedding("<empty>", vec![]).is_err());
+    }
+
+    #[test]
+    fn test_textual_inversion_reregistration() {
+        let mut tokenizer = SimpleTokenizer::new().unwrap();
+        tokenizer.add_embedding("<my-style>", vec![0.0; 768]).unwrap();
+        tokenizer.add_multi_vector_embedding("<my-object>", vec![vec![0.0; 768]; 3]).unwrap();
+
+        // the same number of vectors reuses the trigger's ids
+        tokenizer.add_embedding("<my-style>", vec![1.0; 768]).unwrap();
+        let inversions = tokenizer.textual_inversions().unwrap();
+        assert_eq!(inversions.vectors().len(), 4);
+        assert_eq!(inversions.vectors()[0][0], 1.0);
+        assert_eq!(tokenizer.encode("<my-style>", false, false), vec![49408]);
+
+        // a different number frees the old ids and moves the later embeddings down
+        tokenizer.add_multi_vector_embedding("<my-style>", vec![vec![2.0; 768]; 2]).unwrap();
+        let inversions = tokenizer.textual_inversions().unwrap();
+        assert_eq!(inversions.vectors().len(), 5);
+        assert_eq!(tokenizer.encode("<my-object>", false, false), vec![49408, 49409, 49410]);
+        assert_eq!(tokenizer.encode("<my-style>", false, false), vec![49411, 49412]);
+        assert_eq!(inversions.vectors()[3][0], 2.0);
+    }
+
+    #[test]
+    fn test_from_files() {
+        let dir = std::env::temp_dir().join(format!("clip_tokenizer_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let vocab_path = dir.join("vocab.txt").to_str().unwrap().to_string();
+        let merges_path = dir.join("merges.txt").to_str().unwrap().to_string();
+
+        let merges = vec![("h".to_string(), "i</w>".to_string())];
+        let vocab = construct_vocab(bytes_to_unicode().into_iter().map(|(_, u)| u), &merges);
+        std::fs::write(&vocab_path, vocab.join("\n")).unwrap();
+        std::fs::write(&merges_path, "#version: 0.2\nh i</w>\n").unwrap();
+
+        // the merged symbol follows the 512 byte symbols, then come the special tokens
+        let tokenizer = SimpleTokenizer::from_files(&vocab_path, &merges_path).unwrap();
+        assert_eq!(tokenizer.encode("hi", true, true), vec![513, 512, 514]);
+
+        std::fs::write(&merges_path, "#version: 0.2\nh i</w>\nh i </w>\n").unwrap();
+        let err = SimpleTokenizer::from_files(&vocab_path, &merges_path).unwrap_err();
+        assert!(err.to_string().contains("merges.txt:3:"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
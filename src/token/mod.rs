@@ -1,11 +1,98 @@
 pub mod clip;
 pub mod open_clip;
+pub mod weighting;
+pub mod scheduling;
+pub mod textual_inversion;
+
+use std::error::Error;
+use std::fmt;
+
+use textual_inversion::TextualInversions;
+
+/// Number of tokens, including the start and end of text tokens, that fit in a CLIP context window.
+pub const MAX_TOKENS: usize = 77;
+
+/// A prompt encoded to more tokens than fit in the context window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TooManyTokens {
+    pub n_tokens: usize, 
+    pub max_tokens: usize, 
+}
+
+impl fmt::Display for TooManyTokens {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "The prompt has {} tokens but only {} fit in the context window, the rest would be ignored. Use the chunked conditioning methods for long prompts.", self.n_tokens, self.max_tokens)
+    }
+}
+
+impl Error for TooManyTokens {}
 
 pub trait Tokenizer {
     fn encode(&self, text: &str, add_sot: bool, add_eot: bool) -> Vec<u32>;
-    fn decode(&self, tokens: &[u32]) -> String;
+    /// Decodes every token, special tokens included.
+    fn decode_raw(&self, tokens: &[u32]) -> String;
 
     fn start_of_text_token(&self) -> u32;
     fn end_of_text_token(&self) -> u32;
     fn padding_token(&self) -> u32;
+
+    /// The textual inversion embeddings whose ids `encode` may emit, if any were added.
+    fn textual_inversions(&self) -> Option<&TextualInversions> {
+        None
+    }
+
+    /// Like `encode`, but fails instead of letting the caller silently truncate a prompt longer than `MAX_TOKENS`.
+    fn encode_checked(&self, text: &str, add_sot: bool, add_eot: bool) -> Result<Vec<usize>, TooManyTokens> {
+        let tokens = self.encode(text, add_sot, add_eot);
+
+        if tokens.len() > MAX_TOKENS {
+            return Err(TooManyTokens {
+                n_tokens: tokens.len(), 
+                max_tokens: MAX_TOKENS, 
+            });
+        }
+
+        Ok( tokens.into_iter().map(|t| t as usize).collect() )
+    }
+
+    /// Maps token ids back to text. Start of text tokens are skipped and decoding stops at the first end of text token, 
+    /// so any padding after it is dropped too. `decode(encode(text))` recovers `text` up to lowercasing and whitespace.
+    fn decode(&self, ids: &[usize]) -> String {
+        let sot = self.start_of_text_token() as usize;
+        let eot = self.end_of_text_token() as usize;
+
+        let tokens: Vec<u32> = ids.iter()
+            .cloned()
+            .take_while(|&id| id != eot)
+            .filter(|&id| id != sot)
+            .map(|id| id as u32)
+            .collect();
+
+        self.decode_raw(&tokens).trim_end().to_string()
+    }
+
+    /// Encodes a prompt that may use attention weighting syntax, returning the tokens along 
+    /// with a parallel vector of per-token weights. See `weighting::parse_prompt_attention`.
+    fn encode_weighted(&self, text: &str, add_sot: bool, add_eot: bool) -> (Vec<u32>, Vec<f32>) {
+        let mut tokens = Vec::new();
+        let mut weights = Vec::new();
+
+        if add_sot {
+            tokens.push(self.start_of_text_token());
+            weights.push(1.0);
+        }
+
+        for (segment, weight) in weighting::parse_prompt_attention(text) {
+            let segment_tokens = self.encode(&segment, false, false);
+            weights.extend(std::iter::repeat(weight).take(segment_tokens.len()));
+            tokens.extend(segment_tokens);
+        }
+
+        if add_eot {
+            tokens.push(self.end_of_text_token());
+            weights.push(1.0);
+        }
+
+        (tokens, weights)
+    }
 }
\ No newline at end of file
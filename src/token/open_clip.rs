@@ -1,8 +1,9 @@
-use super::Tokenizer;
+use super::{Tokenizer, textual_inversion::TextualInversions};
 
 use burn::module::Module;
 
 use std::collections::HashMap;
+use std::error::Error;
 use regex::Regex;
 
 use std::fs::File;
@@ -45,18 +46,27 @@ fn whitespace_clean(text: &str) -> String {
     text.split_whitespace().collect::<Vec<&str>>().join(" ")
 }
 
+/// Reads BPE merges, one space separated pair per line in rank order. A `#version` header on the first line 
+/// and blank lines are skipped, any other line that isn't exactly two symbols is an error naming the line.
 fn load_merges(path: &str) -> io::Result<Vec<(String, String)>> {
     let file = File::open(&path)?;
     let reader = io::BufReader::new(file);
     
     let mut merges = Vec::new();
     
-    for line in reader.lines() {
+    for (i, line) in reader.lines().enumerate() {
         let line = line?;
-        let mut words = line.split_whitespace();
-        
-        if let (Some(word1), Some(word2)) = (words.next(), words.next()) {
-            merges.push((word1.into(), word2.into()));
+        if (i == 0 && line.contains("#version")) || line.trim().is_empty() {
+            continue;
+        }
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[..] {
+            [word1, word2] => merges.push((word1.into(), word2.into())), 
+            _ => return Err(io::Error::new(
+                io::ErrorKind::InvalidData, 
+                format!("{}:{}: expected a merge of two symbols but got {:?}", path, i + 1, line), 
+            )), 
         }
     }
     
@@ -68,6 +78,14 @@ fn load_vocab(path: &str) -> io::Result<Vec<String>> {
     io::BufReader::new(file).lines().collect::<io::Result<Vec<String>>>()
 }
 
+/// Looks up the id of a special token, which custom vocabularies must contain too.
+fn special_token(encoder: &HashMap<String, u32>, token: &str) -> io::Result<u32> {
+    encoder.get(token).cloned().ok_or_else(|| io::Error::new(
+        io::ErrorKind::InvalidData, 
+        format!("The vocabulary has no {} token.", token), 
+    ))
+}
+
 #[derive(Module, Clone, Debug)]
 pub struct OpenClipTokenizer {
     byte_encoder: HashMap<u8, char>,
@@ -77,17 +95,28 @@ pub struct OpenClipTokenizer {
     bpe_ranks: HashMap<(String, String), u32>,
     cache: HashMap<String, String>,
     pat: Regex, 
+    start_of_text_token: u32, 
+    end_of_text_token: u32, 
+    textual_inversions: TextualInversions, 
 }
 
 impl OpenClipTokenizer {
+    /// The tokenizer of the OpenCLIP ViT-bigG text encoder, built from the vocabulary and merges that 
+    /// `tokenizer/convert.py` extracts into `tokenizer/open_clip`.
     pub fn new() -> io::Result<Self> {
+        Self::from_files("tokenizer/open_clip/vocab.txt", "tokenizer/open_clip/merges.txt")
+    }
+
+    /// Loads a custom BPE vocabulary: `vocab_path` lists one token per line in id order and `merges_path` the 
+    /// merges in rank order, see `load_merges`. The vocabulary must contain `<|startoftext|>` and `<|endoftext|>`.
+    pub fn from_files(vocab_path: &str, merges_path: &str) -> io::Result<Self> {
         let byte_unicode_values = bytes_to_unicode();
 
         let byte_encoder: HashMap<_, _> = byte_unicode_values.iter().cloned().collect();
         let byte_decoder = byte_encoder.iter().map(|(k,v)| (*v,*k)).collect();
 
-        let merges = load_merges("tokenizer/open_clip/merges.txt")?;
-        let vocab = load_vocab("tokenizer/open_clip/vocab.txt")?;
+        let merges = load_merges(merges_path)?;
+        let vocab = load_vocab(vocab_path)?;
 
         let encoder: HashMap<String, u32> = vocab.iter().cloned().zip((0..).into_iter()).collect();
         let decoder: HashMap<u32, String> = encoder.iter().map(|(k, v)| (*v, k.clone())).collect();
@@ -99,6 +128,11 @@ impl OpenClipTokenizer {
 
         let cache = HashMap::new();
 
+        let start_of_text_token = special_token(&encoder, "<|startoftext|>")?;
+        let end_of_text_token = special_token(&encoder, "<|endoftext|>")?;
+
+        let textual_inversions = TextualInversions::new(encoder.len() as u32);
+
         let pat = Regex::new(r"(?i)<\|startoftext\|>|<\|endoftext\|>|'s|'t|'re|'ve|'m|'ll|'d|\p{L}+|\p{N}|[^\s\p{L}\p{N}]+").unwrap();
 
         Ok( OpenClipTokenizer {
@@ -109,9 +143,43 @@ impl OpenClipTokenizer {
             bpe_ranks: bpe_ranks,
             cache: cache,
             pat: pat, 
+            start_of_text_token, 
+            end_of_text_token, 
+            textual_inversions: textual_inversions, 
         } )
     }
 
+    /// Registers a textual inversion embedding: `trigger` (e.g. `<my-style>`) then encodes to a reserved 
+    /// token id that the text encoder embeds as `embedding` instead of looking it up in its vocabulary.
+    pub fn add_embedding(&mut self, trigger: &str, embedding: Vec<f32>) -> Result<(), Box<dyn Error>> {
+        self.add_multi_vector_embedding(trigger, vec![embedding])
+    }
+
+    /// Like `add_embedding` for embeddings trained with several vectors, which encode to one token per vector.
+    pub fn add_multi_vector_embedding(&mut self, trigger: &str, vectors: Vec<Vec<f32>>) -> Result<(), Box<dyn Error>> {
+        self.textual_inversions.add(trigger, vectors)?;
+        Ok(())
+    }
+
+    fn encode_vocab(&self, text: &str) -> Vec<u32> {
+        let cleaned_text = whitespace_clean(text.trim()).to_lowercase();
+
+        self.pat.find_iter(&cleaned_text)
+            .flat_map(|m| {
+                let token: String = m.as_str().as_bytes().into_iter().map(|b| self.byte_encoder[b]).collect();
+                self.bpe(&token).split(' ').map(|bpe_token| self.encoder[bpe_token]).collect::<Vec<_>>()
+            }).collect()
+    }
+
+    fn decode_vocab(&self, tokens: &[u32]) -> String {
+       let text: String = tokens.iter().map(|t| self.decoder[t].as_str()).collect();
+       let decoded_bytes: Vec<u8> = text.chars()
+           .map(|c| self.byte_decoder[&c])
+           .collect();
+
+       String::from_utf8_lossy(&decoded_bytes[..]).replace("</w>", " ")
+   }
+
     pub fn bpe(&self, token: &str) -> String {
         if let Some(word) = self.cache.get(token) {
             return word.clone();
@@ -171,19 +239,13 @@ impl OpenClipTokenizer {
 
 impl Tokenizer for OpenClipTokenizer {
     fn encode(&self, text: &str, add_sot: bool, add_eot: bool) -> Vec<u32> {
-        let cleaned_text = whitespace_clean(text.trim()).to_lowercase();
-
         let mut bpe_tokens: Vec<u32> = Vec::new();
 
         if add_sot {
             bpe_tokens.push(self.start_of_text_token());
         }
 
-        for m in self.pat.find_iter(&cleaned_text) {
-            let token = m.as_str();
-            let token: String = token.as_bytes().into_iter().map(|b| self.byte_encoder[b]).collect();
-            bpe_tokens.extend(self.bpe(&token).split(' ').map(|bpe_token| self.encoder[bpe_token]))
-        }
+        bpe_tokens.extend( self.textual_inversions.encode(text, |segment| self.encode_vocab(segment)) );
 
         if add_eot {
             bpe_tokens.push(self.end_of_text_token());
@@ -192,26 +254,25 @@ impl Tokenizer for OpenClipTokenizer {
        return bpe_tokens;
    }
 
-    fn decode(&self, tokens: &[u32]) -> String {
-       let text: String = tokens.iter().map(|t| self.decoder[t].as_str()).collect();
-       let decoded_bytes: Vec<u8> = text.chars()
-           .map(|c| self.byte_decoder[&c])
-           .collect();
-
-       String::from_utf8_lossy(&decoded_bytes[..]).replace("</w>", " ")
-   }
+    fn decode_raw(&self, tokens: &[u32]) -> String {
+        self.textual_inversions.decode(tokens, |tokens| self.decode_vocab(tokens))
+    }
 
     fn start_of_text_token(&self) -> u32 {
-       49406
+       self.start_of_text_token
    }
 
     fn end_of_text_token(&self) -> u32 {
-       49407
+       self.end_of_text_token
    }
 
     fn padding_token(&self) -> u32 {
        0
    }
+
+    fn textual_inversions(&self) -> Option<&TextualInversions> {
+        Some(&self.textual_inversions)
+    }
 }
 
 /*#[cfg(test)]
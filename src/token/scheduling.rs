@@ -0,0 +1,82 @@
+use std::error::Error;
+use std::iter;
+
+use regex::Regex;
+
+/// Expands the `[from:to:when]` prompt editing syntax into the prompts used over the denoising steps: `from` is
+/// used until the fraction `when` (in 0..=1) of the steps is done and `to` afterwards, e.g. "a [cat:dog:0.5]"
+/// gives `[(0.0, "a cat"), (0.5, "a dog")]`. Each prompt comes with the fraction of the steps it starts at, in
+/// increasing order and starting at 0.0. Several groups switch independently. Plain `[de-emphasis]` groups and
+/// escaped `\[` brackets are left for `parse_prompt_attention`.
+pub fn parse_prompt_schedule(text: &str) -> Result<Vec<(f32, String)>, Box<dyn Error>> {
+    let re_edit = Regex::new(r"\[([^\[\]:]*):([^\[\]:]*):\s*([+-]?[.\d]+)\s*\]").unwrap();
+
+    let mut edits = Vec::new();
+    for m in re_edit.captures_iter(text) {
+        let range = m.get(0).unwrap().range();
+        if text[..range.start].ends_with('\\') {
+            continue;
+        }
+
+        let when: f32 = m[3].parse().map_err(|_| format!("Invalid prompt edit step {:?} in {:?}.", &m[3], &m[0]))?;
+        if !(0.0..=1.0).contains(&when) {
+            return Err( format!("The prompt edit {:?} must switch at a fraction of the steps between 0 and 1.", &m[0]).into() );
+        }
+
+        edits.push((range, m[1].to_string(), m[2].to_string(), when));
+    }
+
+    let mut starts: Vec<f32> = iter::once(0.0).chain(edits.iter().map(|(_, _, _, when)| *when)).collect();
+    starts.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    starts.dedup();
+
+    let schedule = starts
+        .into_iter()
+        .map(|start| {
+            let mut prompt = String::new();
+            let mut end = 0;
+            for (range, from, to, when) in &edits {
+                prompt.push_str(&text[end..range.start]);
+                prompt.push_str(if start < *when { from } else { to });
+                end = range.end;
+            }
+            prompt.push_str(&text[end..]);
+
+            (start, prompt)
+        })
+        .collect();
+
+    Ok(schedule)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_prompt_schedule() {
+        assert_eq!(parse_prompt_schedule("a photo of a cat").unwrap(), vec![(0.0, "a photo of a cat".to_string())]);
+        assert_eq!(parse_prompt_schedule("a [cat] on [grass:1.2]").unwrap(), vec![(0.0, "a [cat] on [grass:1.2]".to_string())]);
+
+        assert_eq!(
+            parse_prompt_schedule("a [cat:dog:0.5] on a [chair:sofa: 0.25]").unwrap(), 
+            vec![
+                (0.0, "a cat on a chair".to_string()), 
+                (0.25, "a cat on a sofa".to_string()), 
+                (0.5, "a dog on a sofa".to_string()), 
+            ]
+        );
+
+        // an empty side removes the text for that part of the steps
+        assert_eq!(
+            parse_prompt_schedule("a (cat:1.2) [:in a hat:0.3]").unwrap(), 
+            vec![
+                (0.0, "a (cat:1.2) ".to_string()), 
+                (0.3, "a (cat:1.2) in a hat".to_string()), 
+            ]
+        );
+
+        assert_eq!(parse_prompt_schedule("\\[cat:dog:0.5]").unwrap(), vec![(0.0, "\\[cat:dog:0.5]".to_string())]);
+        assert!(parse_prompt_schedule("[cat:dog:10]").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::error::Error;
+use std::ops::Range;
+
+/// Textual inversion embeddings registered with a tokenizer. Every learned vector gets its own token id
+/// past the end of the vocabulary, counting up from `first_id`, so a trigger word with several vectors
+/// encodes to several consecutive ids. The text encoder looks these ids up in `vectors` instead of its
+/// token embedding table.
+#[derive(Debug, Clone)]
+pub struct TextualInversions {
+    first_id: u32,
+    triggers: Vec<(String, Range<u32>)>,
+    vectors: Vec<Vec<f32>>,
+}
+
+impl TextualInversions {
+    pub fn new(first_id: u32) -> Self {
+        Self {
+            first_id,
+            triggers: Vec::new(),
+            vectors: Vec::new(),
+        }
+    }
+
+    /// Registers `trigger` and returns the ids it encodes to. Triggers are matched case insensitively.
+    /// Registering a trigger again replaces its embedding, in place if the number of vectors is unchanged.
+    pub fn add(&mut self, trigger: &str, vectors: Vec<Vec<f32>>) -> Result<Range<u32>, Box<dyn Error>> {
+        if trigger.is_empty() {
+            return Err( "The textual inversion trigger must not be empty.".into() );
+        }
+        if vectors.is_empty() {
+            return Err( format!("The textual inversion embedding for {} has no vectors.", trigger).into() );
+        }
+
+        let trigger = trigger.to_lowercase();
+        if let Some(index) = self.triggers.iter().position(|(t, _)| t == &trigger) {
+            let ids = self.triggers[index].1.clone();
+            let slot = (ids.start - self.first_id) as usize..(ids.end - self.first_id) as usize;
+
+            if slot.len() == vectors.len() {
+                self.vectors.splice(slot, vectors);
+                return Ok(ids);
+            }
+
+            // free the old ids, moving the later embeddings down to close the gap
+            self.vectors.drain(slot);
+            self.triggers.remove(index);
+            let n_freed = ids.end - ids.start;
+            for (_, later) in self.triggers.iter_mut().filter(|(_, later)| later.start >= ids.end) {
+                *later = later.start - n_freed..later.end - n_freed;
+            }
+        }
+
+        let start = self.first_id + self.vectors.len() as u32;
+        let ids = start..start + vectors.len() as u32;
+        self.vectors.extend(vectors);
+        self.triggers.push((trigger, ids.clone()));
+
+        Ok(ids)
+    }
+
+    pub fn first_id(&self) -> u32 {
+        self.first_id
+    }
+
+    /// The learned vectors, indexed by token id minus `first_id`.
+    pub fn vectors(&self) -> &[Vec<f32>] {
+        &self.vectors
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.triggers.is_empty()
+    }
+
+    /// Replaces every trigger word in `text` with its ids and encodes the text in between with `encode`.
+    /// The text is lowercased first, as both CLIP tokenizers lowercase anyway.
+    pub fn encode(&self, text: &str, mut encode: impl FnMut(&str) -> Vec<u32>) -> Vec<u32> {
+        let text = text.to_lowercase();
+        let mut rest = &text[..];
+        let mut tokens = Vec::new();
+
+        // the earliest match wins, and the longest trigger among matches at the same position
+        while let Some((position, trigger, ids)) = self.triggers
+            .iter()
+            .filter_map(|(trigger, ids)| rest.find(trigger.as_str()).map(|position| (position, trigger, ids)))
+            .min_by_key(|(position, trigger, _)| (*position, usize::MAX - trigger.len())) {
+            tokens.extend(encode(&rest[..position]));
+            tokens.extend(ids.clone());
+            rest = &rest[position + trigger.len()..];
+        }
+
+        tokens.extend(encode(rest));
+        tokens
+    }
+
+    /// Decodes runs of vocabulary tokens with `decode` and writes each trigger word once in place of its ids.
+    pub fn decode(&self, tokens: &[u32], decode: impl Fn(&[u32]) -> String) -> String {
+        let mut text = String::new();
+        let mut start = 0;
+
+        for (i, &token) in tokens.iter().enumerate() {
+            if token < self.first_id {
+                continue;
+            }
+
+            text += &decode(&tokens[start..i]);
+            start = i + 1;
+
+            if let Some((trigger, _)) = self.triggers.iter().find(|(_, ids)| ids.start == token) {
+                text += trigger;
+                text += " ";
+            }
+        }
+
+        text + &decode(&tokens[start..])
+    }
+}
@@ -0,0 +1,97 @@
+use regex::Regex;
+
+/// Multiplier applied by each `(...)` group, and divided out by each `[...]` group.
+pub const EMPHASIS_MULTIPLIER: f32 = 1.1;
+
+/// Splits a prompt into text segments and their attention weights using the usual 
+/// `(emphasis)`, `[de-emphasis]` and `(text:1.3)` syntax. Brackets can be escaped with `\`. 
+/// Adjacent segments with equal weight are merged, so a prompt without any weighting syntax 
+/// comes back as a single segment with weight 1.0.
+pub fn parse_prompt_attention(text: &str) -> Vec<(String, f32)> {
+    let re_attention = Regex::new(r"\\\(|\\\)|\\\[|\\\]|\\\\|\\|\(|\[|:\s*([+-]?[.\d]+)\s*\)|\)|\]|[^\\()\[\]:]+|:").unwrap();
+
+    let mut res: Vec<(String, f32)> = Vec::new();
+    let mut round_brackets = Vec::new();
+    let mut square_brackets = Vec::new();
+
+    fn multiply_range(res: &mut [(String, f32)], start: usize, multiplier: f32) {
+        for (_, weight) in &mut res[start..] {
+            *weight *= multiplier;
+        }
+    }
+
+    for m in re_attention.captures_iter(text) {
+        let token = &m[0];
+        let weight = m.get(1).and_then(|w| w.as_str().parse::<f32>().ok());
+
+        if token.starts_with('\\') && token.len() > 1 {
+            res.push((token[1..].to_string(), 1.0));
+        } else if token == "(" {
+            round_brackets.push(res.len());
+        } else if token == "[" {
+            square_brackets.push(res.len());
+        } else if weight.is_some() && !round_brackets.is_empty() {
+            let start = round_brackets.pop().unwrap();
+            multiply_range(&mut res, start, weight.unwrap());
+        } else if token == ")" && !round_brackets.is_empty() {
+            let start = round_brackets.pop().unwrap();
+            multiply_range(&mut res, start, EMPHASIS_MULTIPLIER);
+        } else if token == "]" && !square_brackets.is_empty() {
+            let start = square_brackets.pop().unwrap();
+            multiply_range(&mut res, start, 1.0 / EMPHASIS_MULTIPLIER);
+        } else {
+            res.push((token.to_string(), 1.0));
+        }
+    }
+
+    // unclosed brackets still apply
+    for start in round_brackets {
+        multiply_range(&mut res, start, EMPHASIS_MULTIPLIER);
+    }
+    for start in square_brackets {
+        multiply_range(&mut res, start, 1.0 / EMPHASIS_MULTIPLIER);
+    }
+
+    if res.is_empty() {
+        return vec![(String::new(), 1.0)];
+    }
+
+    let mut merged: Vec<(String, f32)> = Vec::new();
+    for (segment, weight) in res {
+        match merged.last_mut() {
+            Some((last, last_weight)) if *last_weight == weight => last.push_str(&segment), 
+            _ => merged.push((segment, weight)), 
+        }
+    }
+
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_prompt_attention() {
+        assert_eq!(parse_prompt_attention("a photo of a cat"), vec![("a photo of a cat".to_string(), 1.0)]);
+        assert_eq!(parse_prompt_attention("a: b"), vec![("a: b".to_string(), 1.0)]);
+
+        assert_eq!(
+            parse_prompt_attention("a (cat:1.5) on [grass]"), 
+            vec![
+                ("a ".to_string(), 1.0), 
+                ("cat".to_string(), 1.5), 
+                (" on ".to_string(), 1.0), 
+                ("grass".to_string(), 1.0 / EMPHASIS_MULTIPLIER), 
+            ]
+        );
+
+        assert_eq!(
+            parse_prompt_attention("((cat)) \\(dog\\)"), 
+            vec![
+                ("cat".to_string(), EMPHASIS_MULTIPLIER * EMPHASIS_MULTIPLIER), 
+                (" (dog)".to_string(), 1.0), 
+            ]
+        );
+    }
+}